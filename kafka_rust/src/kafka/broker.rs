@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, RwLock};
+
+use anyhow::Result;
+use bytes::BytesMut;
+
+use crate::kafka::codec::{Decode, Int32};
+use crate::kafka::common::ErrorCode;
+use crate::kafka::config::BrokerConfig;
+use crate::kafka::fetch_session::FetchSessionCache;
+use crate::kafka::handlers::{self, api_versions};
+use crate::kafka::header::RequestHeader;
+use crate::kafka::log::{LogConfig, LogManager};
+use crate::kafka::metadata_image::{MetadataImage, PartitionImage, ScramCredential};
+use crate::kafka::metadata_log_file::MetadataLogWriter;
+use crate::kafka::registry::{ApiHandler, ApiRegistry};
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::AllRequests;
+use crate::kafka::responses::Response;
+
+/// State shared by every connection.
+#[derive(Debug)]
+pub struct Broker {
+  pub config: BrokerConfig,
+  pub apis: ApiRegistry,
+  pub metadata: MetadataImage,
+  pub logs: LogManager,
+  pub fetch_sessions: FetchSessionCache,
+  /// SCRAM credentials by user and mechanism, the image's as changed since by
+  /// AlterUserScramCredentials.
+  pub scram_credentials: RwLock<BTreeMap<(String, i8), ScramCredential>>,
+  pub metadata_log: Mutex<MetadataLogWriter>,
+}
+
+impl Broker {
+  pub fn new(config: BrokerConfig, metadata: MetadataImage) -> Broker {
+    let log_config = LogConfig::from(&config);
+    let topic_configs = metadata.topics()
+      .filter_map(|topic| {
+        let overrides = metadata.topic_config(&topic.name)?;
+        Some((topic.name.clone(), log_config.with_overrides(overrides)))
+      })
+      .collect();
+    Broker {
+      logs: LogManager::new(config.log_dirs.clone(), log_config, topic_configs),
+      fetch_sessions: FetchSessionCache::new(config.max_incremental_fetch_session_cache_slots),
+      scram_credentials: RwLock::new(metadata.scram_credentials.clone()),
+      metadata_log: Mutex::new(MetadataLogWriter::new(config.metadata_log_dir())),
+      config,
+      apis: handlers::registry(),
+      metadata,
+    }
+  }
+
+  /// The partition if this broker leads it in `current_leader_epoch`, the epoch the client
+  /// last saw.
+  pub fn leader_partition(&self, topic: &str, partition: i32, current_leader_epoch: i32) -> Result<&PartitionImage, ErrorCode> {
+    let partition = self.metadata.topic(topic)
+      .and_then(|topic| topic.partitions.get(&partition))
+      .ok_or(ErrorCode::UnknownTopicOrPartition)?;
+    if partition.leader != self.config.node_id {
+      return Err(ErrorCode::NotLeaderOrFollower);
+    }
+    // -1 means the client doesn't track leader epochs
+    match current_leader_epoch {
+      epoch if epoch < 0 => Ok(partition),
+      epoch if epoch < partition.leader_epoch => Err(ErrorCode::FencedLeaderEpoch),
+      epoch if epoch > partition.leader_epoch => Err(ErrorCode::UnknownLeaderEpoch),
+      _ => Ok(partition),
+    }
+  }
+
+  /// Decodes one request frame (message_size prefix included) and runs its handler.
+  ///
+  /// Failures inside a handler are answered with that api's error response. An `Err` here
+  /// means there is nothing sensible to answer (unknown api, unsupported version, a body
+  /// that doesn't decode) and, like Kafka, the connection should be closed. `None` is a
+  /// request the client expects no answer to. Apis not exposed on the context's listener are
+  /// treated as unknown.
+  pub fn handle_request(&self, context: &RequestContext, frame: BytesMut) -> Result<Option<Response>> {
+    let (handler, header, frame) = self.decode_header(context, frame)?;
+
+    // An unsupported version could have any body layout so don't try to decode it. Clients
+    // probe with their newest ApiVersions, which gets a v0 answer listing what we do support.
+    if !handler.supports(header.request_api_version) {
+      if header.request_api_key == api_versions::API_KEY {
+        return Ok(Some(api_versions::unsupported_version(self, context, &header)));
+      }
+      anyhow::bail!("{}: {} v{}", ErrorCode::UnsupportedVersion, handler.name, header.request_api_version);
+    }
+    let body = Self::decode_body(handler, &header, frame)?;
+
+    match (handler.handle)(self, context, &header, &body) {
+      Ok(_) if !body.expects_response() => Ok(None),
+      Ok(response) => Ok(Some(response)),
+      // The client isn't reading a response, closing the connection is the only way to tell it
+      Err(e) if !body.expects_response() => {
+        anyhow::bail!("{} failed with no response expected: {:#}", handler.name, e)
+      }
+      Err(e) => {
+        let error_code = e.downcast_ref::<ErrorCode>().copied().unwrap_or(ErrorCode::UnknownServerError);
+        let client_id = header.client_id.as_deref().unwrap_or("-");
+        println!("{} from client {} failed, answering {}: {:#}", handler.name, client_id, error_code, e);
+        Ok(Some(Response::new(&header, (handler.error_response)(&body, error_code))))
+      }
+    }
+  }
+
+  /// Decodes a request frame like `handle_request` but leaves handling it to the caller,
+  /// which is how SASL authentication gets at SaslHandshake and SaslAuthenticate.
+  pub fn decode_request(&self, context: &RequestContext, frame: BytesMut) -> Result<(RequestHeader, AllRequests)> {
+    let (handler, header, frame) = self.decode_header(context, frame)?;
+    if !handler.supports(header.request_api_version) {
+      anyhow::bail!("{}: {} v{}", ErrorCode::UnsupportedVersion, handler.name, header.request_api_version);
+    }
+    let body = Self::decode_body(handler, &header, frame)?;
+    Ok((header, body))
+  }
+
+  fn decode_header(&self, context: &RequestContext, mut frame: BytesMut) -> Result<(&ApiHandler, RequestHeader, BytesMut)> {
+    let _message_size = Int32::decode(&mut frame)?; // the framing layer already checked it
+    let header = RequestHeader::decode(&mut frame, &self.apis)?;
+
+    let Some(handler) = self.apis.get(header.request_api_key).filter(|handler| handler.exposed_on(context.listener_type)) else {
+      anyhow::bail!(
+        "unknown api key {} on listener {} (correlation id {})",
+        header.request_api_key, context.listener_name, header.correlation_id
+      );
+    };
+    println!("process {} v{}", handler.name, header.request_api_version);
+    Ok((handler, header, frame))
+  }
+
+  fn decode_body(handler: &ApiHandler, header: &RequestHeader, frame: BytesMut) -> Result<AllRequests> {
+    AllRequests::from_bytes(header, frame)
+      .map_err(|e| anyhow::anyhow!("{}: malformed {} v{}: {:#}", ErrorCode::InvalidRequest, handler.name, header.request_api_version, e))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::BufMut;
+
+  use super::*;
+  use crate::kafka::handlers::metadata;
+  use crate::kafka::metadata_image::MetadataImage;
+  use crate::kafka::registry::ListenerType;
+  use crate::kafka::responses::AllResponses;
+  use crate::kafka::test_support::{self, temp_dir};
+
+  /// A request frame, message_size included, with a v1 header or a v2 one when `flexible`.
+  fn frame(api_key: i16, api_version: i16, flexible: bool, body: &[u8]) -> BytesMut {
+    let mut request = BytesMut::new();
+    request.put_i16(api_key);
+    request.put_i16(api_version);
+    request.put_i32(7);
+    request.put_i16(4);
+    request.put_slice(b"test");
+    if flexible {
+      request.put_u8(0);
+    }
+    request.put_slice(body);
+    let mut frame = BytesMut::new();
+    frame.put_i32(request.len() as i32);
+    frame.put_slice(&request);
+    frame
+  }
+
+  /// An ApiVersions v3 body, client software name and version as compact strings.
+  fn api_versions_v3(name: &str, version: &str) -> Vec<u8> {
+    let mut body = vec![];
+    for field in [name, version] {
+      body.push(field.len() as u8 + 1);
+      body.extend_from_slice(field.as_bytes());
+    }
+    body.push(0);
+    body
+  }
+
+  fn broker() -> Broker {
+    Broker::new(BrokerConfig::default(), MetadataImage::empty())
+  }
+
+  fn error_code(response: &Response) -> i16 {
+    let AllResponses::ApiVersionsResponse(body) = &response.body else {
+      panic!("expected an ApiVersions response, got {:?}", response.body);
+    };
+    body.error_code
+  }
+
+  #[test]
+  fn finds_partitions_led_here_in_the_clients_epoch() {
+    let log_dirs = temp_dir();
+    let mut broker = test_support::broker(log_dirs.path(), 1, 3);
+    let epoch = |broker: &Broker, topic: &str, partition: i32, current_leader_epoch: i32| {
+      broker.leader_partition(topic, partition, current_leader_epoch).map(|partition| partition.leader_epoch)
+    };
+    assert_eq!(epoch(&broker, "foo", 0, -1), Ok(3));
+    assert_eq!(epoch(&broker, "foo", 0, 3), Ok(3));
+    assert_eq!(epoch(&broker, "foo", 0, 2), Err(ErrorCode::FencedLeaderEpoch));
+    assert_eq!(epoch(&broker, "foo", 0, 4), Err(ErrorCode::UnknownLeaderEpoch));
+    assert_eq!(epoch(&broker, "foo", 1, -1), Err(ErrorCode::UnknownTopicOrPartition));
+    assert_eq!(epoch(&broker, "bar", 0, -1), Err(ErrorCode::UnknownTopicOrPartition));
+
+    broker.config.node_id = 2;
+    assert_eq!(epoch(&broker, "foo", 0, 3), Err(ErrorCode::NotLeaderOrFollower));
+  }
+
+  #[test]
+  fn answers_handler_failures_with_the_apis_error_response() {
+    let broker = broker();
+    let ok = broker.handle_request(&RequestContext::default(), frame(api_versions::API_KEY, 3, true, &api_versions_v3("client", "1.0")));
+    let ok = ok.unwrap().unwrap();
+    assert_eq!((ok.header.correlation_id, error_code(&ok)), (7, ErrorCode::None.code()));
+
+    let invalid = broker.handle_request(&RequestContext::default(), frame(api_versions::API_KEY, 3, true, &api_versions_v3("bad name!", "1.0")));
+    let invalid = invalid.unwrap().unwrap();
+    assert_eq!((invalid.header.correlation_id, invalid.api_version), (7, 3));
+    assert_eq!(error_code(&invalid), ErrorCode::InvalidRequest.code());
+  }
+
+  #[test]
+  fn answers_unsupported_api_versions_with_v0_and_closes_on_other_apis() {
+    let broker = broker();
+    let probe = broker.handle_request(&RequestContext::default(), frame(api_versions::API_KEY, 99, true, &[])).unwrap().unwrap();
+    assert_eq!((probe.api_version, error_code(&probe)), (0, ErrorCode::UnsupportedVersion.code()));
+
+    let error = broker.handle_request(&RequestContext::default(), frame(metadata::API_KEY, 99, true, &[])).unwrap_err();
+    assert!(error.to_string().starts_with("UNSUPPORTED_VERSION (35): Metadata v99"), "{}", error);
+    let error = broker.decode_request(&RequestContext::default(), frame(api_versions::API_KEY, 99, true, &[])).unwrap_err();
+    assert!(error.to_string().starts_with("UNSUPPORTED_VERSION (35)"), "{}", error);
+  }
+
+  #[test]
+  fn closes_on_unknown_and_unexposed_apis() {
+    let broker = broker();
+    let error = broker.handle_request(&RequestContext::default(), frame(999, 0, false, &[])).unwrap_err();
+    assert!(error.to_string().starts_with("unknown api key 999"), "{}", error);
+
+    let controller = RequestContext { listener_name: "CONTROLLER".to_string(), listener_type: ListenerType::Controller, ..Default::default() };
+    let error = broker.handle_request(&controller, frame(metadata::API_KEY, 1, false, &[0, 0, 0, 0])).unwrap_err();
+    assert!(error.to_string().starts_with("unknown api key 3 on listener CONTROLLER"), "{}", error);
+  }
+
+  #[test]
+  fn closes_on_bodies_that_dont_decode() {
+    let broker = broker();
+    let mut truncated = api_versions_v3("client", "1.0");
+    truncated.truncate(4);
+    let error = broker.handle_request(&RequestContext::default(), frame(api_versions::API_KEY, 3, true, &truncated)).unwrap_err();
+    assert!(error.to_string().starts_with("INVALID_REQUEST (42): malformed ApiVersions v3"), "{}", error);
+
+    let error = broker.handle_request(&RequestContext::default(), BytesMut::from(&[0, 0, 0, 2, 0][..])).unwrap_err();
+    assert!(error.to_string().contains("not enough bytes"), "{:#}", error);
+  }
+}
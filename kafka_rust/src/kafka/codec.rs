@@ -0,0 +1,722 @@
+use std::marker::PhantomData;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+// Wire types from https://kafka.apache.org/protocol.html#protocol_types
+//
+// Every wire type is a zero sized marker implementing `Encode<T>` / `Decode<T>` for the
+// rust value it maps to, e.g. `CompactString::encode(buf, &name)` or `Int32::decode(buf)?`.
+// This way structs hold plain values and the message decides per version whether a field
+// goes out as a STRING or a COMPACT_STRING.
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+  #[error("not enough bytes: needed {needed}, {remaining} remaining")]
+  Underflow { needed: usize, remaining: usize },
+  #[error("invalid length: {0}")]
+  InvalidLength(i64),
+  #[error("null value for non-nullable field")]
+  UnexpectedNull,
+  #[error("invalid UTF-8 in string")]
+  InvalidUtf8,
+  #[error("varint is too long")]
+  VarIntTooLong,
+  #[error("unsupported record batch magic {0}")]
+  UnsupportedMagic(i8),
+  #[error("unknown record batch compression codec {0}")]
+  UnknownCompression(i16),
+  #[error("records don't decompress: {0}")]
+  Decompression(std::io::Error),
+  #[error("records decompress to more than {0} bytes")]
+  DecompressedTooLarge(usize),
+}
+
+pub type Result<T> = std::result::Result<T, CodecError>;
+
+pub trait Encode<T: ?Sized> {
+  fn encode(buf: &mut BytesMut, value: &T);
+}
+
+pub trait Decode<T> {
+  fn decode<B: Buf>(buf: &mut B) -> Result<T>;
+}
+
+/// Request/response bodies whose layout depends on the api version.
+pub trait EncodeVersioned {
+  fn encode(&self, buf: &mut BytesMut, version: i16);
+}
+
+pub trait DecodeVersioned: Sized {
+  fn decode<B: Buf>(buf: &mut B, version: i16) -> Result<Self>;
+}
+
+fn ensure<B: Buf>(buf: &B, needed: usize) -> Result<()> {
+  if buf.remaining() < needed {
+    return Err(CodecError::Underflow { needed, remaining: buf.remaining() });
+  }
+  Ok(())
+}
+
+macro_rules! fixed_width {
+  ($name:ident, $ty:ty, $get:ident, $put:ident) => {
+    pub struct $name;
+
+    impl Encode<$ty> for $name {
+      fn encode(buf: &mut BytesMut, value: &$ty) {
+        buf.$put(*value);
+      }
+    }
+
+    impl Decode<$ty> for $name {
+      fn decode<B: Buf>(buf: &mut B) -> Result<$ty> {
+        ensure(buf, std::mem::size_of::<$ty>())?;
+        Ok(buf.$get())
+      }
+    }
+  };
+}
+
+fixed_width!(Int8, i8, get_i8, put_i8);
+fixed_width!(Int16, i16, get_i16, put_i16);
+fixed_width!(Int32, i32, get_i32, put_i32);
+fixed_width!(Int64, i64, get_i64, put_i64);
+fixed_width!(UInt16, u16, get_u16, put_u16);
+fixed_width!(UInt32, u32, get_u32, put_u32);
+fixed_width!(Float64, f64, get_f64, put_f64);
+
+pub struct Boolean;
+
+impl Encode<bool> for Boolean {
+  fn encode(buf: &mut BytesMut, value: &bool) {
+    buf.put_u8(*value as u8);
+  }
+}
+
+impl Decode<bool> for Boolean {
+  fn decode<B: Buf>(buf: &mut B) -> Result<bool> {
+    ensure(buf, 1)?;
+    Ok(buf.get_u8() != 0)
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uuid(pub u128);
+
+impl Uuid {
+  pub const ZERO: Uuid = Uuid(0);
+}
+
+impl std::fmt::Display for Uuid {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let b = self.0.to_be_bytes();
+    for (i, byte) in b.iter().enumerate() {
+      if matches!(i, 4 | 6 | 8 | 10) {
+        write!(f, "-")?;
+      }
+      write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+  }
+}
+
+impl Encode<Uuid> for Uuid {
+  fn encode(buf: &mut BytesMut, value: &Uuid) {
+    buf.put_u128(value.0);
+  }
+}
+
+impl Decode<Uuid> for Uuid {
+  fn decode<B: Buf>(buf: &mut B) -> Result<Uuid> {
+    ensure(buf, 16)?;
+    Ok(Uuid(buf.get_u128()))
+  }
+}
+
+pub struct UnsignedVarInt;
+
+impl Encode<u32> for UnsignedVarInt {
+  fn encode(buf: &mut BytesMut, value: &u32) {
+    let mut v = *value;
+    while v >= 0x80 {
+      buf.put_u8((v as u8 & 0x7f) | 0x80);
+      v >>= 7;
+    }
+    buf.put_u8(v as u8);
+  }
+}
+
+impl Decode<u32> for UnsignedVarInt {
+  fn decode<B: Buf>(buf: &mut B) -> Result<u32> {
+    let mut value: u32 = 0;
+    for i in 0..5 {
+      ensure(buf, 1)?;
+      let b = buf.get_u8();
+      // The 5th byte only has room for the top 4 bits
+      if i == 4 && b & 0x70 != 0 {
+        return Err(CodecError::VarIntTooLong);
+      }
+      value |= ((b & 0x7f) as u32) << (i * 7);
+      if b & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    Err(CodecError::VarIntTooLong)
+  }
+}
+
+pub struct UnsignedVarLong;
+
+impl Encode<u64> for UnsignedVarLong {
+  fn encode(buf: &mut BytesMut, value: &u64) {
+    let mut v = *value;
+    while v >= 0x80 {
+      buf.put_u8((v as u8 & 0x7f) | 0x80);
+      v >>= 7;
+    }
+    buf.put_u8(v as u8);
+  }
+}
+
+impl Decode<u64> for UnsignedVarLong {
+  fn decode<B: Buf>(buf: &mut B) -> Result<u64> {
+    let mut value: u64 = 0;
+    for i in 0..10 {
+      ensure(buf, 1)?;
+      let b = buf.get_u8();
+      // The 10th byte only has room for the top bit
+      if i == 9 && b & 0x7e != 0 {
+        return Err(CodecError::VarIntTooLong);
+      }
+      value |= ((b & 0x7f) as u64) << (i * 7);
+      if b & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    Err(CodecError::VarIntTooLong)
+  }
+}
+
+/// Zigzag encoded signed 32-bit varint, as used inside records.
+pub struct VarInt;
+
+impl Encode<i32> for VarInt {
+  fn encode(buf: &mut BytesMut, value: &i32) {
+    let zigzag = ((value << 1) ^ (value >> 31)) as u32;
+    UnsignedVarInt::encode(buf, &zigzag);
+  }
+}
+
+impl Decode<i32> for VarInt {
+  fn decode<B: Buf>(buf: &mut B) -> Result<i32> {
+    let zigzag = UnsignedVarInt::decode(buf)?;
+    Ok(((zigzag >> 1) as i32) ^ -((zigzag & 1) as i32))
+  }
+}
+
+/// Zigzag encoded signed 64-bit varint, as used inside records.
+pub struct VarLong;
+
+impl Encode<i64> for VarLong {
+  fn encode(buf: &mut BytesMut, value: &i64) {
+    let zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    UnsignedVarLong::encode(buf, &zigzag);
+  }
+}
+
+impl Decode<i64> for VarLong {
+  fn decode<B: Buf>(buf: &mut B) -> Result<i64> {
+    let zigzag = UnsignedVarLong::decode(buf)?;
+    Ok(((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64))
+  }
+}
+
+fn read_bytes<B: Buf>(buf: &mut B, len: usize) -> Result<Bytes> {
+  ensure(buf, len)?;
+  Ok(buf.copy_to_bytes(len))
+}
+
+fn read_string<B: Buf>(buf: &mut B, len: usize) -> Result<String> {
+  let raw = read_bytes(buf, len)?;
+  String::from_utf8(raw.to_vec()).map_err(|_| CodecError::InvalidUtf8)
+}
+
+/// Reads the `N + 1` length used by compact types, `None` meaning null.
+fn read_compact_len<B: Buf>(buf: &mut B) -> Result<Option<usize>> {
+  match UnsignedVarInt::decode(buf)? {
+    0 => Ok(None),
+    n => Ok(Some(n as usize - 1)),
+  }
+}
+
+fn put_compact_len(buf: &mut BytesMut, len: Option<usize>) {
+  let n = len.map_or(0, |len| len as u32 + 1);
+  UnsignedVarInt::encode(buf, &n);
+}
+
+/// STRING: INT16 length followed by UTF-8 bytes.
+pub struct KafkaString;
+
+impl Encode<String> for KafkaString {
+  fn encode(buf: &mut BytesMut, value: &String) {
+    buf.put_i16(value.len() as i16);
+    buf.put_slice(value.as_bytes());
+  }
+}
+
+impl Decode<String> for KafkaString {
+  fn decode<B: Buf>(buf: &mut B) -> Result<String> {
+    match Int16::decode(buf)? {
+      -1 => Err(CodecError::UnexpectedNull),
+      len if len < 0 => Err(CodecError::InvalidLength(len as i64)),
+      len => read_string(buf, len as usize),
+    }
+  }
+}
+
+/// NULLABLE_STRING: like STRING but a length of -1 means null.
+pub struct NullableString;
+
+impl Encode<Option<String>> for NullableString {
+  fn encode(buf: &mut BytesMut, value: &Option<String>) {
+    match value {
+      Some(s) => KafkaString::encode(buf, s),
+      None => buf.put_i16(-1),
+    }
+  }
+}
+
+impl Decode<Option<String>> for NullableString {
+  fn decode<B: Buf>(buf: &mut B) -> Result<Option<String>> {
+    match Int16::decode(buf)? {
+      -1 => Ok(None),
+      len if len < 0 => Err(CodecError::InvalidLength(len as i64)),
+      len => read_string(buf, len as usize).map(Some),
+    }
+  }
+}
+
+/// COMPACT_STRING: UNSIGNED_VARINT length + 1 followed by UTF-8 bytes.
+pub struct CompactString;
+
+impl Encode<String> for CompactString {
+  fn encode(buf: &mut BytesMut, value: &String) {
+    put_compact_len(buf, Some(value.len()));
+    buf.put_slice(value.as_bytes());
+  }
+}
+
+impl Decode<String> for CompactString {
+  fn decode<B: Buf>(buf: &mut B) -> Result<String> {
+    match read_compact_len(buf)? {
+      Some(len) => read_string(buf, len),
+      None => Err(CodecError::UnexpectedNull),
+    }
+  }
+}
+
+/// COMPACT_NULLABLE_STRING: like COMPACT_STRING but a length of 0 means null.
+pub struct CompactNullableString;
+
+impl Encode<Option<String>> for CompactNullableString {
+  fn encode(buf: &mut BytesMut, value: &Option<String>) {
+    match value {
+      Some(s) => CompactString::encode(buf, s),
+      None => put_compact_len(buf, None),
+    }
+  }
+}
+
+impl Decode<Option<String>> for CompactNullableString {
+  fn decode<B: Buf>(buf: &mut B) -> Result<Option<String>> {
+    match read_compact_len(buf)? {
+      Some(len) => read_string(buf, len).map(Some),
+      None => Ok(None),
+    }
+  }
+}
+
+/// BYTES: INT32 length followed by raw bytes.
+pub struct KafkaBytes;
+
+impl Encode<Bytes> for KafkaBytes {
+  fn encode(buf: &mut BytesMut, value: &Bytes) {
+    buf.put_i32(value.len() as i32);
+    buf.put_slice(value);
+  }
+}
+
+impl Decode<Bytes> for KafkaBytes {
+  fn decode<B: Buf>(buf: &mut B) -> Result<Bytes> {
+    match Int32::decode(buf)? {
+      -1 => Err(CodecError::UnexpectedNull),
+      len if len < 0 => Err(CodecError::InvalidLength(len as i64)),
+      len => read_bytes(buf, len as usize),
+    }
+  }
+}
+
+/// NULLABLE_BYTES: like BYTES but a length of -1 means null.
+pub struct NullableBytes;
+
+impl Encode<Option<Bytes>> for NullableBytes {
+  fn encode(buf: &mut BytesMut, value: &Option<Bytes>) {
+    match value {
+      Some(b) => KafkaBytes::encode(buf, b),
+      None => buf.put_i32(-1),
+    }
+  }
+}
+
+impl Decode<Option<Bytes>> for NullableBytes {
+  fn decode<B: Buf>(buf: &mut B) -> Result<Option<Bytes>> {
+    match Int32::decode(buf)? {
+      -1 => Ok(None),
+      len if len < 0 => Err(CodecError::InvalidLength(len as i64)),
+      len => read_bytes(buf, len as usize).map(Some),
+    }
+  }
+}
+
+/// COMPACT_BYTES: UNSIGNED_VARINT length + 1 followed by raw bytes.
+pub struct CompactBytes;
+
+impl Encode<Bytes> for CompactBytes {
+  fn encode(buf: &mut BytesMut, value: &Bytes) {
+    put_compact_len(buf, Some(value.len()));
+    buf.put_slice(value);
+  }
+}
+
+impl Decode<Bytes> for CompactBytes {
+  fn decode<B: Buf>(buf: &mut B) -> Result<Bytes> {
+    match read_compact_len(buf)? {
+      Some(len) => read_bytes(buf, len),
+      None => Err(CodecError::UnexpectedNull),
+    }
+  }
+}
+
+/// COMPACT_NULLABLE_BYTES: like COMPACT_BYTES but a length of 0 means null.
+pub struct CompactNullableBytes;
+
+impl Encode<Option<Bytes>> for CompactNullableBytes {
+  fn encode(buf: &mut BytesMut, value: &Option<Bytes>) {
+    match value {
+      Some(b) => CompactBytes::encode(buf, b),
+      None => put_compact_len(buf, None),
+    }
+  }
+}
+
+impl Decode<Option<Bytes>> for CompactNullableBytes {
+  fn decode<B: Buf>(buf: &mut B) -> Result<Option<Bytes>> {
+    match read_compact_len(buf)? {
+      Some(len) => read_bytes(buf, len).map(Some),
+      None => Ok(None),
+    }
+  }
+}
+
+/// ARRAY: INT32 length followed by the elements encoded with `E`.
+pub struct Array<E>(PhantomData<E>);
+
+impl<T, E: Encode<T>> Encode<Vec<T>> for Array<E> {
+  fn encode(buf: &mut BytesMut, value: &Vec<T>) {
+    encode_array(buf, value, false, |buf, item| E::encode(buf, item));
+  }
+}
+
+impl<T, E: Decode<T>> Decode<Vec<T>> for Array<E> {
+  fn decode<B: Buf>(buf: &mut B) -> Result<Vec<T>> {
+    decode_array(buf, false, |buf| E::decode(buf))
+  }
+}
+
+/// COMPACT_ARRAY: UNSIGNED_VARINT length + 1 followed by the elements encoded with `E`.
+pub struct CompactArray<E>(PhantomData<E>);
+
+impl<T, E: Encode<T>> Encode<Vec<T>> for CompactArray<E> {
+  fn encode(buf: &mut BytesMut, value: &Vec<T>) {
+    encode_array(buf, value, true, |buf, item| E::encode(buf, item));
+  }
+}
+
+impl<T, E: Decode<T>> Decode<Vec<T>> for CompactArray<E> {
+  fn decode<B: Buf>(buf: &mut B) -> Result<Vec<T>> {
+    decode_array(buf, true, |buf| E::decode(buf))
+  }
+}
+
+/// Writes an ARRAY (or COMPACT_ARRAY) whose elements need more context than a marker type
+/// can carry, typically the api version.
+pub fn encode_array<T>(buf: &mut BytesMut, items: &[T], compact: bool, mut f: impl FnMut(&mut BytesMut, &T)) {
+  if compact {
+    put_compact_len(buf, Some(items.len()));
+  } else {
+    buf.put_i32(items.len() as i32);
+  }
+  for item in items {
+    f(buf, item);
+  }
+}
+
+pub fn encode_nullable_array<T>(buf: &mut BytesMut, items: Option<&[T]>, compact: bool, f: impl FnMut(&mut BytesMut, &T)) {
+  match items {
+    Some(items) => encode_array(buf, items, compact, f),
+    None if compact => put_compact_len(buf, None),
+    None => buf.put_i32(-1),
+  }
+}
+
+pub fn decode_nullable_array<B: Buf, T>(buf: &mut B, compact: bool, mut f: impl FnMut(&mut B) -> Result<T>) -> Result<Option<Vec<T>>> {
+  let len = if compact {
+    match read_compact_len(buf)? {
+      Some(len) => len,
+      None => return Ok(None),
+    }
+  } else {
+    match Int32::decode(buf)? {
+      -1 => return Ok(None),
+      len if len < 0 => return Err(CodecError::InvalidLength(len as i64)),
+      len => len as usize,
+    }
+  };
+
+  // Every element takes at least one byte, anything claiming more is garbage
+  if len > buf.remaining() {
+    return Err(CodecError::InvalidLength(len as i64));
+  }
+
+  let mut items = Vec::with_capacity(len);
+  for _ in 0..len {
+    items.push(f(buf)?);
+  }
+  Ok(Some(items))
+}
+
+pub fn decode_array<B: Buf, T>(buf: &mut B, compact: bool, f: impl FnMut(&mut B) -> Result<T>) -> Result<Vec<T>> {
+  decode_nullable_array(buf, compact, f)?.ok_or(CodecError::UnexpectedNull)
+}
+
+/// STRING or COMPACT_STRING depending on whether the message version is flexible.
+pub fn encode_string(buf: &mut BytesMut, value: &String, compact: bool) {
+  if compact {
+    CompactString::encode(buf, value);
+  } else {
+    KafkaString::encode(buf, value);
+  }
+}
+
+pub fn decode_string<B: Buf>(buf: &mut B, compact: bool) -> Result<String> {
+  if compact {
+    CompactString::decode(buf)
+  } else {
+    KafkaString::decode(buf)
+  }
+}
+
+pub fn encode_nullable_string(buf: &mut BytesMut, value: &Option<String>, compact: bool) {
+  if compact {
+    CompactNullableString::encode(buf, value);
+  } else {
+    NullableString::encode(buf, value);
+  }
+}
+
+pub fn decode_nullable_string<B: Buf>(buf: &mut B, compact: bool) -> Result<Option<String>> {
+  if compact {
+    CompactNullableString::decode(buf)
+  } else {
+    NullableString::decode(buf)
+  }
+}
+
+/// Tagged fields section of a flexible version struct: an UNSIGNED_VARINT count followed by
+/// `(tag, size, data)` triples. Tags we don't know about are kept as raw bytes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaggedFields(pub Vec<(u32, Bytes)>);
+
+impl TaggedFields {
+  pub fn get(&self, tag: u32) -> Option<&Bytes> {
+    self.0.iter().find(|(t, _)| *t == tag).map(|(_, data)| data)
+  }
+
+  /// Decodes a tagged field with `E`, `None` if the tag is absent.
+  pub fn get_as<T, E: Decode<T>>(&self, tag: u32) -> Result<Option<T>> {
+    self.get_with(tag, |buf| E::decode(buf))
+  }
+
+  /// Decodes a tagged field with `f`, for fields like nullable arrays that don't have a
+  /// marker type.
+  pub fn get_with<T>(&self, tag: u32, f: impl FnOnce(&mut Bytes) -> Result<T>) -> Result<Option<T>> {
+    match self.get(tag) {
+      Some(data) => f(&mut data.clone()).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  /// Adds (or replaces) a tagged field by encoding `value` with `E`.
+  pub fn put<T, E: Encode<T>>(&mut self, tag: u32, value: &T) {
+    self.put_with(tag, |buf| E::encode(buf, value));
+  }
+
+  /// Adds (or replaces) a tagged field whose contents `f` writes, for fields like arrays of
+  /// structs that don't have a marker type.
+  pub fn put_with(&mut self, tag: u32, f: impl FnOnce(&mut BytesMut)) {
+    let mut data = BytesMut::new();
+    f(&mut data);
+    self.0.retain(|(t, _)| *t != tag);
+    self.0.push((tag, data.freeze()));
+    self.0.sort_by_key(|(t, _)| *t);
+  }
+}
+
+impl Encode<TaggedFields> for TaggedFields {
+  fn encode(buf: &mut BytesMut, value: &TaggedFields) {
+    UnsignedVarInt::encode(buf, &(value.0.len() as u32));
+    for (tag, data) in &value.0 {
+      UnsignedVarInt::encode(buf, tag);
+      UnsignedVarInt::encode(buf, &(data.len() as u32));
+      buf.put_slice(data);
+    }
+  }
+}
+
+impl Decode<TaggedFields> for TaggedFields {
+  fn decode<B: Buf>(buf: &mut B) -> Result<TaggedFields> {
+    let count = UnsignedVarInt::decode(buf)?;
+    let mut fields = vec![];
+    for _ in 0..count {
+      let tag = UnsignedVarInt::decode(buf)?;
+      let size = UnsignedVarInt::decode(buf)? as usize;
+      fields.push((tag, read_bytes(buf, size)?));
+    }
+    Ok(TaggedFields(fields))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encoded(encode: impl FnOnce(&mut BytesMut)) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    encode(&mut buf);
+    buf.to_vec()
+  }
+
+  #[test]
+  fn zigzag_varints_round_trip_at_their_limits() {
+    for (value, bytes) in [
+      (0, vec![0x00]),
+      (-1, vec![0x01]),
+      (1, vec![0x02]),
+      (i32::MAX, vec![0xfe, 0xff, 0xff, 0xff, 0x0f]),
+      (i32::MIN, vec![0xff, 0xff, 0xff, 0xff, 0x0f]),
+    ] {
+      assert_eq!(encoded(|buf| VarInt::encode(buf, &value)), bytes);
+      assert_eq!(VarInt::decode(&mut &bytes[..]).unwrap(), value);
+    }
+
+    let mut max = vec![0xfe];
+    max.extend([0xff; 8]);
+    max.push(0x01);
+    let mut min = vec![0xff; 9];
+    min.push(0x01);
+    for (value, bytes) in [(0, vec![0x00]), (-1, vec![0x01]), (i64::MAX, max), (i64::MIN, min)] {
+      assert_eq!(encoded(|buf| VarLong::encode(buf, &value)), bytes);
+      assert_eq!(VarLong::decode(&mut &bytes[..]).unwrap(), value);
+    }
+  }
+
+  #[test]
+  fn unsigned_varints_reject_bits_past_their_width() {
+    assert_eq!(UnsignedVarInt::decode(&mut &[0xff, 0xff, 0xff, 0xff, 0x0f][..]).unwrap(), u32::MAX);
+    assert!(matches!(UnsignedVarInt::decode(&mut &[0xff, 0xff, 0xff, 0xff, 0x1f][..]), Err(CodecError::VarIntTooLong)));
+    assert!(matches!(UnsignedVarInt::decode(&mut &[0x80, 0x80, 0x80, 0x80, 0x80, 0x00][..]), Err(CodecError::VarIntTooLong)));
+    assert!(matches!(UnsignedVarInt::decode(&mut &[0x80][..]), Err(CodecError::Underflow { .. })));
+
+    let mut max = vec![0xff; 9];
+    max.push(0x01);
+    assert_eq!(UnsignedVarLong::decode(&mut &max[..]).unwrap(), u64::MAX);
+    max[9] = 0x02;
+    assert!(matches!(UnsignedVarLong::decode(&mut &max[..]), Err(CodecError::VarIntTooLong)));
+  }
+
+  #[test]
+  fn compact_lengths_tell_null_from_empty() {
+    assert_eq!(encoded(|buf| CompactNullableString::encode(buf, &None)), [0x00]);
+    assert_eq!(encoded(|buf| CompactNullableString::encode(buf, &Some(String::new()))), [0x01]);
+    assert_eq!(CompactNullableString::decode(&mut &[0x00][..]).unwrap(), None);
+    assert_eq!(CompactNullableString::decode(&mut &[0x01][..]).unwrap(), Some(String::new()));
+    assert_eq!(CompactString::decode(&mut &[0x01][..]).unwrap(), "");
+    assert!(matches!(CompactString::decode(&mut &[0x00][..]), Err(CodecError::UnexpectedNull)));
+
+    assert_eq!(CompactNullableBytes::decode(&mut &[0x00][..]).unwrap(), None);
+    assert_eq!(CompactNullableBytes::decode(&mut &[0x01][..]).unwrap(), Some(Bytes::new()));
+    assert!(matches!(CompactBytes::decode(&mut &[0x00][..]), Err(CodecError::UnexpectedNull)));
+
+    assert_eq!(encoded(|buf| encode_nullable_array::<i32>(buf, None, true, |_, _| {})), [0x00]);
+    assert_eq!(encoded(|buf| CompactArray::<Int32>::encode(buf, &vec![])), [0x01]);
+    assert_eq!(decode_nullable_array(&mut &[0x00][..], true, Int32::decode).unwrap(), None);
+    assert_eq!(decode_nullable_array(&mut &[0x01][..], true, Int32::decode).unwrap(), Some(vec![]));
+    assert!(matches!(CompactArray::<Int32>::decode(&mut &[0x00][..]), Err(CodecError::UnexpectedNull)));
+  }
+
+  #[test]
+  fn nullable_strings_and_bytes_use_minus_one_for_null() {
+    assert_eq!(encoded(|buf| NullableString::encode(buf, &None)), [0xff, 0xff]);
+    assert_eq!(NullableString::decode(&mut &[0xff, 0xff][..]).unwrap(), None);
+    assert_eq!(NullableString::decode(&mut &[0x00, 0x00][..]).unwrap(), Some(String::new()));
+    assert!(matches!(NullableString::decode(&mut &[0xff, 0xfe][..]), Err(CodecError::InvalidLength(-2))));
+    assert!(matches!(KafkaString::decode(&mut &[0xff, 0xff][..]), Err(CodecError::UnexpectedNull)));
+
+    assert_eq!(encoded(|buf| NullableBytes::encode(buf, &None)), [0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(NullableBytes::decode(&mut &[0xff, 0xff, 0xff, 0xff][..]).unwrap(), None);
+    assert!(matches!(KafkaBytes::decode(&mut &[0xff, 0xff, 0xff, 0xff][..]), Err(CodecError::UnexpectedNull)));
+  }
+
+  #[test]
+  fn rejects_lengths_past_the_end_of_the_buffer() {
+    // 1000 elements claimed, 3 bytes left
+    assert!(matches!(
+      decode_array(&mut &[0x00, 0x00, 0x03, 0xe8, 1, 2, 3][..], false, Int8::decode),
+      Err(CodecError::InvalidLength(1000)),
+    ));
+    assert!(matches!(
+      decode_array(&mut &[0xe9, 0x07, 1, 2, 3][..], true, Int8::decode),
+      Err(CodecError::InvalidLength(1000)),
+    ));
+    assert!(matches!(Array::<Int8>::decode(&mut &[0xff, 0xff, 0xff, 0xfe][..]), Err(CodecError::InvalidLength(-2))));
+    assert!(matches!(decode_array(&mut &[0xff, 0xff, 0xff, 0xff][..], false, Int8::decode), Err(CodecError::UnexpectedNull)));
+
+    assert!(matches!(KafkaString::decode(&mut &[0x00, 0x0a, b'a', b'b', b'c'][..]), Err(CodecError::Underflow { needed: 10, remaining: 3 })));
+    assert!(matches!(CompactString::decode(&mut &[0x0b, b'a', b'b', b'c'][..]), Err(CodecError::Underflow { needed: 10, remaining: 3 })));
+    assert!(matches!(KafkaBytes::decode(&mut &[0x00, 0x00, 0x00, 0x0a, 1][..]), Err(CodecError::Underflow { needed: 10, remaining: 1 })));
+    assert!(matches!(KafkaString::decode(&mut &[0x00, 0x02, 0xc3, 0x28][..]), Err(CodecError::InvalidUtf8)));
+  }
+
+  #[test]
+  fn tagged_fields_are_read_whole_so_what_follows_still_decodes() {
+    let mut fields = TaggedFields::default();
+    fields.put::<String, CompactString>(3, &"three".to_string());
+    fields.put::<i32, Int32>(0, &7);
+    fields.put_with(200, |buf| buf.put_slice(&[0xaa; 300])); // a size that takes two varint bytes
+    let mut buf = BytesMut::new();
+    TaggedFields::encode(&mut buf, &fields);
+    Int16::encode(&mut buf, &0x1234);
+
+    let mut buf = buf.freeze();
+    let decoded = TaggedFields::decode(&mut buf).unwrap();
+    assert_eq!(decoded, fields);
+    assert_eq!(decoded.0.iter().map(|(tag, _)| *tag).collect::<Vec<_>>(), vec![0, 3, 200]);
+    assert_eq!(decoded.get_as::<i32, Int32>(0).unwrap(), Some(7));
+    assert_eq!(decoded.get_as::<String, CompactString>(3).unwrap(), Some("three".to_string()));
+    assert_eq!(decoded.get_as::<i32, Int32>(1).unwrap(), None);
+    assert_eq!(Int16::decode(&mut buf).unwrap(), 0x1234);
+
+    // A tag claiming more bytes than there are
+    assert!(matches!(TaggedFields::decode(&mut &[0x01, 0x00, 0x05, 0x00][..]), Err(CodecError::Underflow { .. })));
+  }
+}
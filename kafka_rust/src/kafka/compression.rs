@@ -0,0 +1,176 @@
+use std::io::{self, Read, Write};
+
+use crate::kafka::codec::{CodecError, Result};
+
+/// Xerial's snappy-java stream header, what Kafka's Java clients frame snappy data with.
+const XERIAL_MAGIC: [u8; 8] = [0x82, b'S', b'N', b'A', b'P', b'P', b'Y', 0];
+/// The magic followed by a version and a minimum compatible version, both 1.
+const XERIAL_HEADER_SIZE: usize = 16;
+/// snappy-java's default block size.
+const XERIAL_BLOCK_SIZE: usize = 32 * 1024;
+/// Kafka's default `compression.zstd.level`.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The codec of a record batch's records, stored in the low 3 bits of its attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+  None = 0,
+  Gzip = 1,
+  Snappy = 2,
+  Lz4 = 3,
+  Zstd = 4,
+}
+
+impl Compression {
+  pub fn from_id(id: i16) -> Option<Compression> {
+    match id {
+      0 => Some(Compression::None),
+      1 => Some(Compression::Gzip),
+      2 => Some(Compression::Snappy),
+      3 => Some(Compression::Lz4),
+      4 => Some(Compression::Zstd),
+      _ => None,
+    }
+  }
+
+  /// The codec a `compression.type` config names, other than `producer`.
+  pub fn from_name(name: &str) -> Option<Compression> {
+    match name {
+      "uncompressed" => Some(Compression::None),
+      "gzip" => Some(Compression::Gzip),
+      "snappy" => Some(Compression::Snappy),
+      "lz4" => Some(Compression::Lz4),
+      "zstd" => Some(Compression::Zstd),
+      _ => None,
+    }
+  }
+
+  pub fn id(self) -> i16 {
+    self as i16
+  }
+
+  pub fn compress(self, data: &[u8]) -> Vec<u8> {
+    // Writing into a Vec can't fail
+    match self {
+      Compression::None => data.to_vec(),
+      Compression::Gzip => {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+      }
+      Compression::Snappy => {
+        let mut out = XERIAL_MAGIC.to_vec();
+        out.extend_from_slice(&1i32.to_be_bytes());
+        out.extend_from_slice(&1i32.to_be_bytes());
+        let mut encoder = snap::raw::Encoder::new();
+        for block in data.chunks(XERIAL_BLOCK_SIZE) {
+          let compressed = encoder.compress_vec(block).unwrap();
+          out.extend_from_slice(&(compressed.len() as i32).to_be_bytes());
+          out.extend_from_slice(&compressed);
+        }
+        out
+      }
+      Compression::Lz4 => {
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+      }
+      Compression::Zstd => zstd::stream::encode_all(data, ZSTD_LEVEL).unwrap(),
+    }
+  }
+
+  /// Decompresses `data`, giving up once it comes to more than `max_size` bytes so a tiny
+  /// batch can't blow up into gigabytes.
+  pub fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    match self {
+      Compression::None => read_at_most(data, max_size),
+      Compression::Gzip => read_at_most(flate2::read::MultiGzDecoder::new(data), max_size),
+      Compression::Snappy => snappy_decompress(data, max_size),
+      Compression::Lz4 => read_at_most(lz4_flex::frame::FrameDecoder::new(data), max_size),
+      Compression::Zstd => read_at_most(zstd::stream::read::Decoder::new(data).map_err(CodecError::Decompression)?, max_size),
+    }
+  }
+}
+
+fn read_at_most(reader: impl Read, max_size: usize) -> Result<Vec<u8>> {
+  let mut out = Vec::new();
+  // One byte over is enough to tell it doesn't fit
+  reader.take((max_size as u64).saturating_add(1)).read_to_end(&mut out).map_err(CodecError::Decompression)?;
+  if out.len() > max_size {
+    return Err(CodecError::DecompressedTooLarge(max_size));
+  }
+  Ok(out)
+}
+
+/// Xerial framed snappy as the Java clients write it, or a bare snappy block as librdkafka
+/// does.
+fn snappy_decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+  let invalid = |e| CodecError::Decompression(io::Error::new(io::ErrorKind::InvalidData, e));
+  let truncated = |what| CodecError::Decompression(io::Error::new(io::ErrorKind::UnexpectedEof, what));
+  // Blocks say how big they decompress to, so nothing too large is ever allocated
+  let mut decoder = snap::raw::Decoder::new();
+  let mut decompress = |block: &[u8], size_so_far: usize| {
+    if size_so_far + snap::raw::decompress_len(block).map_err(invalid)? > max_size {
+      return Err(CodecError::DecompressedTooLarge(max_size));
+    }
+    decoder.decompress_vec(block).map_err(invalid)
+  };
+  if !data.starts_with(&XERIAL_MAGIC) || data.len() < XERIAL_HEADER_SIZE {
+    return decompress(data, 0);
+  }
+
+  let mut out = Vec::new();
+  let mut blocks = &data[XERIAL_HEADER_SIZE..];
+  while !blocks.is_empty() {
+    let Some((len, rest)) = blocks.split_first_chunk::<4>() else {
+      return Err(truncated("truncated snappy block length"));
+    };
+    let len = i32::from_be_bytes(*len);
+    if len < 0 || len as usize > rest.len() {
+      return Err(truncated("truncated snappy block"));
+    }
+    let (block, rest) = rest.split_at(len as usize);
+    out.extend_from_slice(&decompress(block, out.len())?);
+    blocks = rest;
+  }
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn every_codec_round_trips() {
+    // Over one xerial block
+    let data: Vec<u8> = (0..100_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+    for compression in [Compression::None, Compression::Gzip, Compression::Snappy, Compression::Lz4, Compression::Zstd] {
+      let compressed = compression.compress(&data);
+      assert_eq!(compression.decompress(&compressed, data.len()).unwrap(), data, "{:?}", compression);
+      let too_large = compression.decompress(&compressed, data.len() - 1);
+      assert!(matches!(too_large, Err(CodecError::DecompressedTooLarge(max_size)) if max_size == data.len() - 1), "{:?}", compression);
+    }
+  }
+
+  #[test]
+  fn stops_decompressing_bombs_at_the_limit() {
+    let bomb = vec![0; 16 << 20];
+    for compression in [Compression::Gzip, Compression::Snappy, Compression::Lz4, Compression::Zstd] {
+      let compressed = compression.compress(&bomb);
+      assert!(compressed.len() < 1 << 20, "{:?}", compression);
+      assert!(matches!(compression.decompress(&compressed, 1 << 20), Err(CodecError::DecompressedTooLarge(_))), "{:?}", compression);
+    }
+  }
+
+  #[test]
+  fn snappy_reads_xerial_frames_and_bare_blocks() {
+    let data = b"snappy snappy snappy snappy".repeat(3_000);
+    let framed = Compression::Snappy.compress(&data);
+    assert!(framed.starts_with(&XERIAL_MAGIC));
+    assert!(framed.len() > XERIAL_HEADER_SIZE + 4);
+
+    let bare = snap::raw::Encoder::new().compress_vec(&data).unwrap();
+    assert_eq!(Compression::Snappy.decompress(&bare, data.len()).unwrap(), data);
+    assert!(Compression::Snappy.decompress(&framed[..framed.len() - 1], data.len()).is_err());
+  }
+}
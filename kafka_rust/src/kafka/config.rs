@@ -0,0 +1,770 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use crate::kafka::log;
+use crate::kafka::sasl;
+
+const USAGE: &str = "usage: kafka [server.properties] [--override name=value]...";
+
+/// A `process.roles` entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessRole {
+  Broker,
+  Controller,
+}
+
+/// How a listener's connections are secured, what `listener.security.protocol.map` maps
+/// listener names to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityProtocol {
+  Plaintext,
+  Ssl,
+  SaslPlaintext,
+  SaslSsl,
+}
+
+impl SecurityProtocol {
+  pub const ALL: [SecurityProtocol; 4] =
+    [SecurityProtocol::Plaintext, SecurityProtocol::Ssl, SecurityProtocol::SaslPlaintext, SecurityProtocol::SaslSsl];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      SecurityProtocol::Plaintext => "PLAINTEXT",
+      SecurityProtocol::Ssl => "SSL",
+      SecurityProtocol::SaslPlaintext => "SASL_PLAINTEXT",
+      SecurityProtocol::SaslSsl => "SASL_SSL",
+    }
+  }
+
+  /// Whether connections start with a TLS handshake.
+  pub fn uses_tls(self) -> bool {
+    matches!(self, SecurityProtocol::Ssl | SecurityProtocol::SaslSsl)
+  }
+
+  /// Whether clients have to authenticate with SASL before anything but ApiVersions.
+  pub fn uses_sasl(self) -> bool {
+    matches!(self, SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl)
+  }
+}
+
+impl FromStr for SecurityProtocol {
+  type Err = String;
+
+  fn from_str(name: &str) -> std::result::Result<SecurityProtocol, String> {
+    SecurityProtocol::ALL.into_iter()
+      .find(|protocol| protocol.name().eq_ignore_ascii_case(name))
+      .ok_or_else(|| format!("unknown security protocol {:?}", name))
+  }
+}
+
+/// `ssl.client.auth`, whether TLS clients have to present a certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SslClientAuth {
+  None,
+  /// Asked for, but clients without one are let in as ANONYMOUS.
+  Requested,
+  Required,
+}
+
+/// One entry of `listeners` or `advertised.listeners`: `NAME://host:port`. An empty host
+/// listens on every interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+  pub listener_name: String,
+  pub host: String,
+  pub port: u16,
+}
+
+impl Endpoint {
+  /// The address to bind.
+  pub fn bind_address(&self) -> String {
+    let host = if self.host.is_empty() { "0.0.0.0" } else { self.host.as_str() };
+    format!("{}:{}", host, self.port)
+  }
+}
+
+impl FromStr for Endpoint {
+  type Err = String;
+
+  fn from_str(endpoint: &str) -> std::result::Result<Endpoint, String> {
+    let (listener_name, address) = endpoint.split_once("://").ok_or("expected NAME://host:port")?;
+    let (host, port) = address.rsplit_once(':').ok_or("expected NAME://host:port")?;
+    if listener_name.is_empty() {
+      return Err("missing listener name".to_string());
+    }
+    // IPv6 hosts come in brackets
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    let port = port.parse().map_err(|_| format!("invalid port {:?}", port))?;
+    Ok(Endpoint { listener_name: listener_name.to_uppercase(), host: host.to_string(), port })
+  }
+}
+
+impl fmt::Display for Endpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}://{}:{}", self.listener_name, self.host, self.port)
+  }
+}
+
+/// A `controller.quorum.voters` entry: `id@host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuorumVoter {
+  pub node_id: i32,
+  pub host: String,
+  pub port: u16,
+}
+
+impl FromStr for QuorumVoter {
+  type Err = String;
+
+  fn from_str(voter: &str) -> std::result::Result<QuorumVoter, String> {
+    let (node_id, address) = voter.split_once('@').ok_or("expected id@host:port")?;
+    let (host, port) = address.rsplit_once(':').ok_or("expected id@host:port")?;
+    Ok(QuorumVoter {
+      node_id: node_id.parse().map_err(|_| format!("invalid node id {:?}", node_id))?,
+      host: host.to_string(),
+      port: port.parse().map_err(|_| format!("invalid port {:?}", port))?,
+    })
+  }
+}
+
+/// Broker settings, named after their server.properties counterparts.
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+  /// `node.id`
+  pub node_id: i32,
+  /// `process.roles`
+  pub process_roles: Vec<ProcessRole>,
+  /// `listeners`, where the broker accepts connections.
+  pub listeners: Vec<Endpoint>,
+  /// `advertised.listeners`, where clients are told to connect, by listener name. Listeners
+  /// missing here are advertised as they're bound.
+  pub advertised_listeners: Vec<Endpoint>,
+  /// `listener.security.protocol.map`, the security protocol of each listener by name.
+  /// Controller listeners missing from it are PLAINTEXT.
+  pub listener_security_protocol_map: BTreeMap<String, SecurityProtocol>,
+  /// `inter.broker.listener.name`, the listener brokers talk to each other on.
+  pub inter_broker_listener_name: String,
+  /// `controller.listener.names`, the listeners only controllers talk on.
+  pub controller_listener_names: Vec<String>,
+  /// `controller.quorum.voters`
+  pub controller_quorum_voters: Vec<QuorumVoter>,
+  /// `ssl.keystore.location`, a PEM file with the private key and certificate chain TLS
+  /// listeners present. Reloaded when it changes.
+  pub ssl_keystore_location: Option<PathBuf>,
+  /// `ssl.truststore.location`, a PEM file with the CA certificates client certificates are
+  /// checked against. Reloaded when it changes.
+  pub ssl_truststore_location: Option<PathBuf>,
+  /// `ssl.client.auth`
+  pub ssl_client_auth: SslClientAuth,
+  /// `sasl.enabled.mechanisms`, what SASL listeners offer in SaslHandshake.
+  pub sasl_enabled_mechanisms: Vec<String>,
+  /// PLAIN passwords by listener and user, the `user_<name>="<password>"` options of
+  /// `listener.name.<listener>.plain.sasl.jaas.config`.
+  pub sasl_plain_users: BTreeMap<String, BTreeMap<String, String>>,
+  /// `sasl.oauthbearer.jwks.endpoint.url`, a `file:` URL of the JWKS OAUTHBEARER tokens are
+  /// verified with, read on every authentication. Unset, only unsecured (`alg` none) tokens
+  /// are accepted, like Kafka's unsecured validator.
+  pub sasl_oauthbearer_jwks_endpoint_url: Option<PathBuf>,
+  /// `sasl.oauthbearer.expected.audience`, tokens need one of these in `aud` when set.
+  pub sasl_oauthbearer_expected_audience: Vec<String>,
+  /// `sasl.oauthbearer.expected.issuer`, the `iss` tokens need when set.
+  pub sasl_oauthbearer_expected_issuer: Option<String>,
+  /// `sasl.oauthbearer.sub.claim.name`, the claim naming the principal.
+  pub sasl_oauthbearer_sub_claim_name: String,
+  /// `connections.max.reauth.ms`, how long a SASL session lasts before the client has to
+  /// re-authenticate, 0 for as long as the connection.
+  pub connections_max_reauth_ms: i64,
+  /// `socket.request.max.bytes`, requests declaring a larger message_size get disconnected.
+  pub socket_request_max_bytes: usize,
+  /// `max.connections`, new connections wait to be accepted while this many are open.
+  pub max_connections: usize,
+  /// `queued.max.requests`, how many requests of one connection are read ahead of the one
+  /// being handled before the broker stops reading from it.
+  pub queued_max_requests: usize,
+  /// `log.dirs` (or `log.dir`), holds the partition directories. A single directory.
+  pub log_dirs: PathBuf,
+  /// `metadata.log.dir`, holds the `__cluster_metadata-0` log. `log.dirs` when unset.
+  pub metadata_log_dir: Option<PathBuf>,
+  /// `num.partitions`, how many partitions a topic gets when it isn't told.
+  pub num_partitions: i32,
+  /// `max.request.partition.size.limit`, the most partitions one DescribeTopicPartitions
+  /// response carries.
+  pub max_request_partition_size_limit: i32,
+  /// `message.max.bytes`, the largest record batch Produce accepts.
+  pub message_max_bytes: usize,
+  /// `log.segment.bytes`, a partition log rolls to a new segment once this size is reached.
+  pub log_segment_bytes: u64,
+  /// `log.roll.ms`, the longest a segment stays active before the log rolls anyway.
+  pub log_roll_ms: i64,
+  /// `log.index.interval.bytes`, how many bytes of batches go between two index entries.
+  pub log_index_interval_bytes: u64,
+  /// `log.index.size.max.bytes`, the largest an offset or time index grows before the
+  /// segment rolls.
+  pub log_index_size_max_bytes: u64,
+  /// `log.retention.ms`, how long a segment is kept after its newest record, -1 for ever.
+  pub log_retention_ms: i64,
+  /// `log.retention.bytes`, the most a partition log keeps before its oldest segments go,
+  /// -1 for no limit.
+  pub log_retention_bytes: i64,
+  /// `log.retention.check.interval.ms`, how often logs are checked for segments to delete.
+  pub log_retention_check_interval_ms: u64,
+  /// `log.cleanup.policy`, `delete` (retention), `compact` or both comma separated.
+  pub log_cleanup_policy: String,
+  /// `log.cleaner.delete.retention.ms`, how long tombstones survive compaction.
+  pub log_cleaner_delete_retention_ms: i64,
+  /// `log.cleaner.min.cleanable.ratio`, the share of a log that has to be dirty (written
+  /// since it was last compacted) before it's compacted again.
+  pub log_cleaner_min_cleanable_ratio: f64,
+  /// `log.cleaner.min.compaction.lag.ms`, how long a record stays uncompacted.
+  pub log_cleaner_min_compaction_lag_ms: i64,
+  /// `log.cleaner.backoff.ms`, how often logs are checked for compaction.
+  pub log_cleaner_backoff_ms: u64,
+  /// `compression.type`, the codec batches are stored with, or `producer` to keep theirs.
+  pub compression_type: String,
+  /// `fetch.max.bytes`, the most record data one Fetch response carries.
+  pub fetch_max_bytes: usize,
+  /// `max.incremental.fetch.session.cache.slots`, how many fetch sessions are kept at once.
+  pub max_incremental_fetch_session_cache_slots: usize,
+}
+
+impl Default for BrokerConfig {
+  fn default() -> Self {
+    BrokerConfig {
+      node_id: 1,
+      process_roles: vec![ProcessRole::Broker],
+      listeners: vec![Endpoint { listener_name: "PLAINTEXT".to_string(), host: "127.0.0.1".to_string(), port: 9092 }],
+      advertised_listeners: vec![],
+      listener_security_protocol_map: SecurityProtocol::ALL.into_iter()
+        .map(|protocol| (protocol.name().to_string(), protocol))
+        .collect(),
+      inter_broker_listener_name: SecurityProtocol::Plaintext.name().to_string(),
+      controller_listener_names: vec![],
+      controller_quorum_voters: vec![],
+      ssl_keystore_location: None,
+      ssl_truststore_location: None,
+      ssl_client_auth: SslClientAuth::None,
+      sasl_enabled_mechanisms: vec!["GSSAPI".to_string()],
+      sasl_plain_users: BTreeMap::new(),
+      sasl_oauthbearer_jwks_endpoint_url: None,
+      sasl_oauthbearer_expected_audience: vec![],
+      sasl_oauthbearer_expected_issuer: None,
+      sasl_oauthbearer_sub_claim_name: "sub".to_string(),
+      connections_max_reauth_ms: 0,
+      socket_request_max_bytes: 100 * 1024 * 1024,
+      max_connections: i32::MAX as usize,
+      queued_max_requests: 500,
+      log_dirs: PathBuf::from("/tmp/kraft-combined-logs"),
+      metadata_log_dir: None,
+      num_partitions: 1,
+      max_request_partition_size_limit: 2000,
+      message_max_bytes: 1024 * 1024 + 12,
+      log_segment_bytes: 1024 * 1024 * 1024,
+      log_roll_ms: 7 * 24 * 60 * 60 * 1000,
+      log_index_interval_bytes: 4096,
+      log_index_size_max_bytes: 10 * 1024 * 1024,
+      log_retention_ms: 7 * 24 * 60 * 60 * 1000,
+      log_retention_bytes: -1,
+      log_retention_check_interval_ms: 5 * 60 * 1000,
+      log_cleanup_policy: "delete".to_string(),
+      log_cleaner_delete_retention_ms: 24 * 60 * 60 * 1000,
+      log_cleaner_min_cleanable_ratio: 0.5,
+      log_cleaner_min_compaction_lag_ms: 0,
+      log_cleaner_backoff_ms: 15 * 1000,
+      compression_type: "producer".to_string(),
+      fetch_max_bytes: 55 * 1024 * 1024,
+      max_incremental_fetch_session_cache_slots: 1000,
+    }
+  }
+}
+
+impl BrokerConfig {
+  /// The config from the command line: an optional server.properties path, then
+  /// `--override name=value` pairs that win over the file.
+  pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<BrokerConfig> {
+    let mut properties = BTreeMap::new();
+    let mut overrides = BTreeMap::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+      if arg == "--override" {
+        let Some(property) = args.next() else {
+          anyhow::bail!("--override needs a name=value\n{}", USAGE);
+        };
+        let Some((name, value)) = property.split_once('=') else {
+          anyhow::bail!("--override {:?} isn't a name=value\n{}", property, USAGE);
+        };
+        overrides.insert(name.trim().to_string(), value.trim().to_string());
+      } else if arg.starts_with('-') || !properties.is_empty() {
+        anyhow::bail!("unexpected argument {:?}\n{}", arg, USAGE);
+      } else {
+        let contents = fs::read_to_string(&arg).with_context(|| format!("reading {}", arg))?;
+        properties = parse_properties(&contents);
+        if properties.is_empty() {
+          println!("{} sets nothing, using the defaults", arg);
+        }
+      }
+    }
+    properties.extend(overrides);
+    BrokerConfig::from_properties(&properties)
+  }
+
+  /// The defaults with `properties` over them, validated. Unknown names are reported and
+  /// ignored, like Kafka does.
+  pub fn from_properties(properties: &BTreeMap<String, String>) -> Result<BrokerConfig> {
+    let mut config = BrokerConfig::default();
+    let (mut retention_ms, mut retention_minutes, mut retention_hours) = (None, None, None);
+    let (mut roll_ms, mut roll_hours) = (None, None);
+    let mut log_dir = None;
+    let mut unknown = vec![];
+
+    for (name, value) in properties {
+      match name.as_str() {
+        "node.id" => config.node_id = parse(name, value)?,
+        "process.roles" => config.process_roles = parse_list(name, value, parse_process_role)?,
+        "listeners" => config.listeners = parse_list(name, value, str::parse)?,
+        "advertised.listeners" => config.advertised_listeners = parse_list(name, value, str::parse)?,
+        "listener.security.protocol.map" => {
+          config.listener_security_protocol_map = parse_list(name, value, |entry| {
+            let (listener, protocol) = entry.split_once(':').ok_or("expected LISTENER:PROTOCOL")?;
+            Ok((listener.trim().to_uppercase(), protocol.trim().parse()?))
+          })?
+          .into_iter()
+          .collect()
+        }
+        "inter.broker.listener.name" => config.inter_broker_listener_name = value.trim().to_uppercase(),
+        "controller.listener.names" => {
+          config.controller_listener_names = parse_list(name, value, |listener| Ok(listener.to_uppercase()))?
+        }
+        "controller.quorum.voters" => config.controller_quorum_voters = parse_list(name, value, str::parse)?,
+        "ssl.keystore.type" | "ssl.truststore.type" if !value.eq_ignore_ascii_case("PEM") => {
+          anyhow::bail!("{} {:?} isn't supported, only PEM", name, value)
+        }
+        "ssl.keystore.type" | "ssl.truststore.type" => {}
+        "ssl.key.password" => anyhow::bail!("ssl.key.password is set but encrypted private keys aren't supported"),
+        "ssl.keystore.location" => config.ssl_keystore_location = Some(PathBuf::from(value)),
+        "ssl.truststore.location" => config.ssl_truststore_location = Some(PathBuf::from(value)),
+        "ssl.client.auth" => config.ssl_client_auth = parse_ssl_client_auth(name, value)?,
+        "sasl.enabled.mechanisms" => {
+          config.sasl_enabled_mechanisms = parse_list(name, value, |mechanism| Ok(mechanism.to_uppercase()))?
+        }
+        _ if name.starts_with("listener.name.") && name.ends_with(".plain.sasl.jaas.config") => {
+          let listener = &name["listener.name.".len()..name.len() - ".plain.sasl.jaas.config".len()];
+          let users = parse_jaas_options(value)
+            .map_err(|reason| anyhow::anyhow!("invalid {}: {}", name, reason))?
+            .into_iter()
+            .filter_map(|(option, password)| Some((option.strip_prefix("user_")?.to_string(), password)))
+            .collect();
+          config.sasl_plain_users.insert(listener.to_uppercase(), users);
+        }
+        "sasl.oauthbearer.jwks.endpoint.url" => {
+          let Some(path) = value.strip_prefix("file://").or(value.strip_prefix("file:")) else {
+            anyhow::bail!("{} {:?} isn't supported, only file: URLs", name, value);
+          };
+          config.sasl_oauthbearer_jwks_endpoint_url = Some(PathBuf::from(path));
+        }
+        "sasl.oauthbearer.expected.audience" => {
+          config.sasl_oauthbearer_expected_audience = parse_list(name, value, |audience| Ok(audience.to_string()))?
+        }
+        "sasl.oauthbearer.expected.issuer" => config.sasl_oauthbearer_expected_issuer = Some(value.clone()),
+        "sasl.oauthbearer.sub.claim.name" => config.sasl_oauthbearer_sub_claim_name = value.clone(),
+        "connections.max.reauth.ms" => config.connections_max_reauth_ms = parse(name, value)?,
+        "socket.request.max.bytes" => config.socket_request_max_bytes = parse(name, value)?,
+        "max.connections" => config.max_connections = parse(name, value)?,
+        "queued.max.requests" => config.queued_max_requests = parse(name, value)?,
+        "log.dirs" => config.log_dirs = parse_log_dirs(name, value)?,
+        "log.dir" => log_dir = Some(parse_log_dirs(name, value)?),
+        "metadata.log.dir" => config.metadata_log_dir = Some(PathBuf::from(value)),
+        "num.partitions" => config.num_partitions = parse(name, value)?,
+        "max.request.partition.size.limit" => config.max_request_partition_size_limit = parse(name, value)?,
+        "message.max.bytes" => config.message_max_bytes = parse(name, value)?,
+        "log.segment.bytes" => config.log_segment_bytes = parse(name, value)?,
+        "log.roll.ms" => roll_ms = Some(parse(name, value)?),
+        "log.roll.hours" => roll_hours = Some(parse::<i64>(name, value)?),
+        "log.index.interval.bytes" => config.log_index_interval_bytes = parse(name, value)?,
+        "log.index.size.max.bytes" => config.log_index_size_max_bytes = parse(name, value)?,
+        "log.retention.ms" => retention_ms = Some(parse(name, value)?),
+        "log.retention.minutes" => retention_minutes = Some(parse::<i64>(name, value)?),
+        "log.retention.hours" => retention_hours = Some(parse::<i64>(name, value)?),
+        "log.retention.bytes" => config.log_retention_bytes = parse(name, value)?,
+        "log.retention.check.interval.ms" => config.log_retention_check_interval_ms = parse(name, value)?,
+        "log.cleanup.policy" => config.log_cleanup_policy = value.clone(),
+        "log.cleaner.delete.retention.ms" => config.log_cleaner_delete_retention_ms = parse(name, value)?,
+        "log.cleaner.min.cleanable.ratio" => config.log_cleaner_min_cleanable_ratio = parse(name, value)?,
+        "log.cleaner.min.compaction.lag.ms" => config.log_cleaner_min_compaction_lag_ms = parse(name, value)?,
+        "log.cleaner.backoff.ms" => config.log_cleaner_backoff_ms = parse(name, value)?,
+        "compression.type" => config.compression_type = value.clone(),
+        // Produce answers log_append_time_ms -1, the broker never stamps batches itself
+        "log.message.timestamp.type" if value != "CreateTime" => {
+          anyhow::bail!("{} {:?} isn't supported, only CreateTime", name, value)
+        }
+        "log.message.timestamp.type" => {}
+        "fetch.max.bytes" => config.fetch_max_bytes = parse(name, value)?,
+        "max.incremental.fetch.session.cache.slots" => config.max_incremental_fetch_session_cache_slots = parse(name, value)?,
+        _ => unknown.push(name.as_str()),
+      }
+    }
+    if !unknown.is_empty() {
+      println!("Ignoring configs the broker doesn't know: {}", unknown.join(", "));
+    }
+
+    // The most precise unit wins, and log.dirs wins over log.dir
+    let retention_minutes = retention_minutes.map(|minutes| minutes * 60 * 1000);
+    let retention_hours = retention_hours.map(|hours| hours * 60 * 60 * 1000);
+    if let Some(retention_ms) = retention_ms.or(retention_minutes).or(retention_hours) {
+      config.log_retention_ms = retention_ms;
+    }
+    if let Some(roll_ms) = roll_ms.or(roll_hours.map(|hours| hours * 60 * 60 * 1000)) {
+      config.log_roll_ms = roll_ms;
+    }
+    if let (Some(log_dir), false) = (log_dir, properties.contains_key("log.dirs")) {
+      config.log_dirs = log_dir;
+    }
+
+    config.validate()?;
+    Ok(config)
+  }
+
+  /// Checks the settings make sense together.
+  pub fn validate(&self) -> Result<()> {
+    if self.node_id < 0 {
+      anyhow::bail!("node.id {} is negative", self.node_id);
+    }
+    if self.process_roles.is_empty() {
+      anyhow::bail!("process.roles is empty");
+    }
+    if self.listeners.is_empty() {
+      anyhow::bail!("listeners is empty");
+    }
+    for (i, listener) in self.listeners.iter().enumerate() {
+      if self.listeners[..i].iter().any(|other| other.listener_name == listener.listener_name) {
+        anyhow::bail!("listeners names {} twice", listener.listener_name);
+      }
+      if self.listeners[..i].iter().any(|other| other.port == listener.port && other.port != 0) {
+        anyhow::bail!("listeners uses port {} twice", listener.port);
+      }
+    }
+    for advertised in &self.advertised_listeners {
+      if !self.listeners.iter().any(|listener| listener.listener_name == advertised.listener_name) {
+        anyhow::bail!("advertised.listeners names {}, which isn't in listeners", advertised.listener_name);
+      }
+      if advertised.host.is_empty() || advertised.host == "0.0.0.0" {
+        anyhow::bail!("advertised.listeners {} has no host clients could connect to", advertised);
+      }
+    }
+    for listener in &self.listeners {
+      if self.security_protocol(&listener.listener_name).is_none() {
+        anyhow::bail!("listener.security.protocol.map has no protocol for listener {}", listener.listener_name);
+      }
+    }
+    let sasl_listeners = self.listeners.iter()
+      .filter(|listener| self.security_protocol(&listener.listener_name).is_some_and(SecurityProtocol::uses_sasl))
+      .collect::<Vec<_>>();
+    if !sasl_listeners.is_empty() {
+      if self.sasl_enabled_mechanisms.is_empty() {
+        anyhow::bail!("SASL listeners need sasl.enabled.mechanisms");
+      }
+      for mechanism in &self.sasl_enabled_mechanisms {
+        if !sasl::MECHANISMS.contains(&mechanism.as_str()) {
+          anyhow::bail!("sasl.enabled.mechanisms {} isn't supported, only {}", mechanism, sasl::MECHANISMS.join(", "));
+        }
+      }
+    }
+    if self.sasl_enabled_mechanisms.iter().any(|mechanism| mechanism == sasl::PLAIN) {
+      for listener in &sasl_listeners {
+        if self.sasl_plain_users.get(&listener.listener_name).map_or(true, BTreeMap::is_empty) {
+          anyhow::bail!(
+            "PLAIN is enabled but listener.name.{}.plain.sasl.jaas.config has no user_ options",
+            listener.listener_name.to_lowercase()
+          );
+        }
+      }
+    }
+    if self.connections_max_reauth_ms < 0 {
+      anyhow::bail!("connections.max.reauth.ms {} is negative", self.connections_max_reauth_ms);
+    }
+    let tls = self.listeners.iter()
+      .any(|listener| self.security_protocol(&listener.listener_name).is_some_and(SecurityProtocol::uses_tls));
+    if tls && self.ssl_keystore_location.is_none() {
+      anyhow::bail!("SSL listeners need ssl.keystore.location");
+    }
+    if tls && self.ssl_client_auth != SslClientAuth::None && self.ssl_truststore_location.is_none() {
+      anyhow::bail!("ssl.client.auth needs ssl.truststore.location to check client certificates against");
+    }
+    if self.process_roles.contains(&ProcessRole::Broker) {
+      if !self.listeners.iter().any(|listener| listener.listener_name == self.inter_broker_listener_name) {
+        anyhow::bail!("inter.broker.listener.name {} isn't in listeners", self.inter_broker_listener_name);
+      }
+      if self.is_controller_listener(&self.inter_broker_listener_name) {
+        anyhow::bail!("inter.broker.listener.name {} is in controller.listener.names", self.inter_broker_listener_name);
+      }
+    }
+    if self.process_roles.contains(&ProcessRole::Controller) {
+      if self.controller_listener_names.is_empty() {
+        anyhow::bail!("a controller needs controller.listener.names");
+      }
+      let voters = &self.controller_quorum_voters;
+      if !voters.is_empty() && !voters.iter().any(|voter| voter.node_id == self.node_id) {
+        anyhow::bail!("node.id {} is a controller but not in controller.quorum.voters", self.node_id);
+      }
+    }
+    if self.num_partitions < 1 {
+      anyhow::bail!("num.partitions {} is less than 1", self.num_partitions);
+    }
+    if !(0.0..=1.0).contains(&self.log_cleaner_min_cleanable_ratio) {
+      anyhow::bail!("log.cleaner.min.cleanable.ratio {} is not between 0 and 1", self.log_cleaner_min_cleanable_ratio);
+    }
+    if log::parse_cleanup_policy(&self.log_cleanup_policy).is_none() {
+      anyhow::bail!("log.cleanup.policy {:?} isn't a list of delete and compact", self.log_cleanup_policy);
+    }
+    if log::parse_compression_type(&self.compression_type).is_none() {
+      anyhow::bail!("unknown compression.type {:?}", self.compression_type);
+    }
+    if self.queued_max_requests == 0 || self.max_connections == 0 {
+      anyhow::bail!("queued.max.requests and max.connections have to be positive");
+    }
+    Ok(())
+  }
+
+  pub fn is_controller_listener(&self, listener_name: &str) -> bool {
+    self.controller_listener_names.iter().any(|name| name == listener_name)
+  }
+
+  pub fn security_protocol(&self, listener_name: &str) -> Option<SecurityProtocol> {
+    match self.listener_security_protocol_map.get(listener_name) {
+      Some(protocol) => Some(*protocol),
+      None if self.is_controller_listener(listener_name) => Some(SecurityProtocol::Plaintext),
+      None => None,
+    }
+  }
+
+  /// Where clients are told to reach `listener`. A listener bound to every interface is
+  /// advertised as `localhost` when `advertised.listeners` doesn't say.
+  pub fn advertised_listener(&self, listener: &Endpoint) -> Endpoint {
+    match self.advertised_listeners.iter().find(|advertised| advertised.listener_name == listener.listener_name) {
+      Some(advertised) => advertised.clone(),
+      None if listener.host.is_empty() || listener.host == "0.0.0.0" => Endpoint { host: "localhost".to_string(), ..listener.clone() },
+      None => listener.clone(),
+    }
+  }
+
+  /// The directory holding `__cluster_metadata-0`.
+  pub fn metadata_log_dir(&self) -> &Path {
+    self.metadata_log_dir.as_deref().unwrap_or(&self.log_dirs)
+  }
+}
+
+/// The `name=value` (or `name: value`) lines of a Java properties file. `#` and `!` start
+/// comments, and a trailing backslash continues the value on the next line.
+pub fn parse_properties(contents: &str) -> BTreeMap<String, String> {
+  let mut properties = BTreeMap::new();
+  let mut lines = contents.lines();
+  while let Some(line) = lines.next() {
+    let mut line = line.trim_start().to_string();
+    if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+      continue;
+    }
+    while line.ends_with('\\') {
+      line.pop();
+      match lines.next() {
+        Some(next) => line.push_str(next.trim_start()),
+        None => break,
+      }
+    }
+
+    let separator = line.find(['=', ':']).unwrap_or(line.len());
+    let (name, value) = line.split_at(separator);
+    let value = value.get(1..).unwrap_or_default();
+    properties.insert(name.trim().to_string(), value.trim().to_string());
+  }
+  properties
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T> {
+  value.trim().parse().map_err(|_| anyhow::anyhow!("invalid {}={:?}", name, value))
+}
+
+/// A comma separated list, empty entries skipped.
+fn parse_list<T>(name: &str, value: &str, parse: impl Fn(&str) -> std::result::Result<T, String>) -> Result<Vec<T>> {
+  value.split(',')
+    .map(str::trim)
+    .filter(|entry| !entry.is_empty())
+    .map(|entry| parse(entry).map_err(|reason| anyhow::anyhow!("invalid {} entry {:?}: {}", name, entry, reason)))
+    .collect()
+}
+
+fn parse_process_role(role: &str) -> std::result::Result<ProcessRole, String> {
+  match role {
+    "broker" => Ok(ProcessRole::Broker),
+    "controller" => Ok(ProcessRole::Controller),
+    _ => Err("expected broker or controller".to_string()),
+  }
+}
+
+fn parse_ssl_client_auth(name: &str, value: &str) -> Result<SslClientAuth> {
+  match value.trim().to_lowercase().as_str() {
+    "none" => Ok(SslClientAuth::None),
+    "requested" => Ok(SslClientAuth::Requested),
+    "required" => Ok(SslClientAuth::Required),
+    _ => anyhow::bail!("invalid {} {:?}, expected none, requested or required", name, value),
+  }
+}
+
+/// The options of a one login module JAAS config, e.g.
+/// `org.apache.kafka.common.security.plain.PlainLoginModule required user_alice="secret";`
+fn parse_jaas_options(value: &str) -> std::result::Result<Vec<(String, String)>, String> {
+  let value = value.trim().strip_suffix(';').ok_or("expected a trailing ;")?;
+  let mut rest = value.trim_start();
+  // The login module class, then its control flag
+  for _ in 0..2 {
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    if end == 0 {
+      return Err("expected a login module and a control flag".to_string());
+    }
+    rest = rest[end..].trim_start();
+  }
+
+  let mut options = vec![];
+  while !rest.is_empty() {
+    let (option, after) = rest.split_once('=').ok_or("expected name=value options")?;
+    let after = after.trim_start();
+    let (value, after) = match after.strip_prefix('"') {
+      Some(quoted) => {
+        let end = quoted.find('"').ok_or("unterminated quote")?;
+        (&quoted[..end], &quoted[end + 1..])
+      }
+      None => after.split_at(after.find(char::is_whitespace).unwrap_or(after.len())),
+    };
+    options.push((option.trim().to_string(), value.to_string()));
+    rest = after.trim_start();
+  }
+  Ok(options)
+}
+
+fn parse_log_dirs(name: &str, value: &str) -> Result<PathBuf> {
+  let dirs = parse_list(name, value, |dir| Ok(PathBuf::from(dir)))?;
+  match &dirs[..] {
+    [dir] => Ok(dir.clone()),
+    [] => anyhow::bail!("{} is empty", name),
+    _ => anyhow::bail!("{} lists {} directories, only one is supported", name, dirs.len()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::test_support::temp_dir;
+
+  fn properties(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+  }
+
+  #[test]
+  fn reads_a_kraft_server_properties() {
+    let contents = "\
+# The role of this server
+process.roles=broker,controller
+node.id=1
+controller.quorum.voters=1@localhost:9093
+listeners=PLAINTEXT://:9092,CONTROLLER://:9093
+advertised.listeners=PLAINTEXT://localhost:9092
+controller.listener.names = CONTROLLER
+log.dirs=/tmp/kraft-combined-logs
+num.partitions: 3
+log.retention.hours=168
+log.segment.bytes=1073741824
+num.network.threads=3
+log.cleanup.policy=compact,\\
+  delete
+";
+    let config = BrokerConfig::from_properties(&parse_properties(contents)).unwrap();
+    assert_eq!(config.process_roles, vec![ProcessRole::Broker, ProcessRole::Controller]);
+    assert_eq!(config.controller_quorum_voters, vec![QuorumVoter { node_id: 1, host: "localhost".to_string(), port: 9093 }]);
+    assert_eq!(config.num_partitions, 3);
+    assert_eq!(config.log_retention_ms, 168 * 60 * 60 * 1000);
+    assert_eq!(config.log_cleanup_policy, "compact,delete");
+
+    let [plaintext, controller] = &config.listeners[..] else {
+      panic!("expected two listeners, got {:?}", config.listeners);
+    };
+    assert_eq!((plaintext.listener_name.as_str(), plaintext.bind_address()), ("PLAINTEXT", "0.0.0.0:9092".to_string()));
+    assert_eq!(config.advertised_listener(plaintext).to_string(), "PLAINTEXT://localhost:9092");
+    assert_eq!(config.advertised_listener(controller).to_string(), "CONTROLLER://localhost:9093");
+    assert_eq!(config.security_protocol("CONTROLLER"), Some(SecurityProtocol::Plaintext));
+    assert_eq!(config.metadata_log_dir(), Path::new("/tmp/kraft-combined-logs"));
+  }
+
+  #[test]
+  fn overrides_win_over_the_file() {
+    let dir = temp_dir();
+    let path = dir.path().join("server.properties");
+    fs::write(&path, "node.id=1\nlog.retention.ms=1000\nlog.retention.minutes=1\n").unwrap();
+
+    let args = [path.to_str().unwrap(), "--override", "node.id=7", "--override", "metadata.log.dir=/tmp/meta"];
+    let config = BrokerConfig::from_args(args.map(str::to_string)).unwrap();
+    assert_eq!((config.node_id, config.log_retention_ms), (7, 1000));
+    assert_eq!(config.metadata_log_dir(), Path::new("/tmp/meta"));
+
+    assert!(BrokerConfig::from_args(["--bogus".to_string()]).is_err());
+    assert!(BrokerConfig::from_args([dir.path().join("missing").to_str().unwrap().to_string()]).is_err());
+  }
+
+  #[test]
+  fn reads_sasl_settings() {
+    let config = BrokerConfig::from_properties(&properties(&[
+      ("listeners", "PLAINTEXT://:9092,SASL_SSL://:9093"),
+      ("ssl.keystore.location", "/tmp/broker.pem"),
+      ("sasl.enabled.mechanisms", "plain,SCRAM-SHA-512"),
+      (
+        "listener.name.sasl_ssl.plain.sasl.jaas.config",
+        "org.apache.kafka.common.security.plain.PlainLoginModule required\n  username=\"admin\" password=\"admin secret\"\n  user_admin=\"admin secret\" user_alice=alice-secret;",
+      ),
+      ("sasl.oauthbearer.jwks.endpoint.url", "file:///etc/kafka/jwks.json"),
+      ("connections.max.reauth.ms", "3600000"),
+    ]))
+    .unwrap();
+    assert_eq!(config.security_protocol("SASL_SSL"), Some(SecurityProtocol::SaslSsl));
+    assert_eq!(config.sasl_enabled_mechanisms, vec!["PLAIN", "SCRAM-SHA-512"]);
+    let users = &config.sasl_plain_users["SASL_SSL"];
+    assert_eq!(users.iter().collect::<Vec<_>>(), vec![(&"admin".to_string(), &"admin secret".to_string()), (&"alice".to_string(), &"alice-secret".to_string())]);
+    assert_eq!(config.sasl_oauthbearer_jwks_endpoint_url.as_deref(), Some(Path::new("/etc/kafka/jwks.json")));
+    assert_eq!(config.connections_max_reauth_ms, 3600000);
+  }
+
+  #[test]
+  fn rejects_invalid_settings() {
+    for (pairs, error) in [
+      (vec![("node.id", "one")], "invalid node.id"),
+      (vec![("listeners", "PLAINTEXT://:port")], "invalid port"),
+      (vec![("listeners", "A://:9092,B://:9092")], "port 9092 twice"),
+      (vec![("process.roles", "controller")], "controller.listener.names"),
+      (vec![("listeners", "CONTROLLER://:9093"), ("controller.listener.names", "CONTROLLER")], "inter.broker.listener.name PLAINTEXT isn't in listeners"),
+      (vec![("inter.broker.listener.name", "CONTROLLER"), ("listeners", "PLAINTEXT://:9092,CONTROLLER://:9093"), ("controller.listener.names", "CONTROLLER")], "is in controller.listener.names"),
+      (vec![("listeners", "INTERNAL://:9092")], "no protocol for listener INTERNAL"),
+      (vec![("listeners", "PLAINTEXT://:9092,SECURE://:9093"), ("listener.security.protocol.map", "PLAINTEXT:PLAINTEXT,SECURE:SASL_PLAINTEXT")], "GSSAPI isn't supported"),
+      (vec![("listeners", "PLAINTEXT://:9092,SASL_PLAINTEXT://:9093"), ("sasl.enabled.mechanisms", "PLAIN")], "listener.name.sasl_plaintext.plain.sasl.jaas.config"),
+      (vec![("listener.name.sasl_plaintext.plain.sasl.jaas.config", "PlainLoginModule required user_alice=\"secret;")], "unterminated quote"),
+      (vec![("sasl.oauthbearer.jwks.endpoint.url", "https://idp/jwks")], "only file: URLs"),
+      (vec![("listeners", "PLAINTEXT://:9092,SSL://:9093")], "need ssl.keystore.location"),
+      (vec![("listeners", "PLAINTEXT://:9092,SSL://:9093"), ("ssl.keystore.location", "/tmp/broker.pem"), ("ssl.client.auth", "required")], "needs ssl.truststore.location"),
+      (vec![("ssl.keystore.type", "JKS")], "only PEM"),
+      (vec![("ssl.client.auth", "sometimes")], "expected none, requested or required"),
+      (vec![("listener.security.protocol.map", "PLAINTEXT:QUIC")], "unknown security protocol"),
+      (vec![("process.roles", "controller"), ("controller.listener.names", "CONTROLLER"), ("listeners", "CONTROLLER://:9093"), ("controller.quorum.voters", "2@localhost:9093")], "not in controller.quorum.voters"),
+      (vec![("advertised.listeners", "OTHER://localhost:9092")], "isn't in listeners"),
+      (vec![("log.dirs", "/a,/b")], "only one"),
+      (vec![("compression.type", "brotli")], "compression.type"),
+      (vec![("log.message.timestamp.type", "LogAppendTime")], "only CreateTime"),
+      (vec![("log.cleanup.policy", "keep")], "log.cleanup.policy"),
+      (vec![("connections.max.reauth.ms", "-1")], "connections.max.reauth.ms"),
+    ] {
+      let result = BrokerConfig::from_properties(&properties(&pairs));
+      let message = format!("{:#}", result.unwrap_err());
+      assert!(message.contains(error), "{:?}: {}", pairs, message);
+    }
+    assert!(BrokerConfig::from_properties(&properties(&[("log.message.timestamp.type", "CreateTime")])).is_ok());
+  }
+}
@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::kafka::codec::Uuid;
+use crate::kafka::common::ErrorCode;
+use crate::kafka::log::TopicPartition;
+
+/// `session_id` of a fetch that is not part of a session.
+pub const INVALID_SESSION_ID: i32 = 0;
+/// `session_epoch` asking for a new session.
+pub const INITIAL_EPOCH: i32 = 0;
+/// `session_epoch` of a sessionless fetch, or one closing its session.
+pub const FINAL_EPOCH: i32 = -1;
+/// How long a session has to go unused before a new one may evict it from a full cache,
+/// Kafka's `MIN_INCREMENTAL_FETCH_SESSION_EVICTION_MS`.
+pub const EVICTABLE_AFTER: Duration = Duration::from_secs(120);
+
+/// What a session remembers about one of its partitions: where the client is reading and
+/// what it was last told, so unchanged partitions can be left out of the next response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedPartition {
+  pub topic_id: Uuid,
+  pub fetch_offset: i64,
+  pub partition_max_bytes: i32,
+  pub current_leader_epoch: i32,
+  pub high_watermark: i64,
+  pub log_start_offset: i64,
+}
+
+/// An incremental fetch session (KIP-227). After the first full fetch the client only sends
+/// partitions whose fetch position changed and gets back only partitions with news.
+#[derive(Debug)]
+pub struct FetchSession {
+  /// The epoch the next request in this session has to carry.
+  pub epoch: i32,
+  pub partitions: BTreeMap<TopicPartition, CachedPartition>,
+  pub last_used: Instant,
+}
+
+fn next_epoch(epoch: i32) -> i32 {
+  if epoch == i32::MAX { 1 } else { epoch + 1 }
+}
+
+#[derive(Debug)]
+pub struct FetchSessionCache {
+  max_sessions: usize,
+  sessions: Mutex<Sessions>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+  next_id: i32,
+  by_id: HashMap<i32, FetchSession>,
+}
+
+impl FetchSessionCache {
+  pub fn new(max_sessions: usize) -> FetchSessionCache {
+    FetchSessionCache {
+      max_sessions,
+      sessions: Mutex::new(Sessions { next_id: 1, by_id: HashMap::new() }),
+    }
+  }
+
+  /// Starts a session holding `partitions`. A full cache makes room by evicting its least
+  /// recently used session if that has been idle for `EVICTABLE_AFTER`, otherwise this
+  /// returns `INVALID_SESSION_ID` and the client has to keep doing full fetches.
+  pub fn create(&self, partitions: BTreeMap<TopicPartition, CachedPartition>) -> i32 {
+    let mut sessions = self.sessions.lock().unwrap();
+    if sessions.by_id.len() >= self.max_sessions {
+      let evictable = sessions.by_id.iter()
+        .min_by_key(|(_, session)| session.last_used)
+        .filter(|(_, session)| session.last_used.elapsed() >= EVICTABLE_AFTER)
+        .map(|(id, _)| *id);
+      let Some(evicted) = evictable else {
+        return INVALID_SESSION_ID;
+      };
+      println!("Evicting fetch session {} to make room for a new one", evicted);
+      sessions.by_id.remove(&evicted);
+    }
+
+    let mut id = sessions.next_id;
+    while id == INVALID_SESSION_ID || sessions.by_id.contains_key(&id) {
+      id = id.checked_add(1).unwrap_or(1);
+    }
+    sessions.next_id = id.checked_add(1).unwrap_or(1);
+    sessions.by_id.insert(id, FetchSession { epoch: next_epoch(INITIAL_EPOCH), partitions, last_used: Instant::now() });
+    id
+  }
+
+  pub fn remove(&self, id: i32) -> Option<FetchSession> {
+    self.sessions.lock().unwrap().by_id.remove(&id)
+  }
+
+  /// Runs `update` on session `id` if `epoch` is the one it expects next, then moves the
+  /// session on to the following epoch. Returns the session's partitions afterwards.
+  pub fn update(
+    &self,
+    id: i32,
+    epoch: i32,
+    update: impl FnOnce(&mut BTreeMap<TopicPartition, CachedPartition>),
+  ) -> Result<BTreeMap<TopicPartition, CachedPartition>, ErrorCode> {
+    let mut sessions = self.sessions.lock().unwrap();
+    let session = sessions.by_id.get_mut(&id).ok_or(ErrorCode::FetchSessionIdNotFound)?;
+    if session.epoch != epoch {
+      return Err(ErrorCode::InvalidFetchSessionEpoch);
+    }
+
+    update(&mut session.partitions);
+    session.epoch = next_epoch(epoch);
+    session.last_used = Instant::now();
+    Ok(session.partitions.clone())
+  }
+
+  /// Remembers the high watermark and log start offset just sent for a partition.
+  pub fn record_sent(&self, id: i32, topic_partition: &TopicPartition, high_watermark: i64, log_start_offset: i64) {
+    let mut sessions = self.sessions.lock().unwrap();
+    let cached = sessions.by_id.get_mut(&id).and_then(|session| session.partitions.get_mut(topic_partition));
+    if let Some(cached) = cached {
+      cached.high_watermark = high_watermark;
+      cached.log_start_offset = log_start_offset;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn partitions(names: &[(&str, i32)]) -> BTreeMap<TopicPartition, CachedPartition> {
+    names.iter()
+      .map(|(topic, partition)| {
+        let cached = CachedPartition {
+          topic_id: Uuid(1),
+          fetch_offset: 0,
+          partition_max_bytes: 1024,
+          current_leader_epoch: -1,
+          high_watermark: -1,
+          log_start_offset: -1,
+        };
+        (TopicPartition::new(topic, *partition), cached)
+      })
+      .collect()
+  }
+
+  fn names(partitions: &BTreeMap<TopicPartition, CachedPartition>) -> Vec<String> {
+    partitions.keys().map(TopicPartition::to_string).collect()
+  }
+
+  #[test]
+  fn updates_sessions_only_at_their_next_epoch() {
+    let cache = FetchSessionCache::new(10);
+    let id = cache.create(partitions(&[("foo", 0)]));
+    assert_ne!(id, INVALID_SESSION_ID);
+
+    let updated = cache.update(id, 1, |cached| cached.extend(partitions(&[("foo", 1)]))).unwrap();
+    assert_eq!(names(&updated), vec!["foo-0", "foo-1"]);
+    // A replayed or skipped epoch leaves the session alone
+    for epoch in [1, 3, INITIAL_EPOCH] {
+      assert_eq!(cache.update(id, epoch, |cached| cached.clear()), Err(ErrorCode::InvalidFetchSessionEpoch));
+    }
+    let updated = cache.update(id, 2, |cached| {
+      cached.remove(&TopicPartition::new("foo", 0));
+    });
+    assert_eq!(names(&updated.unwrap()), vec!["foo-1"]);
+
+    assert_eq!(cache.update(id + 1, 3, |_| {}), Err(ErrorCode::FetchSessionIdNotFound));
+    assert!(cache.remove(id).is_some());
+    assert_eq!(cache.update(id, 3, |_| {}), Err(ErrorCode::FetchSessionIdNotFound));
+  }
+
+  #[test]
+  fn epochs_wrap_around_past_zero() {
+    let cache = FetchSessionCache::new(1);
+    let id = cache.create(partitions(&[("foo", 0)]));
+    cache.sessions.lock().unwrap().by_id.get_mut(&id).unwrap().epoch = i32::MAX;
+    cache.update(id, i32::MAX, |_| {}).unwrap();
+    assert!(cache.update(id, 1, |_| {}).is_ok());
+  }
+
+  #[test]
+  fn full_caches_evict_the_least_recently_used_idle_session() {
+    let cache = FetchSessionCache::new(2);
+    let first = cache.create(partitions(&[("foo", 0)]));
+    let second = cache.create(partitions(&[("foo", 1)]));
+    assert_eq!(cache.create(partitions(&[("foo", 2)])), INVALID_SESSION_ID);
+
+    let idle = |id: i32, idle: Duration| {
+      cache.sessions.lock().unwrap().by_id.get_mut(&id).unwrap().last_used = Instant::now() - idle;
+    };
+    idle(first, EVICTABLE_AFTER / 2);
+    assert_eq!(cache.create(partitions(&[("foo", 2)])), INVALID_SESSION_ID);
+    idle(first, EVICTABLE_AFTER * 2);
+    idle(second, EVICTABLE_AFTER * 3);
+    let third = cache.create(partitions(&[("foo", 2)]));
+    assert_ne!(third, INVALID_SESSION_ID);
+    assert_eq!(cache.update(second, 1, |_| {}), Err(ErrorCode::FetchSessionIdNotFound));
+
+    // Using a session keeps it
+    cache.update(first, 1, |_| {}).unwrap();
+    assert_eq!(cache.create(partitions(&[("foo", 3)])), INVALID_SESSION_ID);
+    assert_eq!(FetchSessionCache::new(0).create(partitions(&[("foo", 0)])), INVALID_SESSION_ID);
+  }
+}
@@ -0,0 +1,107 @@
+use bytes::{Buf, BufMut, BytesMut};
+use thiserror::Error;
+
+// Every request and response on the wire is prefixed with a 4 byte message_size which
+// counts the header + body that follow it, but not itself.
+
+/// Prefixes an encoded header + body with its message_size.
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+  let mut buf = BytesMut::with_capacity(4 + payload.len());
+  buf.put_i32(payload.len() as i32);
+  buf.put_slice(payload);
+  buf.to_vec()
+}
+
+#[derive(Debug, Error)]
+pub enum FramingError {
+  #[error("invalid message_size {0}")]
+  InvalidSize(i32),
+  #[error("request of {size} bytes exceeds socket.request.max.bytes ({max})")]
+  TooLarge { size: usize, max: usize },
+}
+
+/// Accumulates bytes read from a connection and splits them into complete request frames.
+/// A single read may hold a partial request, exactly one, or several pipelined ones.
+#[derive(Debug)]
+pub struct FrameReader {
+  buf: BytesMut,
+  max_request_size: usize,
+}
+
+impl FrameReader {
+  pub fn new(max_request_size: usize) -> FrameReader {
+    FrameReader { buf: BytesMut::with_capacity(4096), max_request_size }
+  }
+
+  pub fn extend(&mut self, data: &[u8]) {
+    self.buf.extend_from_slice(data);
+  }
+
+  /// Bytes received that don't make up a full frame yet.
+  pub fn buffered(&self) -> usize {
+    self.buf.len()
+  }
+
+  /// Splits off the next complete frame (message_size prefix included), or `None` if the
+  /// declared message_size hasn't fully arrived yet.
+  pub fn next_frame(&mut self) -> Result<Option<BytesMut>, FramingError> {
+    if self.buf.len() < 4 {
+      return Ok(None);
+    }
+
+    let message_size = (&self.buf[..4]).get_i32();
+    if message_size < 0 {
+      return Err(FramingError::InvalidSize(message_size));
+    }
+    let size = message_size as usize;
+    if size > self.max_request_size {
+      return Err(FramingError::TooLarge { size, max: self.max_request_size });
+    }
+
+    if self.buf.len() < 4 + size {
+      // Make room for the rest of the request up front instead of growing on every read
+      self.buf.reserve(4 + size - self.buf.len());
+      return Ok(None);
+    }
+
+    Ok(Some(self.buf.split_to(4 + size)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn waits_for_requests_split_across_reads() {
+    let request = frame(b"hello world");
+    let mut reader = FrameReader::new(1024);
+
+    for byte in &request[..request.len() - 1] {
+      reader.extend(&[*byte]);
+      assert!(reader.next_frame().unwrap().is_none());
+    }
+    reader.extend(&request[request.len() - 1..]);
+    assert_eq!(&reader.next_frame().unwrap().unwrap()[..], &request[..]);
+    assert_eq!(reader.buffered(), 0);
+  }
+
+  #[test]
+  fn splits_pipelined_requests() {
+    let mut reader = FrameReader::new(1024);
+    reader.extend(&[frame(b"first"), frame(b"second"), frame(b"thi")].concat());
+
+    assert_eq!(&reader.next_frame().unwrap().unwrap()[4..], b"first");
+    assert_eq!(&reader.next_frame().unwrap().unwrap()[4..], b"second");
+    assert_eq!(&reader.next_frame().unwrap().unwrap()[4..], b"thi");
+    assert!(reader.next_frame().unwrap().is_none());
+  }
+
+  #[test]
+  fn rejects_requests_over_the_limit() {
+    let mut reader = FrameReader::new(8);
+    reader.extend(&frame(b"123456789"));
+
+    assert!(matches!(reader.next_frame(), Err(FramingError::TooLarge { size: 9, max: 8 })));
+  }
+}
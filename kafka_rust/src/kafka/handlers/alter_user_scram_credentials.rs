@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::codec::TaggedFields;
+use crate::kafka::common::ErrorCode;
+use crate::kafka::header::RequestHeader;
+use crate::kafka::metadata_image::ScramCredential;
+use crate::kafka::metadata_records::{MetadataRecord, RemoveUserScramCredentialRecord, UserScramCredentialRecord};
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::{AllRequests, AlterUserScramCredentialsRequest};
+use crate::kafka::responses::{AllResponses, AlterUserScramCredentialsResponse, AlterUserScramCredentialsResult, Response};
+use crate::kafka::sasl::scram::ScramMechanism;
+
+pub const API_KEY: i16 = 51;
+
+/// Adds, replaces and removes SCRAM credentials. A user's alterations are applied all
+/// together or not at all. The records of every user that passed validation go to the
+/// metadata log in one batch before the credentials change, so they survive a restart.
+pub fn handle(broker: &Broker, _context: &RequestContext, header: &RequestHeader, body: &AllRequests) -> Result<Response> {
+  let AllRequests::AlterUserScramCredentialsRequest(request) = body else {
+    anyhow::bail!("AlterUserScramCredentials handler got {:?}", body);
+  };
+
+  // Held until the records are applied so concurrent alterations validate against each other
+  let mut credentials = broker.scram_credentials.write().unwrap();
+
+  let mut results = vec![];
+  let mut records = vec![];
+  for user in users(request) {
+    let (error_code, error_message) = match alterations(&credentials, request, user) {
+      Ok(user_records) => {
+        records.extend(user_records);
+        (ErrorCode::None, None)
+      }
+      Err((error_code, message)) => (error_code, Some(message.to_string())),
+    };
+    results.push(AlterUserScramCredentialsResult {
+      user: user.to_string(),
+      error_code: error_code.code(),
+      error_message,
+      tagged_fields: TaggedFields::default(),
+    });
+  }
+
+  if !records.is_empty() {
+    let offset = broker.metadata_log.lock().unwrap().append(&records)?;
+    println!("Wrote {} SCRAM credential changes to the metadata log at offset {}", records.len(), offset);
+    for record in records {
+      match record {
+        MetadataRecord::UserScramCredential(record) => {
+          credentials.insert((record.name, record.mechanism), ScramCredential {
+            salt: record.salt,
+            stored_key: record.stored_key,
+            server_key: record.server_key,
+            iterations: record.iterations,
+          });
+        }
+        MetadataRecord::RemoveUserScramCredential(record) => {
+          credentials.remove(&(record.name, record.mechanism));
+        }
+        _ => unreachable!("only SCRAM credential records are written"),
+      }
+    }
+  }
+
+  Ok(Response::new(header, AllResponses::AlterUserScramCredentialsResponse(AlterUserScramCredentialsResponse {
+    results,
+    ..Default::default()
+  })))
+}
+
+pub fn error_response(body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  let users = match body {
+    AllRequests::AlterUserScramCredentialsRequest(request) => users(request),
+    _ => vec![],
+  };
+  AllResponses::AlterUserScramCredentialsResponse(AlterUserScramCredentialsResponse {
+    results: users.into_iter()
+      .map(|user| AlterUserScramCredentialsResult {
+        user: user.to_string(),
+        error_code: error_code.code(),
+        error_message: Some(error_code.name().to_string()),
+        tagged_fields: TaggedFields::default(),
+      })
+      .collect(),
+    ..Default::default()
+  })
+}
+
+/// Every user the request alters, in the order they're first named. Each gets one result.
+fn users(request: &AlterUserScramCredentialsRequest) -> Vec<&str> {
+  let mut users: Vec<&str> = vec![];
+  let names = request.deletions.iter().map(|deletion| &deletion.name)
+    .chain(request.upsertions.iter().map(|upsertion| &upsertion.name));
+  for name in names {
+    if !users.contains(&name.as_str()) {
+      users.push(name);
+    }
+  }
+  users
+}
+
+/// The records for `user`'s deletions and upsertions, or why none of them can be applied.
+fn alterations(
+  credentials: &BTreeMap<(String, i8), ScramCredential>,
+  request: &AlterUserScramCredentialsRequest,
+  user: &str,
+) -> std::result::Result<Vec<MetadataRecord>, (ErrorCode, &'static str)> {
+  if user.is_empty() {
+    return Err((ErrorCode::UnacceptableCredential, "Username must not be empty"));
+  }
+  let deletions = request.deletions.iter().filter(|deletion| deletion.name == user);
+  let upsertions = request.upsertions.iter().filter(|upsertion| upsertion.name == user);
+
+  let mut mechanisms = vec![];
+  for mechanism in deletions.clone().map(|deletion| deletion.mechanism).chain(upsertions.clone().map(|upsertion| upsertion.mechanism)) {
+    if ScramMechanism::from_id(mechanism).is_none() {
+      return Err((ErrorCode::UnsupportedSaslMechanism, "Unknown SCRAM mechanism"));
+    }
+    if mechanisms.contains(&mechanism) {
+      return Err((ErrorCode::DuplicateResource, "A user credential cannot be altered twice in the same request"));
+    }
+    mechanisms.push(mechanism);
+  }
+
+  let mut records = vec![];
+  for deletion in deletions {
+    if !credentials.contains_key(&(user.to_string(), deletion.mechanism)) {
+      return Err((ErrorCode::ResourceNotFound, "Attempt to delete a user credential that does not exist"));
+    }
+    records.push(MetadataRecord::RemoveUserScramCredential(RemoveUserScramCredentialRecord {
+      name: user.to_string(),
+      mechanism: deletion.mechanism,
+      tagged_fields: TaggedFields::default(),
+    }));
+  }
+  for upsertion in upsertions {
+    if upsertion.iterations < ScramMechanism::MIN_ITERATIONS {
+      return Err((ErrorCode::UnacceptableCredential, "Too few iterations"));
+    }
+    if upsertion.iterations > ScramMechanism::MAX_ITERATIONS {
+      return Err((ErrorCode::UnacceptableCredential, "Too many iterations"));
+    }
+    if upsertion.salt.is_empty() || upsertion.salted_password.is_empty() {
+      return Err((ErrorCode::UnacceptableCredential, "Salt and salted password must not be empty"));
+    }
+    let mechanism = ScramMechanism::from_id(upsertion.mechanism).expect("checked above");
+    let credential = mechanism.credential(upsertion.salt.clone(), &upsertion.salted_password, upsertion.iterations);
+    records.push(MetadataRecord::UserScramCredential(UserScramCredentialRecord {
+      name: user.to_string(),
+      mechanism: upsertion.mechanism,
+      salt: credential.salt,
+      stored_key: credential.stored_key,
+      server_key: credential.server_key,
+      iterations: credential.iterations,
+      tagged_fields: TaggedFields::default(),
+    }));
+  }
+  Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+
+  use super::*;
+  use crate::kafka::config::BrokerConfig;
+  use crate::kafka::metadata_image::MetadataImage;
+  use crate::kafka::requests::{ScramCredentialDeletion, ScramCredentialUpsertion};
+  use crate::kafka::test_support::temp_dir;
+
+  fn upsertion(name: &str, mechanism: ScramMechanism, password: &str, iterations: i32) -> ScramCredentialUpsertion {
+    let salt = Bytes::from(format!("salt-of-{}", name));
+    ScramCredentialUpsertion {
+      name: name.to_string(),
+      mechanism: mechanism.id(),
+      iterations,
+      salted_password: Bytes::from(mechanism.salted_password(password, &salt, iterations)),
+      salt,
+    }
+  }
+
+  fn deletion(name: &str, mechanism: i8) -> ScramCredentialDeletion {
+    ScramCredentialDeletion { name: name.to_string(), mechanism }
+  }
+
+  fn alter(broker: &Broker, deletions: Vec<ScramCredentialDeletion>, upsertions: Vec<ScramCredentialUpsertion>) -> Vec<(String, i16)> {
+    let request = AllRequests::AlterUserScramCredentialsRequest(AlterUserScramCredentialsRequest { deletions, upsertions });
+    let header = RequestHeader { request_api_key: API_KEY, ..Default::default() };
+    let AllResponses::AlterUserScramCredentialsResponse(response) = handle(broker, &RequestContext::default(), &header, &request).unwrap().body else {
+      panic!("expected an AlterUserScramCredentials response");
+    };
+    response.results.into_iter().map(|result| (result.user, result.error_code)).collect()
+  }
+
+  #[test]
+  fn alters_credentials_through_the_metadata_log() {
+    let dir = temp_dir();
+    let log_dir = dir.path();
+    let config = BrokerConfig { log_dirs: log_dir.to_path_buf(), ..Default::default() };
+    let broker = Broker::new(config.clone(), MetadataImage::empty());
+    let (sha256, sha512) = (ScramMechanism::Sha256, ScramMechanism::Sha512);
+
+    let results = alter(&broker, vec![deletion("carol", 1)], vec![
+      upsertion("alice", sha256, "secret", 4096),
+      upsertion("alice", sha512, "secret", 8192),
+      upsertion("bob", sha256, "secret", 1000),
+      upsertion("dave", sha256, "secret", 4096),
+      upsertion("dave", sha256, "other", 4096),
+    ]);
+    assert_eq!(results, vec![
+      ("carol".to_string(), ErrorCode::ResourceNotFound.code()),
+      ("alice".to_string(), 0),
+      ("bob".to_string(), ErrorCode::UnacceptableCredential.code()),
+      ("dave".to_string(), ErrorCode::DuplicateResource.code()),
+    ]);
+    let results = alter(&broker, vec![deletion("alice", sha512.id())], vec![upsertion("erin", sha512, "secret", 4096)]);
+    assert_eq!(results, vec![("alice".to_string(), 0), ("erin".to_string(), 0)]);
+    let results = alter(&broker, vec![deletion("erin", 3)], vec![]);
+    assert_eq!(results, vec![("erin".to_string(), ErrorCode::UnsupportedSaslMechanism.code())]);
+
+    let expected = [
+      (("alice".to_string(), sha256.id()), upsertion("alice", sha256, "secret", 4096)),
+      (("erin".to_string(), sha512.id()), upsertion("erin", sha512, "secret", 4096)),
+    ];
+    let credentials = broker.scram_credentials.read().unwrap().clone();
+    assert_eq!(credentials.keys().collect::<Vec<_>>(), expected.iter().map(|(key, _)| key).collect::<Vec<_>>());
+    for (key, upsertion) in &expected {
+      let mechanism = ScramMechanism::from_id(upsertion.mechanism).unwrap();
+      assert_eq!(credentials[key], mechanism.credential(upsertion.salt.clone(), &upsertion.salted_password, upsertion.iterations));
+    }
+
+    // Both batches are in the log, replaying it gets to the same credentials
+    let reloaded = MetadataImage::load(log_dir).unwrap();
+    assert_eq!(reloaded.offset, 3);
+    assert_eq!(reloaded.scram_credentials, credentials);
+    let restarted = Broker::new(config, reloaded);
+    let results = alter(&restarted, vec![deletion("erin", sha512.id())], vec![]);
+    assert_eq!(results, vec![("erin".to_string(), 0)]);
+    assert_eq!(MetadataImage::load(log_dir).unwrap().offset, 4);
+  }
+}
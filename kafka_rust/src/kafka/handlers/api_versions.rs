@@ -0,0 +1,142 @@
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::common::ErrorCode;
+use crate::kafka::header::RequestHeader;
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::AllRequests;
+use crate::kafka::responses::{AllResponses, ApiVersion, ApiVersionsResponse, FinalizedFeatureKey, Response, SupportedFeatureKey};
+
+pub const API_KEY: i16 = 18;
+
+/// Features this broker knows how to run with, as `(name, min, max)` levels.
+pub const SUPPORTED_FEATURES: [(&str, i16, i16); 1] = [
+  ("metadata.version", 1, 21),
+];
+
+pub fn handle(broker: &Broker, context: &RequestContext, header: &RequestHeader, body: &AllRequests) -> Result<Response> {
+  let AllRequests::ApiVersionRequest(request) = body else {
+    anyhow::bail!("ApiVersions handler got {:?}", body);
+  };
+  if header.request_api_version >= 3 {
+    println!("client software: {} {}", request.client_software_name, request.client_software_version);
+    if !is_valid_software_field(&request.client_software_name) || !is_valid_software_field(&request.client_software_version) {
+      return Err(anyhow::Error::new(ErrorCode::InvalidRequest).context(format!(
+        "invalid client software {:?} {:?}", request.client_software_name, request.client_software_version
+      )));
+    }
+  }
+
+  Ok(Response::new(header, AllResponses::ApiVersionsResponse(response(broker, context, ErrorCode::None))))
+}
+
+pub fn error_response(_body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  AllResponses::ApiVersionsResponse(ApiVersionsResponse {
+    error_code: error_code.code(),
+    ..Default::default()
+  })
+}
+
+/// Same rule as Kafka's `[a-zA-Z0-9](?:[a-zA-Z0-9\-.]*[a-zA-Z0-9])?`.
+fn is_valid_software_field(value: &str) -> bool {
+  let bytes = value.as_bytes();
+  match (bytes.first(), bytes.last()) {
+    (Some(first), Some(last)) => {
+      first.is_ascii_alphanumeric()
+        && last.is_ascii_alphanumeric()
+        && bytes.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'.')
+    }
+    _ => false,
+  }
+}
+
+/// Clients open with the newest ApiVersions they know. When that's newer than ours the
+/// answer is a v0 response, which every client can parse, carrying UNSUPPORTED_VERSION and
+/// our version ranges so the client can retry with one we support.
+pub fn unsupported_version(broker: &Broker, context: &RequestContext, header: &RequestHeader) -> Response {
+  let mut response = Response::new(header, AllResponses::ApiVersionsResponse(response(broker, context, ErrorCode::UnsupportedVersion)));
+  response.api_version = 0;
+  response
+}
+
+/// The apis exposed on the listener the request came in on.
+fn response(broker: &Broker, context: &RequestContext, error_code: ErrorCode) -> ApiVersionsResponse {
+  let api_keys = broker.apis.iter()
+    .filter(|api| api.exposed_on(context.listener_type))
+    .map(|api| ApiVersion {
+      api_key: api.api_key,
+      min_version: api.min_version,
+      max_version: api.max_version,
+      ..Default::default()
+    })
+    .collect();
+
+  let supported_features = SUPPORTED_FEATURES.iter()
+    .map(|(name, min_version, max_version)| SupportedFeatureKey {
+      name: name.to_string(),
+      min_version: *min_version,
+      max_version: *max_version,
+    })
+    .collect();
+
+  // Levels the controller finalized in the metadata log, epoch'd by the image offset
+  let finalized_features: Vec<_> = broker.metadata.features.iter()
+    .map(|(name, level)| FinalizedFeatureKey {
+      name: name.clone(),
+      max_version_level: *level,
+      min_version_level: *level,
+    })
+    .collect();
+  let finalized_features_epoch = if finalized_features.is_empty() { -1 } else { broker.metadata.offset };
+
+  ApiVersionsResponse {
+    error_code: error_code.code(),
+    api_keys,
+    supported_features,
+    finalized_features_epoch,
+    finalized_features,
+    ..Default::default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::{Buf, Bytes};
+
+  use super::*;
+  use crate::kafka::codec::{decode_array, Decode, Int16, Int32};
+  use crate::kafka::config::BrokerConfig;
+  use crate::kafka::handlers::{describe_cluster, sasl_authenticate, sasl_handshake};
+  use crate::kafka::metadata_image::MetadataImage;
+  use crate::kafka::registry::ListenerType;
+
+  #[test]
+  fn newer_versions_get_a_v0_unsupported_version_answer() {
+    let broker = Broker::new(BrokerConfig::default(), MetadataImage::empty());
+    let header = RequestHeader { request_api_key: API_KEY, request_api_version: 99, correlation_id: 5, flexible: true, ..Default::default() };
+
+    let mut frame = Bytes::from(unsupported_version(&broker, &RequestContext::default(), &header).get_vec());
+    frame.advance(4);
+    assert_eq!(Int32::decode(&mut frame).unwrap(), 5);
+    assert_eq!(Int16::decode(&mut frame).unwrap(), ErrorCode::UnsupportedVersion.code());
+    let api_keys = decode_array(&mut frame, false, |buf| {
+      let key = Int16::decode(buf)?;
+      Ok((key, Int16::decode(buf)?, Int16::decode(buf)?))
+    }).unwrap();
+    let registered: Vec<_> = broker.apis.iter().map(|api| (api.api_key, api.min_version, api.max_version)).collect();
+    assert_eq!(api_keys, registered);
+    assert!(api_keys.contains(&(API_KEY, 0, 4)));
+    assert!(frame.is_empty());
+  }
+
+  #[test]
+  fn controller_listeners_only_advertise_controller_apis() {
+    let broker = Broker::new(BrokerConfig::default(), MetadataImage::empty());
+    let context = RequestContext { listener_name: "CONTROLLER".to_string(), listener_type: ListenerType::Controller, ..Default::default() };
+
+    let api_keys: Vec<_> = response(&broker, &context, ErrorCode::None).api_keys.iter().map(|api| api.api_key).collect();
+    assert_eq!(api_keys, vec![sasl_handshake::API_KEY, API_KEY, sasl_authenticate::API_KEY, describe_cluster::API_KEY]);
+    let everything = response(&broker, &RequestContext::default(), ErrorCode::None).api_keys;
+    assert_eq!(everything.len(), broker.apis.iter().count());
+  }
+}
@@ -0,0 +1,209 @@
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::codec::TaggedFields;
+use crate::kafka::common::{ErrorCode, CLUSTER_AUTHORIZED_OPERATIONS};
+use crate::kafka::config::ProcessRole;
+use crate::kafka::handlers::metadata;
+use crate::kafka::header::RequestHeader;
+use crate::kafka::registry::ListenerType;
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::{AllRequests, BROKER_ENDPOINT_TYPE, CONTROLLER_ENDPOINT_TYPE};
+use crate::kafka::responses::{
+  AllResponses, DescribeClusterBroker, DescribeClusterResponse, Response, AUTHORIZED_OPERATIONS_OMITTED,
+};
+
+pub const API_KEY: i16 = 60;
+
+/// Describes the brokers, as seen from the listener the request came in on, or the
+/// controllers when a v1+ request on a controller listener asks for them. Asking a listener
+/// for the other kind of endpoint is MISMATCHED_ENDPOINT_TYPE, as in Kafka.
+pub fn handle(broker: &Broker, context: &RequestContext, header: &RequestHeader, body: &AllRequests) -> Result<Response> {
+  let AllRequests::DescribeClusterRequest(request) = body else {
+    anyhow::bail!("DescribeCluster handler got {:?}", body);
+  };
+
+  let expected = match context.listener_type {
+    ListenerType::Broker => BROKER_ENDPOINT_TYPE,
+    ListenerType::Controller => CONTROLLER_ENDPOINT_TYPE,
+  };
+  if request.endpoint_type != BROKER_ENDPOINT_TYPE && request.endpoint_type != CONTROLLER_ENDPOINT_TYPE {
+    return Err(anyhow::Error::new(ErrorCode::UnsupportedEndpointType)
+      .context(format!("unknown endpoint type {}", request.endpoint_type)));
+  }
+  if request.endpoint_type != expected {
+    return Err(anyhow::Error::new(ErrorCode::MismatchedEndpointType)
+      .context(format!("endpoint type {} asked of listener {}", request.endpoint_type, context.listener_name)));
+  }
+
+  let (controller_id, brokers) = match context.listener_type {
+    // Like Metadata, name a live broker as the controller
+    ListenerType::Broker => (broker.config.node_id, brokers(broker, context)),
+    ListenerType::Controller => (-1, controllers(broker)),
+  };
+
+  Ok(Response::new(header, AllResponses::DescribeClusterResponse(DescribeClusterResponse {
+    endpoint_type: request.endpoint_type,
+    cluster_id: broker.metadata.cluster_id.clone().unwrap_or_default(),
+    controller_id,
+    brokers,
+    cluster_authorized_operations: if request.include_cluster_authorized_operations {
+      CLUSTER_AUTHORIZED_OPERATIONS
+    } else {
+      AUTHORIZED_OPERATIONS_OMITTED
+    },
+    ..Default::default()
+  })))
+}
+
+pub fn error_response(body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  let endpoint_type = match body {
+    AllRequests::DescribeClusterRequest(request) => request.endpoint_type,
+    _ => BROKER_ENDPOINT_TYPE,
+  };
+  AllResponses::DescribeClusterResponse(DescribeClusterResponse {
+    error_code: error_code.code(),
+    error_message: Some(error_code.name().to_string()),
+    endpoint_type,
+    ..Default::default()
+  })
+}
+
+fn brokers(broker: &Broker, context: &RequestContext) -> Vec<DescribeClusterBroker> {
+  metadata::brokers(broker, context).into_iter()
+    .map(|described| DescribeClusterBroker {
+      broker_id: described.node_id,
+      host: described.host,
+      port: described.port,
+      rack: described.rack,
+      tagged_fields: TaggedFields::default(),
+    })
+    .collect()
+}
+
+/// The `controller.quorum.voters`, or this node's controller listeners when the quorum is
+/// bootstrapped some other way.
+fn controllers(broker: &Broker) -> Vec<DescribeClusterBroker> {
+  let config = &broker.config;
+  if !config.controller_quorum_voters.is_empty() {
+    return config.controller_quorum_voters.iter()
+      .map(|voter| DescribeClusterBroker {
+        broker_id: voter.node_id,
+        host: voter.host.clone(),
+        port: voter.port as i32,
+        ..Default::default()
+      })
+      .collect();
+  }
+  if !config.process_roles.contains(&ProcessRole::Controller) {
+    return vec![];
+  }
+
+  config.listeners.iter()
+    .filter(|listener| config.is_controller_listener(&listener.listener_name))
+    .map(|listener| {
+      let advertised = config.advertised_listener(listener);
+      DescribeClusterBroker {
+        broker_id: config.node_id,
+        host: advertised.host,
+        port: advertised.port as i32,
+        ..Default::default()
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::config::BrokerConfig;
+  use crate::kafka::metadata_image::{BrokerImage, MetadataImage};
+  use crate::kafka::metadata_records::BrokerEndpoint;
+  use crate::kafka::requests::DescribeClusterRequest;
+
+  fn endpoint(name: &str, port: u16) -> BrokerEndpoint {
+    BrokerEndpoint {
+      name: name.to_string(),
+      host: format!("broker-{}", port),
+      port,
+      security_protocol: 0,
+      tagged_fields: TaggedFields::default(),
+    }
+  }
+
+  fn broker() -> Broker {
+    let config = BrokerConfig::from_properties(&[
+      ("process.roles", "broker,controller"),
+      ("listeners", "PLAINTEXT://:9092,INTERNAL://:9192,CONTROLLER://:9093"),
+      ("listener.security.protocol.map", "PLAINTEXT:PLAINTEXT,INTERNAL:PLAINTEXT,CONTROLLER:PLAINTEXT"),
+      ("inter.broker.listener.name", "INTERNAL"),
+      ("controller.listener.names", "CONTROLLER"),
+      ("controller.quorum.voters", "1@controller-1:9093"),
+    ].into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()).unwrap();
+
+    let mut metadata = MetadataImage::empty();
+    metadata.cluster_id = Some("cluster".to_string());
+    for (id, endpoints) in [(1, vec![endpoint("PLAINTEXT", 9092), endpoint("INTERNAL", 9192)]), (2, vec![endpoint("INTERNAL", 9292)])] {
+      metadata.brokers.insert(id, BrokerImage {
+        id,
+        epoch: 0,
+        incarnation_id: Default::default(),
+        endpoints,
+        rack: None,
+        fenced: false,
+        in_controlled_shutdown: false,
+      });
+    }
+    Broker::new(config, metadata)
+  }
+
+  fn context(listener_name: &str, listener_type: ListenerType) -> RequestContext {
+    RequestContext { listener_name: listener_name.to_string(), listener_type, ..Default::default() }
+  }
+
+  fn describe(broker: &Broker, context: &RequestContext, endpoint_type: i8) -> DescribeClusterResponse {
+    let request = AllRequests::DescribeClusterRequest(DescribeClusterRequest { endpoint_type, ..Default::default() });
+    let header = RequestHeader { request_api_key: API_KEY, request_api_version: 1, ..Default::default() };
+    let response = match handle(broker, context, &header, &request) {
+      Ok(response) => response.body,
+      Err(e) => error_response(&request, e.downcast_ref::<ErrorCode>().copied().unwrap()),
+    };
+    let AllResponses::DescribeClusterResponse(response) = response else {
+      panic!("expected a DescribeCluster response");
+    };
+    response
+  }
+
+  fn endpoints(response: &DescribeClusterResponse) -> Vec<(i32, String, i32)> {
+    response.brokers.iter().map(|broker| (broker.broker_id, broker.host.clone(), broker.port)).collect()
+  }
+
+  #[test]
+  fn advertises_brokers_at_the_listener_asked_on() {
+    let broker = broker();
+
+    let internal = describe(&broker, &context("INTERNAL", ListenerType::Broker), BROKER_ENDPOINT_TYPE);
+    assert_eq!((internal.error_code, internal.cluster_id.as_str(), internal.controller_id), (0, "cluster", 1));
+    assert_eq!(endpoints(&internal), vec![(1, "broker-9192".to_string(), 9192), (2, "broker-9292".to_string(), 9292)]);
+
+    // Broker 2 has no PLAINTEXT listener, so external clients don't get to see it
+    let external = describe(&broker, &context("PLAINTEXT", ListenerType::Broker), BROKER_ENDPOINT_TYPE);
+    assert_eq!(endpoints(&external), vec![(1, "broker-9092".to_string(), 9092)]);
+  }
+
+  #[test]
+  fn describes_controllers_only_on_controller_listeners() {
+    let broker = broker();
+
+    let controllers = describe(&broker, &context("CONTROLLER", ListenerType::Controller), CONTROLLER_ENDPOINT_TYPE);
+    assert_eq!((controllers.error_code, controllers.endpoint_type), (0, CONTROLLER_ENDPOINT_TYPE));
+    assert_eq!(endpoints(&controllers), vec![(1, "controller-1".to_string(), 9093)]);
+
+    let mismatched = describe(&broker, &context("PLAINTEXT", ListenerType::Broker), CONTROLLER_ENDPOINT_TYPE);
+    assert_eq!(mismatched.error_code, ErrorCode::MismatchedEndpointType.code());
+    let mismatched = describe(&broker, &context("CONTROLLER", ListenerType::Controller), BROKER_ENDPOINT_TYPE);
+    assert_eq!(mismatched.error_code, ErrorCode::MismatchedEndpointType.code());
+    let unknown = describe(&broker, &context("PLAINTEXT", ListenerType::Broker), 7);
+    assert_eq!(unknown.error_code, ErrorCode::UnsupportedEndpointType.code());
+  }
+}
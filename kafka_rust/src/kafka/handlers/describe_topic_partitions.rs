@@ -0,0 +1,239 @@
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::codec::{TaggedFields, Uuid};
+use crate::kafka::common::{ErrorCode, TOPIC_AUTHORIZED_OPERATIONS};
+use crate::kafka::header::RequestHeader;
+use crate::kafka::metadata_image::{MetadataImage, PartitionImage};
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::{AllRequests, DTPCursor};
+use crate::kafka::responses::{AllResponses, DTPResponse, DTPResponseBodyTopic, DTPResponsePartition, Response};
+
+pub const API_KEY: i16 = 75;
+
+/// Describes the requested topics (every topic when none are named) in name order, starting
+/// at the cursor and stopping once the partition limit is reached. The first partition left
+/// out goes back as `next_cursor`.
+pub fn handle(broker: &Broker, _context: &RequestContext, header: &RequestHeader, body: &AllRequests) -> Result<Response> {
+  let AllRequests::DTPRequest(request) = body else {
+    anyhow::bail!("DescribeTopicPartitions handler got {:?}", body);
+  };
+  let metadata = &broker.metadata;
+
+  let mut names: Vec<&str> = if request.topics.is_empty() {
+    metadata.topics().map(|topic| topic.name.as_str()).collect()
+  } else {
+    request.topics.iter().map(|topic| topic.name.as_str()).collect()
+  };
+  names.sort_unstable();
+  names.dedup();
+
+  if let Some(cursor) = &request.cursor {
+    if !names.contains(&cursor.topic_name.as_str()) {
+      return Err(anyhow::Error::new(ErrorCode::InvalidRequest)
+        .context(format!("cursor topic {:?} is not one of the requested topics", cursor.topic_name)));
+    }
+  }
+
+  // Like Kafka, the client can only ask for fewer partitions than the broker allows
+  let limit = broker.config.max_request_partition_size_limit;
+  let mut remaining = match request.response_partition_limit {
+    requested if requested > 0 => requested.min(limit),
+    _ => limit,
+  };
+
+  let mut topics = vec![];
+  let mut next_cursor = None;
+  for name in names {
+    let first_partition = match &request.cursor {
+      Some(cursor) if name < cursor.topic_name.as_str() => continue,
+      Some(cursor) if name == cursor.topic_name => cursor.partition_index,
+      _ => 0,
+    };
+
+    let Some(topic) = metadata.topic(name) else {
+      topics.push(DTPResponseBodyTopic {
+        error_code: ErrorCode::UnknownTopicOrPartition.code(),
+        topic_name: Some(name.to_string()),
+        topic_id: Uuid::ZERO,
+        topic_authorized_operations: TOPIC_AUTHORIZED_OPERATIONS,
+        ..Default::default()
+      });
+      continue;
+    };
+
+    let mut partitions = vec![];
+    for (index, partition) in topic.partitions.range(first_partition..) {
+      if remaining == 0 {
+        next_cursor = Some(cursor(name, *index));
+        break;
+      }
+      partitions.push(describe_partition(metadata, partition));
+      remaining -= 1;
+    }
+    // Out of room before this topic started, it goes entirely to the next page
+    if partitions.is_empty() && next_cursor.is_some() {
+      break;
+    }
+
+    topics.push(DTPResponseBodyTopic {
+      error_code: ErrorCode::None.code(),
+      topic_name: Some(topic.name.clone()),
+      topic_id: topic.id,
+      is_internal: topic.is_internal(),
+      partitions,
+      topic_authorized_operations: TOPIC_AUTHORIZED_OPERATIONS,
+      tagged_fields: TaggedFields::default(),
+    });
+    if next_cursor.is_some() {
+      break;
+    }
+  }
+
+  Ok(Response::new(header, AllResponses::DTPResponse(DTPResponse {
+    throttle_time: 0,
+    topics,
+    next_cursor,
+    tagged_fields: TaggedFields::default(),
+  })))
+}
+
+fn cursor(topic_name: &str, partition_index: i32) -> DTPCursor {
+  DTPCursor {
+    topic_name: topic_name.to_string(),
+    partition_index,
+    tagged_fields: TaggedFields::default(),
+  }
+}
+
+fn describe_partition(metadata: &MetadataImage, partition: &PartitionImage) -> DTPResponsePartition {
+  let leader_available = partition.leader >= 0 && !metadata.is_broker_offline(partition.leader);
+  let error_code = if leader_available { ErrorCode::None } else { ErrorCode::LeaderNotAvailable };
+
+  DTPResponsePartition {
+    error_code: error_code.code(),
+    partition_index: partition.partition_id,
+    leader_id: partition.leader,
+    leader_epoch: partition.leader_epoch,
+    replica_nodes: partition.replicas.clone(),
+    isr_nodes: partition.isr.clone(),
+    eligible_leader_replicas: partition.eligible_leader_replicas.clone(),
+    last_known_elr: partition.last_known_elr.clone(),
+    offline_replicas: partition.replicas.iter()
+      .copied()
+      .filter(|replica| metadata.is_broker_offline(*replica))
+      .collect(),
+    tagged_fields: TaggedFields::default(),
+  }
+}
+
+pub fn error_response(body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  let topics = match body {
+    AllRequests::DTPRequest(request) => request.topics.iter()
+      .map(|topic| DTPResponseBodyTopic {
+        error_code: error_code.code(),
+        topic_name: Some(topic.name.clone()),
+        ..Default::default()
+      })
+      .collect(),
+    _ => vec![],
+  };
+
+  AllResponses::DTPResponse(DTPResponse {
+    topics,
+    next_cursor: None,
+    ..Default::default()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::config::BrokerConfig;
+  use crate::kafka::metadata_records::{MetadataRecord, PartitionRecord, TopicRecord};
+  use crate::kafka::requests::{DTPRequest, DTPTopic};
+
+  fn broker() -> Broker {
+    let mut metadata = MetadataImage::empty();
+    let mut offset = 0;
+    for (name, id, partitions) in [("foo", 1, 1), ("bar", 2, 2), ("paz", 3, 3)] {
+      let topic = TopicRecord { name: name.to_string(), topic_uuid: Uuid(id), tagged_fields: TaggedFields::default() };
+      metadata.replay(offset, MetadataRecord::Topic(topic));
+      for partition_id in 0..partitions {
+        offset += 1;
+        let partition = PartitionRecord { partition_id, topic_id: Uuid(id), replicas: vec![1], isr: vec![1], leader: 1, ..Default::default() };
+        metadata.replay(offset, MetadataRecord::Partition(partition));
+      }
+      offset += 1;
+    }
+    Broker::new(BrokerConfig::default(), metadata)
+  }
+
+  fn describe(broker: &Broker, topics: &[&str], limit: i32, cursor: Option<DTPCursor>) -> DTPResponse {
+    let request = AllRequests::DTPRequest(DTPRequest {
+      topics: topics.iter()
+        .map(|name| DTPTopic { name: name.to_string() })
+        .collect(),
+      response_partition_limit: limit,
+      cursor,
+    });
+    let header = RequestHeader { request_api_key: API_KEY, flexible: true, ..Default::default() };
+    let AllResponses::DTPResponse(response) = handle(broker, &RequestContext::default(), &header, &request).unwrap().body else {
+      panic!("expected a DescribeTopicPartitions response");
+    };
+    response
+  }
+
+  fn summary(response: &DTPResponse) -> Vec<(String, i16, Vec<i32>)> {
+    response.topics.iter()
+      .map(|topic| (
+        topic.topic_name.clone().unwrap(),
+        topic.error_code,
+        topic.partitions.iter().map(|partition| partition.partition_index).collect(),
+      ))
+      .collect()
+  }
+
+  #[test]
+  fn answers_every_topic_sorted_by_name() {
+    let response = describe(&broker(), &["paz", "unknown", "foo", "bar"], 2000, None);
+
+    assert_eq!(summary(&response), vec![
+      ("bar".to_string(), 0, vec![0, 1]),
+      ("foo".to_string(), 0, vec![0]),
+      ("paz".to_string(), 0, vec![0, 1, 2]),
+      ("unknown".to_string(), 3, vec![]),
+    ]);
+    assert_eq!(response.topics[0].topic_id, Uuid(2));
+    assert_eq!(response.topics[0].topic_authorized_operations, 0x0df8);
+    assert_eq!(response.next_cursor, None);
+  }
+
+  #[test]
+  fn pages_through_partitions_with_the_cursor() {
+    let broker = broker();
+
+    let first = describe(&broker, &[], 2, None);
+    assert_eq!(summary(&first), vec![("bar".to_string(), 0, vec![0, 1])]);
+    assert_eq!(first.next_cursor, Some(cursor("foo", 0)));
+
+    let second = describe(&broker, &[], 2, first.next_cursor);
+    assert_eq!(summary(&second), vec![("foo".to_string(), 0, vec![0]), ("paz".to_string(), 0, vec![0])]);
+    assert_eq!(second.next_cursor, Some(cursor("paz", 1)));
+
+    let third = describe(&broker, &[], 2, second.next_cursor);
+    assert_eq!(summary(&third), vec![("paz".to_string(), 0, vec![1, 2])]);
+    assert_eq!(third.next_cursor, None);
+  }
+
+  #[test]
+  fn cursor_must_name_a_requested_topic() {
+    let request = AllRequests::DTPRequest(DTPRequest {
+      topics: vec![DTPTopic { name: "foo".to_string() }],
+      response_partition_limit: 10,
+      cursor: Some(cursor("bar", 0)),
+    });
+    let error = handle(&broker(), &RequestContext::default(), &RequestHeader::default(), &request).unwrap_err();
+    assert_eq!(error.downcast_ref::<ErrorCode>(), Some(&ErrorCode::InvalidRequest));
+  }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::codec::TaggedFields;
+use crate::kafka::common::ErrorCode;
+use crate::kafka::header::RequestHeader;
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::AllRequests;
+use crate::kafka::responses::{
+  AllResponses, CredentialInfo, DescribeUserScramCredentialsResponse, DescribeUserScramCredentialsResult, Response,
+};
+
+pub const API_KEY: i16 = 50;
+
+/// Lists the SCRAM mechanisms and iterations of the users asked for, every user with
+/// credentials when none are named. Salts and keys never leave the broker.
+pub fn handle(broker: &Broker, _context: &RequestContext, header: &RequestHeader, body: &AllRequests) -> Result<Response> {
+  let AllRequests::DescribeUserScramCredentialsRequest(request) = body else {
+    anyhow::bail!("DescribeUserScramCredentials handler got {:?}", body);
+  };
+
+  let mut described: BTreeMap<&str, Vec<CredentialInfo>> = BTreeMap::new();
+  let credentials = broker.scram_credentials.read().unwrap();
+  for ((user, mechanism), credential) in credentials.iter() {
+    described.entry(user).or_default().push(CredentialInfo {
+      mechanism: *mechanism,
+      iterations: credential.iterations,
+      tagged_fields: TaggedFields::default(),
+    });
+  }
+
+  let results = match request.users.as_deref() {
+    None | Some([]) => described.into_iter().map(|(user, credential_infos)| described_user(user, credential_infos)).collect(),
+    Some(users) => {
+      let mut results: Vec<DescribeUserScramCredentialsResult> = vec![];
+      for user in users {
+        // A user named twice gets one result, an error
+        if let Some(result) = results.iter_mut().find(|result| result.user == user.name) {
+          *result = failed_user(&user.name, ErrorCode::DuplicateResource, "Cannot describe SCRAM credentials for the same user twice in a single request");
+          continue;
+        }
+        results.push(match described.get(user.name.as_str()) {
+          Some(credential_infos) => described_user(&user.name, credential_infos.clone()),
+          None => failed_user(&user.name, ErrorCode::ResourceNotFound, "Attempt to describe a user credential that does not exist"),
+        });
+      }
+      results
+    }
+  };
+
+  Ok(Response::new(header, AllResponses::DescribeUserScramCredentialsResponse(DescribeUserScramCredentialsResponse {
+    results,
+    ..Default::default()
+  })))
+}
+
+pub fn error_response(_body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  AllResponses::DescribeUserScramCredentialsResponse(DescribeUserScramCredentialsResponse {
+    error_code: error_code.code(),
+    error_message: Some(error_code.name().to_string()),
+    ..Default::default()
+  })
+}
+
+fn described_user(user: &str, credential_infos: Vec<CredentialInfo>) -> DescribeUserScramCredentialsResult {
+  DescribeUserScramCredentialsResult { user: user.to_string(), credential_infos, ..Default::default() }
+}
+
+fn failed_user(user: &str, error_code: ErrorCode, message: &str) -> DescribeUserScramCredentialsResult {
+  DescribeUserScramCredentialsResult {
+    user: user.to_string(),
+    error_code: error_code.code(),
+    error_message: Some(format!("{}: {}", message, user)),
+    ..Default::default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+
+  use super::*;
+  use crate::kafka::config::BrokerConfig;
+  use crate::kafka::metadata_image::{MetadataImage, ScramCredential};
+  use crate::kafka::requests::{DescribeUserScramCredentialsRequest, UserName};
+
+  /// Every result's user, error code and (mechanism, iterations) pairs.
+  type Described = Vec<(String, i16, Vec<(i8, i32)>)>;
+
+  fn describe(broker: &Broker, users: Option<&[&str]>) -> Described {
+    let request = AllRequests::DescribeUserScramCredentialsRequest(DescribeUserScramCredentialsRequest {
+      users: users.map(|users| users.iter().map(|name| UserName { name: name.to_string() }).collect()),
+    });
+    let header = RequestHeader { request_api_key: API_KEY, ..Default::default() };
+    let AllResponses::DescribeUserScramCredentialsResponse(response) = handle(broker, &RequestContext::default(), &header, &request).unwrap().body else {
+      panic!("expected a DescribeUserScramCredentials response");
+    };
+    response.results.into_iter()
+      .map(|result| {
+        let infos = result.credential_infos.iter().map(|info| (info.mechanism, info.iterations)).collect();
+        (result.user, result.error_code, infos)
+      })
+      .collect()
+  }
+
+  #[test]
+  fn describes_mechanisms_and_iterations_of_users() {
+    let mut metadata = MetadataImage::empty();
+    for (user, mechanism, iterations) in [("alice", 1, 4096), ("alice", 2, 8192), ("bob", 2, 4096)] {
+      let credential = ScramCredential { salt: Bytes::new(), stored_key: Bytes::new(), server_key: Bytes::new(), iterations };
+      metadata.scram_credentials.insert((user.to_string(), mechanism), credential);
+    }
+    let broker = Broker::new(BrokerConfig::default(), metadata);
+
+    let everyone = describe(&broker, None);
+    assert_eq!(everyone, vec![
+      ("alice".to_string(), 0, vec![(1, 4096), (2, 8192)]),
+      ("bob".to_string(), 0, vec![(2, 4096)]),
+    ]);
+    let asked = describe(&broker, Some(&["bob", "carol", "alice", "alice"]));
+    assert_eq!(asked, vec![
+      ("bob".to_string(), 0, vec![(2, 4096)]),
+      ("carol".to_string(), ErrorCode::ResourceNotFound.code(), vec![]),
+      ("alice".to_string(), ErrorCode::DuplicateResource.code(), vec![]),
+    ]);
+  }
+}
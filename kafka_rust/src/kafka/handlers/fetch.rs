@@ -0,0 +1,543 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+
+use crate::kafka::broker::Broker;
+use crate::kafka::codec::Uuid;
+use crate::kafka::common::{ErrorCode, READ_COMMITTED};
+use crate::kafka::compression::Compression;
+use crate::kafka::fetch_session::{CachedPartition, FINAL_EPOCH, INITIAL_EPOCH, INVALID_SESSION_ID};
+use crate::kafka::header::RequestHeader;
+use crate::kafka::log::TopicPartition;
+use crate::kafka::record_batch::{self, RecordBatch, RecordBatches, COMPRESSION_CODEC_MASK};
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::{AllRequests, FetchRequest};
+use crate::kafka::responses::{AllResponses, FetchPartitionResponse, FetchResponse, FetchTopicResponse, Response};
+
+pub const API_KEY: i16 = 1;
+
+/// The first Fetch version that can carry zstd batches, older ones get them uncompressed.
+const ZSTD_MIN_VERSION: i16 = 10;
+
+/// A partition to read and where to read it from.
+type PartitionFetch = (TopicPartition, CachedPartition);
+
+/// How this request relates to the fetch session cache.
+enum Session {
+  /// No session, every requested partition is answered.
+  None,
+  /// A new session, answered in full like a sessionless fetch.
+  Full(i32),
+  /// An existing session, only partitions with something new are answered.
+  Incremental(i32),
+}
+
+pub fn handle(broker: &Broker, _context: &RequestContext, header: &RequestHeader, body: &AllRequests) -> Result<Response> {
+  let AllRequests::FetchRequest(request) = body else {
+    anyhow::bail!("Fetch handler got {:?}", body);
+  };
+  let version = header.request_api_version;
+  let (requested, unknown_ids) = requested_partitions(broker, request, version);
+  let sessions = &broker.fetch_sessions;
+
+  let (session, fetches) = match (request.session_id, request.session_epoch) {
+    (INVALID_SESSION_ID, FINAL_EPOCH) => (Session::None, requested),
+    (id, FINAL_EPOCH) => {
+      sessions.remove(id);
+      (Session::None, requested)
+    }
+    (id, INITIAL_EPOCH) => {
+      if id != INVALID_SESSION_ID {
+        sessions.remove(id);
+      }
+      let id = sessions.create(requested.iter().cloned().collect());
+      (if id == INVALID_SESSION_ID { Session::None } else { Session::Full(id) }, requested)
+    }
+    (INVALID_SESSION_ID, epoch) => {
+      println!("Fetch with epoch {} but no session", epoch);
+      return Ok(session_error(header, ErrorCode::InvalidFetchSessionEpoch));
+    }
+    (id, epoch) => {
+      let forgotten = forgotten_partitions(broker, request, version);
+      let updated = sessions.update(id, epoch, |partitions| {
+        for (topic_partition, fetch) in requested {
+          match partitions.get_mut(&topic_partition) {
+            // Keep what the client was last told, that's what decides if it hears about it again
+            Some(cached) => {
+              *cached = CachedPartition {
+                high_watermark: cached.high_watermark,
+                log_start_offset: cached.log_start_offset,
+                ..fetch
+              }
+            }
+            None => {
+              partitions.insert(topic_partition, fetch);
+            }
+          }
+        }
+        for topic_partition in &forgotten {
+          partitions.remove(topic_partition);
+        }
+      });
+      match updated {
+        Ok(partitions) => (Session::Incremental(id), partitions.into_iter().collect()),
+        Err(error_code) => {
+          println!("Fetch session {} epoch {} failed: {}", id, epoch, error_code);
+          return Ok(session_error(header, error_code));
+        }
+      }
+    }
+  };
+
+  // Long poll: keep reading until there's min_bytes of data, something went wrong, or
+  // max_wait_ms runs out. Appends wake us up early.
+  let deadline = Instant::now() + Duration::from_millis(request.max_wait_ms.max(0) as u64);
+  let max_bytes = (request.max_bytes.max(0) as usize).min(broker.config.fetch_max_bytes);
+  let read = loop {
+    let seen = broker.logs.appends();
+    let read = read_partitions(broker, version, &fetches, max_bytes, request.isolation_level);
+    let bytes: usize = read.iter().map(|partition| partition.records.as_ref().map_or(0, Bytes::len)).sum();
+    let failed = read.iter().any(|partition| partition.error_code != ErrorCode::None.code());
+    if bytes >= request.min_bytes.max(0) as usize || failed || !broker.logs.wait_for_append(seen, deadline) {
+      break read;
+    }
+  };
+
+  let mut responses: Vec<FetchTopicResponse> = vec![];
+  for ((topic_partition, fetch), partition) in fetches.iter().zip(read) {
+    match session {
+      Session::Incremental(id) => {
+        let changed = partition.records.as_ref().is_some_and(|records| !records.is_empty())
+          || partition.error_code != ErrorCode::None.code()
+          || partition.high_watermark != fetch.high_watermark
+          || partition.log_start_offset != fetch.log_start_offset;
+        if !changed {
+          continue;
+        }
+        sessions.record_sent(id, topic_partition, partition.high_watermark, partition.log_start_offset);
+      }
+      Session::Full(id) => sessions.record_sent(id, topic_partition, partition.high_watermark, partition.log_start_offset),
+      Session::None => {}
+    }
+    push_partition(&mut responses, &topic_partition.topic, fetch.topic_id, partition);
+  }
+  for (topic_id, partition_index) in unknown_ids {
+    let partition = error_partition(partition_index, ErrorCode::UnknownTopicId);
+    push_partition(&mut responses, "", topic_id, partition);
+  }
+
+  Ok(Response::new(header, AllResponses::FetchResponse(FetchResponse {
+    session_id: match session {
+      Session::Full(id) | Session::Incremental(id) => id,
+      Session::None => INVALID_SESSION_ID,
+    },
+    responses,
+    ..Default::default()
+  })))
+}
+
+/// The partitions named in the request, in request order. From v13 topics come by id, ids
+/// the metadata image doesn't know are returned separately.
+fn requested_partitions(broker: &Broker, request: &FetchRequest, version: i16) -> (Vec<PartitionFetch>, Vec<(Uuid, i32)>) {
+  let mut requested = vec![];
+  let mut unknown_ids = vec![];
+  for topic in &request.topics {
+    let (name, topic_id) = if version >= 13 {
+      match broker.metadata.topic_by_id(topic.topic_id) {
+        Some(image) => (image.name.as_str(), topic.topic_id),
+        None => {
+          unknown_ids.extend(topic.partitions.iter().map(|partition| (topic.topic_id, partition.partition)));
+          continue;
+        }
+      }
+    } else {
+      (topic.topic.as_str(), broker.metadata.topic(&topic.topic).map_or(Uuid::ZERO, |image| image.id))
+    };
+
+    for partition in &topic.partitions {
+      requested.push((TopicPartition::new(name, partition.partition), CachedPartition {
+        topic_id,
+        fetch_offset: partition.fetch_offset,
+        partition_max_bytes: partition.partition_max_bytes,
+        current_leader_epoch: partition.current_leader_epoch,
+        high_watermark: -1,
+        log_start_offset: -1,
+      }));
+    }
+  }
+  (requested, unknown_ids)
+}
+
+fn forgotten_partitions(broker: &Broker, request: &FetchRequest, version: i16) -> Vec<TopicPartition> {
+  request.forgotten_topics_data.iter()
+    .filter_map(|topic| {
+      let name = if version >= 13 {
+        broker.metadata.topic_by_id(topic.topic_id)?.name.as_str()
+      } else {
+        topic.topic.as_str()
+      };
+      Some(topic.partitions.iter().map(move |partition| TopicPartition::new(name, *partition)))
+    })
+    .flatten()
+    .collect()
+}
+
+/// Reads every partition, sharing `max_bytes` between them in order. The first partition
+/// with data always gets at least one batch so an oversized batch can't stall the consumer.
+fn read_partitions(broker: &Broker, version: i16, fetches: &[PartitionFetch], max_bytes: usize, isolation_level: i8) -> Vec<FetchPartitionResponse> {
+  let mut remaining = max_bytes;
+  let mut min_one_batch = true;
+
+  fetches.iter()
+    .map(|(topic_partition, fetch)| {
+      let mut partition = match read_partition(broker, version, topic_partition, fetch, remaining, min_one_batch) {
+        Ok(partition) => partition,
+        Err(e) => {
+          let error_code = e.downcast_ref::<ErrorCode>().copied().unwrap_or(ErrorCode::KafkaStorageError);
+          println!("Fetch from {} failed, answering {}: {:#}", topic_partition, error_code, e);
+          error_partition(topic_partition.partition, error_code)
+        }
+      };
+
+      let read = partition.records.as_ref().map_or(0, Bytes::len);
+      if read > 0 {
+        remaining = remaining.saturating_sub(read);
+        min_one_batch = false;
+      }
+      // There are no transactions, but READ_COMMITTED consumers expect a (empty) list
+      if isolation_level == READ_COMMITTED {
+        partition.aborted_transactions = Some(vec![]);
+      }
+      partition
+    })
+    .collect()
+}
+
+fn read_partition(
+  broker: &Broker,
+  version: i16,
+  topic_partition: &TopicPartition,
+  fetch: &CachedPartition,
+  max_bytes: usize,
+  min_one_batch: bool,
+) -> Result<FetchPartitionResponse> {
+  broker.leader_partition(&topic_partition.topic, topic_partition.partition, fetch.current_leader_epoch)?;
+
+  let log = broker.logs.get_or_open(topic_partition)?;
+  let log = log.lock().unwrap();
+  let (log_start_offset, log_end_offset) = (log.log_start_offset(), log.log_end_offset());
+  if fetch.fetch_offset < log_start_offset || fetch.fetch_offset > log_end_offset {
+    return Err(anyhow::Error::new(ErrorCode::OffsetOutOfRange)
+      .context(format!("offset {} is outside [{}, {}]", fetch.fetch_offset, log_start_offset, log_end_offset)));
+  }
+
+  let max_bytes = max_bytes.min(fetch.partition_max_bytes.max(0) as usize);
+  let mut records = log.read(fetch.fetch_offset, max_bytes, min_one_batch)?;
+  if version < ZSTD_MIN_VERSION {
+    records = down_convert(records)?;
+  }
+
+  // Every replica lives here and there are no transactions, so everything appended is
+  // committed and stable
+  Ok(FetchPartitionResponse {
+    partition_index: topic_partition.partition,
+    error_code: ErrorCode::None.code(),
+    high_watermark: log_end_offset,
+    last_stable_offset: log_end_offset,
+    log_start_offset,
+    records: Some(records),
+    ..Default::default()
+  })
+}
+
+/// `records` with zstd batches rewritten uncompressed, for clients that predate zstd. The
+/// rest are passed through untouched.
+fn down_convert(records: Bytes) -> Result<Bytes> {
+  let mut batches = RecordBatches::new(records.clone());
+  let mut converted: Option<BytesMut> = None;
+  let mut start = 0;
+  while let Some(raw) = batches.next_raw() {
+    if record_batch::attributes(&raw) & COMPRESSION_CODEC_MASK == Compression::Zstd.id() {
+      let mut batch = RecordBatch::decode(&mut raw.clone()).context("decoding a zstd batch to down-convert")?;
+      batch.attributes &= !COMPRESSION_CODEC_MASK;
+      let converted = converted.get_or_insert_with(BytesMut::new);
+      converted.extend_from_slice(&records[start..batches.position() - raw.len()]);
+      converted.extend_from_slice(&batch.encode());
+      start = batches.position();
+    }
+  }
+  Ok(match converted {
+    Some(mut converted) => {
+      converted.extend_from_slice(&records[start..]);
+      converted.freeze()
+    }
+    None => records,
+  })
+}
+
+fn error_partition(partition_index: i32, error_code: ErrorCode) -> FetchPartitionResponse {
+  FetchPartitionResponse {
+    partition_index,
+    error_code: error_code.code(),
+    records: Some(Bytes::new()),
+    ..Default::default()
+  }
+}
+
+/// Adds `partition` to the last topic when it's the same one, keeping request order.
+fn push_partition(responses: &mut Vec<FetchTopicResponse>, topic: &str, topic_id: Uuid, partition: FetchPartitionResponse) {
+  match responses.last_mut() {
+    Some(last) if last.topic == topic && last.topic_id == topic_id => last.partitions.push(partition),
+    _ => responses.push(FetchTopicResponse {
+      topic: topic.to_string(),
+      topic_id,
+      partitions: vec![partition],
+      ..Default::default()
+    }),
+  }
+}
+
+fn session_error(header: &RequestHeader, error_code: ErrorCode) -> Response {
+  Response::new(header, AllResponses::FetchResponse(FetchResponse {
+    error_code: error_code.code(),
+    session_id: INVALID_SESSION_ID,
+    ..Default::default()
+  }))
+}
+
+pub fn error_response(body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  let responses = match body {
+    AllRequests::FetchRequest(request) => request.topics.iter()
+      .map(|topic| FetchTopicResponse {
+        topic: topic.topic.clone(),
+        topic_id: topic.topic_id,
+        partitions: topic.partitions.iter()
+          .map(|partition| error_partition(partition.partition, error_code))
+          .collect(),
+        ..Default::default()
+      })
+      .collect(),
+    _ => vec![],
+  };
+
+  AllResponses::FetchResponse(FetchResponse {
+    responses,
+    ..Default::default()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::record_batch::Record;
+  use crate::kafka::requests::{FetchPartition, FetchTopic, ForgottenTopic};
+  use crate::kafka::test_support::{broker, temp_dir, value_batch};
+
+  /// Appends a batch of `values` to partition `partition`, returning its size.
+  fn append(broker: &Broker, partition: i32, values: &[&str]) -> usize {
+    let mut batch = value_batch(values);
+    let log = broker.logs.get_or_open(&TopicPartition::new("foo", partition)).unwrap();
+    log.lock().unwrap().append(&mut batch, 3).unwrap();
+    broker.logs.notify_append();
+    batch.len()
+  }
+
+  fn request(partitions: &[(i32, i64)]) -> FetchRequest {
+    FetchRequest {
+      replica_id: -1,
+      max_bytes: 1024 * 1024,
+      session_epoch: FINAL_EPOCH,
+      topics: vec![FetchTopic {
+        topic: "foo".to_string(),
+        topic_id: Uuid(1),
+        partitions: partitions.iter()
+          .map(|(partition, fetch_offset)| FetchPartition {
+            partition: *partition,
+            fetch_offset: *fetch_offset,
+            partition_max_bytes: 1024 * 1024,
+            ..Default::default()
+          })
+          .collect(),
+      }],
+      ..Default::default()
+    }
+  }
+
+  fn fetch(broker: &Broker, version: i16, request: FetchRequest) -> FetchResponse {
+    let header = RequestHeader { request_api_key: API_KEY, request_api_version: version, ..Default::default() };
+    let AllResponses::FetchResponse(response) = handle(broker, &RequestContext::default(), &header, &AllRequests::FetchRequest(request)).unwrap().body else {
+      panic!("expected a Fetch response");
+    };
+    response
+  }
+
+  fn summary(response: &FetchResponse) -> Vec<(i32, i16, i64, usize)> {
+    response.responses.iter()
+      .flat_map(|topic| topic.partitions.iter())
+      .map(|partition| (
+        partition.partition_index,
+        partition.error_code,
+        partition.high_watermark,
+        partition.records.as_ref().map_or(0, Bytes::len),
+      ))
+      .collect()
+  }
+
+  #[test]
+  fn reads_whole_batches_from_the_fetch_offset() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 2, 3);
+    let first = append(&broker, 0, &["a", "b"]);
+    let second = append(&broker, 0, &["c"]);
+
+    // Offset 1 sits inside the first batch, which comes back whole
+    let response = fetch(&broker, 12, request(&[(0, 1), (1, 0)]));
+    assert_eq!(summary(&response), vec![(0, 0, 3, first + second), (1, 0, 0, 0)]);
+    assert_eq!(response.responses[0].partitions[0].log_start_offset, 0);
+
+    let response = fetch(&broker, 12, request(&[(0, 2)]));
+    assert_eq!(summary(&response), vec![(0, 0, 3, second)]);
+
+    // A partition_max_bytes smaller than the batch still gets the first batch
+    let mut small = request(&[(0, 0)]);
+    small.topics[0].partitions[0].partition_max_bytes = 10;
+    assert_eq!(summary(&fetch(&broker, 12, small)), vec![(0, 0, 3, first)]);
+
+    let response = fetch(&broker, 12, request(&[(0, 4), (7, 0)]));
+    assert_eq!(summary(&response), vec![
+      (0, ErrorCode::OffsetOutOfRange.code(), -1, 0),
+      (7, ErrorCode::UnknownTopicOrPartition.code(), -1, 0),
+    ]);
+
+    // v13+ names topics by id
+    let mut by_id = request(&[(0, 0)]);
+    by_id.topics[0].topic = String::new();
+    by_id.topics.push(FetchTopic { topic_id: Uuid(9), partitions: vec![FetchPartition::default()], ..Default::default() });
+    let response = fetch(&broker, 13, by_id);
+    assert_eq!(summary(&response), vec![(0, 0, 3, first + second), (0, ErrorCode::UnknownTopicId.code(), -1, 0)]);
+    assert_eq!(response.responses[1].topic_id, Uuid(9));
+  }
+
+  #[test]
+  fn down_converts_zstd_batches_for_old_versions() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 2, 3);
+    let log = broker.logs.get_or_open(&TopicPartition::new("foo", 0)).unwrap();
+    for compression in [Compression::Zstd, Compression::Gzip] {
+      let mut batch = RecordBatch {
+        attributes: compression.id(),
+        last_offset_delta: 1,
+        records: ["a", "b"].iter()
+          .enumerate()
+          .map(|(i, value)| Record { offset_delta: i as i32, value: Some(Bytes::from(*value)), ..Default::default() })
+          .collect(),
+        ..Default::default()
+      }
+      .encode();
+      log.lock().unwrap().append(&mut batch, 3).unwrap();
+    }
+
+    let batches = |version: i16| {
+      let response = fetch(&broker, version, request(&[(0, 0)]));
+      let records = response.responses[0].partitions[0].records.clone().unwrap();
+      let mut raw = RecordBatches::new(records.clone());
+      while let Some(raw) = raw.next_raw() {
+        assert!(record_batch::crc_matches(&raw));
+      }
+      RecordBatches::new(records)
+        .map(|batch| {
+          let batch = batch.unwrap();
+          (batch.base_offset, batch.partition_leader_epoch, batch.compression(), batch.records.len())
+        })
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(batches(10), vec![(0, 3, Compression::Zstd.id(), 2), (2, 3, Compression::Gzip.id(), 2)]);
+    assert_eq!(batches(9), vec![(0, 3, Compression::None.id(), 2), (2, 3, Compression::Gzip.id(), 2)]);
+  }
+
+  #[test]
+  fn waits_for_min_bytes_until_an_append() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 2, 3);
+    let mut request = request(&[(0, 0)]);
+    request.min_bytes = 1;
+    request.max_wait_ms = 5_000;
+
+    let started = Instant::now();
+    let (response, appended) = std::thread::scope(|scope| {
+      let appended = scope.spawn(|| {
+        std::thread::sleep(Duration::from_millis(50));
+        append(&broker, 0, &["a"])
+      });
+      (fetch(&broker, 12, request.clone()), appended.join().unwrap())
+    });
+    assert_eq!(summary(&response), vec![(0, 0, 1, appended)]);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // Nothing new past the end, so it gives up after max_wait_ms
+    request.topics[0].partitions[0].fetch_offset = 1;
+    request.max_wait_ms = 20;
+    assert_eq!(summary(&fetch(&broker, 12, request)), vec![(0, 0, 1, 0)]);
+  }
+
+  #[test]
+  fn incremental_sessions_only_answer_changed_partitions() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 2, 3);
+    let first = append(&broker, 0, &["a"]);
+
+    let mut full = request(&[(0, 0), (1, 0)]);
+    full.session_epoch = INITIAL_EPOCH;
+    let response = fetch(&broker, 12, full);
+    assert_ne!(response.session_id, INVALID_SESSION_ID);
+    assert_eq!(summary(&response), vec![(0, 0, 1, first), (1, 0, 0, 0)]);
+    let session_id = response.session_id;
+
+    // The client moves partition 0 along, nothing else changed
+    let mut incremental = request(&[(0, 1)]);
+    incremental.session_id = session_id;
+    incremental.session_epoch = 1;
+    let response = fetch(&broker, 12, incremental.clone());
+    assert_eq!((response.session_id, summary(&response)), (session_id, vec![]));
+
+    let second = append(&broker, 1, &["a", "b"]);
+    incremental.topics.clear();
+    incremental.session_epoch = 2;
+    assert_eq!(summary(&fetch(&broker, 12, incremental.clone())), vec![(1, 0, 2, second)]);
+
+    // Replaying an old epoch or an unknown session fails the whole fetch
+    let response = fetch(&broker, 12, incremental.clone());
+    assert_eq!((response.error_code, response.responses.len()), (ErrorCode::InvalidFetchSessionEpoch.code(), 0));
+    incremental.session_id = session_id + 1;
+    incremental.session_epoch = 3;
+    assert_eq!(fetch(&broker, 12, incremental).error_code, ErrorCode::FetchSessionIdNotFound.code());
+  }
+
+  #[test]
+  fn incremental_fetches_drop_forgotten_partitions() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 2, 3);
+    let mut full = request(&[(0, 0), (1, 0)]);
+    full.session_epoch = INITIAL_EPOCH;
+    let session_id = fetch(&broker, 12, full).session_id;
+
+    // v7-12 name the forgotten topic, v13+ give its id
+    let incremental = |version: i16, epoch: i32, forgotten: Vec<ForgottenTopic>| {
+      let mut incremental = request(&[]);
+      incremental.topics.clear();
+      (incremental.session_id, incremental.session_epoch, incremental.forgotten_topics_data) = (session_id, epoch, forgotten);
+      summary(&fetch(&broker, version, incremental))
+    };
+    let by_name = ForgottenTopic { topic: "foo".to_string(), partitions: vec![1], ..Default::default() };
+    assert_eq!(incremental(12, 1, vec![by_name]), vec![]);
+    let first = append(&broker, 0, &["a"]);
+    append(&broker, 1, &["a"]);
+    assert_eq!(incremental(12, 2, vec![]), vec![(0, 0, 1, first)]);
+
+    let by_id = ForgottenTopic { topic_id: Uuid(1), partitions: vec![0], ..Default::default() };
+    assert_eq!(incremental(13, 3, vec![by_id]), vec![]);
+    append(&broker, 0, &["b"]);
+    assert_eq!(incremental(13, 4, vec![]), vec![]);
+  }
+}
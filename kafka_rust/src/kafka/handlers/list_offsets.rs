@@ -0,0 +1,259 @@
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::common::{ErrorCode, READ_COMMITTED};
+use crate::kafka::header::RequestHeader;
+use crate::kafka::log::TopicPartition;
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::{AllRequests, ListOffsetsPartition};
+use crate::kafka::responses::{
+  AllResponses, ListOffsetsPartitionResponse, ListOffsetsResponse, ListOffsetsTopicResponse, Response,
+};
+
+pub const API_KEY: i16 = 2;
+
+/// `timestamp` asking for the offset the next record gets, or the last stable offset for
+/// READ_COMMITTED.
+pub const LATEST_TIMESTAMP: i64 = -1;
+/// `timestamp` asking for the log start offset.
+pub const EARLIEST_TIMESTAMP: i64 = -2;
+/// v7+, `timestamp` asking for the record with the largest timestamp.
+pub const MAX_TIMESTAMP: i64 = -3;
+/// v8+, `timestamp` asking for the first offset on local disk. Without tiered storage that's
+/// the log start offset.
+pub const EARLIEST_LOCAL_TIMESTAMP: i64 = -4;
+
+const MAX_TIMESTAMP_MIN_VERSION: i16 = 7;
+const EARLIEST_LOCAL_TIMESTAMP_MIN_VERSION: i16 = 8;
+
+pub fn handle(broker: &Broker, _context: &RequestContext, header: &RequestHeader, body: &AllRequests) -> Result<Response> {
+  let AllRequests::ListOffsetsRequest(request) = body else {
+    anyhow::bail!("ListOffsets handler got {:?}", body);
+  };
+
+  let topics = request.topics.iter()
+    .map(|topic| ListOffsetsTopicResponse {
+      name: topic.name.clone(),
+      partitions: topic.partitions.iter()
+        .map(|partition| {
+          let topic_partition = TopicPartition::new(&topic.name, partition.partition_index);
+          list_offset(broker, header.request_api_version, &topic_partition, partition, request.isolation_level).unwrap_or_else(|e| {
+            let error_code = e.downcast_ref::<ErrorCode>().copied().unwrap_or(ErrorCode::KafkaStorageError);
+            println!("ListOffsets of {} failed, answering {}: {:#}", topic_partition, error_code, e);
+            error_partition(partition.partition_index, error_code)
+          })
+        })
+        .collect(),
+      ..Default::default()
+    })
+    .collect();
+
+  Ok(Response::new(header, AllResponses::ListOffsetsResponse(ListOffsetsResponse {
+    topics,
+    ..Default::default()
+  })))
+}
+
+fn list_offset(
+  broker: &Broker,
+  version: i16,
+  topic_partition: &TopicPartition,
+  request: &ListOffsetsPartition,
+  isolation_level: i8,
+) -> Result<ListOffsetsPartitionResponse> {
+  let partition = broker.leader_partition(&topic_partition.topic, topic_partition.partition, request.current_leader_epoch)?;
+  if !is_valid_timestamp(request.timestamp, version) {
+    return Err(anyhow::Error::new(ErrorCode::InvalidRequest)
+      .context(format!("timestamp {} in ListOffsets v{}", request.timestamp, version)));
+  }
+
+  let log = broker.logs.get_or_open(topic_partition)?;
+  let log = log.lock().unwrap();
+  // Every replica lives here and there are no transactions, so the high watermark and the
+  // last stable offset are both the log end offset
+  let (high_watermark, last_stable_offset) = (log.log_end_offset(), log.log_end_offset());
+  let visible_end = if isolation_level == READ_COMMITTED { last_stable_offset } else { high_watermark };
+
+  let (timestamp, offset, leader_epoch) = match request.timestamp {
+    LATEST_TIMESTAMP => (-1, visible_end, partition.leader_epoch),
+    EARLIEST_TIMESTAMP | EARLIEST_LOCAL_TIMESTAMP => {
+      let offset = log.log_start_offset();
+      (-1, offset, log.leader_epoch_at(offset)?.unwrap_or(partition.leader_epoch))
+    }
+    timestamp => {
+      let found = if timestamp == MAX_TIMESTAMP {
+        log.find_max_timestamp()?
+      } else {
+        log.find_offset_by_timestamp(timestamp)?
+      };
+      match found.filter(|(_, offset)| *offset < visible_end) {
+        Some((timestamp, offset)) => (timestamp, offset, log.leader_epoch_at(offset)?.unwrap_or(-1)),
+        None => (-1, -1, -1),
+      }
+    }
+  };
+
+  Ok(ListOffsetsPartitionResponse {
+    partition_index: topic_partition.partition,
+    error_code: ErrorCode::None.code(),
+    timestamp,
+    offset,
+    leader_epoch,
+    ..Default::default()
+  })
+}
+
+/// Whether `timestamp` is a time to look up or a special timestamp `version` knows about.
+fn is_valid_timestamp(timestamp: i64, version: i16) -> bool {
+  match timestamp {
+    LATEST_TIMESTAMP | EARLIEST_TIMESTAMP => true,
+    MAX_TIMESTAMP => version >= MAX_TIMESTAMP_MIN_VERSION,
+    EARLIEST_LOCAL_TIMESTAMP => version >= EARLIEST_LOCAL_TIMESTAMP_MIN_VERSION,
+    timestamp => timestamp >= 0,
+  }
+}
+
+fn error_partition(partition_index: i32, error_code: ErrorCode) -> ListOffsetsPartitionResponse {
+  ListOffsetsPartitionResponse {
+    partition_index,
+    error_code: error_code.code(),
+    ..Default::default()
+  }
+}
+
+pub fn error_response(body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  let topics = match body {
+    AllRequests::ListOffsetsRequest(request) => request.topics.iter()
+      .map(|topic| ListOffsetsTopicResponse {
+        name: topic.name.clone(),
+        partitions: topic.partitions.iter()
+          .map(|partition| error_partition(partition.partition_index, error_code))
+          .collect(),
+        ..Default::default()
+      })
+      .collect(),
+    _ => vec![],
+  };
+  AllResponses::ListOffsetsResponse(ListOffsetsResponse {
+    topics,
+    ..Default::default()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::header::RequestHeader;
+  use crate::kafka::requests::{ListOffsetsRequest, ListOffsetsTopic};
+  use crate::kafka::test_support::{broker, temp_dir, timestamp_batch};
+
+  /// Appends a batch with one record per timestamp in leader epoch `leader_epoch`.
+  fn append(broker: &Broker, timestamps: &[i64], leader_epoch: i32) {
+    let mut batch = timestamp_batch(timestamps);
+    let log = broker.logs.get_or_open(&TopicPartition::new("foo", 0)).unwrap();
+    log.lock().unwrap().append(&mut batch, leader_epoch).unwrap();
+  }
+
+  fn request(partitions: &[(i32, i64)], current_leader_epoch: i32) -> ListOffsetsRequest {
+    ListOffsetsRequest {
+      replica_id: -1,
+      topics: vec![ListOffsetsTopic {
+        name: "foo".to_string(),
+        partitions: partitions.iter()
+          .map(|(partition_index, timestamp)| ListOffsetsPartition {
+            partition_index: *partition_index,
+            current_leader_epoch,
+            timestamp: *timestamp,
+          })
+          .collect(),
+      }],
+      ..Default::default()
+    }
+  }
+
+  /// (error code, timestamp, offset, leader epoch) for each requested (partition, timestamp).
+  fn list_offsets(broker: &Broker, version: i16, request: ListOffsetsRequest) -> Vec<(i16, i64, i64, i32)> {
+    let header = RequestHeader { request_api_key: API_KEY, request_api_version: version, ..Default::default() };
+    let response = handle(broker, &RequestContext::default(), &header, &AllRequests::ListOffsetsRequest(request)).unwrap();
+    let AllResponses::ListOffsetsResponse(response) = response.body else {
+      panic!("not a ListOffsets response");
+    };
+    response.topics[0].partitions.iter()
+      .map(|partition| (partition.error_code, partition.timestamp, partition.offset, partition.leader_epoch))
+      .collect()
+  }
+
+  #[test]
+  fn resolves_special_timestamps_and_time_lookups() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 1, 3);
+    append(&broker, &[100, 200], 2);
+    append(&broker, &[150, 300], 3);
+    append(&broker, &[50], 3);
+
+    let none = ErrorCode::None.code();
+    assert_eq!(
+      list_offsets(&broker, 8, request(&[(0, EARLIEST_TIMESTAMP), (0, EARLIEST_LOCAL_TIMESTAMP), (0, LATEST_TIMESTAMP), (0, MAX_TIMESTAMP)], -1)),
+      vec![(none, -1, 0, 2), (none, -1, 0, 2), (none, -1, 5, 3), (none, 300, 3, 3)],
+    );
+    assert_eq!(
+      list_offsets(&broker, 8, request(&[(0, 0), (0, 150), (0, 250), (0, 301)], 3)),
+      vec![(none, 100, 0, 2), (none, 200, 1, 2), (none, 300, 3, 3), (none, -1, -1, -1)],
+    );
+
+    let log = broker.logs.get_or_open(&TopicPartition::new("foo", 0)).unwrap();
+    log.lock().unwrap().increment_log_start_offset(2);
+    assert_eq!(
+      list_offsets(&broker, 8, request(&[(0, EARLIEST_TIMESTAMP), (0, 0), (0, MAX_TIMESTAMP)], -1)),
+      vec![(none, -1, 2, 3), (none, 150, 2, 3), (none, 300, 3, 3)],
+    );
+    log.lock().unwrap().increment_log_start_offset(4);
+    assert_eq!(
+      list_offsets(&broker, 8, request(&[(0, 0), (0, 250), (0, MAX_TIMESTAMP)], -1)),
+      vec![(none, 50, 4, 3), (none, -1, -1, -1), (none, 50, 4, 3)],
+    );
+  }
+
+  #[test]
+  fn only_accepts_the_special_timestamps_of_the_requests_version() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 1, 3);
+    append(&broker, &[100, 200], 3);
+
+    let (none, invalid) = (ErrorCode::None.code(), ErrorCode::InvalidRequest.code());
+    let errors = |version: i16, timestamps: &[i64]| {
+      let partitions: Vec<_> = timestamps.iter().map(|timestamp| (0, *timestamp)).collect();
+      list_offsets(&broker, version, request(&partitions, -1)).into_iter().map(|(error_code, ..)| error_code).collect::<Vec<_>>()
+    };
+    let special = [LATEST_TIMESTAMP, EARLIEST_TIMESTAMP, MAX_TIMESTAMP, EARLIEST_LOCAL_TIMESTAMP, -5, i64::MIN];
+    assert_eq!(errors(6, &special), vec![none, none, invalid, invalid, invalid, invalid]);
+    assert_eq!(errors(7, &special), vec![none, none, none, invalid, invalid, invalid]);
+    assert_eq!(errors(8, &special), vec![none, none, none, none, invalid, invalid]);
+  }
+
+  #[test]
+  fn read_committed_sees_the_whole_log_without_transactions() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 1, 3);
+    append(&broker, &[100, 200], 3);
+
+    let partitions = [(0, LATEST_TIMESTAMP), (0, MAX_TIMESTAMP), (0, 150)];
+    let mut read_committed = request(&partitions, -1);
+    read_committed.isolation_level = READ_COMMITTED;
+    let none = ErrorCode::None.code();
+    assert_eq!(list_offsets(&broker, 8, read_committed), vec![(none, -1, 2, 3), (none, 200, 1, 3), (none, 200, 1, 3)]);
+    assert_eq!(list_offsets(&broker, 8, request(&partitions, -1)), vec![(none, -1, 2, 3), (none, 200, 1, 3), (none, 200, 1, 3)]);
+  }
+
+  #[test]
+  fn answers_errors_per_partition() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 1, 3);
+    assert_eq!(list_offsets(&broker, 8, request(&[(0, LATEST_TIMESTAMP), (7, LATEST_TIMESTAMP)], -1)), vec![
+      (ErrorCode::None.code(), -1, 0, 3),
+      (ErrorCode::UnknownTopicOrPartition.code(), -1, -1, -1),
+    ]);
+    assert_eq!(list_offsets(&broker, 8, request(&[(0, LATEST_TIMESTAMP)], 2))[0].0, ErrorCode::FencedLeaderEpoch.code());
+    assert_eq!(list_offsets(&broker, 8, request(&[(0, LATEST_TIMESTAMP)], 4))[0].0, ErrorCode::UnknownLeaderEpoch.code());
+  }
+}
@@ -0,0 +1,254 @@
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::codec::{TaggedFields, Uuid};
+use crate::kafka::common::{ErrorCode, CLUSTER_AUTHORIZED_OPERATIONS, TOPIC_AUTHORIZED_OPERATIONS};
+use crate::kafka::header::RequestHeader;
+use crate::kafka::metadata_image::{MetadataImage, TopicImage};
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::{AllRequests, MetadataRequest, MetadataRequestTopic};
+use crate::kafka::responses::{
+  AllResponses, MetadataResponse, MetadataResponseBroker, MetadataResponsePartition, MetadataResponseTopic,
+  Response, AUTHORIZED_OPERATIONS_OMITTED,
+};
+
+pub const API_KEY: i16 = 3;
+
+pub fn handle(broker: &Broker, context: &RequestContext, header: &RequestHeader, body: &AllRequests) -> Result<Response> {
+  let AllRequests::MetadataRequest(request) = body else {
+    anyhow::bail!("Metadata handler got {:?}", body);
+  };
+  let metadata = &broker.metadata;
+
+  let topics = match &request.topics {
+    None => metadata.topics()
+      .map(|topic| describe_topic(metadata, topic, request))
+      .collect(),
+    Some(topics) => topics.iter()
+      .map(|topic| describe_requested_topic(metadata, topic, request))
+      .collect(),
+  };
+
+  Ok(Response::new(header, AllResponses::MetadataResponse(MetadataResponse {
+    brokers: brokers(broker, context),
+    cluster_id: metadata.cluster_id.clone(),
+    // Clients can't reach the KRaft controller, so like Kafka we name a live broker instead
+    controller_id: broker.config.node_id,
+    topics,
+    cluster_authorized_operations: if request.include_cluster_authorized_operations {
+      CLUSTER_AUTHORIZED_OPERATIONS
+    } else {
+      AUTHORIZED_OPERATIONS_OMITTED
+    },
+    ..Default::default()
+  })))
+}
+
+/// Unfenced brokers registered in the metadata log, at their endpoint for the listener the
+/// request came in on, or just this one when nothing registered (a single broker started
+/// from a formatted log dir). Like Kafka, brokers without that listener are left out.
+pub fn brokers(broker: &Broker, context: &RequestContext) -> Vec<MetadataResponseBroker> {
+  let registered: Vec<_> = broker.metadata.brokers.values()
+    .filter(|registration| !registration.fenced)
+    .collect();
+  if !registered.is_empty() {
+    return registered.into_iter()
+      .filter_map(|registration| {
+        let endpoint = registration.endpoints.iter().find(|endpoint| endpoint.name == context.listener_name)?;
+        Some(MetadataResponseBroker {
+          node_id: registration.id,
+          host: endpoint.host.clone(),
+          port: endpoint.port as i32,
+          rack: registration.rack.clone(),
+          tagged_fields: TaggedFields::default(),
+        })
+      })
+      .collect();
+  }
+
+  let Some(listener) = broker.config.listeners.iter().find(|listener| listener.listener_name == context.listener_name) else {
+    return vec![];
+  };
+  let advertised = broker.config.advertised_listener(listener);
+  vec![MetadataResponseBroker {
+    node_id: broker.config.node_id,
+    host: advertised.host,
+    port: advertised.port as i32,
+    rack: None,
+    tagged_fields: TaggedFields::default(),
+  }]
+}
+
+fn describe_requested_topic(metadata: &MetadataImage, requested: &MetadataRequestTopic, request: &MetadataRequest) -> MetadataResponseTopic {
+  // v10+ clients may ask by id, with the name left null
+  let (topic, error_code) = match &requested.name {
+    Some(name) => (metadata.topic(name), ErrorCode::UnknownTopicOrPartition),
+    None => (metadata.topic_by_id(requested.topic_id), ErrorCode::UnknownTopicId),
+  };
+
+  match topic {
+    Some(topic) => describe_topic(metadata, topic, request),
+    // No auto creation, the topic has to exist in the metadata log
+    None => MetadataResponseTopic {
+      error_code: error_code.code(),
+      name: requested.name.clone(),
+      topic_id: requested.topic_id,
+      topic_authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+      ..Default::default()
+    },
+  }
+}
+
+fn describe_topic(metadata: &MetadataImage, topic: &TopicImage, request: &MetadataRequest) -> MetadataResponseTopic {
+  let partitions = topic.partitions.values()
+    .map(|partition| {
+      let leader_available = partition.leader >= 0 && !metadata.is_broker_offline(partition.leader);
+      let error_code = if leader_available { ErrorCode::None } else { ErrorCode::LeaderNotAvailable };
+
+      MetadataResponsePartition {
+        error_code: error_code.code(),
+        partition_index: partition.partition_id,
+        leader_id: partition.leader,
+        leader_epoch: partition.leader_epoch,
+        replica_nodes: partition.replicas.clone(),
+        isr_nodes: partition.isr.clone(),
+        offline_replicas: partition.replicas.iter()
+          .copied()
+          .filter(|replica| metadata.is_broker_offline(*replica))
+          .collect(),
+        tagged_fields: TaggedFields::default(),
+      }
+    })
+    .collect();
+
+  MetadataResponseTopic {
+    error_code: ErrorCode::None.code(),
+    name: Some(topic.name.clone()),
+    topic_id: topic.id,
+    is_internal: topic.is_internal(),
+    partitions,
+    topic_authorized_operations: if request.include_topic_authorized_operations {
+      TOPIC_AUTHORIZED_OPERATIONS
+    } else {
+      AUTHORIZED_OPERATIONS_OMITTED
+    },
+    tagged_fields: TaggedFields::default(),
+  }
+}
+
+pub fn error_response(body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  let topics = match body {
+    AllRequests::MetadataRequest(MetadataRequest { topics: Some(topics), .. }) => topics.iter()
+      .map(|topic| MetadataResponseTopic {
+        error_code: error_code.code(),
+        name: topic.name.clone(),
+        topic_id: topic.topic_id,
+        topic_authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+        ..Default::default()
+      })
+      .collect(),
+    _ => vec![],
+  };
+
+  AllResponses::MetadataResponse(MetadataResponse {
+    topics,
+    ..Default::default()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::{BufMut, BytesMut};
+
+  use super::*;
+  use crate::kafka::codec::{decode_array, Decode, DecodeVersioned, Int16, Int32};
+  use crate::kafka::config::BrokerConfig;
+  use crate::kafka::metadata_records::{MetadataRecord, PartitionRecord, TopicRecord};
+
+  fn broker() -> Broker {
+    let mut metadata = MetadataImage::empty();
+    let topic = TopicRecord { name: "foo".to_string(), topic_uuid: Uuid(7), tagged_fields: TaggedFields::default() };
+    metadata.replay(0, MetadataRecord::Topic(topic));
+    let partition = PartitionRecord { partition_id: 0, topic_id: Uuid(7), replicas: vec![1], isr: vec![1], leader: 1, ..Default::default() };
+    metadata.replay(1, MetadataRecord::Partition(partition));
+    Broker::new(BrokerConfig::default(), metadata)
+  }
+
+  fn metadata(broker: &Broker, version: i16, topics: Option<&[&str]>) -> MetadataResponse {
+    let request = MetadataRequest {
+      topics: topics.map(|names| names.iter()
+        .map(|name| MetadataRequestTopic { name: Some(name.to_string()), ..Default::default() })
+        .collect()),
+      ..Default::default()
+    };
+    let header = RequestHeader { request_api_key: API_KEY, request_api_version: version, ..Default::default() };
+    let AllResponses::MetadataResponse(response) = handle(broker, &RequestContext::default(), &header, &AllRequests::MetadataRequest(request)).unwrap().body else {
+      panic!("expected a Metadata response");
+    };
+    response
+  }
+
+  #[test]
+  fn describes_known_and_unknown_topics() {
+    let broker = broker();
+    let response = metadata(&broker, 12, Some(&["foo", "nope"]));
+
+    assert_eq!(response.brokers.len(), 1);
+    assert_eq!((response.brokers[0].node_id, response.brokers[0].host.as_str(), response.brokers[0].port), (1, "127.0.0.1", 9092));
+    assert_eq!(response.controller_id, 1);
+
+    let foo = &response.topics[0];
+    assert_eq!((foo.error_code, foo.topic_id), (0, Uuid(7)));
+    assert_eq!(foo.partitions.len(), 1);
+    assert_eq!((foo.partitions[0].leader_id, foo.partitions[0].replica_nodes.clone()), (1, vec![1]));
+    assert_eq!(response.topics[1].error_code, ErrorCode::UnknownTopicOrPartition.code());
+
+    let everything = metadata(&broker, 12, None);
+    assert_eq!(everything.topics.iter().map(|topic| topic.name.clone()).collect::<Vec<_>>(), vec![Some("foo".to_string())]);
+  }
+
+  #[test]
+  fn v0_empty_topic_list_means_all_topics() {
+    let mut body = BytesMut::new();
+    body.put_i32(0);
+    let request = MetadataRequest::decode(&mut body, 0).unwrap();
+    assert!(request.topics.is_none());
+
+    // v1 has a real null for that and an empty array means no topics
+    let mut body = BytesMut::new();
+    body.put_i32(0);
+    let request = MetadataRequest::decode(&mut body, 1).unwrap();
+    assert_eq!(request.topics.map(|topics| topics.len()), Some(0));
+  }
+
+  #[test]
+  fn encodes_v0_without_later_fields() {
+    let header = RequestHeader { request_api_key: API_KEY, request_api_version: 0, correlation_id: 3, ..Default::default() };
+    let request = AllRequests::MetadataRequest(MetadataRequest { topics: None, ..Default::default() });
+    let frame = handle(&broker(), &RequestContext::default(), &header, &request).unwrap().get_vec();
+    let mut payload = bytes::Bytes::from(frame).split_off(8); // message_size, correlation_id
+
+    let brokers = decode_array(&mut payload, false, |buf| {
+      let node_id = Int32::decode(buf)?;
+      let _host = crate::kafka::codec::KafkaString::decode(buf)?;
+      let _port = Int32::decode(buf)?;
+      Ok(node_id)
+    }).unwrap();
+    assert_eq!(brokers, vec![1]);
+    let topics = decode_array(&mut payload, false, |buf| {
+      let _error_code = Int16::decode(buf)?;
+      let name = crate::kafka::codec::KafkaString::decode(buf)?;
+      let partitions = decode_array(buf, false, |buf| {
+        let _error_code = Int16::decode(buf)?;
+        let index = Int32::decode(buf)?;
+        let _leader = Int32::decode(buf)?;
+        let _replicas = decode_array(buf, false, Int32::decode)?;
+        let _isr = decode_array(buf, false, Int32::decode)?;
+        Ok(index)
+      })?;
+      Ok((name, partitions))
+    }).unwrap();
+    assert_eq!(topics, vec![("foo".to_string(), vec![0])]);
+    assert!(payload.is_empty());
+  }
+}
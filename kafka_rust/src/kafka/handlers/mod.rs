@@ -0,0 +1,136 @@
+pub mod alter_user_scram_credentials;
+pub mod api_versions;
+pub mod describe_cluster;
+pub mod describe_topic_partitions;
+pub mod describe_user_scram_credentials;
+pub mod fetch;
+pub mod list_offsets;
+pub mod metadata;
+pub mod produce;
+pub mod sasl_authenticate;
+pub mod sasl_handshake;
+
+use crate::kafka::registry::{ApiHandler, ApiRegistry, ListenerType};
+
+const BROKER: &[ListenerType] = &[ListenerType::Broker];
+const BROKER_AND_CONTROLLER: &[ListenerType] = &[ListenerType::Broker, ListenerType::Controller];
+
+/// Every api the broker implements. Adding an api here is all it takes for ApiVersions to
+/// advertise it on the listeners it is exposed on.
+pub fn registry() -> ApiRegistry {
+  let mut apis = ApiRegistry::new();
+
+  apis.register(ApiHandler {
+    api_key: produce::API_KEY,
+    name: "Produce",
+    min_version: 3,
+    max_version: 11,
+    first_flexible_version: Some(9),
+    listeners: BROKER,
+    handle: produce::handle,
+    error_response: produce::error_response,
+  });
+  apis.register(ApiHandler {
+    api_key: fetch::API_KEY,
+    name: "Fetch",
+    min_version: 4,
+    max_version: 16,
+    first_flexible_version: Some(12),
+    listeners: BROKER,
+    handle: fetch::handle,
+    error_response: fetch::error_response,
+  });
+  apis.register(ApiHandler {
+    api_key: list_offsets::API_KEY,
+    name: "ListOffsets",
+    min_version: 1,
+    max_version: 8,
+    first_flexible_version: Some(6),
+    listeners: BROKER,
+    handle: list_offsets::handle,
+    error_response: list_offsets::error_response,
+  });
+  apis.register(ApiHandler {
+    api_key: metadata::API_KEY,
+    name: "Metadata",
+    min_version: 0,
+    max_version: 12,
+    first_flexible_version: Some(9),
+    listeners: BROKER,
+    handle: metadata::handle,
+    error_response: metadata::error_response,
+  });
+  apis.register(ApiHandler {
+    api_key: api_versions::API_KEY,
+    name: "ApiVersions",
+    min_version: 0,
+    max_version: 4,
+    first_flexible_version: Some(3),
+    listeners: BROKER_AND_CONTROLLER,
+    handle: api_versions::handle,
+    error_response: api_versions::error_response,
+  });
+  apis.register(ApiHandler {
+    api_key: describe_topic_partitions::API_KEY,
+    name: "DescribeTopicPartitions",
+    min_version: 0,
+    max_version: 0,
+    first_flexible_version: Some(0),
+    listeners: BROKER,
+    handle: describe_topic_partitions::handle,
+    error_response: describe_topic_partitions::error_response,
+  });
+  apis.register(ApiHandler {
+    api_key: describe_cluster::API_KEY,
+    name: "DescribeCluster",
+    min_version: 0,
+    max_version: 1,
+    first_flexible_version: Some(0),
+    listeners: BROKER_AND_CONTROLLER,
+    handle: describe_cluster::handle,
+    error_response: describe_cluster::error_response,
+  });
+  // v0 sent the mechanism's tokens unframed after the handshake, only pre-1.0 clients use it
+  apis.register(ApiHandler {
+    api_key: sasl_handshake::API_KEY,
+    name: "SaslHandshake",
+    min_version: 1,
+    max_version: 1,
+    first_flexible_version: None,
+    listeners: BROKER_AND_CONTROLLER,
+    handle: sasl_handshake::handle,
+    error_response: sasl_handshake::error_response,
+  });
+  apis.register(ApiHandler {
+    api_key: sasl_authenticate::API_KEY,
+    name: "SaslAuthenticate",
+    min_version: 0,
+    max_version: 2,
+    first_flexible_version: Some(2),
+    listeners: BROKER_AND_CONTROLLER,
+    handle: sasl_authenticate::handle,
+    error_response: sasl_authenticate::error_response,
+  });
+  apis.register(ApiHandler {
+    api_key: describe_user_scram_credentials::API_KEY,
+    name: "DescribeUserScramCredentials",
+    min_version: 0,
+    max_version: 0,
+    first_flexible_version: Some(0),
+    listeners: BROKER,
+    handle: describe_user_scram_credentials::handle,
+    error_response: describe_user_scram_credentials::error_response,
+  });
+  apis.register(ApiHandler {
+    api_key: alter_user_scram_credentials::API_KEY,
+    name: "AlterUserScramCredentials",
+    min_version: 0,
+    max_version: 0,
+    first_flexible_version: Some(0),
+    listeners: BROKER,
+    handle: alter_user_scram_credentials::handle,
+    error_response: alter_user_scram_credentials::error_response,
+  });
+
+  apis
+}
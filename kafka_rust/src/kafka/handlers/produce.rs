@@ -0,0 +1,335 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+
+use crate::kafka::broker::Broker;
+use crate::kafka::codec::CodecError;
+use crate::kafka::common::ErrorCode;
+use crate::kafka::compression::Compression;
+use crate::kafka::header::RequestHeader;
+use crate::kafka::log::TopicPartition;
+use crate::kafka::record_batch::{self, RecordBatch, RecordBatches, COMPRESSION_CODEC_MASK, MAGIC_OFFSET, MAGIC_V2};
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::{AllRequests, ProducePartitionData, ProduceRequest};
+use crate::kafka::responses::{AllResponses, ProducePartitionResponse, ProduceResponse, ProduceTopicResponse, Response};
+
+pub const API_KEY: i16 = 0;
+
+/// The first Produce version clients may send zstd batches with.
+const ZSTD_MIN_VERSION: i16 = 7;
+
+pub fn handle(broker: &Broker, _context: &RequestContext, header: &RequestHeader, body: &AllRequests) -> Result<Response> {
+  let AllRequests::ProduceRequest(request) = body else {
+    anyhow::bail!("Produce handler got {:?}", body);
+  };
+
+  let responses: Vec<_> = request.topic_data.iter()
+    .map(|topic| ProduceTopicResponse {
+      name: topic.name.clone(),
+      partition_responses: topic.partition_data.iter()
+        .map(|partition| produce_partition(broker, header.request_api_version, request, &topic.name, partition))
+        .collect(),
+      ..Default::default()
+    })
+    .collect();
+
+  // With acks=0 nobody reads the per-partition errors, so fail the whole request instead
+  if request.acks == 0 {
+    let failed = responses.iter()
+      .flat_map(|topic| topic.partition_responses.iter().map(move |partition| (topic, partition)))
+      .find(|(_, partition)| partition.error_code != ErrorCode::None.code());
+    if let Some((topic, partition)) = failed {
+      let error_code = ErrorCode::from_code(partition.error_code).unwrap_or(ErrorCode::UnknownServerError);
+      return Err(anyhow::Error::new(error_code).context(format!("{}-{}", topic.name, partition.index)));
+    }
+  }
+
+  Ok(Response::new(header, AllResponses::ProduceResponse(ProduceResponse {
+    responses,
+    ..Default::default()
+  })))
+}
+
+fn produce_partition(broker: &Broker, version: i16, request: &ProduceRequest, topic: &str, data: &ProducePartitionData) -> ProducePartitionResponse {
+  match append(broker, version, request, topic, data) {
+    Ok(response) => response,
+    Err(e) => {
+      let error_code = e.downcast_ref::<ErrorCode>().copied().unwrap_or(ErrorCode::KafkaStorageError);
+      println!("Produce to {}-{} failed, answering {}: {:#}", topic, data.index, error_code, e);
+      ProducePartitionResponse {
+        index: data.index,
+        error_code: error_code.code(),
+        // Only worth sending when there's more to say than the error code itself
+        error_message: (e.chain().count() > 1).then(|| e.to_string()),
+        ..Default::default()
+      }
+    }
+  }
+}
+
+fn append(broker: &Broker, version: i16, request: &ProduceRequest, topic: &str, data: &ProducePartitionData) -> Result<ProducePartitionResponse> {
+  if !matches!(request.acks, -1..=1) {
+    return Err(anyhow::Error::new(ErrorCode::InvalidRequiredAcks).context(format!("acks={}", request.acks)));
+  }
+
+  // Produce requests don't carry the leader epoch the client saw
+  let partition = broker.leader_partition(topic, data.index, -1)?;
+
+  let (mut batch, decoded) = validate(data.records.as_ref(), broker.config.message_max_bytes, version)?;
+  if decoded.is_transactional() && request.transactional_id.is_none() {
+    return Err(invalid(ErrorCode::TransactionalIdAuthorizationFailed, "transactional batch without a transactional.id".to_string()));
+  }
+  // A topic with its own compression.type stores batches with that codec, whatever the
+  // producer used
+  let compression_type = broker.logs.config_for(topic).compression_type;
+  if let Some(compression) = compression_type.filter(|compression| compression.id() != decoded.compression()) {
+    batch = RecordBatch {
+      attributes: (decoded.attributes & !COMPRESSION_CODEC_MASK) | compression.id(),
+      ..decoded
+    }
+    .encode();
+  }
+
+  // One broker holds every replica, so acks=1 and acks=-1 are both satisfied by the append
+  let log = broker.logs.get_or_open(&TopicPartition::new(topic, data.index))?;
+  let mut log = log.lock().unwrap();
+  let base_offset = log.append(&mut batch, partition.leader_epoch)?;
+  broker.logs.notify_append();
+
+  Ok(ProducePartitionResponse {
+    index: data.index,
+    error_code: ErrorCode::None.code(),
+    base_offset,
+    log_append_time_ms: -1,
+    log_start_offset: log.log_start_offset(),
+    ..Default::default()
+  })
+}
+
+fn invalid(error_code: ErrorCode, message: String) -> anyhow::Error {
+  anyhow::Error::new(error_code).context(message)
+}
+
+/// Checks `records` is the single well formed RecordBatch v2 that Produce v3+ carries and
+/// hands back a copy the log can assign offsets to, along with its decompressed records.
+fn validate(records: Option<&Bytes>, message_max_bytes: usize, version: i16) -> Result<(BytesMut, RecordBatch)> {
+  let records = records.filter(|records| !records.is_empty())
+    .ok_or_else(|| invalid(ErrorCode::InvalidRecord, "no records".to_string()))?;
+  if records.len() > message_max_bytes {
+    return Err(invalid(
+      ErrorCode::MessageTooLarge,
+      format!("batch of {} bytes is larger than message.max.bytes {}", records.len(), message_max_bytes),
+    ));
+  }
+
+  let mut batches = RecordBatches::new(records.clone());
+  let raw = batches.next_raw()
+    .ok_or_else(|| invalid(ErrorCode::CorruptMessage, "records don't hold a complete batch".to_string()))?;
+  if batches.position() != records.len() {
+    return Err(invalid(ErrorCode::InvalidRecord, "Produce v3+ carries exactly one record batch per partition".to_string()));
+  }
+
+  if raw[MAGIC_OFFSET] as i8 != MAGIC_V2 {
+    return Err(invalid(ErrorCode::InvalidRecord, format!("record batch magic {} is not 2", raw[MAGIC_OFFSET] as i8)));
+  }
+  if !record_batch::verify_crc(&raw) {
+    return Err(invalid(ErrorCode::CorruptMessage, "record batch CRC doesn't match its contents".to_string()));
+  }
+
+  // Checked before decoding, which would only call these corrupt
+  let compression = record_batch::attributes(&raw) & COMPRESSION_CODEC_MASK;
+  match Compression::from_id(compression) {
+    None => return Err(invalid(ErrorCode::UnsupportedCompressionType, format!("unknown compression codec {}", compression))),
+    Some(Compression::Zstd) if version < ZSTD_MIN_VERSION => {
+      return Err(invalid(ErrorCode::UnsupportedCompressionType, format!("zstd needs Produce v{}+", ZSTD_MIN_VERSION)));
+    }
+    Some(_) => {}
+  }
+  // Compressed records were checked against message.max.bytes above, decompressed ones
+  // aren't allowed past it either
+  let batch = RecordBatch::decode_at_most(&mut raw.clone(), message_max_bytes).map_err(|e| match e {
+    CodecError::DecompressedTooLarge(_) => invalid(ErrorCode::MessageTooLarge, format!("record batch {e}, message.max.bytes")),
+    e => invalid(ErrorCode::CorruptMessage, format!("record batch doesn't decode: {e}")),
+  })?;
+  if batch.records.is_empty() || batch.records.len() as i64 != batch.last_offset_delta as i64 + 1 {
+    return Err(invalid(
+      ErrorCode::InvalidRecord,
+      format!("{} records with last offset delta {}", batch.records.len(), batch.last_offset_delta),
+    ));
+  }
+  if let Some((expected, record)) = batch.records.iter().enumerate().find(|(i, record)| record.offset_delta as usize != *i) {
+    return Err(invalid(
+      ErrorCode::InvalidRecord,
+      format!("record {} has offset delta {}", expected, record.offset_delta),
+    ));
+  }
+
+  Ok((BytesMut::from(&raw[..]), batch))
+}
+
+pub fn error_response(body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  let responses = match body {
+    AllRequests::ProduceRequest(request) => request.topic_data.iter()
+      .map(|topic| ProduceTopicResponse {
+        name: topic.name.clone(),
+        partition_responses: topic.partition_data.iter()
+          .map(|partition| ProducePartitionResponse {
+            index: partition.index,
+            error_code: error_code.code(),
+            ..Default::default()
+          })
+          .collect(),
+        ..Default::default()
+      })
+      .collect(),
+    _ => vec![],
+  };
+
+  AllResponses::ProduceResponse(ProduceResponse {
+    responses,
+    ..Default::default()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::codec::{TaggedFields, Uuid};
+  use crate::kafka::config::BrokerConfig;
+  use crate::kafka::metadata_image::TOPIC_RESOURCE;
+  use crate::kafka::metadata_records::{ConfigRecord, MetadataRecord, PartitionRecord, TopicRecord};
+  use crate::kafka::requests::ProduceTopicData;
+  use crate::kafka::test_support::{broker, temp_dir, value_batch};
+
+  fn produce(broker: &Broker, topic: &str, records: BytesMut) -> ProducePartitionResponse {
+    produce_version(broker, 11, topic, records)
+  }
+
+  fn produce_version(broker: &Broker, version: i16, topic: &str, records: BytesMut) -> ProducePartitionResponse {
+    let request = AllRequests::ProduceRequest(ProduceRequest {
+      acks: -1,
+      timeout_ms: 1000,
+      topic_data: vec![ProduceTopicData {
+        name: topic.to_string(),
+        partition_data: vec![ProducePartitionData { index: 0, records: Some(records.freeze()) }],
+      }],
+      ..Default::default()
+    });
+    let header = RequestHeader { request_api_key: API_KEY, request_api_version: version, ..Default::default() };
+    let AllResponses::ProduceResponse(mut response) = handle(broker, &RequestContext::default(), &header, &request).unwrap().body else {
+      panic!("expected a Produce response");
+    };
+    response.responses.remove(0).partition_responses.remove(0)
+  }
+
+  #[test]
+  fn appends_batches_with_assigned_offsets() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 1, 4);
+
+    let first = produce(&broker, "foo", value_batch(&["a", "b"]));
+    let second = produce(&broker, "foo", value_batch(&["c"]));
+    assert_eq!((first.error_code, first.base_offset, first.log_start_offset), (0, 0, 0));
+    assert_eq!((second.error_code, second.base_offset), (0, 2));
+
+    let log = std::fs::read(broker.config.log_dirs.join("foo-0").join("00000000000000000000.log")).unwrap();
+    let batches = RecordBatches::new(Bytes::from(log)).collect::<crate::kafka::codec::Result<Vec<_>>>().unwrap();
+    assert_eq!(batches.iter().map(|batch| (batch.base_offset, batch.partition_leader_epoch)).collect::<Vec<_>>(), vec![(0, 4), (2, 4)]);
+
+    // A reopened log carries on where the file ends
+    let reopened = Broker::new(broker.config.clone(), broker.metadata.clone());
+    assert_eq!(produce(&reopened, "foo", value_batch(&["d"])).base_offset, 3);
+  }
+
+  #[test]
+  fn rejects_bad_batches_and_unknown_partitions() {
+    let log_dirs = temp_dir();
+    let broker = broker(log_dirs.path(), 1, 4);
+
+    let mut corrupt = value_batch(&["a"]);
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0xff;
+    assert_eq!(produce(&broker, "foo", corrupt).error_code, ErrorCode::CorruptMessage.code());
+
+    let mut two_batches = value_batch(&["a"]);
+    two_batches.extend_from_slice(&value_batch(&["b"]));
+    assert_eq!(produce(&broker, "foo", two_batches).error_code, ErrorCode::InvalidRecord.code());
+
+    assert_eq!(produce(&broker, "nope", value_batch(&["a"])).error_code, ErrorCode::UnknownTopicOrPartition.code());
+
+    let mut transactional = RecordBatch::decode(&mut value_batch(&["a"]).freeze()).unwrap();
+    transactional.attributes = 0x10; // isTransactional
+    let transactional = produce(&broker, "foo", transactional.encode());
+    assert_eq!(transactional.error_code, ErrorCode::TransactionalIdAuthorizationFailed.code());
+  }
+
+  #[test]
+  fn caps_decompressed_records_at_message_max_bytes() {
+    let log_dirs = temp_dir();
+    let base = broker(log_dirs.path(), 1, 4);
+    let broker = Broker::new(BrokerConfig { message_max_bytes: 1_000, ..base.config.clone() }, base.metadata.clone());
+
+    let fits = "x".repeat(500);
+    let bomb = "x".repeat(5_000);
+    for compression in [Compression::Gzip, Compression::Snappy, Compression::Lz4, Compression::Zstd] {
+      assert_eq!(produce(&broker, "foo", compressed(&[&fits], compression)).error_code, 0, "{:?}", compression);
+      let records = compressed(&[&bomb], compression);
+      assert!(records.len() < 1_000, "{:?}", compression);
+      assert_eq!(produce(&broker, "foo", records).error_code, ErrorCode::MessageTooLarge.code(), "{:?}", compression);
+    }
+  }
+
+  /// `value_batch` with its records compressed.
+  fn compressed(values: &[&str], compression: Compression) -> BytesMut {
+    let mut decoded = RecordBatch::decode(&mut value_batch(values).freeze()).unwrap();
+    decoded.attributes = compression.id();
+    decoded.encode()
+  }
+
+  #[test]
+  fn stores_compressed_batches_recompressing_to_the_topic_codec() {
+    let log_dirs = temp_dir();
+    let base = broker(log_dirs.path(), 1, 4);
+    let mut metadata = base.metadata.clone();
+    let topic = TopicRecord { name: "bar".to_string(), topic_uuid: Uuid(2), tagged_fields: TaggedFields::default() };
+    metadata.replay(2, MetadataRecord::Topic(topic));
+    let partition = PartitionRecord { partition_id: 0, topic_id: Uuid(2), replicas: vec![1], isr: vec![1], leader: 1, leader_epoch: 4, ..Default::default() };
+    metadata.replay(3, MetadataRecord::Partition(partition));
+    let config = ConfigRecord { resource_type: TOPIC_RESOURCE, resource_name: "bar".to_string(), name: "compression.type".to_string(), value: Some("lz4".to_string()), tagged_fields: TaggedFields::default() };
+    metadata.replay(4, MetadataRecord::Config(config));
+    let broker = Broker::new(base.config.clone(), metadata);
+
+    for compression in [Compression::Gzip, Compression::Snappy, Compression::Zstd] {
+      assert_eq!(produce(&broker, "foo", compressed(&["a", "b"], compression)).error_code, 0);
+      assert_eq!(produce(&broker, "bar", compressed(&["a", "b"], compression)).error_code, 0);
+    }
+    let stored = |topic: &str| {
+      let log = std::fs::read(broker.config.log_dirs.join(format!("{}-0", topic)).join("00000000000000000000.log")).unwrap();
+      RecordBatches::new(Bytes::from(log))
+        .map(|batch| {
+          let batch = batch.unwrap();
+          (batch.base_offset, batch.compression(), batch.records.iter().map(|record| record.value.clone().unwrap()).collect::<Vec<_>>())
+        })
+        .collect::<Vec<_>>()
+    };
+    let values = vec![Bytes::from("a"), Bytes::from("b")];
+    assert_eq!(stored("foo"), vec![
+      (0, Compression::Gzip.id(), values.clone()),
+      (2, Compression::Snappy.id(), values.clone()),
+      (4, Compression::Zstd.id(), values.clone()),
+    ]);
+    assert_eq!(stored("bar"), vec![
+      (0, Compression::Lz4.id(), values.clone()),
+      (2, Compression::Lz4.id(), values.clone()),
+      (4, Compression::Lz4.id(), values),
+    ]);
+
+    // zstd came with Produce v7
+    let old_zstd = produce_version(&broker, 6, "foo", compressed(&["a"], Compression::Zstd));
+    assert_eq!(old_zstd.error_code, ErrorCode::UnsupportedCompressionType.code());
+    let mut unknown = compressed(&["a"], Compression::None);
+    unknown[record_batch::ATTRIBUTES_OFFSET + 1] = 7;
+    let crc = record_batch::compute_crc(&unknown);
+    unknown[record_batch::CRC_OFFSET..record_batch::ATTRIBUTES_OFFSET].copy_from_slice(&crc.to_be_bytes());
+    assert_eq!(produce(&broker, "foo", unknown).error_code, ErrorCode::UnsupportedCompressionType.code());
+  }
+}
@@ -0,0 +1,26 @@
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::common::ErrorCode;
+use crate::kafka::header::RequestHeader;
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::AllRequests;
+use crate::kafka::responses::{AllResponses, Response, SaslAuthenticateResponse};
+
+pub const API_KEY: i16 = 36;
+
+/// SaslAuthenticate only means something during authentication, which the connection's
+/// `SaslAuthenticator` handles. Reaching the handler is ILLEGAL_SASL_STATE: a PLAINTEXT or
+/// SSL listener, or a client that already authenticated and didn't send a SaslHandshake.
+pub fn handle(_broker: &Broker, context: &RequestContext, _header: &RequestHeader, _body: &AllRequests) -> Result<Response> {
+  Err(anyhow::Error::new(ErrorCode::IllegalSaslState)
+    .context(format!("SaslAuthenticate outside authentication on listener {}", context.listener_name)))
+}
+
+pub fn error_response(_body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  AllResponses::SaslAuthenticateResponse(SaslAuthenticateResponse {
+    error_code: error_code.code(),
+    error_message: Some(error_code.name().to_string()),
+    ..Default::default()
+  })
+}
@@ -0,0 +1,30 @@
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::common::ErrorCode;
+use crate::kafka::header::RequestHeader;
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::AllRequests;
+use crate::kafka::responses::{AllResponses, Response, SaslHandshakeResponse};
+
+pub const API_KEY: i16 = 17;
+
+/// SaslHandshake on a SASL listener is taken care of by the connection's
+/// `SaslAuthenticator`. Anywhere else there is nothing to authenticate, like Kafka this is
+/// ILLEGAL_SASL_STATE.
+pub fn handle(_broker: &Broker, context: &RequestContext, _header: &RequestHeader, _body: &AllRequests) -> Result<Response> {
+  Err(anyhow::Error::new(ErrorCode::IllegalSaslState)
+    .context(format!("SaslHandshake on {} listener {}", context.security_protocol.name(), context.listener_name)))
+}
+
+/// The answer to a handshake, listing the mechanisms the client can pick from.
+pub fn response(broker: &Broker, error_code: ErrorCode) -> AllResponses {
+  AllResponses::SaslHandshakeResponse(SaslHandshakeResponse {
+    error_code: error_code.code(),
+    mechanisms: broker.config.sasl_enabled_mechanisms.clone(),
+  })
+}
+
+pub fn error_response(_body: &AllRequests, error_code: ErrorCode) -> AllResponses {
+  AllResponses::SaslHandshakeResponse(SaslHandshakeResponse { error_code: error_code.code(), mechanisms: vec![] })
+}
@@ -0,0 +1,151 @@
+use bytes::{Buf, BytesMut};
+
+use crate::kafka::codec::{Decode, Encode, Int16, Int32, NullableString, Result, TaggedFields};
+use crate::kafka::registry::ApiRegistry;
+
+const API_VERSIONS: i16 = 18;
+const CONTROLLED_SHUTDOWN: i16 = 7;
+
+/// Request header v0 (key, version, correlation id), v1 (+ client_id) or v2 (+ tagged fields).
+#[derive(Debug, Clone, Default)]
+pub struct RequestHeader {
+  pub request_api_key: i16,
+  pub request_api_version: i16,
+  pub correlation_id: i32,
+  pub client_id: Option<String>,
+  /// Whether this api version uses the flexible encoding, which also decides the header
+  /// versions. Unknown apis are treated as non-flexible, that's enough to get at the
+  /// correlation id.
+  pub flexible: bool,
+}
+
+impl RequestHeader {
+  pub fn header_version(api_key: i16, api_version: i16, flexible: bool) -> i16 {
+    if flexible {
+      2
+    } else if api_key == CONTROLLED_SHUTDOWN && api_version == 0 {
+      0
+    } else {
+      1
+    }
+  }
+
+  pub fn decode<B: Buf>(buf: &mut B, apis: &ApiRegistry) -> Result<RequestHeader> {
+    let request_api_key = Int16::decode(buf)?;
+    let request_api_version = Int16::decode(buf)?;
+    let correlation_id = Int32::decode(buf)?;
+
+    let flexible = apis.is_flexible(request_api_key, request_api_version);
+    let header_version = Self::header_version(request_api_key, request_api_version, flexible);
+    // client_id stays a plain NULLABLE_STRING even in v2 headers
+    let client_id = if header_version >= 1 { NullableString::decode(buf)? } else { None };
+    if header_version >= 2 {
+      TaggedFields::decode(buf)?;
+    }
+
+    Ok(RequestHeader {
+      request_api_key,
+      request_api_version,
+      correlation_id,
+      client_id,
+      flexible,
+    })
+  }
+}
+
+/// Response header v0 (correlation id) or v1 (+ tagged fields).
+#[derive(Debug, Clone, Default)]
+pub struct ResponseHeader {
+  pub correlation_id: i32,
+  pub version: i16,
+  pub tagged_fields: TaggedFields,
+}
+
+impl ResponseHeader {
+  pub fn for_request(request: &RequestHeader) -> ResponseHeader {
+    // ApiVersions always answers with a v0 header so clients that don't know the broker's
+    // versions yet can still parse it
+    let version = if request.flexible && request.request_api_key != API_VERSIONS { 1 } else { 0 };
+    ResponseHeader {
+      correlation_id: request.correlation_id,
+      version,
+      tagged_fields: TaggedFields::default(),
+    }
+  }
+
+  pub fn encode(&self, buf: &mut BytesMut) {
+    Int32::encode(buf, &self.correlation_id);
+    if self.version >= 1 {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::BufMut;
+
+  use super::*;
+  use crate::kafka::handlers::{self, api_versions, describe_topic_partitions, fetch, metadata, sasl_handshake};
+
+  /// A header for `api_key` v`api_version` with correlation id 7, followed by `rest`.
+  fn header(api_key: i16, api_version: i16, rest: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    buf.put_i16(api_key);
+    buf.put_i16(api_version);
+    buf.put_i32(7);
+    buf.put_slice(rest);
+    buf
+  }
+
+  #[test]
+  fn reads_v1_headers_with_and_without_a_client_id() {
+    let apis = handlers::registry();
+    let mut buf = header(metadata::API_KEY, 8, &[0xff, 0xff, 0xab]);
+    let decoded = RequestHeader::decode(&mut buf, &apis).unwrap();
+    assert_eq!((decoded.correlation_id, decoded.client_id, decoded.flexible), (7, None, false));
+    assert_eq!(&buf[..], [0xab]);
+
+    let mut buf = header(metadata::API_KEY, 8, &[0x00, 0x03, b'c', b'l', b'i', 0xab]);
+    assert_eq!(RequestHeader::decode(&mut buf, &apis).unwrap().client_id.as_deref(), Some("cli"));
+    assert_eq!(&buf[..], [0xab]);
+  }
+
+  #[test]
+  fn reads_v2_headers_past_their_tagged_fields() {
+    let apis = handlers::registry();
+    // client_id is still a NULLABLE_STRING, then one tagged field (tag 0, 2 bytes)
+    let mut buf = header(metadata::API_KEY, 9, &[0x00, 0x03, b'c', b'l', b'i', 0x01, 0x00, 0x02, 0x01, 0x02, 0xab]);
+    let decoded = RequestHeader::decode(&mut buf, &apis).unwrap();
+    assert_eq!((decoded.client_id.as_deref(), decoded.flexible), (Some("cli"), true));
+    assert_eq!(&buf[..], [0xab]);
+
+    let mut truncated = header(metadata::API_KEY, 9, &[0xff, 0xff, 0x01, 0x00, 0x05, 0x01]);
+    assert!(RequestHeader::decode(&mut truncated, &apis).is_err());
+  }
+
+  #[test]
+  fn picks_the_header_version_from_each_apis_first_flexible_version() {
+    let apis = handlers::registry();
+    for (api_key, api_version, flexible, header_version) in [
+      (api_versions::API_KEY, 2, false, 1),
+      (api_versions::API_KEY, 3, true, 2),
+      (metadata::API_KEY, 8, false, 1),
+      (metadata::API_KEY, 9, true, 2),
+      (fetch::API_KEY, 11, false, 1),
+      (fetch::API_KEY, 12, true, 2),
+      (describe_topic_partitions::API_KEY, 0, true, 2),
+      (sasl_handshake::API_KEY, 1, false, 1),
+      // Unknown apis get a v1 header, enough to reach the correlation id
+      (999, 0, false, 1),
+    ] {
+      assert_eq!(apis.is_flexible(api_key, api_version), flexible, "api {} v{}", api_key, api_version);
+      assert_eq!(RequestHeader::header_version(api_key, api_version, flexible), header_version, "api {} v{}", api_key, api_version);
+    }
+    assert_eq!(RequestHeader::header_version(CONTROLLED_SHUTDOWN, 0, false), 0);
+
+    let flexible = |api_key| RequestHeader { request_api_key: api_key, flexible: true, ..Default::default() };
+    assert_eq!(ResponseHeader::for_request(&flexible(metadata::API_KEY)).version, 1);
+    assert_eq!(ResponseHeader::for_request(&flexible(api_versions::API_KEY)).version, 0);
+  }
+}
@@ -0,0 +1,979 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use crate::kafka::compression::Compression;
+use crate::kafka::config::BrokerConfig;
+use crate::kafka::log_cleaner::{self, CLEANER_OFFSET_CHECKPOINT_FILE};
+use crate::kafka::log_segment::{now_ms, LogSegment, CLEANED_SUFFIX, LOG_FILE_SUFFIX, SWAP_SUFFIX};
+use crate::kafka::metadata_image::METADATA_LOG_DIR;
+use crate::kafka::offset_checkpoint::OffsetCheckpointFile;
+use crate::kafka::record_batch;
+
+/// A partition of a topic, displayed like its directory name (`foo-0`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TopicPartition {
+  pub topic: String,
+  pub partition: i32,
+}
+
+impl TopicPartition {
+  pub fn new(topic: &str, partition: i32) -> TopicPartition {
+    TopicPartition { topic: topic.to_string(), partition }
+  }
+
+  /// The partition a log directory belongs to, `None` for anything not named like one.
+  pub fn from_dir_name(name: &str) -> Option<TopicPartition> {
+    let (topic, partition) = name.rsplit_once('-')?;
+    let partition = partition.parse().ok().filter(|partition| *partition >= 0)?;
+    (!topic.is_empty()).then(|| TopicPartition::new(topic, partition))
+  }
+}
+
+/// Written to `log.dirs` once every log is flushed on shutdown. Startup only scans the logs
+/// for damage when it's missing.
+pub const CLEAN_SHUTDOWN_FILE: &str = ".kafka_cleanshutdown";
+
+/// In `log.dirs`, the log start offset of every partition, which retention can move past the
+/// first offset still on disk.
+pub const LOG_START_OFFSET_CHECKPOINT_FILE: &str = "log-start-offset-checkpoint";
+
+/// The partitions with a directory in `log_dirs`.
+fn partition_dirs(log_dirs: &Path) -> Result<Vec<TopicPartition>> {
+  let entries = match fs::read_dir(log_dirs) {
+    Ok(entries) => entries,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(e) => return Err(e).with_context(|| format!("listing {}", log_dirs.display())),
+  };
+  let mut partitions = vec![];
+  for entry in entries {
+    let entry = entry?;
+    let Some(topic_partition) = entry.file_name().to_str().and_then(TopicPartition::from_dir_name) else {
+      continue;
+    };
+    if entry.file_type()?.is_dir() {
+      partitions.push(topic_partition);
+    }
+  }
+  Ok(partitions)
+}
+
+/// Startup check of every partition log (`__cluster_metadata-0` included) before anything
+/// reads them. After a clean shutdown there's nothing to do. Otherwise the active segment of
+/// each log, the only one appended to, is validated batch by batch and cut back to its last
+/// good batch.
+pub fn recover_log_dirs(log_dirs: &Path, config: &LogConfig) -> Result<()> {
+  let marker = log_dirs.join(CLEAN_SHUTDOWN_FILE);
+  if marker.exists() {
+    // Removed now so a crash from here on is noticed next time
+    fs::remove_file(&marker).with_context(|| format!("removing {}", marker.display()))?;
+    println!("{} was shut down cleanly, skipping log recovery", log_dirs.display());
+    return Ok(());
+  }
+
+  for topic_partition in partition_dirs(log_dirs)? {
+    Log::open(log_dirs, topic_partition, config.clone())?.recover()?;
+  }
+  Ok(())
+}
+
+impl fmt::Display for TopicPartition {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}-{}", self.topic, self.partition)
+  }
+}
+
+/// Per-log settings, from the broker's `log.*` defaults and the topic's own configs.
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+  /// `segment.bytes`
+  pub segment_bytes: u64,
+  /// `segment.ms`
+  pub segment_ms: i64,
+  /// `index.interval.bytes`
+  pub index_interval_bytes: u64,
+  /// `segment.index.bytes`
+  pub segment_index_bytes: u64,
+  /// `retention.ms`
+  pub retention_ms: i64,
+  /// `retention.bytes`
+  pub retention_bytes: i64,
+  /// `cleanup.policy` includes `delete`: `retention.ms` and `retention.bytes` apply.
+  pub delete: bool,
+  /// `cleanup.policy` includes `compact`: the log cleaner keeps the latest record per key.
+  pub compact: bool,
+  /// `delete.retention.ms`
+  pub delete_retention_ms: i64,
+  /// `min.cleanable.dirty.ratio`
+  pub min_cleanable_dirty_ratio: f64,
+  /// `min.compaction.lag.ms`
+  pub min_compaction_lag_ms: i64,
+  /// `compression.type`, none for `producer`: batches are stored as they were produced.
+  pub compression_type: Option<Compression>,
+}
+
+/// A `cleanup.policy` list as (delete, compact).
+pub fn parse_cleanup_policy(policy: &str) -> Option<(bool, bool)> {
+  let (mut delete, mut compact) = (false, false);
+  for policy in policy.split(',') {
+    match policy.trim() {
+      "delete" => delete = true,
+      "compact" => compact = true,
+      _ => return None,
+    }
+  }
+  Some((delete, compact))
+}
+
+/// A `compression.type`, `Some(None)` for `producer`.
+pub fn parse_compression_type(compression_type: &str) -> Option<Option<Compression>> {
+  match compression_type {
+    "producer" => Some(None),
+    name => Compression::from_name(name).map(Some),
+  }
+}
+
+impl LogConfig {
+  /// These settings with a topic's configs (from its `ConfigRecord`s) over them. Values that
+  /// don't parse are ignored.
+  pub fn with_overrides(&self, overrides: &BTreeMap<String, String>) -> LogConfig {
+    let mut config = self.clone();
+    for (name, value) in overrides {
+      let parsed = match name.as_str() {
+        "segment.bytes" => value.parse().map(|value| config.segment_bytes = value).is_ok(),
+        "segment.ms" => value.parse().map(|value| config.segment_ms = value).is_ok(),
+        "index.interval.bytes" => value.parse().map(|value| config.index_interval_bytes = value).is_ok(),
+        "segment.index.bytes" => value.parse().map(|value| config.segment_index_bytes = value).is_ok(),
+        "retention.ms" => value.parse().map(|value| config.retention_ms = value).is_ok(),
+        "retention.bytes" => value.parse().map(|value| config.retention_bytes = value).is_ok(),
+        "cleanup.policy" => parse_cleanup_policy(value).map(|policy| (config.delete, config.compact) = policy).is_some(),
+        "delete.retention.ms" => value.parse().map(|value| config.delete_retention_ms = value).is_ok(),
+        "min.cleanable.dirty.ratio" => value.parse().map(|value| config.min_cleanable_dirty_ratio = value).is_ok(),
+        "min.compaction.lag.ms" => value.parse().map(|value| config.min_compaction_lag_ms = value).is_ok(),
+        "compression.type" => parse_compression_type(value).map(|compression| config.compression_type = compression).is_some(),
+        // Batches keep the producer's timestamps, there's no LogAppendTime
+        "message.timestamp.type" => value == "CreateTime",
+        _ => true,
+      };
+      if !parsed {
+        println!("Ignoring topic config {}={:?}, not a valid value", name, value);
+      }
+    }
+    config
+  }
+}
+
+impl From<&BrokerConfig> for LogConfig {
+  fn from(config: &BrokerConfig) -> LogConfig {
+    let (delete, compact) = parse_cleanup_policy(&config.log_cleanup_policy).unwrap_or_else(|| {
+      println!("Ignoring log.cleanup.policy={:?}, using delete", config.log_cleanup_policy);
+      (true, false)
+    });
+    let compression_type = parse_compression_type(&config.compression_type).unwrap_or_else(|| {
+      println!("Ignoring compression.type={:?}, using producer", config.compression_type);
+      None
+    });
+    LogConfig {
+      segment_bytes: config.log_segment_bytes,
+      segment_ms: config.log_roll_ms,
+      index_interval_bytes: config.log_index_interval_bytes,
+      segment_index_bytes: config.log_index_size_max_bytes,
+      retention_ms: config.log_retention_ms,
+      retention_bytes: config.log_retention_bytes,
+      delete,
+      compact,
+      delete_retention_ms: config.log_cleaner_delete_retention_ms,
+      min_cleanable_dirty_ratio: config.log_cleaner_min_cleanable_ratio,
+      min_compaction_lag_ms: config.log_cleaner_min_compaction_lag_ms,
+      compression_type,
+    }
+  }
+}
+
+/// The on-disk log of one partition: a directory `<log.dirs>/<topic>-<partition>` of
+/// segments, each named after its base offset. Batches are appended to the last segment,
+/// which rolls over to a new one when it gets too big or too old.
+#[derive(Debug)]
+pub struct Log {
+  pub topic_partition: TopicPartition,
+  pub dir: PathBuf,
+  config: LogConfig,
+  /// By base offset, never empty.
+  segments: BTreeMap<i64, LogSegment>,
+  log_start_offset: i64,
+}
+
+impl Log {
+  /// Opens (creating if needed) the partition's log and every segment already in it.
+  pub fn open(log_dirs: &Path, topic_partition: TopicPartition, config: LogConfig) -> Result<Log> {
+    let dir = log_dirs.join(topic_partition.to_string());
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+
+    let mut segments = BTreeMap::new();
+    let mut swaps = vec![];
+    for entry in fs::read_dir(&dir).with_context(|| format!("listing {}", dir.display()))? {
+      let path = entry?.path();
+      let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+      if name.ends_with(CLEANED_SUFFIX) {
+        // The cleaner stopped before its segment was complete
+        fs::remove_file(&path).with_context(|| format!("deleting {}", path.display()))?;
+        continue;
+      }
+      if let Some(name) = name.strip_suffix(SWAP_SUFFIX) {
+        let log_file = format!(".{}", LOG_FILE_SUFFIX);
+        swaps.extend(name.strip_suffix(&log_file).and_then(|stem| stem.parse::<i64>().ok()));
+        continue;
+      }
+      if path.extension().and_then(|extension| extension.to_str()) != Some(LOG_FILE_SUFFIX) {
+        continue;
+      }
+      let Some(base_offset) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) else {
+        println!("Ignoring {}, not named after an offset", path.display());
+        continue;
+      };
+      segments.insert(base_offset, LogSegment::open(&dir, base_offset, &config)?);
+    }
+    // The cleaner stopped while swapping a complete segment in, finish replacing the
+    // segments it covers
+    for base_offset in swaps {
+      let mut swap = LogSegment::open_with_suffix(&dir, base_offset, &config, SWAP_SUFFIX)?;
+      let replaced: Vec<i64> = segments.range(base_offset..swap.next_offset()).map(|(base_offset, _)| *base_offset).collect();
+      for replaced in replaced {
+        segments.remove(&replaced).unwrap().delete()?;
+      }
+      swap.change_suffix("")?;
+      segments.insert(base_offset, swap);
+    }
+    if segments.is_empty() {
+      segments.insert(0, LogSegment::open(&dir, 0, &config)?);
+    }
+
+    Ok(Log {
+      log_start_offset: *segments.keys().next().unwrap(),
+      topic_partition,
+      dir,
+      config,
+      segments,
+    })
+  }
+
+  pub fn config(&self) -> &LogConfig {
+    &self.config
+  }
+
+  pub fn log_start_offset(&self) -> i64 {
+    self.log_start_offset
+  }
+
+  /// Offset the next appended record gets.
+  pub fn log_end_offset(&self) -> i64 {
+    self.active_segment().next_offset()
+  }
+
+  pub fn segments(&self) -> impl Iterator<Item = &LogSegment> {
+    self.segments.values()
+  }
+
+  /// Bytes in all segments.
+  pub fn size(&self) -> u64 {
+    self.segments.values().map(LogSegment::size).sum()
+  }
+
+  /// Moves the log start offset up to `offset` (at most the log end offset). Segments
+  /// wholly before it are deleted by the next `delete_old_segments`.
+  pub fn increment_log_start_offset(&mut self, offset: i64) {
+    if offset > self.log_start_offset {
+      self.log_start_offset = offset.min(self.log_end_offset());
+    }
+  }
+
+  fn active_segment(&self) -> &LogSegment {
+    self.segments.values().next_back().expect("a log always has a segment")
+  }
+
+  fn active_segment_mut(&mut self) -> &mut LogSegment {
+    self.segments.values_mut().next_back().expect("a log always has a segment")
+  }
+
+  /// Appends one validated batch, assigning it the next offsets. Returns its base offset.
+  pub fn append(&mut self, batch: &mut [u8], leader_epoch: i32) -> Result<i64> {
+    let base_offset = self.log_end_offset();
+    record_batch::assign_offset(batch, base_offset, leader_epoch);
+    let last_offset = base_offset + record_batch::last_offset_delta(batch) as i64;
+
+    if self.active_segment().should_roll(batch, last_offset, now_ms(), &self.config) {
+      self.roll()?;
+    }
+    if let Err(e) = self.active_segment_mut().append(batch) {
+      // Part of the batch could have made it to the file, where the next append would land
+      // behind it
+      self.truncate_to(base_offset)?;
+      return Err(e.context(format!("appending to {}", self.topic_partition)));
+    }
+    Ok(base_offset)
+  }
+
+  /// Validates the active segment after an unclean shutdown, see `LogSegment::recover`.
+  pub fn recover(&mut self) -> Result<()> {
+    let truncated = self.active_segment_mut().recover()
+      .with_context(|| format!("recovering {}", self.topic_partition))?;
+    if truncated > 0 {
+      println!("Recovered {} to log end offset {}", self.topic_partition, self.log_end_offset());
+    }
+    Ok(())
+  }
+
+  /// Writes every segment through to disk.
+  pub fn flush(&self) -> Result<()> {
+    for segment in self.segments.values() {
+      segment.flush().with_context(|| format!("flushing {}", self.topic_partition))?;
+    }
+    Ok(())
+  }
+
+  /// Puts the segment the cleaner wrote (named with `CLEANED_SUFFIX`) in place of the
+  /// segments at `replaced`. Renaming it to `SWAP_SUFFIX` first commits the swap: if the
+  /// broker dies before it's done, `open` completes it.
+  pub fn replace_segments(&mut self, mut cleaned: LogSegment, replaced: &[i64]) -> Result<()> {
+    cleaned.flush()?;
+    cleaned.change_suffix(SWAP_SUFFIX)?;
+    for base_offset in replaced {
+      if let Some(segment) = self.segments.remove(base_offset) {
+        segment.delete()?;
+      }
+    }
+    cleaned.change_suffix("")?;
+    self.segments.insert(cleaned.base_offset, cleaned);
+    Ok(())
+  }
+
+  /// Starts a new active segment at the log end offset.
+  fn roll(&mut self) -> Result<()> {
+    let base_offset = self.log_end_offset();
+    self.active_segment_mut().on_roll()?;
+    let segment = LogSegment::open(&self.dir, base_offset, &self.config)?;
+    self.segments.insert(base_offset, segment);
+    println!("Rolled {} to a new segment at offset {}", self.topic_partition, base_offset);
+    Ok(())
+  }
+
+  /// Deletes the oldest segments once they're past `retention.ms`, beyond `retention.bytes`
+  /// (both only with the `delete` cleanup policy) or entirely before the log start offset,
+  /// which then moves up to the first segment left. Returns how many segments went.
+  pub fn delete_old_segments(&mut self, now_ms: i64) -> Result<usize> {
+    let retention_ms = self.config.retention_ms;
+    let mut deleted = if !self.config.delete || retention_ms < 0 {
+      0
+    } else {
+      self.delete_segments_while("retention.ms", |segment, _| {
+        let timestamp = match segment.largest_timestamp() {
+          -1 => segment.last_modified_ms().unwrap_or(now_ms),
+          timestamp => timestamp,
+        };
+        now_ms - timestamp > retention_ms
+      })?
+    };
+
+    if self.config.delete && self.config.retention_bytes >= 0 {
+      let mut excess = self.size() as i64 - self.config.retention_bytes;
+      deleted += self.delete_segments_while("retention.bytes", |segment, _| {
+        let fits = excess - segment.size() as i64 >= 0;
+        if fits {
+          excess -= segment.size() as i64;
+        }
+        fits
+      })?;
+    }
+
+    let log_start_offset = self.log_start_offset;
+    deleted += self.delete_segments_while("log start offset", |_, next| {
+      next.is_some_and(|next| next.base_offset <= log_start_offset)
+    })?;
+    Ok(deleted)
+  }
+
+  /// Deletes segments from the oldest on for as long as `delete(segment, next segment)`
+  /// holds. An empty active segment is never deleted; a full one is, after rolling so the
+  /// log keeps a segment to append to.
+  fn delete_segments_while(
+    &mut self,
+    reason: &str,
+    mut delete: impl FnMut(&LogSegment, Option<&LogSegment>) -> bool,
+  ) -> Result<usize> {
+    let mut deletable = vec![];
+    let mut segments = self.segments.values().peekable();
+    while let Some(segment) = segments.next() {
+      let next = segments.peek().copied();
+      if (next.is_none() && segment.size() == 0) || !delete(segment, next) {
+        break;
+      }
+      deletable.push(segment.base_offset);
+    }
+    if deletable.is_empty() {
+      return Ok(0);
+    }
+
+    if deletable.len() == self.segments.len() {
+      self.roll()?;
+    }
+    for base_offset in &deletable {
+      self.segments.remove(base_offset).unwrap().delete()?;
+    }
+    self.log_start_offset = self.log_start_offset.max(*self.segments.keys().next().unwrap());
+    println!(
+      "Deleted {} segments of {} past {}, log start offset is now {}",
+      deletable.len(),
+      self.topic_partition,
+      reason,
+      self.log_start_offset,
+    );
+    Ok(deletable.len())
+  }
+
+  /// Whole batches starting with the one holding `offset`, up to `max_bytes`, all from the
+  /// same segment. See `LogSegment::read`.
+  pub fn read(&self, offset: i64, max_bytes: usize, min_one_batch: bool) -> Result<Bytes> {
+    let first = self.segments.range(..=offset).next_back().map_or(self.log_start_offset, |(base_offset, _)| *base_offset);
+    // `offset` can be just past the end of its segment, the batch is then in the next one
+    for segment in self.segments.range(first..).map(|(_, segment)| segment) {
+      let records = segment.read(offset, max_bytes, min_one_batch)
+        .with_context(|| format!("reading {} at offset {}", self.topic_partition, offset))?;
+      if !records.is_empty() {
+        return Ok(records);
+      }
+    }
+    Ok(Bytes::new())
+  }
+
+  /// The first record from the log start offset on with a timestamp at or after
+  /// `timestamp`, as (timestamp, offset).
+  pub fn find_offset_by_timestamp(&self, timestamp: i64) -> Result<Option<(i64, i64)>> {
+    for segment in self.segments.values().filter(|segment| segment.largest_timestamp() >= timestamp) {
+      if let Some(found) = segment.find_offset_by_timestamp(timestamp, self.log_start_offset)? {
+        return Ok(Some(found));
+      }
+    }
+    Ok(None)
+  }
+
+  /// The record from the log start offset on with the largest timestamp (the first of them
+  /// if there are several), as (timestamp, offset).
+  pub fn find_max_timestamp(&self) -> Result<Option<(i64, i64)>> {
+    let mut max: Option<(i64, i64)> = None;
+    for segment in self.segments.values() {
+      // A segment's largest timestamp may be from records before the log start offset, so
+      // it only rules segments out
+      if max.is_some_and(|(timestamp, _)| segment.largest_timestamp() <= timestamp) {
+        continue;
+      }
+      if let Some(found) = segment.find_max_timestamp(self.log_start_offset)? {
+        if max.map_or(true, |(timestamp, _)| found.0 > timestamp) {
+          max = Some(found);
+        }
+      }
+    }
+    Ok(max)
+  }
+
+  /// The leader epoch the batch holding `offset` was appended in, `None` past the end.
+  pub fn leader_epoch_at(&self, offset: i64) -> Result<Option<i32>> {
+    let batch = self.read(offset, 0, true)?;
+    Ok((!batch.is_empty()).then(|| record_batch::partition_leader_epoch(&batch)))
+  }
+
+  /// Removes everything from `offset` on. A batch holding `offset` goes entirely, so the
+  /// log can end up a little shorter than asked.
+  pub fn truncate_to(&mut self, offset: i64) -> Result<()> {
+    if offset <= self.log_start_offset && offset < self.log_end_offset() {
+      return self.truncate_fully_and_start_at(offset);
+    }
+
+    let removed: Vec<i64> = self.segments.range(offset + 1..).map(|(base_offset, _)| *base_offset).collect();
+    let removed_segments = removed.len();
+    for base_offset in removed {
+      self.segments.remove(&base_offset).unwrap().delete()?;
+    }
+    if self.active_segment_mut().truncate_to(offset)? > 0 || removed_segments > 0 {
+      println!("Truncated {} to offset {}", self.topic_partition, self.log_end_offset());
+    }
+    Ok(())
+  }
+
+  /// Deletes every segment and starts over, empty, at `offset`.
+  pub fn truncate_fully_and_start_at(&mut self, offset: i64) -> Result<()> {
+    for (_, segment) in std::mem::take(&mut self.segments) {
+      segment.delete()?;
+    }
+    self.segments.insert(offset, LogSegment::open(&self.dir, offset, &self.config)?);
+    self.log_start_offset = offset;
+    Ok(())
+  }
+}
+
+/// Every partition log this broker has opened, each behind its own lock so appends to
+/// different partitions don't wait on each other.
+#[derive(Debug)]
+pub struct LogManager {
+  log_dirs: PathBuf,
+  config: LogConfig,
+  /// Topics with configs of their own.
+  topic_configs: HashMap<String, LogConfig>,
+  log_start_offsets: OffsetCheckpointFile,
+  /// The first dirty offset of every compacted log.
+  cleaner_offsets: OffsetCheckpointFile,
+  /// What the checkpoint held at startup, applied to logs as they're opened.
+  checkpointed_log_start_offsets: HashMap<TopicPartition, i64>,
+  logs: Mutex<HashMap<TopicPartition, Arc<Mutex<Log>>>>,
+  /// Bumped after every append, waited on by fetches that want more data.
+  appends: Mutex<u64>,
+  appended: Condvar,
+}
+
+impl LogManager {
+  pub fn new(log_dirs: PathBuf, config: LogConfig, topic_configs: HashMap<String, LogConfig>) -> LogManager {
+    let log_start_offsets = OffsetCheckpointFile::new(log_dirs.join(LOG_START_OFFSET_CHECKPOINT_FILE));
+    let checkpointed_log_start_offsets = log_start_offsets.read().unwrap_or_else(|e| {
+      println!("Ignoring log start offset checkpoint: {:#}", e);
+      HashMap::new()
+    });
+    LogManager {
+      cleaner_offsets: OffsetCheckpointFile::new(log_dirs.join(CLEANER_OFFSET_CHECKPOINT_FILE)),
+      log_dirs,
+      config,
+      topic_configs,
+      log_start_offsets,
+      checkpointed_log_start_offsets,
+      logs: Mutex::new(HashMap::new()),
+      appends: Mutex::new(0),
+      appended: Condvar::new(),
+    }
+  }
+
+  /// The append counter, for a later `wait_for_append`.
+  pub fn appends(&self) -> u64 {
+    *self.appends.lock().unwrap()
+  }
+
+  /// Wakes up fetches waiting for data.
+  pub fn notify_append(&self) {
+    *self.appends.lock().unwrap() += 1;
+    self.appended.notify_all();
+  }
+
+  /// Blocks until something is appended after `seen` (a value of `appends()`) or `deadline`
+  /// passes. Returns whether there was an append.
+  pub fn wait_for_append(&self, seen: u64, deadline: Instant) -> bool {
+    let mut appends = self.appends.lock().unwrap();
+    while *appends == seen {
+      let timeout = deadline.saturating_duration_since(Instant::now());
+      if timeout == Duration::ZERO {
+        return false;
+      }
+      appends = self.appended.wait_timeout(appends, timeout).unwrap().0;
+    }
+    true
+  }
+
+  /// One retention pass: deletes the expired segments of every partition log (not the
+  /// metadata log) and checkpoints the log start offsets. A log that fails is reported
+  /// and skipped.
+  pub fn cleanup(&self) -> Result<()> {
+    let now_ms = now_ms();
+    for topic_partition in partition_dirs(&self.log_dirs)? {
+      if topic_partition.to_string() == METADATA_LOG_DIR {
+        continue;
+      }
+      let deleted = self.get_or_open(&topic_partition)
+        .and_then(|log| log.lock().unwrap().delete_old_segments(now_ms));
+      if let Err(e) = deleted {
+        println!("Retention of {} failed: {:#}", topic_partition, e);
+      }
+    }
+    self.checkpoint_log_start_offsets()
+  }
+
+  /// One log cleaner pass: compacts the logs of topics with the `compact` cleanup policy
+  /// that are dirty enough, see `log_cleaner::clean`, and checkpoints how far each got.
+  pub fn compact(&self) -> Result<()> {
+    let mut first_dirty_offsets = self.cleaner_offsets.read().unwrap_or_else(|e| {
+      println!("Ignoring cleaner offset checkpoint: {:#}", e);
+      HashMap::new()
+    });
+    let now_ms = now_ms();
+    let mut cleaned_any = false;
+    for topic_partition in partition_dirs(&self.log_dirs)? {
+      if topic_partition.to_string() == METADATA_LOG_DIR || !self.config_for(&topic_partition.topic).compact {
+        continue;
+      }
+      let checkpoint = first_dirty_offsets.get(&topic_partition).copied();
+      let cleaned = self.get_or_open(&topic_partition)
+        .and_then(|log| log_cleaner::clean(&log, checkpoint, now_ms));
+      match cleaned {
+        Ok(Some(first_dirty_offset)) => {
+          first_dirty_offsets.insert(topic_partition, first_dirty_offset);
+          cleaned_any = true;
+        }
+        Ok(None) => {}
+        Err(e) => println!("Compaction of {} failed: {:#}", topic_partition, e),
+      }
+    }
+    if cleaned_any {
+      self.cleaner_offsets.write(&first_dirty_offsets)?;
+    }
+    Ok(())
+  }
+
+  /// Writes the log start offset of every partition to the checkpoint, the ones read at
+  /// startup standing in for logs not opened since.
+  pub fn checkpoint_log_start_offsets(&self) -> Result<()> {
+    let mut offsets = self.checkpointed_log_start_offsets.clone();
+    for (topic_partition, log) in self.logs.lock().unwrap().iter() {
+      offsets.insert(topic_partition.clone(), log.lock().unwrap().log_start_offset());
+    }
+    fs::create_dir_all(&self.log_dirs)?;
+    self.log_start_offsets.write(&offsets)
+  }
+
+  /// Flushes every open log, checkpoints the log start offsets and leaves the clean
+  /// shutdown marker behind.
+  ///
+  /// An append racing with this is still in the page cache when the process exits. At worst
+  /// it's torn, which opening the segment truncates even without a recovery scan.
+  pub fn shutdown(&self) -> Result<()> {
+    for log in self.logs.lock().unwrap().values() {
+      log.lock().unwrap().flush()?;
+    }
+    self.checkpoint_log_start_offsets()?;
+    let marker = self.log_dirs.join(CLEAN_SHUTDOWN_FILE);
+    fs::write(&marker, b"").with_context(|| format!("writing {}", marker.display()))
+  }
+
+  pub fn config_for(&self, topic: &str) -> &LogConfig {
+    self.topic_configs.get(topic).unwrap_or(&self.config)
+  }
+
+  pub fn get_or_open(&self, topic_partition: &TopicPartition) -> Result<Arc<Mutex<Log>>> {
+    let mut logs = self.logs.lock().unwrap();
+    if let Some(log) = logs.get(topic_partition) {
+      return Ok(Arc::clone(log));
+    }
+
+    let mut log = Log::open(&self.log_dirs, topic_partition.clone(), self.config_for(&topic_partition.topic).clone())?;
+    match self.checkpointed_log_start_offsets.get(topic_partition) {
+      // Recovery cut the log back below offsets already deleted, start over after them
+      // rather than hand those offsets out again
+      Some(offset) if *offset > log.log_end_offset() => log.truncate_fully_and_start_at(*offset)?,
+      Some(offset) => log.increment_log_start_offset(*offset),
+      None => {}
+    }
+    let log = Arc::new(Mutex::new(log));
+    logs.insert(topic_partition.clone(), Arc::clone(&log));
+    Ok(log)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+
+  use super::*;
+  use crate::kafka::record_batch::RecordBatches;
+  use crate::kafka::test_support::{log_config, temp_dir, timestamp_batch};
+
+  fn base_offsets(records: Bytes) -> Vec<i64> {
+    RecordBatches::new(records).map(|batch| batch.unwrap().base_offset).collect()
+  }
+
+  fn files(log: &Log) -> Vec<String> {
+    let mut files: Vec<_> = fs::read_dir(&log.dir).unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect();
+    files.sort();
+    files
+  }
+
+  #[test]
+  fn rolls_segments_by_size_and_reads_across_them() {
+    let log_dirs = temp_dir();
+    let batch_size = timestamp_batch(&[0, 0]).len() as u64;
+    let config = LogConfig { segment_bytes: batch_size * 2, ..log_config() };
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config.clone()).unwrap();
+    for i in 0..5 {
+      assert_eq!(log.append(&mut timestamp_batch(&[i, i]), 0).unwrap(), i * 2);
+    }
+
+    assert_eq!(log.segments().map(|segment| segment.base_offset).collect::<Vec<_>>(), vec![0, 4, 8]);
+    assert_eq!(files(&log)[..3], [
+      "00000000000000000000.index".to_string(),
+      "00000000000000000000.log".to_string(),
+      "00000000000000000000.timeindex".to_string(),
+    ]);
+    assert_eq!(log.log_end_offset(), 10);
+
+    // Reads stay within one segment, and an offset inside a batch gets the whole batch
+    assert_eq!(base_offsets(log.read(1, 1 << 20, true).unwrap()), vec![0, 2]);
+    assert_eq!(base_offsets(log.read(4, 1 << 20, true).unwrap()), vec![4, 6]);
+    assert_eq!(base_offsets(log.read(7, batch_size as usize + 10, false).unwrap()), vec![6]);
+    assert_eq!(base_offsets(log.read(7, 10, false).unwrap()), Vec::<i64>::new());
+    assert_eq!(base_offsets(log.read(7, 10, true).unwrap()), vec![6]);
+    assert!(log.read(10, 1 << 20, true).unwrap().is_empty());
+
+    // A reopened log finds its segments and carries on
+    let mut reopened = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap();
+    assert_eq!((reopened.log_start_offset(), reopened.log_end_offset()), (0, 10));
+    assert_eq!(base_offsets(reopened.read(5, 1 << 20, true).unwrap()), vec![4, 6]);
+    assert_eq!(reopened.append(&mut timestamp_batch(&[9]), 0).unwrap(), 10);
+  }
+
+  #[test]
+  fn rolls_segments_by_time() {
+    let config = LogConfig { segment_ms: 1_000, ..log_config() };
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap();
+    for timestamp in [10_000, 10_500, 11_000, 11_001] {
+      log.append(&mut timestamp_batch(&[timestamp]), 0).unwrap();
+    }
+    assert_eq!(log.segments().map(|segment| segment.base_offset).collect::<Vec<_>>(), vec![0, 3]);
+  }
+
+  #[test]
+  fn finds_offsets_by_timestamp() {
+    let batch_size = timestamp_batch(&[0, 0]).len() as u64;
+    let config = LogConfig { segment_bytes: batch_size * 2, ..log_config() };
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap();
+    log.append(&mut timestamp_batch(&[100, 200]), 0).unwrap();
+    log.append(&mut timestamp_batch(&[150, 300]), 0).unwrap();
+    log.append(&mut timestamp_batch(&[400, 500]), 0).unwrap();
+
+    assert_eq!(log.find_offset_by_timestamp(0).unwrap(), Some((100, 0)));
+    assert_eq!(log.find_offset_by_timestamp(150).unwrap(), Some((200, 1)));
+    assert_eq!(log.find_offset_by_timestamp(250).unwrap(), Some((300, 3)));
+    assert_eq!(log.find_offset_by_timestamp(301).unwrap(), Some((400, 4)));
+    assert_eq!(log.find_offset_by_timestamp(501).unwrap(), None);
+    assert_eq!(log.find_max_timestamp().unwrap(), Some((500, 5)));
+  }
+
+  #[test]
+  fn finds_timestamps_from_the_log_start_offset_on() {
+    let batch_size = timestamp_batch(&[100, 900]).len() as u64;
+    let config = LogConfig { segment_bytes: batch_size * 2, ..log_config() };
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap();
+    log.append(&mut timestamp_batch(&[100, 900]), 0).unwrap();
+    log.append(&mut timestamp_batch(&[200, 300]), 0).unwrap();
+    log.append(&mut timestamp_batch(&[400, 350]), 0).unwrap();
+    assert_eq!(log.segments().map(|segment| segment.base_offset).collect::<Vec<_>>(), vec![0, 4]);
+    assert_eq!(log.find_max_timestamp().unwrap(), Some((900, 1)));
+
+    // The first segment's largest timestamp is now before the log start offset
+    log.increment_log_start_offset(2);
+    assert_eq!(log.find_max_timestamp().unwrap(), Some((400, 4)));
+    assert_eq!(log.find_offset_by_timestamp(0).unwrap(), Some((200, 2)));
+    assert_eq!(log.find_offset_by_timestamp(320).unwrap(), Some((400, 4)));
+    assert_eq!(log.find_offset_by_timestamp(500).unwrap(), None);
+
+    log.increment_log_start_offset(5);
+    assert_eq!(log.find_max_timestamp().unwrap(), Some((350, 5)));
+    assert_eq!(log.find_offset_by_timestamp(0).unwrap(), Some((350, 5)));
+  }
+
+  #[test]
+  fn truncates_to_an_offset() {
+    let batch_size = timestamp_batch(&[0, 0]).len() as u64;
+    let small_segments = LogConfig { segment_bytes: batch_size * 2, ..log_config() };
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), small_segments).unwrap();
+    for i in 0..4 {
+      log.append(&mut timestamp_batch(&[i * 100, i * 100]), 0).unwrap();
+    }
+
+    // Offset 3 is inside the batch at 2, which goes too
+    log.truncate_to(3).unwrap();
+    assert_eq!(log.log_end_offset(), 2);
+    assert_eq!(log.segments().count(), 1);
+    assert_eq!(files(&log).len(), 3);
+    assert_eq!(log.find_offset_by_timestamp(150).unwrap(), None);
+    assert_eq!(log.append(&mut timestamp_batch(&[700]), 0).unwrap(), 2);
+    assert_eq!(base_offsets(log.read(0, 1 << 20, true).unwrap()), vec![0, 2]);
+
+    log.truncate_to(0).unwrap();
+    assert_eq!((log.log_start_offset(), log.log_end_offset()), (0, 0));
+
+    // Part of a batch whose append failed is cut off, appends carry on after the last batch
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), log_config()).unwrap();
+    log.append(&mut timestamp_batch(&[100]), 0).unwrap();
+    fs::OpenOptions::new().append(true).open(segment_file(&log, "log")).unwrap().write_all(&timestamp_batch(&[200])[..20]).unwrap();
+    log.truncate_to(log.log_end_offset()).unwrap();
+    assert_eq!(log.append(&mut timestamp_batch(&[300]), 0).unwrap(), 1);
+    assert_eq!(base_offsets(log.read(0, 1 << 20, true).unwrap()), vec![0, 1]);
+  }
+
+  fn segment_file(log: &Log, suffix: &str) -> PathBuf {
+    log.dir.join(format!("00000000000000000000.{}", suffix))
+  }
+
+  #[test]
+  fn recovery_truncates_torn_and_corrupt_batches() {
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), log_config()).unwrap();
+    for i in 0..3 {
+      log.append(&mut timestamp_batch(&[i]), 0).unwrap();
+    }
+    let good = fs::metadata(segment_file(&log, "log")).unwrap().len() / 3 * 2;
+    drop(log);
+
+    // Flip a byte in the last batch's records and leave half a batch after it
+    let mut contents = fs::read(log_dirs.path().join("foo-0/00000000000000000000.log")).unwrap();
+    let last = contents.len() - 1;
+    contents[last] ^= 0xff;
+    contents.extend_from_slice(&timestamp_batch(&[9])[..30]);
+    fs::write(log_dirs.path().join("foo-0/00000000000000000000.log"), contents).unwrap();
+
+    let corrupt = record_batch::corrupt_batches();
+    recover_log_dirs(log_dirs.path(), &log_config()).unwrap();
+    assert!(record_batch::corrupt_batches() > corrupt);
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), log_config()).unwrap();
+    assert_eq!(log.log_end_offset(), 2);
+    assert_eq!(fs::metadata(segment_file(&log, "log")).unwrap().len(), good);
+    assert_eq!(log.append(&mut timestamp_batch(&[5]), 0).unwrap(), 2);
+  }
+
+  #[test]
+  fn rebuilds_missing_and_corrupt_indexes() {
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), log_config()).unwrap();
+    for i in 0..4 {
+      log.append(&mut timestamp_batch(&[i * 100]), 0).unwrap();
+    }
+    let index = fs::read(segment_file(&log, "index")).unwrap();
+    let time_index = fs::read(segment_file(&log, "timeindex")).unwrap();
+    assert_eq!((index.len(), time_index.len()), (3 * 8, 3 * 12));
+
+    fs::remove_file(segment_file(&log, "index")).unwrap();
+    fs::write(segment_file(&log, "timeindex"), [0xff; 24]).unwrap();
+    drop(log);
+
+    let log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), log_config()).unwrap();
+    assert_eq!(fs::read(segment_file(&log, "index")).unwrap(), index);
+    assert_eq!(fs::read(segment_file(&log, "timeindex")).unwrap(), time_index);
+    assert_eq!(log.find_offset_by_timestamp(150).unwrap(), Some((200, 2)));
+  }
+
+  #[test]
+  fn clean_shutdown_skips_recovery_once() {
+    let log_dirs = temp_dir();
+    let manager = LogManager::new(log_dirs.path().to_path_buf(), log_config(), HashMap::new());
+    let log = manager.get_or_open(&TopicPartition::new("foo", 0)).unwrap();
+    log.lock().unwrap().append(&mut timestamp_batch(&[0]), 0).unwrap();
+    manager.shutdown().unwrap();
+    assert!(log_dirs.path().join(CLEAN_SHUTDOWN_FILE).exists());
+
+    // A bad CRC isn't looked for after a clean shutdown, but the marker is used up
+    let path = log_dirs.path().join("foo-0/00000000000000000000.log");
+    let mut contents = fs::read(&path).unwrap();
+    let last = contents.len() - 1;
+    contents[last] ^= 0xff;
+    fs::write(&path, contents).unwrap();
+    recover_log_dirs(log_dirs.path(), &log_config()).unwrap();
+    assert!(!log_dirs.path().join(CLEAN_SHUTDOWN_FILE).exists());
+    assert_eq!(Log::open(log_dirs.path(), TopicPartition::new("foo", 0), log_config()).unwrap().log_end_offset(), 1);
+
+    recover_log_dirs(log_dirs.path(), &log_config()).unwrap();
+    assert_eq!(Log::open(log_dirs.path(), TopicPartition::new("foo", 0), log_config()).unwrap().log_end_offset(), 0);
+  }
+
+  #[test]
+  fn deletes_segments_past_retention_ms() {
+    let batch_size = timestamp_batch(&[0]).len() as u64;
+    let overrides = BTreeMap::from([
+      ("retention.ms".to_string(), "1500".to_string()),
+      ("segment.bytes".to_string(), batch_size.to_string()),
+    ]);
+    let config = log_config().with_overrides(&overrides);
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap();
+    for timestamp in [1_000, 2_000, 3_000, 4_000] {
+      log.append(&mut timestamp_batch(&[timestamp]), 0).unwrap();
+    }
+
+    assert_eq!(log.delete_old_segments(4_500).unwrap(), 2);
+    assert_eq!(log.segments().map(|segment| segment.base_offset).collect::<Vec<_>>(), vec![2, 3]);
+    assert_eq!(log.log_start_offset(), 2);
+    assert_eq!(files(&log).len(), 6);
+
+    // The active segment goes too once it expires, the log rolls to keep appending
+    assert_eq!(log.delete_old_segments(100_000).unwrap(), 2);
+    assert_eq!((log.log_start_offset(), log.log_end_offset()), (4, 4));
+    assert_eq!(log.delete_old_segments(100_000).unwrap(), 0);
+    assert_eq!(log.append(&mut timestamp_batch(&[5_000]), 0).unwrap(), 4);
+  }
+
+  #[test]
+  fn deletes_segments_beyond_retention_bytes_and_log_start_offset() {
+    let batch_size = timestamp_batch(&[0]).len() as u64;
+    let config = LogConfig {
+      segment_bytes: batch_size,
+      retention_bytes: (batch_size * 5 / 2) as i64,
+      ..log_config()
+    };
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap();
+    for i in 0..4 {
+      log.append(&mut timestamp_batch(&[i]), 0).unwrap();
+    }
+
+    // 4 batches against 2.5 allowed, only whole segments go
+    assert_eq!(log.delete_old_segments(0).unwrap(), 1);
+    assert_eq!((log.log_start_offset(), log.size()), (1, batch_size * 3));
+
+    log.increment_log_start_offset(3);
+    assert_eq!(log.delete_old_segments(0).unwrap(), 2);
+    assert_eq!(log.segments().map(|segment| segment.base_offset).collect::<Vec<_>>(), vec![3]);
+    assert_eq!(log.log_start_offset(), 3);
+  }
+
+  #[test]
+  fn checkpoints_log_start_offsets() {
+    let log_dirs = temp_dir();
+    let manager = LogManager::new(log_dirs.path().to_path_buf(), log_config(), HashMap::new());
+    let log = manager.get_or_open(&TopicPartition::new("foo", 0)).unwrap();
+    for i in 0..3 {
+      log.lock().unwrap().append(&mut timestamp_batch(&[i]), 0).unwrap();
+    }
+    // Inside the only segment, so nothing can be deleted and only the checkpoint has it
+    log.lock().unwrap().increment_log_start_offset(2);
+    manager.get_or_open(&TopicPartition::new("bar", 1)).unwrap();
+    manager.cleanup().unwrap();
+    assert_eq!(
+      fs::read_to_string(log_dirs.path().join(LOG_START_OFFSET_CHECKPOINT_FILE)).unwrap(),
+      "0\n2\nbar 1 0\nfoo 0 2\n",
+    );
+
+    let manager = LogManager::new(log_dirs.path().to_path_buf(), log_config(), HashMap::new());
+    let log = manager.get_or_open(&TopicPartition::new("foo", 0)).unwrap();
+    assert_eq!(log.lock().unwrap().log_start_offset(), 2);
+    assert_eq!(base_offsets(log.lock().unwrap().read(2, 1 << 20, true).unwrap()), vec![2]);
+
+    // Partitions not opened since startup keep their checkpointed offsets
+    manager.shutdown().unwrap();
+    assert_eq!(OffsetCheckpointFile::new(log_dirs.path().join(LOG_START_OFFSET_CHECKPOINT_FILE)).read().unwrap().len(), 2);
+
+    // A log that lost offsets the checkpoint had already moved past starts over after them
+    OffsetCheckpointFile::new(log_dirs.path().join(LOG_START_OFFSET_CHECKPOINT_FILE))
+      .write(&[(TopicPartition::new("foo", 0), 10)].into())
+      .unwrap();
+    let manager = LogManager::new(log_dirs.path().to_path_buf(), log_config(), HashMap::new());
+    let log = manager.get_or_open(&TopicPartition::new("foo", 0)).unwrap();
+    let mut log = log.lock().unwrap();
+    assert_eq!((log.log_start_offset(), log.log_end_offset()), (10, 10));
+    assert_eq!(log.append(&mut timestamp_batch(&[3]), 0).unwrap(), 10);
+  }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use crate::kafka::log::{Log, LogConfig, TopicPartition};
+use crate::kafka::log_segment::{LogSegment, SegmentSnapshot, CLEANED_SUFFIX};
+use crate::kafka::record_batch::RecordBatch;
+
+/// In `log.dirs`, the first dirty offset of every compacted log: where the part of it
+/// written since its last compaction starts.
+pub const CLEANER_OFFSET_CHECKPOINT_FILE: &str = "cleaner-offset-checkpoint";
+
+#[derive(Debug, Default)]
+struct CleanerStats {
+  records_read: usize,
+  records_removed: usize,
+  bytes_read: u64,
+  bytes_written: u64,
+}
+
+/// Compacts `log` (KIP-58 style) if enough of it is dirty. `checkpoint` is its first dirty
+/// offset from the last compaction, if any.
+///
+/// The dirty part runs from there to the active segment, or to the first segment with
+/// records younger than `min.compaction.lag.ms`. Compaction waits until that part is at least
+/// `min.cleanable.dirty.ratio` of the log up to it. Then the latest offset of every key in
+/// the dirty part is collected, and every segment before its end is rewritten without the
+/// records a later one with the same key replaces. Tombstones (records without a value)
+/// are kept until they've been in the cleaned part for `delete.retention.ms`, so consumers
+/// get to see them.
+///
+/// The log is only locked to pick the segments and to swap each cleaned one in, appends and
+/// reads go on while the cleaned segments are written.
+///
+/// Returns the new first dirty offset, or `None` when the log wasn't dirty enough or changed
+/// while it was compacted.
+pub fn clean(log: &Mutex<Log>, checkpoint: Option<i64>, now_ms: i64) -> Result<Option<i64>> {
+  let Some(cleanable) = cleanable(&log.lock().unwrap(), checkpoint, now_ms)? else {
+    return Ok(None);
+  };
+  let Cleanable { dir, topic_partition, config, first_dirty_offset, first_uncleanable_offset, segments } = cleanable;
+
+  let mut offsets = HashMap::new();
+  for segment in segments.iter().filter(|segment| segment.next_offset() > first_dirty_offset) {
+    for raw in segment.batches() {
+      let Some(batch) = decode_for_cleaning(raw?)? else {
+        continue;
+      };
+      for record in batch.log_records().filter(|record| record.offset >= first_dirty_offset) {
+        if let Some(key) = record.key {
+          offsets.insert(key, record.offset);
+        }
+      }
+    }
+  }
+
+  // Tombstones go once the last clean segment is delete.retention.ms newer than theirs
+  let delete_horizon_ms = segments.iter()
+    .take_while(|segment| segment.next_offset() <= first_dirty_offset)
+    .last()
+    .map_or(i64::MIN, |segment| segment.largest_timestamp() - config.delete_retention_ms);
+
+  // Small segments are merged as long as the result stays within segment.bytes
+  let mut groups: Vec<Vec<&SegmentSnapshot>> = vec![];
+  let mut group_size = 0;
+  for segment in &segments {
+    match groups.last_mut() {
+      Some(group)
+        if group_size + segment.size() <= config.segment_bytes
+          && segment.next_offset() - group[0].base_offset <= i32::MAX as i64 =>
+      {
+        group.push(segment);
+        group_size += segment.size();
+      }
+      _ => {
+        groups.push(vec![segment]);
+        group_size = segment.size();
+      }
+    }
+  }
+
+  let mut stats = CleanerStats::default();
+  for group in &groups {
+    let mut cleaned = LogSegment::open_with_suffix(&dir, group[0].base_offset, &config, CLEANED_SUFFIX)?;
+    if let Err(e) = clean_into(group, &mut cleaned, &offsets, delete_horizon_ms, &mut stats).and_then(|_| cleaned.flush()) {
+      cleaned.delete()?;
+      return Err(e);
+    }
+    if !swap(&mut log.lock().unwrap(), cleaned, group)? {
+      println!("{} changed while it was compacted, compacting it again on the next pass", topic_partition);
+      return Ok(None);
+    }
+  }
+
+  println!(
+    "Compacted {} up to offset {}: {} of {} records removed, {} bytes down to {} in {} segments",
+    topic_partition,
+    first_uncleanable_offset,
+    stats.records_removed,
+    stats.records_read,
+    stats.bytes_read,
+    stats.bytes_written,
+    groups.len(),
+  );
+  Ok(Some(first_uncleanable_offset))
+}
+
+/// The part of a log a compaction rewrites, taken while it's locked.
+struct Cleanable {
+  dir: PathBuf,
+  topic_partition: TopicPartition,
+  config: LogConfig,
+  first_dirty_offset: i64,
+  first_uncleanable_offset: i64,
+  /// Every segment before the first uncleanable offset.
+  segments: Vec<SegmentSnapshot>,
+}
+
+/// What of `log` gets compacted, or `None` when it isn't dirty enough, see `clean`.
+fn cleanable(log: &Log, checkpoint: Option<i64>, now_ms: i64) -> Result<Option<Cleanable>> {
+  let config = log.config().clone();
+  // A checkpoint past the end is from before a truncation, start over
+  let first_dirty_offset = checkpoint
+    .filter(|offset| *offset <= log.log_end_offset())
+    .unwrap_or_default()
+    .max(log.log_start_offset());
+
+  let active_base_offset = log.segments().last().expect("a log always has a segment").base_offset;
+  let first_uncleanable_offset = log.segments()
+    .filter(|segment| segment.next_offset() > first_dirty_offset)
+    .find(|segment| {
+      segment.base_offset == active_base_offset
+        || (config.min_compaction_lag_ms > 0 && segment.largest_timestamp() > now_ms - config.min_compaction_lag_ms)
+    })
+    .map_or(active_base_offset, |segment| segment.base_offset);
+
+  let cleanable = || log.segments().take_while(|segment| segment.base_offset < first_uncleanable_offset);
+  let (clean_bytes, dirty_bytes) = cleanable().fold((0, 0), |(clean, dirty), segment| {
+    if segment.next_offset() <= first_dirty_offset {
+      (clean + segment.size(), dirty)
+    } else {
+      (clean, dirty + segment.size())
+    }
+  });
+  if dirty_bytes == 0 || (dirty_bytes as f64) / ((clean_bytes + dirty_bytes) as f64) < config.min_cleanable_dirty_ratio {
+    return Ok(None);
+  }
+
+  Ok(Some(Cleanable {
+    dir: log.dir.clone(),
+    topic_partition: log.topic_partition.clone(),
+    config,
+    first_dirty_offset,
+    first_uncleanable_offset,
+    segments: cleanable().map(LogSegment::snapshot).collect::<Result<_>>()?,
+  }))
+}
+
+/// Copies the records of the segments of `group` worth keeping to `cleaned`.
+fn clean_into(
+  group: &[&SegmentSnapshot],
+  cleaned: &mut LogSegment,
+  offsets: &HashMap<Bytes, i64>,
+  delete_horizon_ms: i64,
+  stats: &mut CleanerStats,
+) -> Result<()> {
+  for segment in group {
+    let retain_deletes = segment.largest_timestamp() > delete_horizon_ms;
+    for raw in segment.batches() {
+      let raw = raw?;
+      stats.bytes_read += raw.len() as u64;
+      if let Some(kept) = filter_batch(raw, offsets, retain_deletes, stats)? {
+        stats.bytes_written += kept.len() as u64;
+        cleaned.append(&kept)?;
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Puts `cleaned` in place of the segments of `group`, unless one of them was truncated or
+/// deleted since: then `cleaned` is deleted instead and false returned.
+fn swap(log: &mut Log, cleaned: LogSegment, group: &[&SegmentSnapshot]) -> Result<bool> {
+  let unchanged = group.iter().all(|snapshot| log.segments().any(|segment| snapshot.matches(segment)));
+  if !unchanged {
+    cleaned.delete()?;
+    return Ok(false);
+  }
+  let replaced: Vec<_> = group.iter().map(|snapshot| snapshot.base_offset).collect();
+  log.replace_segments(cleaned, &replaced)?;
+  Ok(true)
+}
+
+/// The batch's records, decompressed, or `None` for a transaction marker, which is kept
+/// whole without a look inside.
+fn decode_for_cleaning(raw: Bytes) -> Result<Option<RecordBatch>> {
+  let batch = RecordBatch::decode(&mut raw.clone()).context("decoding a batch to compact")?;
+  Ok((!batch.is_control()).then_some(batch))
+}
+
+/// The batch without the records a later one with the same key replaces, and without
+/// tombstones unless `retain_deletes`. `None` when nothing is left. A batch that keeps all
+/// its records is returned as is; otherwise it's rewritten, keeping its offsets, timestamps,
+/// last offset delta and codec.
+fn filter_batch(
+  raw: Bytes,
+  offsets: &HashMap<Bytes, i64>,
+  retain_deletes: bool,
+  stats: &mut CleanerStats,
+) -> Result<Option<Bytes>> {
+  let Some(mut batch) = decode_for_cleaning(raw.clone())? else {
+    return Ok(Some(raw));
+  };
+
+  let count = batch.records.len();
+  let base_offset = batch.base_offset;
+  batch.records.retain(|record| {
+    let Some(key) = &record.key else {
+      return true;
+    };
+    let offset = base_offset + record.offset_delta as i64;
+    offsets.get(key).map_or(true, |latest| offset >= *latest) && (record.value.is_some() || retain_deletes)
+  });
+  stats.records_read += count;
+  stats.records_removed += count - batch.records.len();
+
+  Ok(match batch.records.len() {
+    0 => None,
+    kept if kept == count => Some(raw),
+    _ => Some(batch.encode().freeze()),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::*;
+  use crate::kafka::compression::Compression;
+  use crate::kafka::log::{LogConfig, TopicPartition};
+  use crate::kafka::log_segment::SWAP_SUFFIX;
+  use crate::kafka::record_batch::{self, RecordBatches};
+  use crate::kafka::test_support::{keyed_batch, log_config, temp_dir};
+
+  fn config() -> LogConfig {
+    LogConfig { delete: false, compact: true, delete_retention_ms: 1_000, min_cleanable_dirty_ratio: 0.0, ..log_config() }
+  }
+
+  /// (offset, key, value) of every record left in the log.
+  fn contents(log: &Log) -> Vec<(i64, String, Option<String>)> {
+    let text = |bytes: Bytes| String::from_utf8(bytes.to_vec()).unwrap();
+    RecordBatches::new(read_all(log))
+      .flat_map(|batch| batch.unwrap().log_records().collect::<Vec<_>>())
+      .map(|record| (record.offset, text(record.key.unwrap()), record.value.map(text)))
+      .collect()
+  }
+
+  fn read_all(log: &Log) -> Bytes {
+    let mut records = vec![];
+    let mut offset = log.log_start_offset();
+    loop {
+      let read = log.read(offset, 1 << 20, true).unwrap();
+      let Some(last) = RecordBatches::new(read.clone()).last() else {
+        return Bytes::from(records);
+      };
+      offset = last.unwrap().last_offset() + 1;
+      records.extend_from_slice(&read);
+    }
+  }
+
+  fn append(log: &Mutex<Log>, records: &[(&str, Option<&str>)], timestamp: i64) {
+    log.lock().unwrap().append(&mut keyed_batch(records, timestamp), 0).unwrap();
+  }
+
+  fn entry(offset: i64, key: &str, value: Option<&str>) -> (i64, String, Option<String>) {
+    (offset, key.to_string(), value.map(str::to_string))
+  }
+
+  #[test]
+  fn keeps_the_latest_record_per_key() {
+    let config = LogConfig { segment_bytes: keyed_batch(&[("k", Some("v")); 2], 0).len() as u64, ..config() };
+    let log_dirs = temp_dir();
+    let log = Mutex::new(Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap());
+    append(&log, &[("a", Some("1")), ("b", Some("1"))], 100);
+    append(&log, &[("a", Some("2")), ("c", Some("1"))], 200);
+    append(&log, &[("b", None), ("d", Some("1"))], 300);
+    append(&log, &[("a", Some("3"))], 400); // active, not cleaned
+
+    assert_eq!(clean(&log, None, 1_000).unwrap(), Some(6));
+    let cleaned = log.lock().unwrap();
+    assert_eq!(contents(&cleaned), vec![
+      entry(2, "a", Some("2")), // the later `a` is in the active segment
+      entry(3, "c", Some("1")),
+      entry(4, "b", None), // a tombstone, kept for now
+      entry(5, "d", Some("1")),
+      entry(6, "a", Some("3")),
+    ]);
+    assert_eq!(cleaned.segments().map(|segment| segment.base_offset).collect::<Vec<_>>(), vec![0, 2, 4, 6]);
+    assert_eq!((cleaned.log_start_offset(), cleaned.log_end_offset()), (0, 7));
+    // Offsets inside removed records find the next one left
+    assert_eq!(RecordBatches::new(cleaned.read(1, 1 << 20, true).unwrap()).next().unwrap().unwrap().base_offset, 2);
+    drop(cleaned);
+
+    // Nothing new to compact
+    assert_eq!(clean(&log, Some(6), 1_000).unwrap(), None);
+  }
+
+  #[test]
+  fn compacts_compressed_batches_keeping_their_codec() {
+    let log_dirs = temp_dir();
+    let log = Mutex::new(Log::open(log_dirs.path(), TopicPartition::new("foo", 0), LogConfig { segment_bytes: 1, ..config() }).unwrap());
+    for (records, compression) in [(&[("a", Some("1")), ("b", Some("1"))], Compression::Gzip), (&[("a", Some("2")), ("c", Some("1"))], Compression::Zstd)] {
+      let mut batch = RecordBatch::decode(&mut Bytes::from(keyed_batch(records, 0))).unwrap();
+      batch.attributes = compression.id();
+      log.lock().unwrap().append(&mut batch.encode().to_vec(), 0).unwrap();
+    }
+    append(&log, &[("d", Some("1"))], 0);
+
+    assert_eq!(clean(&log, None, 1_000).unwrap(), Some(4));
+    assert_eq!(contents(&log.lock().unwrap()), vec![
+      entry(1, "b", Some("1")),
+      entry(2, "a", Some("2")),
+      entry(3, "c", Some("1")),
+      entry(4, "d", Some("1")),
+    ]);
+    let codecs: Vec<_> = RecordBatches::new(read_all(&log.lock().unwrap())).map(|batch| batch.unwrap().compression()).collect();
+    assert_eq!(codecs, vec![Compression::Gzip.id(), Compression::Zstd.id(), Compression::None.id()]);
+  }
+
+  #[test]
+  fn removes_tombstones_after_delete_retention_ms() {
+    let config = LogConfig { segment_bytes: 1, delete_retention_ms: 500, ..config() };
+    let log_dirs = temp_dir();
+    let log = Mutex::new(Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap());
+    append(&log, &[("a", Some("1"))], 100);
+    append(&log, &[("a", None)], 200);
+    append(&log, &[("b", Some("1"))], 300);
+    assert_eq!(clean(&log, None, 0).unwrap(), Some(2));
+    assert_eq!(contents(&log.lock().unwrap()), vec![entry(1, "a", None), entry(2, "b", Some("1"))]);
+
+    // The clean part of the log reaches 200, then 300: not delete.retention.ms past the
+    // tombstone yet
+    append(&log, &[("b", Some("2"))], 1_000);
+    assert_eq!(clean(&log, Some(2), 0).unwrap(), Some(3));
+    append(&log, &[("c", Some("1"))], 2_000);
+    assert_eq!(clean(&log, Some(3), 0).unwrap(), Some(4));
+    assert_eq!(contents(&log.lock().unwrap()), vec![entry(1, "a", None), entry(3, "b", Some("2")), entry(4, "c", Some("1"))]);
+
+    // Then 1_000
+    append(&log, &[("d", Some("1"))], 3_000);
+    assert_eq!(clean(&log, Some(4), 0).unwrap(), Some(5));
+    assert_eq!(contents(&log.lock().unwrap()), vec![entry(3, "b", Some("2")), entry(4, "c", Some("1")), entry(5, "d", Some("1"))]);
+  }
+
+  #[test]
+  fn waits_for_the_dirty_ratio_and_compaction_lag() {
+    let config = LogConfig { segment_bytes: 1, min_cleanable_dirty_ratio: 0.5, min_compaction_lag_ms: 500, ..config() };
+    let log_dirs = temp_dir();
+    let log = Mutex::new(Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap());
+    for (key, timestamp) in [("a", 100), ("b", 200), ("c", 300), ("a", 1_000), ("d", 1_100)] {
+      append(&log, &[(key, Some("v"))], timestamp);
+    }
+
+    // At 1_200 the segment with the second `a` is too young, one of the three segments
+    // before it is dirty
+    assert_eq!(clean(&log, Some(2), 1_200).unwrap(), None);
+    // Later the segment with the second `a` counts too, but it's only a quarter of the log
+    assert_eq!(clean(&log, Some(3), 2_000).unwrap(), None);
+    assert_eq!(clean(&log, Some(2), 2_000).unwrap(), Some(4));
+    assert_eq!(contents(&log.lock().unwrap()).into_iter().map(|(offset, ..)| offset).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+  }
+
+  #[test]
+  fn drops_the_cleaned_segment_when_the_log_changed_meanwhile() {
+    let log_dirs = temp_dir();
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), LogConfig { segment_bytes: 1, ..config() }).unwrap();
+    for key in ["a", "a", "b"] {
+      log.append(&mut keyed_batch(&[(key, Some("v"))], 0), 0).unwrap();
+    }
+    let cleanable = cleanable(&log, None, 0).unwrap().unwrap();
+    let group: Vec<_> = cleanable.segments.iter().collect();
+    let cleaned = || LogSegment::open_with_suffix(&log_dirs.path().join("foo-0"), 0, &cleanable.config, CLEANED_SUFFIX).unwrap();
+
+    log.truncate_to(1).unwrap();
+    assert!(!swap(&mut log, cleaned(), &group).unwrap());
+    assert_eq!(log.segments().map(|segment| segment.base_offset).collect::<Vec<_>>(), vec![0, 1]);
+    assert!(fs::read_dir(&log.dir).unwrap().all(|entry| !entry.unwrap().path().to_string_lossy().ends_with(CLEANED_SUFFIX)));
+  }
+
+  #[test]
+  fn open_finishes_an_interrupted_swap() {
+    let log_dirs = temp_dir();
+    let config = LogConfig { segment_bytes: 1, ..config() };
+    let mut log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config.clone()).unwrap();
+    for key in ["a", "a", "b"] {
+      log.append(&mut keyed_batch(&[(key, Some("v"))], 0), 0).unwrap();
+    }
+
+    // A cleaned segment for offsets 0 and 1 that made it to .swap, and one that didn't
+    let mut swap = LogSegment::open_with_suffix(&log.dir, 0, &config, SWAP_SUFFIX).unwrap();
+    let mut kept = keyed_batch(&[("a", Some("v"))], 0);
+    record_batch::assign_offset(&mut kept, 1, 0);
+    swap.append(&kept).unwrap();
+    LogSegment::open_with_suffix(&log.dir, 2, &config, CLEANED_SUFFIX).unwrap();
+    drop(log);
+
+    let log = Log::open(log_dirs.path(), TopicPartition::new("foo", 0), config).unwrap();
+    assert_eq!(log.segments().map(|segment| segment.base_offset).collect::<Vec<_>>(), vec![0, 2]);
+    assert_eq!(contents(&log), vec![entry(1, "a", Some("v")), entry(2, "b", Some("v"))]);
+    let mut files: Vec<_> = fs::read_dir(&log.dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    files.sort();
+    assert_eq!(files.len(), 6, "{:?}", files);
+  }
+}
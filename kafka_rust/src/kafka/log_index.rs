@@ -0,0 +1,275 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, BytesMut};
+
+/// Relative offset (int32) and file position (int32).
+const OFFSET_ENTRY_SIZE: usize = 8;
+/// Timestamp (int64) and relative offset (int32).
+const TIME_ENTRY_SIZE: usize = 12;
+
+/// Opens an index file for appending and returns its complete entries.
+fn open_index(path: &Path, entry_size: usize) -> Result<(File, Vec<u8>)> {
+  let mut contents = match fs::read(path) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+    Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+  };
+  let file = OpenOptions::new()
+    .create(true)
+    .read(true)
+    .append(true)
+    .open(path)
+    .with_context(|| format!("opening {}", path.display()))?;
+
+  let complete = contents.len() - contents.len() % entry_size;
+  if complete < contents.len() {
+    file.set_len(complete as u64)?;
+    contents.truncate(complete);
+  }
+  Ok((file, contents))
+}
+
+/// The `.index` file of a segment: sparse entries mapping an offset to the position in the
+/// `.log` file of the batch holding it. Offsets are stored relative to the segment's base
+/// offset, so a segment can't span more than `i32::MAX` offsets.
+#[derive(Debug)]
+pub struct OffsetIndex {
+  path: PathBuf,
+  file: File,
+  base_offset: i64,
+  /// (offset, position), both increasing.
+  entries: Vec<(i64, u64)>,
+  max_entries: usize,
+}
+
+impl OffsetIndex {
+  pub fn open(path: PathBuf, base_offset: i64, max_index_size: u64) -> Result<OffsetIndex> {
+    let (file, contents) = open_index(&path, OFFSET_ENTRY_SIZE)?;
+    let entries = contents.chunks_exact(OFFSET_ENTRY_SIZE)
+      .map(|mut entry| (base_offset + entry.get_i32() as i64, entry.get_u32() as u64))
+      .collect();
+
+    Ok(OffsetIndex {
+      path,
+      file,
+      base_offset,
+      entries,
+      max_entries: max_index_size as usize / OFFSET_ENTRY_SIZE,
+    })
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.entries.len() >= self.max_entries
+  }
+
+  pub fn last_entry(&self) -> Option<(i64, u64)> {
+    self.entries.last().copied()
+  }
+
+  /// Adds an entry, ignoring offsets not past the last one.
+  pub fn append(&mut self, offset: i64, position: u64) -> Result<()> {
+    if self.last_entry().is_some_and(|(last, _)| offset <= last) || self.is_full() {
+      return Ok(());
+    }
+
+    let mut entry = BytesMut::with_capacity(OFFSET_ENTRY_SIZE);
+    entry.put_i32((offset - self.base_offset) as i32);
+    entry.put_u32(position as u32);
+    self.file.write_all(&entry).with_context(|| format!("appending to {}", self.path.display()))?;
+    self.entries.push((offset, position));
+    Ok(())
+  }
+
+  /// The entry with the largest offset at or before `offset`, or the start of the segment.
+  /// Reading from its position and skipping batches finds `offset`.
+  pub fn lookup(&self, offset: i64) -> (i64, u64) {
+    match self.entries.partition_point(|(entry, _)| *entry <= offset) {
+      0 => (self.base_offset, 0),
+      i => self.entries[i - 1],
+    }
+  }
+
+  /// Drops the entries for `offset` and beyond.
+  pub fn truncate_to(&mut self, offset: i64) -> Result<()> {
+    let keep = self.entries.partition_point(|(entry, _)| *entry < offset);
+    self.entries.truncate(keep);
+    self.file.set_len((keep * OFFSET_ENTRY_SIZE) as u64)
+      .with_context(|| format!("truncating {}", self.path.display()))
+  }
+
+  /// Whether the entries could have been written for a `.log` file of `log_size` bytes:
+  /// offsets and positions strictly increasing, all inside the segment.
+  pub fn sanity_check(&self, log_size: u64) -> bool {
+    self.entries.first().map_or(true, |(offset, _)| *offset >= self.base_offset)
+      && self.entries.last().map_or(true, |(_, position)| *position < log_size)
+      && self.entries.windows(2).all(|pair| pair[0].0 < pair[1].0 && pair[0].1 < pair[1].1)
+  }
+
+  /// Drops every entry, for a rebuild.
+  pub fn clear(&mut self) -> Result<()> {
+    self.entries.clear();
+    self.file.set_len(0).with_context(|| format!("truncating {}", self.path.display()))
+  }
+
+  pub fn flush(&self) -> Result<()> {
+    self.file.sync_all().with_context(|| format!("flushing {}", self.path.display()))
+  }
+
+  /// Moves the file to `path`, keeping it open.
+  pub fn rename_to(&mut self, path: PathBuf) -> Result<()> {
+    fs::rename(&self.path, &path).with_context(|| format!("renaming {} to {}", self.path.display(), path.display()))?;
+    self.path = path;
+    Ok(())
+  }
+
+  pub fn delete(self) -> Result<()> {
+    fs::remove_file(&self.path).with_context(|| format!("deleting {}", self.path.display()))
+  }
+}
+
+/// The `.timeindex` file of a segment: sparse entries holding the largest timestamp seen so
+/// far and the offset it was seen at. Every record before that offset is no later than the
+/// timestamp.
+#[derive(Debug)]
+pub struct TimeIndex {
+  path: PathBuf,
+  file: File,
+  base_offset: i64,
+  /// (timestamp, offset)
+  entries: Vec<(i64, i64)>,
+  max_entries: usize,
+}
+
+impl TimeIndex {
+  pub fn open(path: PathBuf, base_offset: i64, max_index_size: u64) -> Result<TimeIndex> {
+    let (file, contents) = open_index(&path, TIME_ENTRY_SIZE)?;
+    let entries = contents.chunks_exact(TIME_ENTRY_SIZE)
+      .map(|mut entry| (entry.get_i64(), base_offset + entry.get_i32() as i64))
+      .collect();
+
+    Ok(TimeIndex {
+      path,
+      file,
+      base_offset,
+      entries,
+      max_entries: max_index_size as usize / TIME_ENTRY_SIZE,
+    })
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.entries.len() >= self.max_entries
+  }
+
+  pub fn last_entry(&self) -> Option<(i64, i64)> {
+    self.entries.last().copied()
+  }
+
+  /// Adds an entry if `timestamp` is later than the last one. Timestamps of records can go
+  /// backwards, the index only keeps the maximum so far.
+  pub fn maybe_append(&mut self, timestamp: i64, offset: i64) -> Result<()> {
+    if self.last_entry().is_some_and(|(last, _)| timestamp <= last) || self.is_full() {
+      return Ok(());
+    }
+
+    let mut entry = BytesMut::with_capacity(TIME_ENTRY_SIZE);
+    entry.put_i64(timestamp);
+    entry.put_i32((offset - self.base_offset) as i32);
+    self.file.write_all(&entry).with_context(|| format!("appending to {}", self.path.display()))?;
+    self.entries.push((timestamp, offset));
+    Ok(())
+  }
+
+  /// The entry with the largest timestamp at or before `timestamp`, or the start of the
+  /// segment. Records from its offset on are where a search for `timestamp` starts.
+  pub fn lookup(&self, timestamp: i64) -> (i64, i64) {
+    match self.entries.partition_point(|(entry, _)| *entry <= timestamp) {
+      0 => (-1, self.base_offset),
+      i => self.entries[i - 1],
+    }
+  }
+
+  /// Drops the entries pointing at `offset` and beyond.
+  pub fn truncate_to(&mut self, offset: i64) -> Result<()> {
+    let keep = self.entries.partition_point(|(_, entry)| *entry < offset);
+    self.entries.truncate(keep);
+    self.file.set_len((keep * TIME_ENTRY_SIZE) as u64)
+      .with_context(|| format!("truncating {}", self.path.display()))
+  }
+
+  /// Whether timestamps and offsets are strictly increasing and inside the segment.
+  pub fn sanity_check(&self) -> bool {
+    self.entries.first().map_or(true, |(_, offset)| *offset >= self.base_offset)
+      && self.entries.windows(2).all(|pair| pair[0].0 < pair[1].0 && pair[0].1 < pair[1].1)
+  }
+
+  /// Drops every entry, for a rebuild.
+  pub fn clear(&mut self) -> Result<()> {
+    self.entries.clear();
+    self.file.set_len(0).with_context(|| format!("truncating {}", self.path.display()))
+  }
+
+  pub fn flush(&self) -> Result<()> {
+    self.file.sync_all().with_context(|| format!("flushing {}", self.path.display()))
+  }
+
+  /// Moves the file to `path`, keeping it open.
+  pub fn rename_to(&mut self, path: PathBuf) -> Result<()> {
+    fs::rename(&self.path, &path).with_context(|| format!("renaming {} to {}", self.path.display(), path.display()))?;
+    self.path = path;
+    Ok(())
+  }
+
+  pub fn delete(self) -> Result<()> {
+    fs::remove_file(&self.path).with_context(|| format!("deleting {}", self.path.display()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::test_support::temp_dir;
+
+  #[test]
+  fn offset_index_finds_the_closest_entry_below() {
+    let dir = temp_dir();
+    let path = dir.path().join("00000000000000000100.index");
+    let mut index = OffsetIndex::open(path.clone(), 100, 1024).unwrap();
+    index.append(103, 4000).unwrap();
+    index.append(110, 9000).unwrap();
+    index.append(105, 1).unwrap(); // out of order, ignored
+
+    assert_eq!(index.lookup(99), (100, 0));
+    assert_eq!(index.lookup(103), (103, 4000));
+    assert_eq!(index.lookup(109), (103, 4000));
+    assert_eq!(index.lookup(500), (110, 9000));
+    assert_eq!(fs::read(&path).unwrap().len(), 16);
+
+    index.truncate_to(110).unwrap();
+    let reopened = OffsetIndex::open(path, 100, 1024).unwrap();
+    assert_eq!((reopened.lookup(500), reopened.last_entry()), ((103, 4000), Some((103, 4000))));
+  }
+
+  #[test]
+  fn time_index_keeps_only_increasing_timestamps() {
+    let dir = temp_dir();
+    let path = dir.path().join("00000000000000000000.timeindex");
+    let mut index = TimeIndex::open(path.clone(), 0, 24).unwrap();
+    index.maybe_append(1_000, 2).unwrap();
+    index.maybe_append(900, 5).unwrap();
+    index.maybe_append(2_000, 8).unwrap();
+    index.maybe_append(3_000, 9).unwrap(); // over the 2 entries 24 bytes hold
+    assert!(index.is_full());
+
+    assert_eq!(index.lookup(500), (-1, 0));
+    assert_eq!(index.lookup(1_500), (1_000, 2));
+    assert_eq!(index.lookup(5_000), (2_000, 8));
+
+    // A torn trailing entry is dropped on open
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&[1, 2, 3]).unwrap();
+    let reopened = TimeIndex::open(path, 0, 24).unwrap();
+    assert_eq!(reopened.last_entry(), Some((2_000, 8)));
+  }
+}
@@ -0,0 +1,478 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes};
+
+use crate::kafka::log::LogConfig;
+use crate::kafka::log_index::{OffsetIndex, TimeIndex};
+use crate::kafka::record_batch::{
+  self, RecordBatch, RecordBatches, BATCH_OVERHEAD, LAST_OFFSET_DELTA_OFFSET, MAX_TIMESTAMP_OFFSET, RECORD_BATCH_HEADER_SIZE,
+};
+
+pub const LOG_FILE_SUFFIX: &str = "log";
+pub const INDEX_FILE_SUFFIX: &str = "index";
+pub const TIME_INDEX_FILE_SUFFIX: &str = "timeindex";
+/// Appended to the file names of a segment the cleaner is writing.
+pub const CLEANED_SUFFIX: &str = ".cleaned";
+/// Appended to the file names of a cleaned segment while it replaces the segments it was
+/// written from.
+pub const SWAP_SUFFIX: &str = ".swap";
+
+/// Segment files are named after the first offset they hold, zero padded to 20 digits.
+pub fn file_name(base_offset: i64, suffix: &str) -> String {
+  format!("{:020}.{}", base_offset, suffix)
+}
+
+pub fn now_ms() -> i64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as i64)
+}
+
+/// Where a batch sits in the segment file, read from its header alone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchPosition {
+  pub base_offset: i64,
+  pub last_offset: i64,
+  pub max_timestamp: i64,
+  pub position: u64,
+  pub size: u64,
+}
+
+impl BatchPosition {
+  fn parse(header: &[u8], position: u64) -> BatchPosition {
+    let base_offset = (&header[..8]).get_i64();
+    BatchPosition {
+      base_offset,
+      last_offset: base_offset + (&header[LAST_OFFSET_DELTA_OFFSET..]).get_i32() as i64,
+      max_timestamp: (&header[MAX_TIMESTAMP_OFFSET..]).get_i64(),
+      position,
+      size: BATCH_OVERHEAD as u64 + (&header[8..12]).get_i32() as u64,
+    }
+  }
+}
+
+/// The header of the batch at `position`, or `None` when no complete batch starts there
+/// before `end`.
+fn batch_at(file: &File, position: u64, end: u64) -> Result<Option<BatchPosition>> {
+  if position + RECORD_BATCH_HEADER_SIZE as u64 > end {
+    return Ok(None);
+  }
+  let mut header = [0; RECORD_BATCH_HEADER_SIZE];
+  file.read_exact_at(&mut header, position)?;
+  let batch_length = (&header[8..12]).get_i32();
+  if batch_length < (RECORD_BATCH_HEADER_SIZE - BATCH_OVERHEAD) as i32 || position + BATCH_OVERHEAD as u64 + batch_length as u64 > end {
+    return Ok(None);
+  }
+  Ok(Some(BatchPosition::parse(&header, position)))
+}
+
+/// One `.log` file of a partition with its `.index` and `.timeindex`. Only the last segment
+/// of a log (the active one) is appended to.
+#[derive(Debug)]
+pub struct LogSegment {
+  pub base_offset: i64,
+  dir: PathBuf,
+  /// After the file extensions, `.cleaned` and `.swap` while the cleaner replaces segments.
+  suffix: String,
+  file: File,
+  offset_index: OffsetIndex,
+  time_index: TimeIndex,
+  size: u64,
+  next_offset: i64,
+  index_interval_bytes: u64,
+  bytes_since_last_index_entry: u64,
+  max_timestamp_so_far: i64,
+  offset_of_max_timestamp: i64,
+  /// Max timestamp of the first batch, what time based rolling is measured from.
+  rolling_based_timestamp: Option<i64>,
+  created_ms: i64,
+}
+
+/// A segment's batches up to where it ended when the snapshot was taken, for reading
+/// without holding its log. The file stays open, so a segment deleted in the meantime can
+/// still be read.
+#[derive(Debug)]
+pub struct SegmentSnapshot {
+  pub base_offset: i64,
+  file: File,
+  size: u64,
+  next_offset: i64,
+  largest_timestamp: i64,
+}
+
+impl SegmentSnapshot {
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  pub fn next_offset(&self) -> i64 {
+    self.next_offset
+  }
+
+  pub fn largest_timestamp(&self) -> i64 {
+    self.largest_timestamp
+  }
+
+  /// Whether `segment` still is what this is a snapshot of.
+  pub fn matches(&self, segment: &LogSegment) -> bool {
+    segment.base_offset == self.base_offset && segment.size == self.size && segment.next_offset == self.next_offset
+  }
+
+  /// Every batch in the snapshot, in order.
+  pub fn batches(&self) -> impl Iterator<Item = Result<Bytes>> + '_ {
+    let mut position = 0;
+    std::iter::from_fn(move || {
+      let batch = match batch_at(&self.file, position, self.size) {
+        Ok(batch) => batch?,
+        Err(e) => return Some(Err(e)),
+      };
+      position += batch.size;
+      let mut raw = vec![0; batch.size as usize];
+      let read = self.file.read_exact_at(&mut raw, batch.position)
+        .with_context(|| format!("reading segment {} at offset {}", self.base_offset, batch.base_offset));
+      Some(read.map(|_| Bytes::from(raw)))
+    })
+  }
+}
+
+impl LogSegment {
+  /// Opens (creating if needed) the segment starting at `base_offset`. Batches past the
+  /// last index entry are walked to find the end of the segment and indexed as they would
+  /// have been on append, so a missing or corrupt index is rebuilt from scratch.
+  pub fn open(dir: &Path, base_offset: i64, config: &LogConfig) -> Result<LogSegment> {
+    LogSegment::open_with_suffix(dir, base_offset, config, "")
+  }
+
+  /// Opens the segment whose file names carry `suffix`, see `change_suffix`.
+  pub fn open_with_suffix(dir: &Path, base_offset: i64, config: &LogConfig, suffix: &str) -> Result<LogSegment> {
+    let path = dir.join(file_name(base_offset, LOG_FILE_SUFFIX) + suffix);
+    let file = OpenOptions::new()
+      .create(true)
+      .read(true)
+      .append(true)
+      .open(&path)
+      .with_context(|| format!("opening {}", path.display()))?;
+    let len = file.metadata()?.len();
+    let index_path = dir.join(file_name(base_offset, INDEX_FILE_SUFFIX) + suffix);
+    let mut offset_index = OffsetIndex::open(index_path, base_offset, config.segment_index_bytes)?;
+    let time_index_path = dir.join(file_name(base_offset, TIME_INDEX_FILE_SUFFIX) + suffix);
+    let mut time_index = TimeIndex::open(time_index_path, base_offset, config.segment_index_bytes)?;
+    if !offset_index.sanity_check(len) || !time_index.sanity_check() {
+      println!("Rebuilding corrupt indexes of {}", path.display());
+      offset_index.clear()?;
+      time_index.clear()?;
+    }
+
+    let mut segment = LogSegment {
+      base_offset,
+      dir: dir.to_path_buf(),
+      suffix: suffix.to_string(),
+      file,
+      offset_index,
+      time_index,
+      size: 0,
+      next_offset: base_offset,
+      index_interval_bytes: config.index_interval_bytes,
+      bytes_since_last_index_entry: 0,
+      max_timestamp_so_far: -1,
+      offset_of_max_timestamp: base_offset,
+      rolling_based_timestamp: None,
+      created_ms: now_ms(),
+    };
+    (segment.max_timestamp_so_far, segment.offset_of_max_timestamp) = segment.time_index.last_entry().unwrap_or((-1, base_offset));
+
+    let (_, mut position) = segment.offset_index.last_entry().unwrap_or((base_offset, 0));
+    segment.size = position;
+    while let Some(batch) = batch_at(&segment.file, position, len)? {
+      segment.track(&batch)?;
+      position += batch.size;
+    }
+    // A crash mid-append leaves part of a batch behind, drop it so new batches line up
+    if position < len {
+      println!("Truncating {} bytes of partial batch from {}", len - position, path.display());
+      segment.file.set_len(position)?;
+    }
+    segment.rolling_based_timestamp = batch_at(&segment.file, 0, segment.size)?.map(|batch| batch.max_timestamp);
+    Ok(segment)
+  }
+
+  /// Rebuilds the segment after an unclean shutdown: every batch is read back and checked
+  /// (length, magic, CRC, increasing offsets), the indexes are rebuilt, and the file is
+  /// truncated at the first batch that fails. Returns the number of bytes truncated.
+  pub fn recover(&mut self) -> Result<u64> {
+    self.offset_index.clear()?;
+    self.time_index.clear()?;
+    self.size = 0;
+    self.next_offset = self.base_offset;
+    self.bytes_since_last_index_entry = 0;
+    self.max_timestamp_so_far = -1;
+    self.offset_of_max_timestamp = self.base_offset;
+
+    let path = self.path();
+    let len = self.file.metadata()?.len();
+    let mut position = 0;
+    while let Some(batch) = batch_at(&self.file, position, len)? {
+      let mut raw = vec![0; batch.size as usize];
+      self.file.read_exact_at(&mut raw, position)?;
+      if let Err(reason) = self.check_batch(&raw, &batch) {
+        println!("Found {} at byte {} of {}", reason, position, path.display());
+        break;
+      }
+      self.track(&batch)?;
+      position += batch.size;
+    }
+
+    let truncated = len - position;
+    if truncated > 0 {
+      println!("Truncating {} bytes from {}", truncated, path.display());
+      self.file.set_len(position)?;
+    }
+    self.rolling_based_timestamp = batch_at(&self.file, 0, self.size)?.map(|batch| batch.max_timestamp);
+    Ok(truncated)
+  }
+
+  /// Why the batch `raw` can't follow what's already in the segment, if it can't.
+  fn check_batch(&self, raw: &[u8], batch: &BatchPosition) -> std::result::Result<(), String> {
+    if raw[record_batch::MAGIC_OFFSET] as i8 != record_batch::MAGIC_V2 {
+      return Err(format!("a batch with magic {}", raw[record_batch::MAGIC_OFFSET] as i8));
+    }
+    if !record_batch::verify_crc(raw) {
+      return Err(format!("a batch at offset {} failing its CRC", batch.base_offset));
+    }
+    if batch.base_offset < self.next_offset || batch.last_offset < batch.base_offset {
+      return Err(format!("a batch with offsets {}..={} after offset {}", batch.base_offset, batch.last_offset, self.next_offset));
+    }
+    Ok(())
+  }
+
+  /// The `.log` file.
+  fn path(&self) -> PathBuf {
+    self.dir.join(file_name(self.base_offset, LOG_FILE_SUFFIX) + &self.suffix)
+  }
+
+  /// Renames the segment's files to end in `suffix` instead, `""` for a regular segment.
+  pub fn change_suffix(&mut self, suffix: &str) -> Result<()> {
+    let from = self.path();
+    let to = self.dir.join(file_name(self.base_offset, LOG_FILE_SUFFIX) + suffix);
+    fs::rename(&from, &to).with_context(|| format!("renaming {} to {}", from.display(), to.display()))?;
+    self.offset_index.rename_to(self.dir.join(file_name(self.base_offset, INDEX_FILE_SUFFIX) + suffix))?;
+    self.time_index.rename_to(self.dir.join(file_name(self.base_offset, TIME_INDEX_FILE_SUFFIX) + suffix))?;
+    self.suffix = suffix.to_string();
+    Ok(())
+  }
+
+  /// Updates the segment's end, timestamps and indexes for a batch written at its end.
+  fn track(&mut self, batch: &BatchPosition) -> Result<()> {
+    if batch.max_timestamp > self.max_timestamp_so_far {
+      self.max_timestamp_so_far = batch.max_timestamp;
+      self.offset_of_max_timestamp = batch.last_offset;
+    }
+    if self.bytes_since_last_index_entry > self.index_interval_bytes {
+      self.offset_index.append(batch.last_offset, batch.position)?;
+      self.time_index.maybe_append(self.max_timestamp_so_far, self.offset_of_max_timestamp)?;
+      self.bytes_since_last_index_entry = 0;
+    }
+    self.bytes_since_last_index_entry += batch.size;
+    self.size = batch.position + batch.size;
+    self.next_offset = batch.last_offset + 1;
+    Ok(())
+  }
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// The offset after the last one in this segment.
+  pub fn next_offset(&self) -> i64 {
+    self.next_offset
+  }
+
+  pub fn largest_timestamp(&self) -> i64 {
+    self.max_timestamp_so_far
+  }
+
+  /// When the `.log` file was last written, for segments whose records carry no timestamps.
+  pub fn last_modified_ms(&self) -> Result<i64> {
+    let modified = self.file.metadata()?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as i64))
+  }
+
+  /// Whether `batch` should go to a new segment instead: this one would get too big or
+  /// too old, an index is full, or its offsets no longer fit the index's relative offsets.
+  pub fn should_roll(&self, batch: &[u8], last_offset: i64, now_ms: i64, config: &LogConfig) -> bool {
+    if self.size == 0 {
+      return false;
+    }
+    let waited = match self.rolling_based_timestamp {
+      Some(first) if first >= 0 => record_batch::max_timestamp(batch) - first,
+      _ => now_ms - self.created_ms,
+    };
+
+    self.size + batch.len() as u64 > config.segment_bytes
+      || waited > config.segment_ms
+      || self.offset_index.is_full()
+      || self.time_index.is_full()
+      || last_offset - self.base_offset > i32::MAX as i64
+  }
+
+  /// Appends a batch that already has its offsets assigned.
+  pub fn append(&mut self, batch: &[u8]) -> Result<()> {
+    let position = BatchPosition::parse(batch, self.size);
+    self.file
+      .write_all(batch)
+      .with_context(|| format!("appending to {}", self.path().display()))?;
+    if self.size == 0 {
+      self.rolling_based_timestamp = Some(position.max_timestamp);
+    }
+    self.track(&position)
+  }
+
+  /// Called when the log rolls past this segment. Indexes the largest timestamp so a search
+  /// for it lands here.
+  pub fn on_roll(&mut self) -> Result<()> {
+    self.time_index.maybe_append(self.max_timestamp_so_far, self.offset_of_max_timestamp)
+  }
+
+  /// The segment as it is now, readable without access to the segment itself.
+  pub fn snapshot(&self) -> Result<SegmentSnapshot> {
+    Ok(SegmentSnapshot {
+      base_offset: self.base_offset,
+      file: self.file.try_clone()?,
+      size: self.size,
+      next_offset: self.next_offset,
+      largest_timestamp: self.max_timestamp_so_far,
+    })
+  }
+
+  /// The first batch holding `offset` or a later one.
+  pub fn translate_offset(&self, offset: i64) -> Result<Option<BatchPosition>> {
+    let (_, mut position) = self.offset_index.lookup(offset);
+    while let Some(batch) = batch_at(&self.file, position, self.size)? {
+      if batch.last_offset >= offset {
+        return Ok(Some(batch));
+      }
+      position += batch.size;
+    }
+    Ok(None)
+  }
+
+  /// Whole batches starting with the one holding `offset`, up to `max_bytes`. With
+  /// `min_one_batch` the first batch comes back even when it alone is over the limit, so a
+  /// consumer can't get stuck behind a batch larger than its fetch size.
+  pub fn read(&self, offset: i64, max_bytes: usize, min_one_batch: bool) -> Result<Bytes> {
+    let Some(start) = self.translate_offset(offset)? else {
+      return Ok(Bytes::new());
+    };
+
+    let mut len = (max_bytes as u64).min(self.size - start.position);
+    if len < start.size {
+      if !min_one_batch {
+        return Ok(Bytes::new());
+      }
+      len = start.size;
+    }
+
+    let mut buf = vec![0; len as usize];
+    self.file
+      .read_exact_at(&mut buf, start.position)
+      .with_context(|| format!("reading segment {} at offset {}", self.base_offset, start.base_offset))?;
+    // Cut a batch only partly within max_bytes
+    let mut buf = Bytes::from(buf);
+    let mut batches = RecordBatches::new(buf.clone());
+    while batches.next_raw().is_some() {}
+    buf.truncate(batches.position());
+    Ok(buf)
+  }
+
+  /// The first record from `starting_offset` on with a timestamp at or after `timestamp`, as
+  /// (timestamp, offset).
+  pub fn find_offset_by_timestamp(&self, timestamp: i64, starting_offset: i64) -> Result<Option<(i64, i64)>> {
+    let (_, offset) = self.time_index.lookup(timestamp);
+    let (_, mut position) = self.offset_index.lookup(offset.max(starting_offset));
+
+    while let Some(batch) = batch_at(&self.file, position, self.size)? {
+      if batch.max_timestamp >= timestamp && batch.last_offset >= starting_offset {
+        let found = self.decode(&batch)?.log_records()
+          .find(|record| record.offset >= starting_offset && record.timestamp >= timestamp)
+          .map(|record| (record.timestamp, record.offset));
+        if found.is_some() {
+          return Ok(found);
+        }
+      }
+      position += batch.size;
+    }
+    Ok(None)
+  }
+
+  /// The first record from `starting_offset` on with the largest timestamp, as (timestamp,
+  /// offset).
+  pub fn find_max_timestamp(&self, starting_offset: i64) -> Result<Option<(i64, i64)>> {
+    // With the whole segment in range its largest timestamp is the one to look for
+    if starting_offset <= self.base_offset {
+      return match self.largest_timestamp() {
+        timestamp if timestamp >= 0 => self.find_offset_by_timestamp(timestamp, starting_offset),
+        _ => Ok(None),
+      };
+    }
+
+    let (_, mut position) = self.offset_index.lookup(starting_offset);
+    let mut max: Option<(i64, i64)> = None;
+    while let Some(batch) = batch_at(&self.file, position, self.size)? {
+      if batch.last_offset >= starting_offset && max.map_or(batch.max_timestamp >= 0, |(timestamp, _)| batch.max_timestamp > timestamp) {
+        for record in self.decode(&batch)?.log_records().filter(|record| record.offset >= starting_offset) {
+          if max.map_or(record.timestamp >= 0, |(timestamp, _)| record.timestamp > timestamp) {
+            max = Some((record.timestamp, record.offset));
+          }
+        }
+      }
+      position += batch.size;
+    }
+    Ok(max)
+  }
+
+  fn decode(&self, batch: &BatchPosition) -> Result<RecordBatch> {
+    let mut raw = vec![0; batch.size as usize];
+    self.file.read_exact_at(&mut raw, batch.position)?;
+    RecordBatch::decode(&mut Bytes::from(raw)).with_context(|| format!("decoding batch at offset {}", batch.base_offset))
+  }
+
+  /// Removes the batch holding `offset` and everything after it, along with whatever a
+  /// failed append left past the last batch. Returns the bytes of batches removed.
+  pub fn truncate_to(&mut self, offset: i64) -> Result<u64> {
+    let Some(batch) = self.translate_offset(offset)? else {
+      self.file.set_len(self.size)?;
+      return Ok(0);
+    };
+
+    let removed = self.size - batch.position;
+    self.file.set_len(batch.position)?;
+    self.offset_index.truncate_to(batch.base_offset)?;
+    self.time_index.truncate_to(batch.base_offset)?;
+    self.size = batch.position;
+    self.next_offset = batch.base_offset;
+    self.bytes_since_last_index_entry = 0;
+    (self.max_timestamp_so_far, self.offset_of_max_timestamp) = self.time_index.last_entry().unwrap_or((-1, self.base_offset));
+    if self.size == 0 {
+      self.rolling_based_timestamp = None;
+    }
+    Ok(removed)
+  }
+
+  /// Writes the segment and its indexes through to disk.
+  pub fn flush(&self) -> Result<()> {
+    self.file.sync_all()?;
+    self.offset_index.flush()?;
+    self.time_index.flush()
+  }
+
+  /// Deletes the segment's files.
+  pub fn delete(self) -> Result<()> {
+    let path = self.path();
+    fs::remove_file(&path).with_context(|| format!("deleting {}", path.display()))?;
+    self.offset_index.delete()?;
+    self.time_index.delete()
+  }
+}
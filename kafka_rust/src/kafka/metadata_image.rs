@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use crate::kafka::codec::Uuid;
+use crate::kafka::metadata_log_file::{self, MetadataLogFile};
+use crate::kafka::metadata_records::{
+  BrokerEndpoint, MetadataRecord, PartitionChangeRecord, PartitionRecord, NO_LEADER_CHANGE,
+};
+
+/// Directory of the KRaft metadata log inside `log.dirs`.
+pub const METADATA_LOG_DIR: &str = "__cluster_metadata-0";
+
+/// `ConfigRecord.ResourceType` of topic configs.
+pub const TOPIC_RESOURCE: i8 = 2;
+
+/// Topics the brokers themselves maintain.
+pub const INTERNAL_TOPICS: [&str; 3] = ["__consumer_offsets", "__transaction_state", "__share_group_state"];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PartitionImage {
+  pub partition_id: i32,
+  pub replicas: Vec<i32>,
+  pub isr: Vec<i32>,
+  pub removing_replicas: Vec<i32>,
+  pub adding_replicas: Vec<i32>,
+  pub leader: i32,
+  pub leader_recovery_state: i8,
+  pub leader_epoch: i32,
+  pub partition_epoch: i32,
+  pub directories: Vec<Uuid>,
+  pub eligible_leader_replicas: Vec<i32>,
+  pub last_known_elr: Vec<i32>,
+}
+
+impl PartitionImage {
+  fn from_record(record: PartitionRecord) -> PartitionImage {
+    PartitionImage {
+      partition_id: record.partition_id,
+      replicas: record.replicas,
+      isr: record.isr,
+      removing_replicas: record.removing_replicas,
+      adding_replicas: record.adding_replicas,
+      leader: record.leader,
+      leader_recovery_state: record.leader_recovery_state,
+      leader_epoch: record.leader_epoch,
+      partition_epoch: record.partition_epoch,
+      directories: record.directories,
+      eligible_leader_replicas: record.eligible_leader_replicas.unwrap_or_default(),
+      last_known_elr: record.last_known_elr.unwrap_or_default(),
+    }
+  }
+
+  /// Like the controller, a leader change bumps the leader epoch and every change bumps the
+  /// partition epoch.
+  fn apply(&mut self, change: PartitionChangeRecord) {
+    if let Some(isr) = change.isr {
+      self.isr = isr;
+    }
+    if change.leader != NO_LEADER_CHANGE {
+      self.leader = change.leader;
+      self.leader_epoch += 1;
+    }
+    if let Some(replicas) = change.replicas {
+      self.replicas = replicas;
+    }
+    if let Some(removing_replicas) = change.removing_replicas {
+      self.removing_replicas = removing_replicas;
+    }
+    if let Some(adding_replicas) = change.adding_replicas {
+      self.adding_replicas = adding_replicas;
+    }
+    if change.leader_recovery_state != -1 {
+      self.leader_recovery_state = change.leader_recovery_state;
+    }
+    if let Some(elr) = change.eligible_leader_replicas {
+      self.eligible_leader_replicas = elr;
+    }
+    if let Some(last_known_elr) = change.last_known_elr {
+      self.last_known_elr = last_known_elr;
+    }
+    if let Some(directories) = change.directories {
+      self.directories = directories;
+    }
+    self.partition_epoch += 1;
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopicImage {
+  pub name: String,
+  pub id: Uuid,
+  pub partitions: BTreeMap<i32, PartitionImage>,
+}
+
+impl TopicImage {
+  pub fn is_internal(&self) -> bool {
+    INTERNAL_TOPICS.contains(&self.name.as_str())
+  }
+}
+
+/// What a SCRAM server needs to check a user's password without knowing it (RFC 5802).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScramCredential {
+  pub salt: Bytes,
+  pub stored_key: Bytes,
+  pub server_key: Bytes,
+  pub iterations: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerImage {
+  pub id: i32,
+  pub epoch: i64,
+  pub incarnation_id: Uuid,
+  pub endpoints: Vec<BrokerEndpoint>,
+  pub rack: Option<String>,
+  pub fenced: bool,
+  pub in_controlled_shutdown: bool,
+}
+
+/// The cluster state the metadata log describes, built by replaying its records in order.
+///
+/// Built once at startup and only read afterwards, so handlers share it through the `Broker`.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataImage {
+  /// `cluster.id` from the log dir's meta.properties, written when the storage was formatted.
+  pub cluster_id: Option<String>,
+  /// Offset of the last record applied, -1 for an empty log.
+  pub offset: i64,
+  topics: HashMap<Uuid, TopicImage>,
+  topic_ids: BTreeMap<String, Uuid>,
+  /// Finalized feature levels, e.g. `metadata.version`.
+  pub features: BTreeMap<String, i16>,
+  /// Configs by (resource type, resource name).
+  pub configs: BTreeMap<(i8, String), BTreeMap<String, String>>,
+  pub brokers: BTreeMap<i32, BrokerImage>,
+  /// Start of the next producer id block the controller will hand out.
+  pub next_producer_id: i64,
+  /// SCRAM credentials by user and mechanism (`UserScramCredentialRecord.Mechanism`).
+  pub scram_credentials: BTreeMap<(String, i8), ScramCredential>,
+}
+
+impl MetadataImage {
+  pub fn empty() -> MetadataImage {
+    MetadataImage { offset: -1, ..Default::default() }
+  }
+
+  /// Replays every segment of the metadata log under `log_dir`. A missing log is an empty
+  /// cluster, not an error.
+  pub fn load(log_dir: &Path) -> Result<MetadataImage> {
+    let cluster_id = read_cluster_id(log_dir)?;
+    let dir = log_dir.join(METADATA_LOG_DIR);
+    let segments = match metadata_log_file::segments(&dir) {
+      Ok(segments) => segments,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        println!("No metadata log in {}, starting with an empty image", dir.display());
+        return Ok(MetadataImage { cluster_id, ..MetadataImage::empty() });
+      }
+      Err(e) => return Err(e).with_context(|| format!("listing {}", dir.display())),
+    };
+
+    let mut image = MetadataImage { cluster_id, ..MetadataImage::empty() };
+    for segment in segments {
+      let contents = fs::read(&segment).with_context(|| format!("reading {}", segment.display()))?;
+      let log_file = MetadataLogFile::from_bytes(Bytes::from(contents))?;
+      for record in log_file.records() {
+        let Some(value) = record.value else { continue };
+        let metadata_record = MetadataRecord::from_value(value)
+          .with_context(|| format!("decoding metadata record at offset {}", record.offset))?;
+        image.replay(record.offset, metadata_record);
+      }
+    }
+
+    println!(
+      "Loaded metadata image at offset {}: {} topics, {} brokers, features {:?}",
+      image.offset,
+      image.topics.len(),
+      image.brokers.len(),
+      image.features
+    );
+    Ok(image)
+  }
+
+  /// Applies one record. Deltas for things we don't know about (a change to a partition of
+  /// a removed topic, say) are ignored.
+  pub fn replay(&mut self, offset: i64, record: MetadataRecord) {
+    self.offset = offset;
+
+    match record {
+      MetadataRecord::FeatureLevel(feature) => {
+        // Level 0 means the feature was disabled
+        if feature.feature_level == 0 {
+          self.features.remove(&feature.name);
+        } else {
+          self.features.insert(feature.name, feature.feature_level);
+        }
+      }
+      MetadataRecord::Topic(topic) => {
+        self.topic_ids.insert(topic.name.clone(), topic.topic_uuid);
+        self.topics.insert(topic.topic_uuid, TopicImage {
+          name: topic.name,
+          id: topic.topic_uuid,
+          partitions: BTreeMap::new(),
+        });
+      }
+      MetadataRecord::Partition(partition) => {
+        if let Some(topic) = self.topics.get_mut(&partition.topic_id) {
+          topic.partitions.insert(partition.partition_id, PartitionImage::from_record(partition));
+        }
+      }
+      MetadataRecord::PartitionChange(change) => {
+        let partition = self.topics.get_mut(&change.topic_id)
+          .and_then(|topic| topic.partitions.get_mut(&change.partition_id));
+        if let Some(partition) = partition {
+          partition.apply(change);
+        }
+      }
+      MetadataRecord::RemoveTopic(remove) => {
+        if let Some(topic) = self.topics.remove(&remove.topic_id) {
+          self.topic_ids.remove(&topic.name);
+          self.configs.remove(&(TOPIC_RESOURCE, topic.name));
+        }
+      }
+      MetadataRecord::Config(config) => {
+        let resource = self.configs.entry((config.resource_type, config.resource_name.clone())).or_default();
+        match config.value {
+          Some(value) => {
+            resource.insert(config.name, value);
+          }
+          None => {
+            resource.remove(&config.name);
+            if resource.is_empty() {
+              self.configs.remove(&(config.resource_type, config.resource_name));
+            }
+          }
+        }
+      }
+      MetadataRecord::RegisterBroker(broker) => {
+        self.brokers.insert(broker.broker_id, BrokerImage {
+          id: broker.broker_id,
+          epoch: broker.broker_epoch,
+          incarnation_id: broker.incarnation_id,
+          endpoints: broker.end_points,
+          rack: broker.rack,
+          fenced: broker.fenced,
+          in_controlled_shutdown: broker.in_controlled_shutdown,
+        });
+      }
+      MetadataRecord::UnregisterBroker(broker) => {
+        self.brokers.remove(&broker.broker_id);
+      }
+      MetadataRecord::FenceBroker(fence) => {
+        if let Some(broker) = self.brokers.get_mut(&fence.id) {
+          broker.fenced = true;
+        }
+      }
+      MetadataRecord::UnfenceBroker(unfence) => {
+        if let Some(broker) = self.brokers.get_mut(&unfence.id) {
+          broker.fenced = false;
+        }
+      }
+      MetadataRecord::ProducerIds(producer_ids) => {
+        self.next_producer_id = producer_ids.next_producer_id;
+      }
+      MetadataRecord::UserScramCredential(credential) => {
+        self.scram_credentials.insert((credential.name, credential.mechanism), ScramCredential {
+          salt: credential.salt,
+          stored_key: credential.stored_key,
+          server_key: credential.server_key,
+          iterations: credential.iterations,
+        });
+      }
+      MetadataRecord::RemoveUserScramCredential(credential) => {
+        self.scram_credentials.remove(&(credential.name, credential.mechanism));
+      }
+      // ACLs and quotas aren't enforced
+      MetadataRecord::AccessControlEntry(_) | MetadataRecord::ClientQuota(_) | MetadataRecord::Unknown { .. } => {}
+    }
+  }
+
+  pub fn topic(&self, name: &str) -> Option<&TopicImage> {
+    self.topic_ids.get(name).and_then(|id| self.topics.get(id))
+  }
+
+  pub fn topic_by_id(&self, id: Uuid) -> Option<&TopicImage> {
+    self.topics.get(&id)
+  }
+
+  /// Every topic, ordered by name.
+  pub fn topics(&self) -> impl Iterator<Item = &TopicImage> + '_ {
+    self.topic_ids.values().filter_map(|id| self.topics.get(id))
+  }
+
+  /// A replica is offline when its broker is fenced or not registered. A log without any
+  /// registrations (a single broker started from a formatted log dir) has every replica online.
+  pub fn is_broker_offline(&self, id: i32) -> bool {
+    !self.brokers.is_empty() && self.brokers.get(&id).map_or(true, |broker| broker.fenced)
+  }
+
+  pub fn topic_config(&self, topic: &str) -> Option<&BTreeMap<String, String>> {
+    self.configs.get(&(TOPIC_RESOURCE, topic.to_string()))
+  }
+}
+
+fn read_cluster_id(log_dir: &Path) -> Result<Option<String>> {
+  let path = log_dir.join("meta.properties");
+  let contents = match fs::read_to_string(&path) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+  };
+
+  Ok(contents.lines()
+    .filter_map(|line| line.split_once('='))
+    .find(|(key, _)| key.trim() == "cluster.id")
+    .map(|(_, value)| value.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::codec::TaggedFields;
+  use crate::kafka::metadata_records::{ConfigRecord, FeatureLevelRecord, RemoveTopicRecord, TopicRecord};
+  use crate::kafka::record_batch::{self, Record, RecordBatch};
+  use crate::kafka::test_support::temp_dir;
+
+  fn topic(name: &str, id: u128) -> MetadataRecord {
+    MetadataRecord::Topic(TopicRecord { name: name.to_string(), topic_uuid: Uuid(id), tagged_fields: TaggedFields::default() })
+  }
+
+  fn partition(topic_id: u128, partition_id: i32, leader: i32) -> MetadataRecord {
+    MetadataRecord::Partition(PartitionRecord {
+      partition_id,
+      topic_id: Uuid(topic_id),
+      replicas: vec![1, 2],
+      isr: vec![1, 2],
+      removing_replicas: vec![],
+      adding_replicas: vec![],
+      leader,
+      leader_recovery_state: 0,
+      leader_epoch: 0,
+      partition_epoch: 0,
+      directories: vec![],
+      eligible_leader_replicas: None,
+      last_known_elr: None,
+      tagged_fields: TaggedFields::default(),
+    })
+  }
+
+  #[test]
+  fn replays_records_in_order() {
+    let mut image = MetadataImage::empty();
+    let records = vec![
+      MetadataRecord::FeatureLevel(FeatureLevelRecord { name: "metadata.version".to_string(), feature_level: 20, tagged_fields: TaggedFields::default() }),
+      topic("foo", 1),
+      partition(1, 0, 1),
+      topic("bar", 2),
+      partition(2, 0, 1),
+      MetadataRecord::Config(ConfigRecord { resource_type: TOPIC_RESOURCE, resource_name: "bar".to_string(), name: "cleanup.policy".to_string(), value: Some("compact".to_string()), tagged_fields: TaggedFields::default() }),
+      MetadataRecord::PartitionChange(PartitionChangeRecord {
+        partition_id: 0,
+        topic_id: Uuid(1),
+        isr: Some(vec![2]),
+        leader: 2,
+        replicas: None,
+        removing_replicas: None,
+        adding_replicas: None,
+        leader_recovery_state: -1,
+        eligible_leader_replicas: None,
+        last_known_elr: None,
+        directories: None,
+        tagged_fields: TaggedFields::default(),
+      }),
+      MetadataRecord::RemoveTopic(RemoveTopicRecord { topic_id: Uuid(2), tagged_fields: TaggedFields::default() }),
+    ];
+    for (offset, record) in records.into_iter().enumerate() {
+      image.replay(offset as i64, record);
+    }
+
+    assert_eq!(image.offset, 7);
+    assert_eq!(image.features.get("metadata.version"), Some(&20));
+
+    let foo = image.topic("foo").unwrap();
+    assert_eq!(foo.id, Uuid(1));
+    let partition = &foo.partitions[&0];
+    assert_eq!((partition.leader, partition.isr.clone(), partition.replicas.clone()), (2, vec![2], vec![1, 2]));
+    assert_eq!((partition.leader_epoch, partition.partition_epoch), (1, 1));
+
+    assert!(image.topic("bar").is_none());
+    assert!(image.topic_by_id(Uuid(2)).is_none());
+    assert!(image.topic_config("bar").is_none());
+    assert_eq!(image.topics().map(|topic| topic.name.as_str()).collect::<Vec<_>>(), vec!["foo"]);
+  }
+
+  #[test]
+  fn missing_log_is_an_empty_image() {
+    let image = MetadataImage::load(Path::new("/nonexistent/kraft-logs")).unwrap();
+    assert_eq!(image.offset, -1);
+    assert_eq!(image.topics().count(), 0);
+  }
+
+  #[test]
+  fn load_rejects_batches_failing_their_crc() {
+    let dir = temp_dir();
+    let log_dir = dir.path();
+    fs::create_dir_all(log_dir.join(METADATA_LOG_DIR)).unwrap();
+    let segment = log_dir.join(METADATA_LOG_DIR).join("00000000000000000000.log");
+    let mut batch = RecordBatch { records: vec![Record::default()], ..Default::default() }.encode();
+    fs::write(&segment, &batch).unwrap();
+    assert!(MetadataImage::load(log_dir).is_ok());
+
+    let last = batch.len() - 1;
+    batch[last] ^= 0xff;
+    fs::write(&segment, &batch).unwrap();
+    let corrupt = record_batch::corrupt_batches();
+    let error = MetadataImage::load(log_dir).unwrap_err();
+    assert!(error.to_string().contains("CRC"), "{:#}", error);
+    assert!(record_batch::corrupt_batches() > corrupt);
+  }
+}
@@ -1,153 +1,231 @@
-use anyhow::Result;
-use bytes::{Buf, BytesMut};
-
-#[derive(Debug, Clone)]
-pub enum MetadataComponents {
-  MetadataLogFile(MetadataLogFile),
-  Record(Record),
-  TopicRecord(TopicRecord)
-}
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Default)]
-pub struct TopicRecord {
-  pub frame_version: u8,
-  pub type_: u8,
-  pub version: u8,
-  pub name_length: u8,
-  pub name: Vec<u8>,
-  pub topic_uuid: u128,
-  pub tagged_fields_count: u8,
-}
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes};
 
-#[derive(Debug, Clone, Default)]
-pub struct Record {
-  pub length: u8,
-  pub attributes: u8,
-  pub timestamp_delta: u8,
-  pub offset_delta: u8,
-  pub key_length: u8,
-  pub value_length: u8,
-  pub topic_records: Vec<TopicRecord>,
-}
+use crate::kafka::log_segment::now_ms;
+use crate::kafka::metadata_image::METADATA_LOG_DIR;
+use crate::kafka::metadata_records::{MetadataRecord, TopicRecord};
+use crate::kafka::record_batch::{self, LogRecord, Record, RecordBatch, RecordBatches};
 
+/// The `__cluster_metadata` log: RecordBatches whose record values are metadata records.
 #[derive(Debug, Clone, Default)]
 pub struct MetadataLogFile {
-  pub base_offset: u64,
-  pub batch_length: u32,
-  pub partition_leader_epoch: u32,
-  pub magic_byte: u8,
-  pub crc: u32,
-  pub attributes: u16,
-  pub last_offset_delta: u32,
-  pub base_timestamp: u64,
-  pub max_timestamp: u64,
-  pub producer_id: u64,
-  pub producer_epoch: u16,
-  pub base_sequence: u32,
-  pub records_length: u32,
-  pub records: Vec<Record>,
-  pub headers_array_count: u8
+  pub batches: Vec<RecordBatch>,
 }
 
 impl MetadataLogFile {
-  pub fn from_bytes(mut input: BytesMut) -> Result<MetadataLogFile> {
-    let base_offset = input.get_u64();
-    println!("base_offset: {}", base_offset);
-    let batch_length = input.get_u32();
-    println!("batch_length: {}", batch_length);
-    if base_offset == (1 as u64) {
-      input.get_uint(batch_length as usize);
-      // input.
+  pub fn from_bytes(input: Bytes) -> Result<MetadataLogFile> {
+    let mut batches = RecordBatches::new(input.clone());
+    let mut parsed = vec![];
+    while let Some(raw) = batches.next_raw() {
+      let position = batches.position() - raw.len();
+      if !record_batch::verify_crc(&raw) {
+        anyhow::bail!("metadata log batch at byte {} fails its CRC check", position);
+      }
+      let batch = RecordBatch::decode(&mut raw.clone())
+        .with_context(|| format!("decoding the metadata log batch at byte {}", position))?;
+      parsed.push(batch);
     }
-    println!("batch_length: {}", batch_length);
-    let partition_leader_epoch = input.get_u32();
-    let magic_byte = input.get_u8();
-    let crc = input.get_u32();
-    let attributes = input.get_u16();
-    let last_offset_delta = input.get_u32();
-    let base_timestamp = input.get_u64();
-    let max_timestamp = input.get_u64();
-    let producer_id = input.get_u64();
-    let producer_epoch = input.get_u16();
-    let base_sequence = input.get_u32();
-
-    let records_length = input.get_u32();
-    let mut records: Vec<Record> = vec![];
-    for _ in 0..records_length {
-      let length = input.get_u8();
-      println!("!!!!!!!!!!length: {}", length);
-      let attributes = input.get_u8();
-      let timestamp_delta = input.get_u8();
-      let offset_delta = input.get_u8();
-      let key_length = input.get_u8();
-      if key_length >= (2 as u8) {
-        todo!("shouldn't reach here for now!");
+    if batches.position() < input.remaining() {
+      println!("Ignoring {} trailing bytes of partial batch in metadata log", input.remaining() - batches.position());
+    }
+
+    Ok(MetadataLogFile { batches: parsed })
+  }
+
+  pub fn records(&self) -> impl Iterator<Item = LogRecord> + '_ {
+    self.batches.iter().flat_map(|batch| batch.log_records())
+  }
+}
+
+/// The `.log` segments of the metadata log in `dir`, in log order.
+pub fn segments(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+  let mut segments = fs::read_dir(dir)?
+    .map(|entry| entry.map(|entry| entry.path()))
+    .collect::<std::io::Result<Vec<_>>>()?
+    .into_iter()
+    .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+    .collect::<Vec<_>>();
+  // Segment names are zero padded base offsets so they sort in log order
+  segments.sort();
+  Ok(segments)
+}
+
+/// Appends to the metadata log under a log dir. There is no controller quorum to write
+/// through, so the metadata changes made with the broker's own apis (SCRAM credentials) are
+/// written to the log directly, where the next start replays them.
+#[derive(Debug)]
+pub struct MetadataLogWriter {
+  dir: PathBuf,
+  /// Offset and leader epoch of the next batch, read from the log on the first append.
+  next: Option<(i64, i32)>,
+}
+
+impl MetadataLogWriter {
+  pub fn new(log_dir: &Path) -> MetadataLogWriter {
+    MetadataLogWriter { dir: log_dir.join(METADATA_LOG_DIR), next: None }
+  }
+
+  /// Appends `records` as one batch to the newest segment, creating the first segment of a
+  /// missing log, and returns the offset of the first record.
+  pub fn append(&mut self, records: &[MetadataRecord]) -> Result<i64> {
+    let segments = match segments(&self.dir) {
+      Ok(segments) => segments,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        fs::create_dir_all(&self.dir).with_context(|| format!("creating {}", self.dir.display()))?;
+        vec![]
       }
+      Err(e) => return Err(e).with_context(|| format!("listing {}", self.dir.display())),
+    };
+    let segment = match segments.last() {
+      Some(newest) => newest.clone(),
+      None => self.dir.join(format!("{:020}.log", 0)),
+    };
+    let (base_offset, leader_epoch) = match self.next {
+      Some(next) => next,
+      None => next_batch(&segments)?,
+    };
+
+    let now = now_ms();
+    let batch = RecordBatch {
+      base_offset,
+      partition_leader_epoch: leader_epoch,
+      last_offset_delta: records.len() as i32 - 1,
+      base_timestamp: now,
+      max_timestamp: now,
+      producer_id: -1,
+      producer_epoch: -1,
+      base_sequence: -1,
+      records: records.iter()
+        .enumerate()
+        .map(|(offset_delta, record)| {
+          Ok(Record { offset_delta: offset_delta as i32, value: Some(record.to_value()?), ..Default::default() })
+        })
+        .collect::<Result<_>>()?,
+      ..Default::default()
+    };
+    let mut file = OpenOptions::new().create(true).append(true).open(&segment)
+      .with_context(|| format!("opening {}", segment.display()))?;
+    file.write_all(&batch.encode()).with_context(|| format!("appending to {}", segment.display()))?;
+    file.sync_data().with_context(|| format!("syncing {}", segment.display()))?;
+
+    self.next = Some((batch.last_offset() + 1, leader_epoch));
+    Ok(base_offset)
+  }
+}
+
+/// The offset and leader epoch following the last batch of the log made of `segments`. A
+/// newest segment without batches, as after a roll, starts at the base offset in its name
+/// and keeps the leader epoch of the batches before it.
+fn next_batch(segments: &[PathBuf]) -> Result<(i64, i32)> {
+  let Some((newest, older)) = segments.split_last() else {
+    return Ok((0, 0));
+  };
+  if let Some(last) = last_batch(newest)? {
+    return Ok((last.last_offset() + 1, last.partition_leader_epoch));
+  }
+
+  let base_offset = newest.file_stem()
+    .and_then(|stem| stem.to_str())
+    .and_then(|stem| stem.parse::<i64>().ok())
+    .with_context(|| format!("{} isn't named after its base offset", newest.display()))?;
+  let mut leader_epoch = 0;
+  for segment in older.iter().rev() {
+    if let Some(last) = last_batch(segment)? {
+      leader_epoch = last.partition_leader_epoch;
+      break;
+    }
+  }
+  Ok((base_offset, leader_epoch))
+}
+
+/// The last batch of `segment`, `None` when it's missing or empty.
+fn last_batch(segment: &Path) -> Result<Option<RecordBatch>> {
+  let contents = match fs::read(segment) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e).with_context(|| format!("reading {}", segment.display())),
+  };
+  Ok(MetadataLogFile::from_bytes(Bytes::from(contents))?.batches.pop())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::codec::TaggedFields;
+  use crate::kafka::metadata_records::RemoveUserScramCredentialRecord;
+  use crate::kafka::test_support::temp_dir;
+
+  fn removal(name: &str, mechanism: i8) -> MetadataRecord {
+    MetadataRecord::RemoveUserScramCredential(RemoveUserScramCredentialRecord {
+      name: name.to_string(),
+      mechanism,
+      tagged_fields: TaggedFields::default(),
+    })
+  }
+
+  fn read(path: &Path) -> MetadataLogFile {
+    MetadataLogFile::from_bytes(Bytes::from(fs::read(path).unwrap())).unwrap()
+  }
 
-      let value_length = input.get_u8();
-      println!("!!!!!!!!!!value_length: {} (should be like lenght - 6)", value_length);
-      let mut topic_records: Vec<TopicRecord> = vec![];
-      // println!("from_bytes, lenght of feature level records: {}", value_length);
-      // for _ in 0..value_length {
-        let frame_version = input.get_u8();
-        let type_ = input.get_u8();
-
-        println!("type: {}", type_);
-        
-        let version = input.get_u8();
-        let name_length = input.get_u8();
-        let name = (0..(name_length - 1) as usize)
-          .map(|_| input.get_u8())
-          .collect::<Vec<u8>>();
-        let topic_uuid: u128 = input.get_u128();
-        println!("name: {:?}", String::from_utf8(name.clone()).expect("Invalid UTF-8"));
-        let tagged_fields_count = input.get_u8();
-        // if type_ == (12 as u8) {
-          let jee = TopicRecord { 
-            frame_version: frame_version,
-            type_: type_,
-            version: version,
-            name_length: name_length,
-            name: name,
-            topic_uuid: topic_uuid,
-            tagged_fields_count: tagged_fields_count 
-          };
-
-          // println!("topic record: {:?}", jee);
-
-          topic_records.push(jee);
-        // }
-
-      // }
-
-      records.push(Record { length ,
-        attributes: attributes ,
-        timestamp_delta: timestamp_delta ,
-        offset_delta: offset_delta ,
-        key_length: key_length ,
-        value_length: value_length ,
-        topic_records: topic_records  
-      });
+  /// (offset, leader epoch) of every batch in `path`.
+  fn batches(path: &Path) -> Vec<(i64, i32)> {
+    read(path).batches.iter().map(|batch| (batch.base_offset, batch.partition_leader_epoch)).collect()
+  }
+
+  /// A segment of `batch_count` one record batches from `base_offset` on, in `leader_epoch`.
+  fn write_segment(path: &Path, base_offset: i64, batch_count: i64, leader_epoch: i32) {
+    let mut contents = vec![];
+    for offset in base_offset..base_offset + batch_count {
+      let record = Record { value: Some(removal("foo", 1).to_value().unwrap()), ..Default::default() };
+      let batch = RecordBatch { base_offset: offset, partition_leader_epoch: leader_epoch, records: vec![record], ..Default::default() };
+      contents.extend_from_slice(&batch.encode());
     }
-    
-    let headers_array_count = input.get_u8();
-
-    Ok(MetadataLogFile { 
-      base_offset: base_offset,
-      batch_length: batch_length,
-      partition_leader_epoch: partition_leader_epoch,
-      magic_byte: magic_byte,
-      crc: crc,
-      attributes: attributes,
-      last_offset_delta: last_offset_delta,
-      base_timestamp: base_timestamp,
-      max_timestamp: max_timestamp,
-      producer_id: producer_id,
-      producer_epoch: producer_epoch,
-      base_sequence: base_sequence,
-      records_length: records_length,
-      records: records,
-      headers_array_count: headers_array_count, 
-    })    
+    fs::write(path, contents).unwrap();
+  }
+
+  #[test]
+  fn creates_a_missing_log() {
+    let log_dirs = temp_dir();
+    let mut writer = MetadataLogWriter::new(log_dirs.path());
+    assert_eq!(writer.append(&[removal("foo", 1), removal("bar", 2)]).unwrap(), 0);
+    assert_eq!(writer.append(&[removal("baz", 1)]).unwrap(), 2);
+
+    let segment = log_dirs.path().join(METADATA_LOG_DIR).join(format!("{:020}.log", 0));
+    assert_eq!(batches(&segment), vec![(0, 0), (2, 0)]);
+    let records: Vec<_> = read(&segment).records()
+      .map(|record| (record.offset, MetadataRecord::from_value(record.value.unwrap()).unwrap()))
+      .collect();
+    assert_eq!(records, vec![(0, removal("foo", 1)), (1, removal("bar", 2)), (2, removal("baz", 1))]);
+  }
+
+  #[test]
+  fn carries_on_after_the_last_batch() {
+    let log_dirs = temp_dir();
+    let dir = log_dirs.path().join(METADATA_LOG_DIR);
+    fs::create_dir_all(&dir).unwrap();
+    let segment = dir.join(format!("{:020}.log", 0));
+    write_segment(&segment, 0, 3, 5);
+
+    assert_eq!(MetadataLogWriter::new(log_dirs.path()).append(&[removal("bar", 2)]).unwrap(), 3);
+    assert_eq!(batches(&segment), vec![(0, 5), (1, 5), (2, 5), (3, 5)]);
+  }
+
+  #[test]
+  fn starts_a_rolled_segment_at_its_base_offset() {
+    let log_dirs = temp_dir();
+    let dir = log_dirs.path().join(METADATA_LOG_DIR);
+    fs::create_dir_all(&dir).unwrap();
+    write_segment(&dir.join(format!("{:020}.log", 0)), 0, 1234, 7);
+    let rolled = dir.join(format!("{:020}.log", 1234));
+    fs::write(&rolled, b"").unwrap();
+
+    let mut writer = MetadataLogWriter::new(log_dirs.path());
+    assert_eq!(writer.append(&[removal("bar", 2)]).unwrap(), 1234);
+    assert_eq!(writer.append(&[removal("baz", 1)]).unwrap(), 1235);
+    assert_eq!(batches(&rolled), vec![(1234, 7), (1235, 7)]);
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,705 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::kafka::codec::{
+  decode_array, decode_nullable_array, Boolean, CompactArray, CompactBytes, CompactNullableString, CompactString,
+  Decode, DecodeVersioned, Encode, EncodeVersioned, Float64, Int16, Int32, Int64, Int8, TaggedFields, UInt16,
+  UnsignedVarInt, Uuid,
+};
+
+// KRaft metadata records, the values of the records in the `__cluster_metadata` log.
+// Schemas are the *Record.json files under metadata/src/main/resources/common/metadata in
+// the Kafka repo. Every record version is flexible.
+
+/// Frame version written by every KRaft controller.
+pub const FRAME_VERSION: u32 = 1;
+
+pub const REGISTER_BROKER_RECORD: i16 = 0;
+pub const UNREGISTER_BROKER_RECORD: i16 = 1;
+pub const TOPIC_RECORD: i16 = 2;
+pub const PARTITION_RECORD: i16 = 3;
+pub const CONFIG_RECORD: i16 = 4;
+pub const PARTITION_CHANGE_RECORD: i16 = 5;
+pub const ACCESS_CONTROL_ENTRY_RECORD: i16 = 6;
+pub const FENCE_BROKER_RECORD: i16 = 7;
+pub const UNFENCE_BROKER_RECORD: i16 = 8;
+pub const REMOVE_TOPIC_RECORD: i16 = 9;
+pub const USER_SCRAM_CREDENTIAL_RECORD: i16 = 11;
+pub const FEATURE_LEVEL_RECORD: i16 = 12;
+pub const CLIENT_QUOTA_RECORD: i16 = 14;
+pub const PRODUCER_IDS_RECORD: i16 = 15;
+pub const REMOVE_USER_SCRAM_CREDENTIAL_RECORD: i16 = 22;
+
+/// `PartitionChangeRecord.Leader` when the leader didn't change.
+pub const NO_LEADER_CHANGE: i32 = -2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataRecord {
+  RegisterBroker(RegisterBrokerRecord),
+  UnregisterBroker(UnregisterBrokerRecord),
+  Topic(TopicRecord),
+  Partition(PartitionRecord),
+  Config(ConfigRecord),
+  PartitionChange(PartitionChangeRecord),
+  AccessControlEntry(AccessControlEntryRecord),
+  FenceBroker(FenceBrokerRecord),
+  UnfenceBroker(FenceBrokerRecord),
+  RemoveTopic(RemoveTopicRecord),
+  FeatureLevel(FeatureLevelRecord),
+  ClientQuota(ClientQuotaRecord),
+  ProducerIds(ProducerIdsRecord),
+  UserScramCredential(UserScramCredentialRecord),
+  RemoveUserScramCredential(RemoveUserScramCredentialRecord),
+  /// A record type we don't model (delegation tokens, no-op records...).
+  Unknown { type_: i16, version: i16, data: Bytes },
+}
+
+impl MetadataRecord {
+  /// Decodes a metadata log record value: the frame version, record type and record
+  /// version as UNSIGNED_VARINTs followed by the record itself.
+  pub fn from_value(mut value: Bytes) -> Result<MetadataRecord> {
+    let frame_version = UnsignedVarInt::decode(&mut value)?;
+    if frame_version != FRAME_VERSION {
+      bail!("unknown metadata record frame version {frame_version}");
+    }
+    let type_ = UnsignedVarInt::decode(&mut value)? as i16;
+    let version = UnsignedVarInt::decode(&mut value)? as i16;
+
+    let max_version = match type_ {
+      REGISTER_BROKER_RECORD => 3,
+      PARTITION_RECORD | PARTITION_CHANGE_RECORD => 2,
+      UNREGISTER_BROKER_RECORD | TOPIC_RECORD | CONFIG_RECORD | ACCESS_CONTROL_ENTRY_RECORD | FENCE_BROKER_RECORD
+      | UNFENCE_BROKER_RECORD | REMOVE_TOPIC_RECORD | FEATURE_LEVEL_RECORD | CLIENT_QUOTA_RECORD
+      | PRODUCER_IDS_RECORD | USER_SCRAM_CREDENTIAL_RECORD | REMOVE_USER_SCRAM_CREDENTIAL_RECORD => 0,
+      _ => return Ok(MetadataRecord::Unknown { type_, version, data: value }),
+    };
+    if !(0..=max_version).contains(&version) {
+      bail!("unsupported version {version} of metadata record type {type_}");
+    }
+
+    let buf = &mut value;
+    Ok(match type_ {
+      REGISTER_BROKER_RECORD => MetadataRecord::RegisterBroker(RegisterBrokerRecord::decode(buf, version)?),
+      UNREGISTER_BROKER_RECORD => MetadataRecord::UnregisterBroker(UnregisterBrokerRecord::decode(buf, version)?),
+      TOPIC_RECORD => MetadataRecord::Topic(TopicRecord::decode(buf, version)?),
+      PARTITION_RECORD => MetadataRecord::Partition(PartitionRecord::decode(buf, version)?),
+      CONFIG_RECORD => MetadataRecord::Config(ConfigRecord::decode(buf, version)?),
+      PARTITION_CHANGE_RECORD => MetadataRecord::PartitionChange(PartitionChangeRecord::decode(buf, version)?),
+      ACCESS_CONTROL_ENTRY_RECORD => {
+        MetadataRecord::AccessControlEntry(AccessControlEntryRecord::decode(buf, version)?)
+      }
+      FENCE_BROKER_RECORD => MetadataRecord::FenceBroker(FenceBrokerRecord::decode(buf, version)?),
+      UNFENCE_BROKER_RECORD => MetadataRecord::UnfenceBroker(FenceBrokerRecord::decode(buf, version)?),
+      REMOVE_TOPIC_RECORD => MetadataRecord::RemoveTopic(RemoveTopicRecord::decode(buf, version)?),
+      FEATURE_LEVEL_RECORD => MetadataRecord::FeatureLevel(FeatureLevelRecord::decode(buf, version)?),
+      CLIENT_QUOTA_RECORD => MetadataRecord::ClientQuota(ClientQuotaRecord::decode(buf, version)?),
+      PRODUCER_IDS_RECORD => MetadataRecord::ProducerIds(ProducerIdsRecord::decode(buf, version)?),
+      USER_SCRAM_CREDENTIAL_RECORD => {
+        MetadataRecord::UserScramCredential(UserScramCredentialRecord::decode(buf, version)?)
+      }
+      REMOVE_USER_SCRAM_CREDENTIAL_RECORD => {
+        MetadataRecord::RemoveUserScramCredential(RemoveUserScramCredentialRecord::decode(buf, version)?)
+      }
+      _ => unreachable!("unknown types return above"),
+    })
+  }
+
+  /// Encodes the record as a metadata log record value, the inverse of `from_value`. Only
+  /// the records the broker writes itself, SCRAM credential changes, can be encoded.
+  pub fn to_value(&self) -> Result<Bytes> {
+    let mut buf = BytesMut::new();
+    let (type_, record): (i16, &dyn EncodeVersioned) = match self {
+      MetadataRecord::UserScramCredential(record) => (USER_SCRAM_CREDENTIAL_RECORD, record),
+      MetadataRecord::RemoveUserScramCredential(record) => (REMOVE_USER_SCRAM_CREDENTIAL_RECORD, record),
+      _ => bail!("writing {:?} isn't supported", self),
+    };
+    UnsignedVarInt::encode(&mut buf, &FRAME_VERSION);
+    UnsignedVarInt::encode(&mut buf, &(type_ as u32));
+    UnsignedVarInt::encode(&mut buf, &0);
+    record.encode(&mut buf, 0);
+    Ok(buf.freeze())
+  }
+}
+
+fn int32_array<B: Buf>(buf: &mut B) -> crate::kafka::codec::Result<Vec<i32>> {
+  CompactArray::<Int32>::decode(buf)
+}
+
+fn nullable_int32_array(buf: &mut Bytes) -> crate::kafka::codec::Result<Option<Vec<i32>>> {
+  decode_nullable_array(buf, true, Int32::decode)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerEndpoint {
+  pub name: String,
+  pub host: String,
+  pub port: u16,
+  pub security_protocol: i16,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for BrokerEndpoint {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<BrokerEndpoint> {
+    Ok(BrokerEndpoint {
+      name: CompactString::decode(buf)?,
+      host: CompactString::decode(buf)?,
+      port: UInt16::decode(buf)?,
+      security_protocol: Int16::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerFeature {
+  pub name: String,
+  pub min_supported_version: i16,
+  pub max_supported_version: i16,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for BrokerFeature {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<BrokerFeature> {
+    Ok(BrokerFeature {
+      name: CompactString::decode(buf)?,
+      min_supported_version: Int16::decode(buf)?,
+      max_supported_version: Int16::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterBrokerRecord {
+  pub broker_id: i32,
+  /// v2+
+  pub is_migrating_zk_broker: bool,
+  pub incarnation_id: Uuid,
+  pub broker_epoch: i64,
+  pub end_points: Vec<BrokerEndpoint>,
+  pub features: Vec<BrokerFeature>,
+  pub rack: Option<String>,
+  pub fenced: bool,
+  /// v1+
+  pub in_controlled_shutdown: bool,
+  /// v3+
+  pub log_dirs: Vec<Uuid>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for RegisterBrokerRecord {
+  fn decode<B: Buf>(buf: &mut B, version: i16) -> crate::kafka::codec::Result<RegisterBrokerRecord> {
+    let broker_id = Int32::decode(buf)?;
+    let is_migrating_zk_broker = if version >= 2 { Boolean::decode(buf)? } else { false };
+    let incarnation_id = Uuid::decode(buf)?;
+    let broker_epoch = Int64::decode(buf)?;
+    let end_points = decode_array(buf, true, |buf| BrokerEndpoint::decode(buf, version))?;
+    let features = decode_array(buf, true, |buf| BrokerFeature::decode(buf, version))?;
+    let rack = CompactNullableString::decode(buf)?;
+    let fenced = Boolean::decode(buf)?;
+    let in_controlled_shutdown = if version >= 1 { Boolean::decode(buf)? } else { false };
+    let log_dirs = if version >= 3 { CompactArray::<Uuid>::decode(buf)? } else { vec![] };
+
+    Ok(RegisterBrokerRecord {
+      broker_id,
+      is_migrating_zk_broker,
+      incarnation_id,
+      broker_epoch,
+      end_points,
+      features,
+      rack,
+      fenced,
+      in_controlled_shutdown,
+      log_dirs,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnregisterBrokerRecord {
+  pub broker_id: i32,
+  pub broker_epoch: i64,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for UnregisterBrokerRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<UnregisterBrokerRecord> {
+    Ok(UnregisterBrokerRecord {
+      broker_id: Int32::decode(buf)?,
+      broker_epoch: Int64::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+/// Both FenceBrokerRecord and UnfenceBrokerRecord.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FenceBrokerRecord {
+  pub id: i32,
+  pub epoch: i64,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for FenceBrokerRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<FenceBrokerRecord> {
+    Ok(FenceBrokerRecord {
+      id: Int32::decode(buf)?,
+      epoch: Int64::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopicRecord {
+  pub name: String,
+  pub topic_uuid: Uuid,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for TopicRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<TopicRecord> {
+    Ok(TopicRecord {
+      name: CompactString::decode(buf)?,
+      topic_uuid: Uuid::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoveTopicRecord {
+  pub topic_id: Uuid,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for RemoveTopicRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<RemoveTopicRecord> {
+    Ok(RemoveTopicRecord {
+      topic_id: Uuid::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PartitionRecord {
+  pub partition_id: i32,
+  pub topic_id: Uuid,
+  pub replicas: Vec<i32>,
+  pub isr: Vec<i32>,
+  pub removing_replicas: Vec<i32>,
+  pub adding_replicas: Vec<i32>,
+  pub leader: i32,
+  /// Tag 0
+  pub leader_recovery_state: i8,
+  pub leader_epoch: i32,
+  pub partition_epoch: i32,
+  /// v1+
+  pub directories: Vec<Uuid>,
+  /// Tag 1, v2+
+  pub eligible_leader_replicas: Option<Vec<i32>>,
+  /// Tag 2, v2+
+  pub last_known_elr: Option<Vec<i32>>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for PartitionRecord {
+  fn decode<B: Buf>(buf: &mut B, version: i16) -> crate::kafka::codec::Result<PartitionRecord> {
+    let partition_id = Int32::decode(buf)?;
+    let topic_id = Uuid::decode(buf)?;
+    let replicas = int32_array(buf)?;
+    let isr = int32_array(buf)?;
+    let removing_replicas = int32_array(buf)?;
+    let adding_replicas = int32_array(buf)?;
+    let leader = Int32::decode(buf)?;
+    let leader_epoch = Int32::decode(buf)?;
+    let partition_epoch = Int32::decode(buf)?;
+    let directories = if version >= 1 { CompactArray::<Uuid>::decode(buf)? } else { vec![] };
+    let tagged_fields = TaggedFields::decode(buf)?;
+
+    let (eligible_leader_replicas, last_known_elr) = if version >= 2 {
+      (
+        tagged_fields.get_with(1, nullable_int32_array)?.flatten(),
+        tagged_fields.get_with(2, nullable_int32_array)?.flatten(),
+      )
+    } else {
+      (None, None)
+    };
+
+    Ok(PartitionRecord {
+      partition_id,
+      topic_id,
+      replicas,
+      isr,
+      removing_replicas,
+      adding_replicas,
+      leader,
+      leader_recovery_state: tagged_fields.get_as::<_, Int8>(0)?.unwrap_or(0),
+      leader_epoch,
+      partition_epoch,
+      directories,
+      eligible_leader_replicas,
+      last_known_elr,
+      tagged_fields,
+    })
+  }
+}
+
+/// Changes to a partition; every field after the topic id is a tagged field and `None`
+/// (or `NO_LEADER_CHANGE` for the leader) means unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionChangeRecord {
+  pub partition_id: i32,
+  pub topic_id: Uuid,
+  /// Tag 0
+  pub isr: Option<Vec<i32>>,
+  /// Tag 1
+  pub leader: i32,
+  /// Tag 2
+  pub replicas: Option<Vec<i32>>,
+  /// Tag 3
+  pub removing_replicas: Option<Vec<i32>>,
+  /// Tag 4
+  pub adding_replicas: Option<Vec<i32>>,
+  /// Tag 5, -1 when unchanged
+  pub leader_recovery_state: i8,
+  /// Tag 6, v2+
+  pub eligible_leader_replicas: Option<Vec<i32>>,
+  /// Tag 7, v2+
+  pub last_known_elr: Option<Vec<i32>>,
+  /// Tag 8, v1+
+  pub directories: Option<Vec<Uuid>>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for PartitionChangeRecord {
+  fn decode<B: Buf>(buf: &mut B, version: i16) -> crate::kafka::codec::Result<PartitionChangeRecord> {
+    let partition_id = Int32::decode(buf)?;
+    let topic_id = Uuid::decode(buf)?;
+    let tagged_fields = TaggedFields::decode(buf)?;
+    let tags = &tagged_fields;
+
+    let (eligible_leader_replicas, last_known_elr) = if version >= 2 {
+      (tags.get_with(6, nullable_int32_array)?.flatten(), tags.get_with(7, nullable_int32_array)?.flatten())
+    } else {
+      (None, None)
+    };
+    let directories = if version >= 1 {
+      tags.get_with(8, |buf| decode_nullable_array(buf, true, Uuid::decode))?.flatten()
+    } else {
+      None
+    };
+
+    Ok(PartitionChangeRecord {
+      partition_id,
+      topic_id,
+      isr: tags.get_with(0, nullable_int32_array)?.flatten(),
+      leader: tags.get_as::<_, Int32>(1)?.unwrap_or(NO_LEADER_CHANGE),
+      replicas: tags.get_with(2, nullable_int32_array)?.flatten(),
+      removing_replicas: tags.get_with(3, nullable_int32_array)?.flatten(),
+      adding_replicas: tags.get_with(4, nullable_int32_array)?.flatten(),
+      leader_recovery_state: tags.get_as::<_, Int8>(5)?.unwrap_or(-1),
+      eligible_leader_replicas,
+      last_known_elr,
+      directories,
+      tagged_fields,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigRecord {
+  /// 2 = topic, 4 = broker, 8 = broker logger
+  pub resource_type: i8,
+  pub resource_name: String,
+  pub name: String,
+  /// `None` deletes the config
+  pub value: Option<String>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for ConfigRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<ConfigRecord> {
+    Ok(ConfigRecord {
+      resource_type: Int8::decode(buf)?,
+      resource_name: CompactString::decode(buf)?,
+      name: CompactString::decode(buf)?,
+      value: CompactNullableString::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessControlEntryRecord {
+  pub id: Uuid,
+  pub resource_type: i8,
+  pub resource_name: String,
+  pub pattern_type: i8,
+  pub principal: String,
+  pub host: String,
+  pub operation: i8,
+  pub permission_type: i8,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for AccessControlEntryRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<AccessControlEntryRecord> {
+    Ok(AccessControlEntryRecord {
+      id: Uuid::decode(buf)?,
+      resource_type: Int8::decode(buf)?,
+      resource_name: CompactString::decode(buf)?,
+      pattern_type: Int8::decode(buf)?,
+      principal: CompactString::decode(buf)?,
+      host: CompactString::decode(buf)?,
+      operation: Int8::decode(buf)?,
+      permission_type: Int8::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureLevelRecord {
+  pub name: String,
+  pub feature_level: i16,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for FeatureLevelRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<FeatureLevelRecord> {
+    Ok(FeatureLevelRecord {
+      name: CompactString::decode(buf)?,
+      feature_level: Int16::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientQuotaEntity {
+  /// "user", "client-id" or "ip"
+  pub entity_type: String,
+  /// `None` for the default entity
+  pub entity_name: Option<String>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for ClientQuotaEntity {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<ClientQuotaEntity> {
+    Ok(ClientQuotaEntity {
+      entity_type: CompactString::decode(buf)?,
+      entity_name: CompactNullableString::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientQuotaRecord {
+  pub entity: Vec<ClientQuotaEntity>,
+  pub key: String,
+  pub value: f64,
+  pub remove: bool,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for ClientQuotaRecord {
+  fn decode<B: Buf>(buf: &mut B, version: i16) -> crate::kafka::codec::Result<ClientQuotaRecord> {
+    Ok(ClientQuotaRecord {
+      entity: decode_array(buf, true, |buf| ClientQuotaEntity::decode(buf, version))?,
+      key: CompactString::decode(buf)?,
+      value: Float64::decode(buf)?,
+      remove: Boolean::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProducerIdsRecord {
+  pub broker_id: i32,
+  pub broker_epoch: i64,
+  /// First producer id of the next block to hand out
+  pub next_producer_id: i64,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for ProducerIdsRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<ProducerIdsRecord> {
+    Ok(ProducerIdsRecord {
+      broker_id: Int32::decode(buf)?,
+      broker_epoch: Int64::decode(buf)?,
+      next_producer_id: Int64::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+/// A user's salted password for one SCRAM mechanism, added or replaced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserScramCredentialRecord {
+  pub name: String,
+  /// 1 = SCRAM-SHA-256, 2 = SCRAM-SHA-512
+  pub mechanism: i8,
+  pub salt: Bytes,
+  pub stored_key: Bytes,
+  pub server_key: Bytes,
+  pub iterations: i32,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for UserScramCredentialRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<UserScramCredentialRecord> {
+    Ok(UserScramCredentialRecord {
+      name: CompactString::decode(buf)?,
+      mechanism: Int8::decode(buf)?,
+      salt: CompactBytes::decode(buf)?,
+      stored_key: CompactBytes::decode(buf)?,
+      server_key: CompactBytes::decode(buf)?,
+      iterations: Int32::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+impl EncodeVersioned for UserScramCredentialRecord {
+  fn encode(&self, buf: &mut BytesMut, _version: i16) {
+    CompactString::encode(buf, &self.name);
+    Int8::encode(buf, &self.mechanism);
+    CompactBytes::encode(buf, &self.salt);
+    CompactBytes::encode(buf, &self.stored_key);
+    CompactBytes::encode(buf, &self.server_key);
+    Int32::encode(buf, &self.iterations);
+    TaggedFields::encode(buf, &self.tagged_fields);
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoveUserScramCredentialRecord {
+  pub name: String,
+  pub mechanism: i8,
+  pub tagged_fields: TaggedFields,
+}
+
+impl DecodeVersioned for RemoveUserScramCredentialRecord {
+  fn decode<B: Buf>(buf: &mut B, _version: i16) -> crate::kafka::codec::Result<RemoveUserScramCredentialRecord> {
+    Ok(RemoveUserScramCredentialRecord {
+      name: CompactString::decode(buf)?,
+      mechanism: Int8::decode(buf)?,
+      tagged_fields: TaggedFields::decode(buf)?,
+    })
+  }
+}
+
+impl EncodeVersioned for RemoveUserScramCredentialRecord {
+  fn encode(&self, buf: &mut BytesMut, _version: i16) {
+    CompactString::encode(buf, &self.name);
+    Int8::encode(buf, &self.mechanism);
+    TaggedFields::encode(buf, &self.tagged_fields);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::BufMut;
+
+  use super::*;
+  use crate::kafka::codec::encode_nullable_array;
+
+  fn value(type_: i16, version: i16, body: impl FnOnce(&mut BytesMut)) -> Bytes {
+    let mut buf = BytesMut::new();
+    UnsignedVarInt::encode(&mut buf, &FRAME_VERSION);
+    UnsignedVarInt::encode(&mut buf, &(type_ as u32));
+    UnsignedVarInt::encode(&mut buf, &(version as u32));
+    body(&mut buf);
+    buf.freeze()
+  }
+
+  #[test]
+  fn decodes_partition_record_v1() {
+    let topic_id = Uuid(0x71);
+    let dir = Uuid(0x10000000000040008000000000000001);
+    let record = value(PARTITION_RECORD, 1, |buf| {
+      buf.put_i32(1);
+      Uuid::encode(buf, &topic_id);
+      CompactArray::<Int32>::encode(buf, &vec![1, 2]);
+      CompactArray::<Int32>::encode(buf, &vec![1]);
+      CompactArray::<Int32>::encode(buf, &vec![]);
+      CompactArray::<Int32>::encode(buf, &vec![]);
+      buf.put_i32(1);
+      buf.put_i32(5);
+      buf.put_i32(7);
+      CompactArray::<Uuid>::encode(buf, &vec![dir]);
+      let mut tags = TaggedFields::default();
+      tags.put::<_, Int8>(0, &1);
+      TaggedFields::encode(buf, &tags);
+    });
+
+    let MetadataRecord::Partition(partition) = MetadataRecord::from_value(record).unwrap() else {
+      panic!("expected a PartitionRecord");
+    };
+    assert_eq!((partition.partition_id, partition.topic_id, partition.leader), (1, topic_id, 1));
+    assert_eq!((partition.replicas, partition.isr), (vec![1, 2], vec![1]));
+    assert_eq!((partition.leader_epoch, partition.partition_epoch), (5, 7));
+    assert_eq!(partition.directories, vec![dir]);
+    assert_eq!(partition.leader_recovery_state, 1);
+    assert_eq!(partition.eligible_leader_replicas, None);
+  }
+
+  #[test]
+  fn decodes_partition_change_tagged_fields() {
+    let record = value(PARTITION_CHANGE_RECORD, 2, |buf| {
+      buf.put_i32(0);
+      Uuid::encode(buf, &Uuid(9));
+      let mut tags = TaggedFields::default();
+      tags.put_with(0, |buf| encode_nullable_array(buf, Some(&[2, 3][..]), true, |buf, id| buf.put_i32(*id)));
+      tags.put::<_, Int32>(1, &3);
+      tags.put_with(6, |buf| encode_nullable_array(buf, Some(&[1][..]), true, |buf, id| buf.put_i32(*id)));
+      TaggedFields::encode(buf, &tags);
+    });
+
+    let MetadataRecord::PartitionChange(change) = MetadataRecord::from_value(record).unwrap() else {
+      panic!("expected a PartitionChangeRecord");
+    };
+    assert_eq!(change.isr, Some(vec![2, 3]));
+    assert_eq!(change.leader, 3);
+    assert_eq!(change.replicas, None);
+    assert_eq!(change.leader_recovery_state, -1);
+    assert_eq!(change.eligible_leader_replicas, Some(vec![1]));
+  }
+
+  #[test]
+  fn scram_credential_records_round_trip() {
+    let records = [
+      MetadataRecord::UserScramCredential(UserScramCredentialRecord {
+        name: "alice".to_string(),
+        mechanism: 2,
+        salt: Bytes::from_static(b"salt"),
+        stored_key: Bytes::from_static(b"stored"),
+        server_key: Bytes::from_static(b"server"),
+        iterations: 8192,
+        tagged_fields: TaggedFields::default(),
+      }),
+      MetadataRecord::RemoveUserScramCredential(RemoveUserScramCredentialRecord {
+        name: "alice".to_string(),
+        mechanism: 1,
+        tagged_fields: TaggedFields::default(),
+      }),
+    ];
+    for record in records {
+      assert_eq!(MetadataRecord::from_value(record.to_value().unwrap()).unwrap(), record);
+    }
+  }
+
+  #[test]
+  fn keeps_unknown_record_types_and_rejects_newer_versions() {
+    let no_op = value(20, 0, |buf| buf.put_u8(0));
+    assert!(matches!(MetadataRecord::from_value(no_op).unwrap(), MetadataRecord::Unknown { type_: 20, .. }));
+
+    let future_topic = value(TOPIC_RECORD, 1, |buf| buf.put_u8(0));
+    assert!(MetadataRecord::from_value(future_topic).is_err());
+  }
+}
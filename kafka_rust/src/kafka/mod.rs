@@ -1,4 +1,27 @@
+pub mod broker;
+pub mod codec;
+pub mod framing;
+pub mod handlers;
+pub mod header;
+pub mod registry;
 pub mod requests;
 pub mod responses;
 pub mod common;
-pub mod metadata_log_file;
\ No newline at end of file
+pub mod compression;
+pub mod config;
+pub mod fetch_session;
+pub mod log;
+pub mod log_cleaner;
+pub mod log_index;
+pub mod log_segment;
+pub mod metadata_image;
+pub mod metadata_log_file;
+pub mod metadata_records;
+pub mod network;
+pub mod offset_checkpoint;
+pub mod record_batch;
+pub mod request_context;
+pub mod sasl;
+pub mod ssl;
+#[cfg(test)]
+pub mod test_support;
@@ -0,0 +1,386 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::framing::FrameReader;
+use crate::kafka::registry::ListenerType;
+use crate::kafka::request_context::{KafkaPrincipal, RequestContext};
+use crate::kafka::sasl::SaslAuthenticator;
+use crate::kafka::ssl::{self, SslFactory};
+
+/// Accepts connections on every configured listener until the process exits. All listeners
+/// are bound before any connection is accepted, so a port in use fails startup.
+pub async fn serve(broker: Arc<Broker>) -> Result<()> {
+  // One keystore and truststore for every SSL and SASL_SSL listener
+  let mut ssl_factory = None;
+  let mut listeners = vec![];
+  for endpoint in &broker.config.listeners {
+    let security_protocol = broker.config.security_protocol(&endpoint.listener_name)
+      .with_context(|| format!("no security protocol for listener {}", endpoint.listener_name))?;
+    let listener_type = if broker.config.is_controller_listener(&endpoint.listener_name) {
+      ListenerType::Controller
+    } else {
+      ListenerType::Broker
+    };
+    let listener = TcpListener::bind(endpoint.bind_address()).await
+      .with_context(|| format!("binding {}", endpoint))?;
+    println!("Listening for {:?} connections on {} ({})", listener_type, endpoint, security_protocol.name());
+    let tls = match (security_protocol.uses_tls(), &ssl_factory) {
+      (false, _) => None,
+      (true, Some(factory)) => Some(Arc::clone(factory)),
+      (true, None) => {
+        let factory = Arc::new(SslFactory::new(&broker.config)?);
+        ssl_factory = Some(Arc::clone(&factory));
+        Some(factory)
+      }
+    };
+    let context = RequestContext {
+      listener_name: endpoint.listener_name.clone(),
+      security_protocol,
+      listener_type,
+      client_address: None,
+      principal: KafkaPrincipal::anonymous(),
+    };
+    listeners.push((listener, context, tls));
+  }
+
+  // max.connections counts connections across all listeners
+  let connections = Arc::new(Semaphore::new(broker.config.max_connections));
+  let mut accepting = JoinSet::new();
+  for (listener, context, tls) in listeners {
+    accepting.spawn(accept(listener, context, tls, Arc::clone(&connections), Arc::clone(&broker)));
+  }
+  while let Some(accepted) = accepting.join_next().await {
+    accepted??;
+  }
+  Ok(())
+}
+
+/// Hands every connection on `listener` its own task while `connections` has permits left.
+/// `context` describes the listener, each connection gets a copy with its client's address.
+/// With `tls`, connections start with a TLS handshake and the client certificate, if any,
+/// names the principal.
+async fn accept(
+  listener: TcpListener,
+  context: RequestContext,
+  tls: Option<Arc<SslFactory>>,
+  connections: Arc<Semaphore>,
+  broker: Arc<Broker>,
+) -> Result<()> {
+  loop {
+    // At the limit, leave new connections in the backlog until one closes
+    let permit = Arc::clone(&connections).acquire_owned().await?;
+    let (stream, peer) = match listener.accept().await {
+      Ok(accepted) => accepted,
+      Err(e) => {
+        println!("Error accepting connection: {}", e);
+        continue;
+      }
+    };
+
+    let broker = Arc::clone(&broker);
+    let mut context = RequestContext { client_address: Some(peer), ..context.clone() };
+    let tls = tls.clone();
+    // The handshake runs in the connection's task so a slow client can't hold up the others
+    tokio::spawn(async move {
+      let served = match tls {
+        None => handle_connection(stream, context, broker).await,
+        // Getting the acceptor may reload the keystore from disk
+        Some(factory) => match tokio::task::spawn_blocking(move || factory.acceptor()).await {
+          Ok(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) => {
+              context.principal = ssl::principal(stream.get_ref().1);
+              println!("TLS connection from {} on {} as {}", peer, context.listener_name, context.principal);
+              handle_connection(stream, context, broker).await
+            }
+            Err(e) => Err(anyhow::Error::new(e).context("TLS handshake")),
+          },
+          Err(e) => Err(anyhow::Error::new(e).context("loading the SSL keystore")),
+        },
+      };
+      if let Err(e) = served {
+        println!("Connection from {} failed: {:#}", peer, e);
+      }
+      drop(permit);
+    });
+  }
+}
+
+/// Serves one connection. A read task splits incoming bytes into requests and a write task
+/// sends responses back, while requests are handled here one at a time so responses go out
+/// in request order, as Kafka clients expect of pipelined requests.
+///
+/// Both sides are bounded by `queued.max.requests`: when a client stops reading its
+/// responses, the write task blocks, handling stops, and so does reading its requests.
+///
+/// On SASL listeners requests go through the connection's `SaslAuthenticator` first.
+async fn handle_connection<S>(stream: S, mut context: RequestContext, broker: Arc<Broker>) -> Result<()>
+where
+  S: AsyncRead + AsyncWrite + Send + 'static,
+{
+  let mut sasl = context.security_protocol.uses_sasl().then(SaslAuthenticator::default);
+  let (reader, writer) = tokio::io::split(stream);
+  let (request_sender, mut requests) = mpsc::channel(broker.config.queued_max_requests);
+  let (response_sender, responses) = mpsc::channel(broker.config.queued_max_requests);
+  let read_task = tokio::spawn(read_requests(reader, broker.config.socket_request_max_bytes, request_sender));
+  let write_task = tokio::spawn(write_responses(writer, responses));
+
+  while let Some(frame) = requests.recv().await {
+    let handler_broker = Arc::clone(&broker);
+    // Handlers block, Fetch for up to its max_wait_ms. Authentication changes the context,
+    // so it goes along with the authenticator and both come back.
+    let (handled, handled_context, handled_sasl) = tokio::task::spawn_blocking(move || {
+      let handled = match sasl.as_mut() {
+        Some(authenticator) => authenticator.handle_request(&handler_broker, &mut context, frame),
+        None => handler_broker.handle_request(&context, frame),
+      };
+      (handled, context, sasl)
+    })
+    .await?;
+    (context, sasl) = (handled_context, handled_sasl);
+    let response = match handled {
+      Ok(Some(response)) => response,
+      Ok(None) => continue,
+      Err(e) => {
+        println!("Closing connection: {:#}", e);
+        break;
+      }
+    };
+    // The write task only goes away when writing failed, which it reports below
+    if response_sender.send(response.get_vec()).await.is_err() {
+      break;
+    }
+  }
+
+  // Whatever is still queued is answered before the connection closes
+  drop(response_sender);
+  let read = if read_task.is_finished() {
+    read_task.await?
+  } else {
+    read_task.abort();
+    Ok(())
+  };
+  write_task.await??;
+  read
+}
+
+/// Reads requests into `requests` until the client closes the connection, sends a frame
+/// that can't be a request, or the connection stops being served.
+async fn read_requests(mut reader: impl AsyncRead + Unpin, max_request_size: usize, requests: mpsc::Sender<BytesMut>) -> Result<()> {
+  let mut frames = FrameReader::new(max_request_size);
+  let mut buffer: [u8; 4096] = [0; 4096];
+
+  loop {
+    let bytes_read = reader.read(&mut buffer).await.context("reading requests")?;
+    if bytes_read == 0 {
+      if frames.buffered() > 0 {
+        println!("Connection closed by client with a partial request of {} bytes", frames.buffered());
+      } else {
+        println!("Connection closed by client");
+      }
+      return Ok(());
+    }
+    frames.extend(&buffer[..bytes_read]);
+
+    // One read can carry several pipelined requests
+    loop {
+      match frames.next_frame() {
+        Ok(Some(frame)) => {
+          if requests.send(frame).await.is_err() {
+            return Ok(());
+          }
+        }
+        Ok(None) => break,
+        Err(e) => {
+          println!("Closing connection: {}", e);
+          return Ok(());
+        }
+      }
+    }
+  }
+}
+
+async fn write_responses(mut writer: impl AsyncWrite + Unpin, mut responses: mpsc::Receiver<Vec<u8>>) -> Result<()> {
+  while let Some(response) = responses.recv().await {
+    writer.write_all(&response).await.context("writing a response")?;
+    // TLS streams hold on to records until flushed
+    writer.flush().await.context("writing a response")?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::{Buf, BufMut};
+
+  use super::*;
+  use crate::kafka::config::{BrokerConfig, SecurityProtocol};
+  use crate::kafka::handlers::api_versions;
+  use crate::kafka::metadata_image::MetadataImage;
+  use crate::kafka::test_support::temp_dir;
+
+  /// An ApiVersions v0 request, which has an empty body.
+  fn api_versions_request(correlation_id: i32) -> BytesMut {
+    let mut request = BytesMut::new();
+    request.put_i32(10);
+    request.put_i16(api_versions::API_KEY);
+    request.put_i16(0);
+    request.put_i32(correlation_id);
+    request.put_i16(-1); // client_id
+    request
+  }
+
+  async fn listen(config: BrokerConfig) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    let broker = Arc::new(Broker::new(config, MetadataImage::empty()));
+    tokio::spawn(accept(listener, RequestContext::default(), None, connections, broker));
+    addr
+  }
+
+  /// The correlation id of the next response.
+  async fn read_response(stream: &mut (impl AsyncRead + Unpin)) -> i32 {
+    let size = stream.read_i32().await.unwrap();
+    let mut response = vec![0; size as usize];
+    stream.read_exact(&mut response).await.unwrap();
+    (&response[..]).get_i32()
+  }
+
+  #[tokio::test]
+  async fn answers_pipelined_requests_in_order() {
+    let mut stream = TcpStream::connect(listen(BrokerConfig::default()).await).await.unwrap();
+
+    let mut requests = BytesMut::new();
+    for correlation_id in 1..=20 {
+      requests.extend_from_slice(&api_versions_request(correlation_id));
+    }
+    // Split mid-request, the rest has to wait for the next read
+    stream.write_all(&requests[..25]).await.unwrap();
+    stream.write_all(&requests[25..]).await.unwrap();
+
+    for correlation_id in 1..=20 {
+      assert_eq!(read_response(&mut stream).await, correlation_id);
+    }
+  }
+
+  #[tokio::test]
+  async fn holds_connections_beyond_max_connections() {
+    let addr = listen(BrokerConfig { max_connections: 1, ..Default::default() }).await;
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    // Connecting succeeds through the backlog, but nothing is read until the first closes
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    second.write_all(&api_versions_request(2)).await.unwrap();
+
+    first.write_all(&api_versions_request(1)).await.unwrap();
+    assert_eq!(read_response(&mut first).await, 1);
+    let waiting = tokio::time::timeout(std::time::Duration::from_millis(100), read_response(&mut second)).await;
+    assert!(waiting.is_err());
+
+    drop(first);
+    assert_eq!(read_response(&mut second).await, 2);
+  }
+
+  #[tokio::test]
+  async fn answers_over_tls() {
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    let dir = temp_dir();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let keystore = dir.path().join("keystore.pem");
+    std::fs::write(&keystore, format!("{}{}", certified.key_pair.serialize_pem(), certified.cert.pem())).unwrap();
+    let config = BrokerConfig { ssl_keystore_location: Some(keystore), ..Default::default() };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let context = RequestContext { listener_name: "SSL".to_string(), security_protocol: SecurityProtocol::Ssl, ..Default::default() };
+    let tls = Arc::new(SslFactory::new(&config).unwrap());
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    let broker = Arc::new(Broker::new(config, MetadataImage::empty()));
+    tokio::spawn(accept(listener, context, Some(tls), connections, broker));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::clone(certified.cert.der())).unwrap();
+    let client = ClientConfig::builder_with_provider(Arc::new(tokio_rustls::rustls::crypto::ring::default_provider()))
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = tokio_rustls::TlsConnector::from(Arc::new(client))
+      .connect(ServerName::try_from("localhost").unwrap(), stream)
+      .await
+      .unwrap();
+
+    for correlation_id in 1..=3 {
+      stream.write_all(&api_versions_request(correlation_id)).await.unwrap();
+    }
+    for correlation_id in 1..=3 {
+      assert_eq!(read_response(&mut stream).await, correlation_id);
+    }
+  }
+
+  #[tokio::test]
+  async fn authenticates_sasl_connections_first() {
+    use crate::kafka::handlers::{sasl_authenticate, sasl_handshake};
+
+    let config = BrokerConfig {
+      sasl_enabled_mechanisms: vec!["PLAIN".to_string()],
+      sasl_plain_users: [("SASL_PLAINTEXT".to_string(), [("alice".to_string(), "alice-secret".to_string())].into())].into(),
+      ..Default::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let context = RequestContext {
+      listener_name: "SASL_PLAINTEXT".to_string(),
+      security_protocol: SecurityProtocol::SaslPlaintext,
+      ..Default::default()
+    };
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    let broker = Arc::new(Broker::new(config, MetadataImage::empty()));
+    tokio::spawn(accept(listener, context, None, connections, broker));
+
+    // SaslHandshake v1 for PLAIN, then SaslAuthenticate v0 with the PLAIN message
+    let sasl_requests = |correlation_id: i32, password: &str| {
+      let mut requests = BytesMut::new();
+      requests.put_i32(10 + 2 + 5);
+      requests.put_i16(sasl_handshake::API_KEY);
+      requests.put_i16(1);
+      requests.put_i32(correlation_id);
+      requests.put_i16(-1);
+      requests.put_i16(5);
+      requests.put_slice(b"PLAIN");
+      let message = format!("\0alice\0{}", password);
+      requests.put_i32(10 + 4 + message.len() as i32);
+      requests.put_i16(sasl_authenticate::API_KEY);
+      requests.put_i16(0);
+      requests.put_i32(correlation_id + 1);
+      requests.put_i16(-1);
+      requests.put_i32(message.len() as i32);
+      requests.put_slice(message.as_bytes());
+      requests
+    };
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&sasl_requests(1, "alice-secret")).await.unwrap();
+    stream.write_all(&api_versions_request(3)).await.unwrap();
+    for correlation_id in 1..=3 {
+      assert_eq!(read_response(&mut stream).await, correlation_id);
+    }
+
+    // The failure is answered, then the connection is closed
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&sasl_requests(1, "guess")).await.unwrap();
+    stream.write_all(&api_versions_request(3)).await.unwrap();
+    assert_eq!(read_response(&mut stream).await, 1);
+    assert_eq!(read_response(&mut stream).await, 2);
+    assert!(stream.read_i32().await.is_err());
+  }
+}
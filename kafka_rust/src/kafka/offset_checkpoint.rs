@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use crate::kafka::log::TopicPartition;
+
+const VERSION: i32 = 0;
+
+/// A per-partition offset file in Kafka's checkpoint format: a version line, an entry count
+/// line, then one `<topic> <partition> <offset>` line per partition.
+#[derive(Debug, Clone)]
+pub struct OffsetCheckpointFile {
+  path: PathBuf,
+}
+
+impl OffsetCheckpointFile {
+  pub fn new(path: PathBuf) -> OffsetCheckpointFile {
+    OffsetCheckpointFile { path }
+  }
+
+  /// The checkpointed offsets, none when the file doesn't exist yet.
+  pub fn read(&self) -> Result<HashMap<TopicPartition, i64>> {
+    let contents = match fs::read_to_string(&self.path) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+      Err(e) => return Err(e).with_context(|| format!("reading {}", self.path.display())),
+    };
+    let malformed = |line: &str| anyhow::anyhow!("malformed line {:?} in {}", line, self.path.display());
+
+    let mut lines = contents.lines();
+    let version = lines.next().unwrap_or_default();
+    if version.trim().parse::<i32>().ok() != Some(VERSION) {
+      anyhow::bail!("unsupported version {:?} of {}", version, self.path.display());
+    }
+    let count = lines.next().unwrap_or_default();
+    let count: usize = count.trim().parse().map_err(|_| malformed(count))?;
+
+    let mut offsets = HashMap::with_capacity(count);
+    for line in lines.by_ref().take(count) {
+      let fields: Vec<&str> = line.split(' ').collect();
+      let [topic, partition, offset] = fields[..] else {
+        return Err(malformed(line));
+      };
+      let partition = partition.parse().map_err(|_| malformed(line))?;
+      let offset = offset.parse().map_err(|_| malformed(line))?;
+      offsets.insert(TopicPartition::new(topic, partition), offset);
+    }
+    if offsets.len() != count {
+      anyhow::bail!("{} lists {} entries but has {}", self.path.display(), count, offsets.len());
+    }
+    Ok(offsets)
+  }
+
+  /// Replaces the file, through a temporary file so a crash leaves either the old or the
+  /// new contents.
+  pub fn write(&self, offsets: &HashMap<TopicPartition, i64>) -> Result<()> {
+    let mut entries: Vec<_> = offsets.iter().collect();
+    entries.sort();
+    let mut contents = format!("{}\n{}\n", VERSION, entries.len());
+    for (topic_partition, offset) in entries {
+      contents.push_str(&format!("{} {} {}\n", topic_partition.topic, topic_partition.partition, offset));
+    }
+
+    let tmp = self.path.with_extension("tmp");
+    fs::write(&tmp, contents).with_context(|| format!("writing {}", tmp.display()))?;
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, &self.path).with_context(|| format!("replacing {}", self.path.display()))
+  }
+}
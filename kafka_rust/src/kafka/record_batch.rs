@@ -0,0 +1,586 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::kafka::codec::{CodecError, Decode, Encode, Int16, Int32, Int64, Int8, Result, UInt32, VarInt, VarLong};
+use crate::kafka::compression::Compression;
+
+// RecordBatch (magic v2) from https://kafka.apache.org/documentation/#recordbatch
+//
+// baseOffset: int64
+// batchLength: int32
+// partitionLeaderEpoch: int32
+// magic: int8 (current magic value is 2)
+// crc: uint32
+// attributes: int16
+// lastOffsetDelta: int32
+// baseTimestamp: int64
+// maxTimestamp: int64
+// producerId: int64
+// producerEpoch: int16
+// baseSequence: int32
+// records: [Record]
+
+/// baseOffset + batchLength, the part of the batch header not counted in batchLength.
+pub const BATCH_OVERHEAD: usize = 12;
+/// Full batch header size up to and including the records count.
+pub const RECORD_BATCH_HEADER_SIZE: usize = 61;
+pub const MAGIC_V2: i8 = 2;
+
+/// Byte offsets of header fields inside a serialized batch.
+pub const PARTITION_LEADER_EPOCH_OFFSET: usize = 12;
+pub const MAGIC_OFFSET: usize = 16;
+pub const CRC_OFFSET: usize = 17;
+/// The CRC covers everything from the attributes to the end of the batch.
+pub const ATTRIBUTES_OFFSET: usize = 21;
+pub const LAST_OFFSET_DELTA_OFFSET: usize = 23;
+pub const MAX_TIMESTAMP_OFFSET: usize = 35;
+
+pub const COMPRESSION_CODEC_MASK: i16 = 0x07;
+const TIMESTAMP_TYPE_MASK: i16 = 0x08;
+const TRANSACTIONAL_MASK: i16 = 0x10;
+const CONTROL_MASK: i16 = 0x20;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordHeader {
+  pub key: String,
+  pub value: Option<Bytes>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Record {
+  pub attributes: i8,
+  pub timestamp_delta: i64,
+  pub offset_delta: i32,
+  pub key: Option<Bytes>,
+  pub value: Option<Bytes>,
+  pub headers: Vec<RecordHeader>,
+}
+
+impl Record {
+  pub fn decode<B: Buf>(buf: &mut B) -> Result<Record> {
+    let length = VarInt::decode(buf)?;
+    if length < 0 || length as usize > buf.remaining() {
+      return Err(CodecError::InvalidLength(length as i64));
+    }
+    // Decode from exactly `length` bytes so a bad record can't eat into the next one
+    let mut record = buf.copy_to_bytes(length as usize);
+
+    let attributes = Int8::decode(&mut record)?;
+    let timestamp_delta = VarLong::decode(&mut record)?;
+    let offset_delta = VarInt::decode(&mut record)?;
+    let key = read_varint_bytes(&mut record)?;
+    let value = read_varint_bytes(&mut record)?;
+
+    let header_count = VarInt::decode(&mut record)?;
+    if header_count < 0 || header_count as usize > record.remaining() {
+      return Err(CodecError::InvalidLength(header_count as i64));
+    }
+    let mut headers = Vec::with_capacity(header_count as usize);
+    for _ in 0..header_count {
+      let key = read_varint_bytes(&mut record)?.ok_or(CodecError::UnexpectedNull)?;
+      let key = String::from_utf8(key.to_vec()).map_err(|_| CodecError::InvalidUtf8)?;
+      let value = read_varint_bytes(&mut record)?;
+      headers.push(RecordHeader { key, value });
+    }
+
+    Ok(Record {
+      attributes,
+      timestamp_delta,
+      offset_delta,
+      key,
+      value,
+      headers,
+    })
+  }
+
+  pub fn encode(&self, buf: &mut BytesMut) {
+    let mut body = BytesMut::new();
+    body.put_i8(self.attributes);
+    VarLong::encode(&mut body, &self.timestamp_delta);
+    VarInt::encode(&mut body, &self.offset_delta);
+    write_varint_bytes(&mut body, self.key.as_deref());
+    write_varint_bytes(&mut body, self.value.as_deref());
+    VarInt::encode(&mut body, &(self.headers.len() as i32));
+    for header in &self.headers {
+      write_varint_bytes(&mut body, Some(header.key.as_bytes()));
+      write_varint_bytes(&mut body, header.value.as_deref());
+    }
+
+    VarInt::encode(buf, &(body.len() as i32));
+    buf.put_slice(&body);
+  }
+}
+
+/// Varint length prefixed bytes, a length of -1 meaning null.
+fn read_varint_bytes<B: Buf>(buf: &mut B) -> Result<Option<Bytes>> {
+  match VarInt::decode(buf)? {
+    -1 => Ok(None),
+    len if len < 0 || len as usize > buf.remaining() => Err(CodecError::InvalidLength(len as i64)),
+    len => Ok(Some(buf.copy_to_bytes(len as usize))),
+  }
+}
+
+fn write_varint_bytes(buf: &mut BytesMut, value: Option<&[u8]>) {
+  match value {
+    Some(value) => {
+      VarInt::encode(buf, &(value.len() as i32));
+      buf.put_slice(value);
+    }
+    None => VarInt::encode(buf, &-1),
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordBatch {
+  pub base_offset: i64,
+  pub batch_length: i32,
+  pub partition_leader_epoch: i32,
+  pub magic: i8,
+  pub crc: u32,
+  pub attributes: i16,
+  pub last_offset_delta: i32,
+  pub base_timestamp: i64,
+  pub max_timestamp: i64,
+  pub producer_id: i64,
+  pub producer_epoch: i16,
+  pub base_sequence: i32,
+  pub records: Vec<Record>,
+}
+
+impl RecordBatch {
+  /// Decodes one complete batch. `buf` must hold at least the whole batch, see `RecordBatches`
+  /// for walking a log file.
+  pub fn decode<B: Buf>(buf: &mut B) -> Result<RecordBatch> {
+    RecordBatch::decode_at_most(buf, usize::MAX)
+  }
+
+  /// Decodes one complete batch like `decode`, failing with `DecompressedTooLarge` when its
+  /// records decompress to more than `max_records_size` bytes.
+  pub fn decode_at_most<B: Buf>(buf: &mut B, max_records_size: usize) -> Result<RecordBatch> {
+    let base_offset = Int64::decode(buf)?;
+    let batch_length = Int32::decode(buf)?;
+    if batch_length < (RECORD_BATCH_HEADER_SIZE - BATCH_OVERHEAD) as i32 || batch_length as usize > buf.remaining() {
+      return Err(CodecError::InvalidLength(batch_length as i64));
+    }
+    let mut batch = buf.copy_to_bytes(batch_length as usize);
+
+    let partition_leader_epoch = Int32::decode(&mut batch)?;
+    let magic = Int8::decode(&mut batch)?;
+    if magic != MAGIC_V2 {
+      // Message sets v0/v1 predate KRaft and aren't something we write or read
+      return Err(CodecError::UnsupportedMagic(magic));
+    }
+    let crc = UInt32::decode(&mut batch)?;
+    let attributes = Int16::decode(&mut batch)?;
+    let last_offset_delta = Int32::decode(&mut batch)?;
+    let base_timestamp = Int64::decode(&mut batch)?;
+    let max_timestamp = Int64::decode(&mut batch)?;
+    let producer_id = Int64::decode(&mut batch)?;
+    let producer_epoch = Int16::decode(&mut batch)?;
+    let base_sequence = Int32::decode(&mut batch)?;
+
+    let records_count = Int32::decode(&mut batch)?;
+    let compression = attributes & COMPRESSION_CODEC_MASK;
+    let mut records_data = match Compression::from_id(compression) {
+      Some(Compression::None) => batch,
+      Some(codec) => Bytes::from(codec.decompress(&batch, max_records_size)?),
+      None => return Err(CodecError::UnknownCompression(compression)),
+    };
+    if records_count < 0 || records_count as usize > records_data.remaining() {
+      return Err(CodecError::InvalidLength(records_count as i64));
+    }
+    let mut records = Vec::with_capacity(records_count as usize);
+    for _ in 0..records_count {
+      records.push(Record::decode(&mut records_data)?);
+    }
+
+    Ok(RecordBatch {
+      base_offset,
+      batch_length,
+      partition_leader_epoch,
+      magic,
+      crc,
+      attributes,
+      last_offset_delta,
+      base_timestamp,
+      max_timestamp,
+      producer_id,
+      producer_epoch,
+      base_sequence,
+      records,
+    })
+  }
+
+  /// Serializes the batch, its records compressed with the codec its attributes name (an
+  /// unknown one writes them uncompressed). The batch length, records count and CRC are
+  /// worked out from the records, whatever the fields say.
+  pub fn encode(&self) -> BytesMut {
+    let compression = Compression::from_id(self.compression()).unwrap_or(Compression::None);
+    let mut buf = BytesMut::new();
+    buf.put_i64(self.base_offset);
+    buf.put_i32(0); // batch length, filled in below
+    buf.put_i32(self.partition_leader_epoch);
+    buf.put_i8(MAGIC_V2);
+    buf.put_u32(0); // crc, filled in below
+    buf.put_i16((self.attributes & !COMPRESSION_CODEC_MASK) | compression.id());
+    buf.put_i32(self.last_offset_delta);
+    buf.put_i64(self.base_timestamp);
+    buf.put_i64(self.max_timestamp);
+    buf.put_i64(self.producer_id);
+    buf.put_i16(self.producer_epoch);
+    buf.put_i32(self.base_sequence);
+    buf.put_i32(self.records.len() as i32);
+    let mut records = BytesMut::new();
+    for record in &self.records {
+      record.encode(&mut records);
+    }
+    buf.put_slice(&compression.compress(&records));
+
+    let batch_length = (buf.len() - BATCH_OVERHEAD) as i32;
+    buf[8..BATCH_OVERHEAD].copy_from_slice(&batch_length.to_be_bytes());
+    let crc = compute_crc(&buf);
+    buf[CRC_OFFSET..ATTRIBUTES_OFFSET].copy_from_slice(&crc.to_be_bytes());
+    buf
+  }
+
+  /// Compression codec of the records: 0 none, 1 gzip, 2 snappy, 3 lz4, 4 zstd.
+  pub fn compression(&self) -> i16 {
+    self.attributes & COMPRESSION_CODEC_MASK
+  }
+
+  pub fn last_offset(&self) -> i64 {
+    self.base_offset + self.last_offset_delta as i64
+  }
+
+  /// Timestamps were set by the broker on append (LogAppendTime) rather than by the producer.
+  pub fn is_log_append_time(&self) -> bool {
+    self.attributes & TIMESTAMP_TYPE_MASK != 0
+  }
+
+  pub fn is_transactional(&self) -> bool {
+    self.attributes & TRANSACTIONAL_MASK != 0
+  }
+
+  /// Control batches hold transaction markers rather than user data.
+  pub fn is_control(&self) -> bool {
+    self.attributes & CONTROL_MASK != 0
+  }
+
+  /// The batch's records with absolute offsets and timestamps.
+  pub fn log_records(&self) -> impl Iterator<Item = LogRecord> + '_ {
+    self.records.iter().map(move |record| LogRecord {
+      offset: self.base_offset + record.offset_delta as i64,
+      timestamp: if self.is_log_append_time() {
+        self.max_timestamp
+      } else {
+        self.base_timestamp + record.timestamp_delta
+      },
+      key: record.key.clone(),
+      value: record.value.clone(),
+      headers: record.headers.clone(),
+    })
+  }
+}
+
+/// CRC-32C of a serialized batch, as stored in its crc field.
+pub fn compute_crc(raw: &[u8]) -> u32 {
+  crc32c::crc32c(&raw[ATTRIBUTES_OFFSET..])
+}
+
+/// Whether the crc field of a serialized batch matches its contents.
+pub fn crc_matches(raw: &[u8]) -> bool {
+  raw.len() >= RECORD_BATCH_HEADER_SIZE && (&raw[CRC_OFFSET..ATTRIBUTES_OFFSET]).get_u32() == compute_crc(raw)
+}
+
+/// Batches `verify_crc` has rejected since startup. Anything but zero points at a bad disk,
+/// or a bad client for Produce requests.
+static CORRUPT_BATCHES: AtomicU64 = AtomicU64::new(0);
+
+/// `crc_matches` for data coming in from disk or the network, counting the batches that
+/// fail it.
+pub fn verify_crc(raw: &[u8]) -> bool {
+  if crc_matches(raw) {
+    return true;
+  }
+  let corrupt = CORRUPT_BATCHES.fetch_add(1, Ordering::Relaxed) + 1;
+  println!("Record batch failed its CRC check, {} corrupt batches since startup", corrupt);
+  false
+}
+
+/// How many batches failed their CRC check since startup.
+pub fn corrupt_batches() -> u64 {
+  CORRUPT_BATCHES.load(Ordering::Relaxed)
+}
+
+/// How often main checks `corrupt_batches` for `CorruptBatchReport`.
+pub const CORRUPT_BATCHES_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Turns `corrupt_batches` into a periodic log line, so a disk going bad stands out even
+/// after the per-batch lines scrolled by. Quiet while the count stays put.
+#[derive(Debug, Default)]
+pub struct CorruptBatchReport {
+  reported: u64,
+}
+
+impl CorruptBatchReport {
+  /// The line to log for the current count, `None` when no batch failed since the last one.
+  pub fn update(&mut self, corrupt_batches: u64) -> Option<String> {
+    if corrupt_batches <= self.reported {
+      return None;
+    }
+    let new = corrupt_batches - self.reported;
+    self.reported = corrupt_batches;
+    Some(format!(
+      "{} record batches failed their CRC check since the last report, {} since startup",
+      new, corrupt_batches
+    ))
+  }
+}
+
+/// Attributes of a serialized batch.
+pub fn attributes(raw: &[u8]) -> i16 {
+  (&raw[ATTRIBUTES_OFFSET..ATTRIBUTES_OFFSET + 2]).get_i16()
+}
+
+/// Offset of the last record in a serialized batch relative to its base offset.
+pub fn last_offset_delta(raw: &[u8]) -> i32 {
+  (&raw[LAST_OFFSET_DELTA_OFFSET..LAST_OFFSET_DELTA_OFFSET + 4]).get_i32()
+}
+
+/// Largest record timestamp in a serialized batch, -1 when it has none.
+pub fn max_timestamp(raw: &[u8]) -> i64 {
+  (&raw[MAX_TIMESTAMP_OFFSET..MAX_TIMESTAMP_OFFSET + 8]).get_i64()
+}
+
+/// Leader epoch of the broker that appended a serialized batch.
+pub fn partition_leader_epoch(raw: &[u8]) -> i32 {
+  (&raw[PARTITION_LEADER_EPOCH_OFFSET..MAGIC_OFFSET]).get_i32()
+}
+
+/// Rewrites the base offset and partition leader epoch of a serialized batch. Neither is
+/// covered by the CRC so the batch stays valid.
+pub fn assign_offset(raw: &mut [u8], base_offset: i64, partition_leader_epoch: i32) {
+  raw[..8].copy_from_slice(&base_offset.to_be_bytes());
+  raw[PARTITION_LEADER_EPOCH_OFFSET..MAGIC_OFFSET].copy_from_slice(&partition_leader_epoch.to_be_bytes());
+}
+
+/// A record as seen by a reader of the log, independent of how it was batched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+  pub offset: i64,
+  pub timestamp: i64,
+  pub key: Option<Bytes>,
+  pub value: Option<Bytes>,
+  pub headers: Vec<RecordHeader>,
+}
+
+/// Walks every batch in a log segment (or any other concatenation of batches) until EOF.
+///
+/// A trailing partial batch, as left behind by a crash mid-append, ends the iteration without
+/// an error; `position()` then tells where the valid data stops.
+#[derive(Debug, Clone)]
+pub struct RecordBatches {
+  buf: Bytes,
+  position: usize,
+  failed: bool,
+}
+
+impl RecordBatches {
+  pub fn new(buf: Bytes) -> RecordBatches {
+    RecordBatches { buf, position: 0, failed: false }
+  }
+
+  /// Byte offset just past the last batch successfully returned.
+  pub fn position(&self) -> usize {
+    self.position
+  }
+
+  /// Size of the next complete batch, or `None` at EOF or when only part of it is there.
+  fn next_batch_size(&self) -> Option<usize> {
+    let rest = &self.buf[self.position..];
+    if rest.len() < BATCH_OVERHEAD {
+      return None;
+    }
+    let batch_length = (&rest[8..12]).get_i32();
+    if batch_length < 0 {
+      return None;
+    }
+    let size = BATCH_OVERHEAD + batch_length as usize;
+    (rest.len() >= size).then_some(size)
+  }
+
+  /// The raw bytes of each complete batch, without decoding them.
+  pub fn next_raw(&mut self) -> Option<Bytes> {
+    let size = self.next_batch_size()?;
+    let raw = self.buf.slice(self.position..self.position + size);
+    self.position += size;
+    Some(raw)
+  }
+}
+
+impl Iterator for RecordBatches {
+  type Item = Result<RecordBatch>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    // Nothing sensible can follow a batch that didn't decode
+    if self.failed {
+      return None;
+    }
+    let mut raw = self.next_raw()?;
+    let size = raw.len();
+    let batch = RecordBatch::decode(&mut raw);
+    if batch.is_err() {
+      // Leave position() at the start of the bad batch
+      self.failed = true;
+      self.position -= size;
+    }
+    Some(batch)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn varint_bytes(buf: &mut BytesMut, value: Option<&[u8]>) {
+    match value {
+      Some(v) => {
+        VarInt::encode(buf, &(v.len() as i32));
+        buf.put_slice(v);
+      }
+      None => VarInt::encode(buf, &-1),
+    }
+  }
+
+  fn record(offset_delta: i32, timestamp_delta: i64, key: Option<&[u8]>, value: &[u8], headers: &[(&str, &[u8])]) -> BytesMut {
+    let mut body = BytesMut::new();
+    body.put_i8(0);
+    VarLong::encode(&mut body, &timestamp_delta);
+    VarInt::encode(&mut body, &offset_delta);
+    varint_bytes(&mut body, key);
+    varint_bytes(&mut body, Some(value));
+    VarInt::encode(&mut body, &(headers.len() as i32));
+    for (key, value) in headers {
+      varint_bytes(&mut body, Some(key.as_bytes()));
+      varint_bytes(&mut body, Some(value));
+    }
+
+    let mut record = BytesMut::new();
+    VarInt::encode(&mut record, &(body.len() as i32));
+    record.put_slice(&body);
+    record
+  }
+
+  fn batch(base_offset: i64, base_timestamp: i64, records: &[BytesMut]) -> BytesMut {
+    let mut rest = BytesMut::new();
+    rest.put_i32(0); // partition leader epoch
+    rest.put_i8(MAGIC_V2);
+    rest.put_u32(0); // crc, not checked here
+    rest.put_i16(0);
+    rest.put_i32(records.len() as i32 - 1);
+    rest.put_i64(base_timestamp);
+    rest.put_i64(base_timestamp);
+    rest.put_i64(-1);
+    rest.put_i16(-1);
+    rest.put_i32(-1);
+    rest.put_i32(records.len() as i32);
+    for record in records {
+      rest.put_slice(record);
+    }
+
+    let mut batch = BytesMut::new();
+    batch.put_i64(base_offset);
+    batch.put_i32(rest.len() as i32);
+    batch.put_slice(&rest);
+    batch
+  }
+
+  #[test]
+  fn reads_every_batch_with_varint_fields_null_keys_and_headers() {
+    // A value over 63 bytes needs a two byte zigzag varint length
+    let long_value = [7u8; 200];
+    let mut log = batch(0, 1_000, &[record(0, 0, None, b"first", &[])]);
+    log.put_slice(&batch(1, 2_000, &[
+      record(0, 5, Some(b"k"), &long_value, &[("trace", b"abc")]),
+      record(1, 300, None, b"third", &[]),
+    ]));
+
+    let records: Vec<_> = RecordBatches::new(log.freeze()).flat_map(|batch| batch.unwrap().log_records().collect::<Vec<_>>()).collect();
+
+    assert_eq!(records.len(), 3);
+    assert_eq!((records[0].offset, records[0].timestamp, records[0].key.clone()), (0, 1_000, None));
+    assert_eq!(records[1].offset, 1);
+    assert_eq!(records[1].timestamp, 2_005);
+    assert_eq!(records[1].key.as_deref(), Some(&b"k"[..]));
+    assert_eq!(records[1].value.as_deref(), Some(&long_value[..]));
+    assert_eq!(records[1].headers, vec![RecordHeader { key: "trace".to_string(), value: Some(Bytes::from_static(b"abc")) }]);
+    assert_eq!((records[2].offset, records[2].timestamp), (2, 2_300));
+    assert_eq!(records[2].value.as_deref(), Some(&b"third"[..]));
+  }
+
+  #[test]
+  fn encodes_what_it_decodes() {
+    let raw = batch(5, 1_000, &[
+      record(0, 0, Some(b"k"), b"v", &[("h", b"x")]),
+      record(1, 7, None, b"w", &[]),
+    ]);
+    let decoded = RecordBatch::decode(&mut raw.clone().freeze()).unwrap();
+
+    let encoded = decoded.encode();
+    assert_eq!(&encoded[ATTRIBUTES_OFFSET..], &raw[ATTRIBUTES_OFFSET..]);
+    assert!(crc_matches(&encoded));
+    assert_eq!(RecordBatch::decode(&mut encoded.freeze()).unwrap().records, decoded.records);
+  }
+
+  #[test]
+  fn compresses_and_decompresses_records() {
+    let raw = batch(5, 1_000, &[record(0, 0, Some(b"k"), &[b'v'; 1_000], &[]), record(1, 7, None, b"w", &[])]);
+    let decoded = RecordBatch::decode(&mut raw.clone().freeze()).unwrap();
+
+    for compression in [Compression::Gzip, Compression::Snappy, Compression::Lz4, Compression::Zstd] {
+      let encoded = RecordBatch { attributes: compression.id(), ..decoded.clone() }.encode();
+      assert!(encoded.len() < raw.len(), "{:?}", compression);
+      assert!(crc_matches(&encoded));
+      let reread = RecordBatch::decode(&mut encoded.freeze()).unwrap();
+      assert_eq!((reread.compression(), reread.records), (compression.id(), decoded.records.clone()));
+    }
+
+    let mut unknown = raw.clone();
+    unknown[ATTRIBUTES_OFFSET + 1] = 5;
+    assert!(matches!(RecordBatch::decode(&mut unknown.freeze()), Err(CodecError::UnknownCompression(5))));
+  }
+
+  #[test]
+  fn stops_before_a_partial_trailing_batch() {
+    let first = batch(0, 0, &[record(0, 0, None, b"ok", &[])]);
+    let second = batch(1, 0, &[record(0, 0, None, b"torn", &[])]);
+    let mut log = first.clone();
+    log.put_slice(&second[..second.len() - 3]);
+
+    let mut batches = RecordBatches::new(log.freeze());
+    assert_eq!(batches.next().unwrap().unwrap().base_offset, 0);
+    assert!(batches.next().is_none());
+    assert_eq!(batches.position(), first.len());
+  }
+
+  #[test]
+  fn reports_corrupt_batches_only_when_more_failed() {
+    let mut report = CorruptBatchReport::default();
+    assert_eq!(report.update(0), None);
+    assert_eq!(
+      report.update(2).unwrap(),
+      "2 record batches failed their CRC check since the last report, 2 since startup"
+    );
+    assert_eq!(report.update(2), None);
+
+    // What main reports from: the count since startup, which other tests add to as well
+    let mut report = CorruptBatchReport::default();
+    report.update(corrupt_batches());
+    let mut raw = batch(0, 0, &[record(0, 0, None, b"value", &[])]);
+    raw[RECORD_BATCH_HEADER_SIZE] ^= 0xff;
+    assert!(!verify_crc(&raw));
+    assert!(report.update(corrupt_batches()).is_some());
+  }
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::common::ErrorCode;
+use crate::kafka::header::RequestHeader;
+use crate::kafka::request_context::RequestContext;
+use crate::kafka::requests::AllRequests;
+use crate::kafka::responses::{AllResponses, Response};
+
+pub type Handler = fn(&Broker, &RequestContext, &RequestHeader, &AllRequests) -> Result<Response>;
+
+/// The two kinds of listener: broker listeners serve clients, controller listeners (those in
+/// `controller.listener.names`) serve the quorum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerType {
+  Broker,
+  Controller,
+}
+
+/// Builds the response for a request that failed as a whole, with `ErrorCode` set on every
+/// entry the response has room for.
+pub type ErrorResponse = fn(&AllRequests, ErrorCode) -> AllResponses;
+
+/// An api the broker implements, along with the versions it understands.
+#[derive(Debug, Clone)]
+pub struct ApiHandler {
+  pub api_key: i16,
+  pub name: &'static str,
+  pub min_version: i16,
+  pub max_version: i16,
+  /// First version using the flexible encoding (compact types, tagged fields, header v2),
+  /// `None` if no supported version is flexible.
+  pub first_flexible_version: Option<i16>,
+  /// Listeners the api is served on. Anywhere else it isn't advertised and the connection
+  /// is closed, as for an unknown api.
+  pub listeners: &'static [ListenerType],
+  pub handle: Handler,
+  pub error_response: ErrorResponse,
+}
+
+impl ApiHandler {
+  pub fn supports(&self, version: i16) -> bool {
+    (self.min_version..=self.max_version).contains(&version)
+  }
+
+  pub fn exposed_on(&self, listener_type: ListenerType) -> bool {
+    self.listeners.contains(&listener_type)
+  }
+
+  pub fn is_flexible(&self, version: i16) -> bool {
+    self.first_flexible_version.is_some_and(|first| version >= first)
+  }
+}
+
+/// All implemented apis keyed by api key. ApiVersions advertises exactly what is in here.
+#[derive(Debug, Clone, Default)]
+pub struct ApiRegistry {
+  handlers: BTreeMap<i16, ApiHandler>,
+}
+
+impl ApiRegistry {
+  pub fn new() -> ApiRegistry {
+    ApiRegistry::default()
+  }
+
+  pub fn register(&mut self, handler: ApiHandler) {
+    self.handlers.insert(handler.api_key, handler);
+  }
+
+  pub fn get(&self, api_key: i16) -> Option<&ApiHandler> {
+    self.handlers.get(&api_key)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &ApiHandler> {
+    self.handlers.values()
+  }
+
+  pub fn is_flexible(&self, api_key: i16, version: i16) -> bool {
+    self.get(api_key).is_some_and(|handler| handler.is_flexible(version))
+  }
+}
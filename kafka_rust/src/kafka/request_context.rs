@@ -0,0 +1,55 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use crate::kafka::config::SecurityProtocol;
+use crate::kafka::registry::ListenerType;
+
+/// Who a connection authenticated as, `User:name` like Kafka's principals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaPrincipal {
+  pub principal_type: String,
+  pub name: String,
+}
+
+impl KafkaPrincipal {
+  pub const USER_TYPE: &'static str = "User";
+
+  pub fn user(name: &str) -> KafkaPrincipal {
+    KafkaPrincipal { principal_type: KafkaPrincipal::USER_TYPE.to_string(), name: name.to_string() }
+  }
+
+  /// Connections that didn't authenticate: PLAINTEXT, and SSL without a client certificate.
+  pub fn anonymous() -> KafkaPrincipal {
+    KafkaPrincipal::user("ANONYMOUS")
+  }
+}
+
+impl fmt::Display for KafkaPrincipal {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.principal_type, self.name)
+  }
+}
+
+/// Where a request came from: the listener its connection was accepted on, the client and
+/// who it authenticated as.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+  pub listener_name: String,
+  pub security_protocol: SecurityProtocol,
+  pub listener_type: ListenerType,
+  pub client_address: Option<SocketAddr>,
+  pub principal: KafkaPrincipal,
+}
+
+impl Default for RequestContext {
+  /// An anonymous client on the default PLAINTEXT broker listener.
+  fn default() -> RequestContext {
+    RequestContext {
+      listener_name: SecurityProtocol::Plaintext.name().to_string(),
+      security_protocol: SecurityProtocol::Plaintext,
+      listener_type: ListenerType::Broker,
+      client_address: None,
+      principal: KafkaPrincipal::anonymous(),
+    }
+  }
+}
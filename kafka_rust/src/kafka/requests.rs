@@ -1,120 +1,704 @@
 use anyhow::Result;
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 
+use crate::kafka::codec::{
+  decode_array, decode_nullable_array, decode_nullable_string, decode_string, Boolean, CompactBytes,
+  CompactNullableBytes, CompactNullableString, CompactString, Decode, DecodeVersioned, Encode, EncodeVersioned, Int16,
+  Int32, Int64, Int8, KafkaBytes, NullableBytes, TaggedFields, Uuid,
+};
+use crate::kafka::header::RequestHeader;
+
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum AllRequests {
   ApiVersionRequest(ApiVersionRequest),
-  DTPRequest(DTPRequest)
+  DTPRequest(DTPRequest),
+  MetadataRequest(MetadataRequest),
+  ProduceRequest(ProduceRequest),
+  FetchRequest(FetchRequest),
+  ListOffsetsRequest(ListOffsetsRequest),
+  DescribeClusterRequest(DescribeClusterRequest),
+  SaslHandshakeRequest(SaslHandshakeRequest),
+  SaslAuthenticateRequest(SaslAuthenticateRequest),
+  DescribeUserScramCredentialsRequest(DescribeUserScramCredentialsRequest),
+  AlterUserScramCredentialsRequest(AlterUserScramCredentialsRequest),
 }
 
 impl AllRequests {
-  pub fn from_bytes(input: BytesMut) -> Result<AllRequests> {
-    let mut peek = input.clone(); // Clone input so we don't consume the actual buffer
-
-    let _message_size = peek.get_i32(); // skip size
-    let api_key = peek.get_i16();       // this tells us which request type it is
+  /// Decodes the body that follows `header`.
+  pub fn from_bytes(header: &RequestHeader, mut input: BytesMut) -> Result<AllRequests> {
+    let version = header.request_api_version;
 
-    match api_key {
+    match header.request_api_key {
         18 => {
             // ApiVersions
-            let request = ApiVersionRequest::from_bytes(input)?;
+            let request = ApiVersionRequest::decode(&mut input, version)?;
             Ok(AllRequests::ApiVersionRequest(request))
         }
+        0 => {
+            // Produce
+            let request = ProduceRequest::decode(&mut input, version)?;
+            Ok(AllRequests::ProduceRequest(request))
+        }
+        1 => {
+            // Fetch
+            let request = FetchRequest::decode(&mut input, version)?;
+            Ok(AllRequests::FetchRequest(request))
+        }
+        2 => {
+            // ListOffsets
+            let request = ListOffsetsRequest::decode(&mut input, version)?;
+            Ok(AllRequests::ListOffsetsRequest(request))
+        }
+        3 => {
+            // Metadata
+            let request = MetadataRequest::decode(&mut input, version)?;
+            Ok(AllRequests::MetadataRequest(request))
+        }
+        17 => {
+            // SaslHandshake
+            let request = SaslHandshakeRequest::decode(&mut input, version)?;
+            Ok(AllRequests::SaslHandshakeRequest(request))
+        }
+        36 => {
+            // SaslAuthenticate
+            let request = SaslAuthenticateRequest::decode(&mut input, version)?;
+            Ok(AllRequests::SaslAuthenticateRequest(request))
+        }
+        50 => {
+            // DescribeUserScramCredentials
+            let request = DescribeUserScramCredentialsRequest::decode(&mut input, version)?;
+            Ok(AllRequests::DescribeUserScramCredentialsRequest(request))
+        }
+        51 => {
+            // AlterUserScramCredentials
+            let request = AlterUserScramCredentialsRequest::decode(&mut input, version)?;
+            Ok(AllRequests::AlterUserScramCredentialsRequest(request))
+        }
+        60 => {
+            // DescribeCluster
+            let request = DescribeClusterRequest::decode(&mut input, version)?;
+            Ok(AllRequests::DescribeClusterRequest(request))
+        }
         75 => {
             // DTP
-            let request = DTPRequest::from_bytes(input)?;
+            let request = DTPRequest::decode(&mut input, version)?;
             Ok(AllRequests::DTPRequest(request))
         }
-        _ => Err(anyhow::anyhow!("Unsupported API key: {}", api_key)),
+        api_key => Err(anyhow::anyhow!("Unsupported API key: {}", api_key)),
     }
   }
+
+  /// Produce with acks=0 is fire and forget, the client reads no response for it.
+  pub fn expects_response(&self) -> bool {
+    !matches!(self, AllRequests::ProduceRequest(ProduceRequest { acks: 0, .. }))
+  }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct ApiVersionRequest {
-    pub message_size: i32,
-    pub request_api_key: i16,
-    pub request_api_version: i16,
-    pub correlation_id: i32
+  pub client_software_name: String,
+  pub client_software_version: String,
 }
 
-impl ApiVersionRequest {
-  pub fn from_bytes(mut input: BytesMut) -> Result<ApiVersionRequest> {
-      let message_size = input.get_i32();
-      let request_api_key = input.get_i16();
-      let request_api_version = input.get_i16();
-      let correlation_id = input.get_i32();
-      Ok(ApiVersionRequest {
-          message_size,
-          request_api_key,
-          request_api_version,
-          correlation_id
-      })
+impl DecodeVersioned for ApiVersionRequest {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<ApiVersionRequest> {
+    // v0-v2 have an empty body
+    if version < 3 {
+      return Ok(ApiVersionRequest::default());
+    }
+
+    let request = ApiVersionRequest {
+      client_software_name: CompactString::decode(input)?,
+      client_software_version: CompactString::decode(input)?,
+    };
+    TaggedFields::decode(input)?;
+    Ok(request)
   }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DTPTopic {
-    pub name_length: i8,
-    pub name: Vec<i8>,
-    pub tag_buffer: i8,
+    pub name: String,
+}
+
+impl DecodeVersioned for DTPTopic {
+  fn decode<B: Buf>(input: &mut B, _version: i16) -> crate::kafka::codec::Result<DTPTopic> {
+    let topic = DTPTopic { name: CompactString::decode(input)? };
+    TaggedFields::decode(input)?;
+    Ok(topic)
+  }
+}
+
+/// Where a paginated DescribeTopicPartitions resumes: the first partition not yet returned.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DTPCursor {
+  pub topic_name: String,
+  pub partition_index: i32,
+  pub tagged_fields: TaggedFields,
 }
 
+impl DecodeVersioned for DTPCursor {
+  fn decode<B: Buf>(input: &mut B, _version: i16) -> crate::kafka::codec::Result<DTPCursor> {
+    Ok(DTPCursor {
+      topic_name: CompactString::decode(input)?,
+      partition_index: Int32::decode(input)?,
+      tagged_fields: TaggedFields::decode(input)?,
+    })
+  }
+}
+
+impl EncodeVersioned for DTPCursor {
+  fn encode(&self, buf: &mut BytesMut, _version: i16) {
+    CompactString::encode(buf, &self.topic_name);
+    Int32::encode(buf, &self.partition_index);
+    TaggedFields::encode(buf, &self.tagged_fields);
+  }
+}
+
+#[derive(Debug, Clone)]
 pub struct DTPRequest {
-  pub message_size: i32,
-  pub request_api_key: i16,
-  pub request_api_version: i16,
-  pub correlation_id: i32,
-  pub client_length: i16,
-  pub client_content: Vec<i8>,
-  pub tag_buffer: i8,
-  pub topic_array_length: i8,
   pub topics: Vec<DTPTopic>,
   pub response_partition_limit: i32,
-  pub cursor: i8,
-  pub tag_buffer_end: i8
-}
-
-impl DTPRequest {
-  pub fn from_bytes(mut input: BytesMut) -> Result<DTPRequest> {
-    let message_size = input.get_i32();
-    let request_api_key = input.get_i16();
-    let request_api_version = input.get_i16();
-    let correlation_id = input.get_i32();
-
-    let client_length = input.get_i16();
-    let client_content = (0..client_length as usize)
-      .map(|_| input.get_i8())
-      .collect::<Vec<i8>>();
-    let tag_buffer = input.get_i8();
-
-    let mut topics: Vec<DTPTopic> = vec![];
-    let topic_array_length = input.get_i8();
-    for _ in 0..topic_array_length - 1 {
-      let topic_len = input.get_i8();
-      let name = (0..(topic_len-1) as usize)
-        .map(|_| input.get_i8())
-        .collect::<Vec<i8>>();
-      let tag_buffer = input.get_i8();
-      topics.push({DTPTopic{name_length: topic_len, name, tag_buffer}});
-    }
-
-    let response_partition_limit = input.get_i32();
-    let cursor = input.get_i8();
-    let tag_buffer_end = input.get_i8();
+  pub cursor: Option<DTPCursor>,
+}
+
+impl DecodeVersioned for DTPRequest {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<DTPRequest> {
+    let topics = decode_array(input, true, |buf| DTPTopic::decode(buf, version))?;
+    let response_partition_limit = Int32::decode(input)?;
+    // Nullable struct: -1 for null, 1 followed by the struct otherwise
+    let cursor = match Int8::decode(input)? {
+      -1 => None,
+      _ => Some(DTPCursor::decode(input, version)?),
+    };
+    TaggedFields::decode(input)?;
 
     Ok(DTPRequest {
-      message_size,
-      request_api_key,
-      request_api_version,
-      correlation_id,
-      client_length, 
-      client_content,
-      tag_buffer,
-      topic_array_length,
       topics,
       response_partition_limit,
       cursor,
-      tag_buffer_end,
     })
   }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetadataRequestTopic {
+  /// v10+
+  pub topic_id: Uuid,
+  /// Nullable from v10, when the topic is asked for by id.
+  pub name: Option<String>,
+}
+
+impl DecodeVersioned for MetadataRequestTopic {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<MetadataRequestTopic> {
+    let flexible = version >= 9;
+    let topic_id = if version >= 10 { Uuid::decode(input)? } else { Uuid::ZERO };
+    let name = if version >= 10 {
+      decode_nullable_string(input, flexible)?
+    } else {
+      Some(decode_string(input, flexible)?)
+    };
+
+    let topic = MetadataRequestTopic {
+      topic_id,
+      name,
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(topic)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetadataRequest {
+  /// `None` asks for every topic. v0 has no null and uses an empty array for that instead.
+  pub topics: Option<Vec<MetadataRequestTopic>>,
+  /// v4+
+  #[allow(dead_code)] // topics only come from the metadata log, none are created on request
+  pub allow_auto_topic_creation: bool,
+  /// v8-10
+  pub include_cluster_authorized_operations: bool,
+  /// v8+
+  pub include_topic_authorized_operations: bool,
+}
+
+impl DecodeVersioned for MetadataRequest {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<MetadataRequest> {
+    let flexible = version >= 9;
+    let topics = match decode_nullable_array(input, flexible, |buf| MetadataRequestTopic::decode(buf, version))? {
+      Some(topics) if version == 0 && topics.is_empty() => None,
+      topics => topics,
+    };
+    let allow_auto_topic_creation = if version >= 4 { Boolean::decode(input)? } else { true };
+    let include_cluster_authorized_operations = if (8..=10).contains(&version) { Boolean::decode(input)? } else { false };
+    let include_topic_authorized_operations = if version >= 8 { Boolean::decode(input)? } else { false };
+
+    let request = MetadataRequest {
+      topics,
+      allow_auto_topic_creation,
+      include_cluster_authorized_operations,
+      include_topic_authorized_operations,
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(request)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProducePartitionData {
+  pub index: i32,
+  /// The RecordBatch(es) to append, still serialized.
+  pub records: Option<Bytes>,
+}
+
+impl DecodeVersioned for ProducePartitionData {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<ProducePartitionData> {
+    let flexible = version >= 9;
+    let partition = ProducePartitionData {
+      index: Int32::decode(input)?,
+      records: if flexible { CompactNullableBytes::decode(input)? } else { NullableBytes::decode(input)? },
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(partition)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProduceTopicData {
+  pub name: String,
+  pub partition_data: Vec<ProducePartitionData>,
+}
+
+impl DecodeVersioned for ProduceTopicData {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<ProduceTopicData> {
+    let flexible = version >= 9;
+    let topic = ProduceTopicData {
+      name: decode_string(input, flexible)?,
+      partition_data: decode_array(input, flexible, |buf| ProducePartitionData::decode(buf, version))?,
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(topic)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProduceRequest {
+  /// v3+
+  pub transactional_id: Option<String>,
+  /// 0 = no response, 1 = leader wrote it, -1 = all in-sync replicas wrote it.
+  pub acks: i16,
+  #[allow(dead_code)] // how long to wait on followers, and there are none
+  pub timeout_ms: i32,
+  pub topic_data: Vec<ProduceTopicData>,
+}
+
+impl DecodeVersioned for ProduceRequest {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<ProduceRequest> {
+    let flexible = version >= 9;
+    let request = ProduceRequest {
+      transactional_id: if version >= 3 { decode_nullable_string(input, flexible)? } else { None },
+      acks: Int16::decode(input)?,
+      timeout_ms: Int32::decode(input)?,
+      topic_data: decode_array(input, flexible, |buf| ProduceTopicData::decode(buf, version))?,
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(request)
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchPartition {
+  pub partition: i32,
+  /// v9+, -1 when the client doesn't know it
+  pub current_leader_epoch: i32,
+  pub fetch_offset: i64,
+  /// v12+, followers use it to find where their log diverged
+  #[allow(dead_code)] // nothing replicates from this broker
+  pub last_fetched_epoch: i32,
+  /// v5+, only meaningful for follower fetches
+  #[allow(dead_code)] // nothing replicates from this broker
+  pub log_start_offset: i64,
+  pub partition_max_bytes: i32,
+}
+
+impl Default for FetchPartition {
+  fn default() -> Self {
+    FetchPartition {
+      partition: 0,
+      current_leader_epoch: -1,
+      fetch_offset: 0,
+      last_fetched_epoch: -1,
+      log_start_offset: -1,
+      partition_max_bytes: 0,
+    }
+  }
+}
+
+impl DecodeVersioned for FetchPartition {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<FetchPartition> {
+    let partition = Int32::decode(input)?;
+    let current_leader_epoch = if version >= 9 { Int32::decode(input)? } else { -1 };
+    let fetch_offset = Int64::decode(input)?;
+    let last_fetched_epoch = if version >= 12 { Int32::decode(input)? } else { -1 };
+    let log_start_offset = if version >= 5 { Int64::decode(input)? } else { -1 };
+
+    let partition = FetchPartition {
+      partition,
+      current_leader_epoch,
+      fetch_offset,
+      last_fetched_epoch,
+      log_start_offset,
+      partition_max_bytes: Int32::decode(input)?,
+    };
+    if version >= 12 {
+      TaggedFields::decode(input)?;
+    }
+    Ok(partition)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FetchTopic {
+  /// v0-12
+  pub topic: String,
+  /// v13+
+  pub topic_id: Uuid,
+  pub partitions: Vec<FetchPartition>,
+}
+
+impl DecodeVersioned for FetchTopic {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<FetchTopic> {
+    let flexible = version >= 12;
+    let topic = if version <= 12 { decode_string(input, flexible)? } else { String::new() };
+    let topic_id = if version >= 13 { Uuid::decode(input)? } else { Uuid::ZERO };
+
+    let topic = FetchTopic {
+      topic,
+      topic_id,
+      partitions: decode_array(input, flexible, |buf| FetchPartition::decode(buf, version))?,
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(topic)
+  }
+}
+
+/// Partitions an incremental fetch session should stop fetching.
+#[derive(Debug, Clone, Default)]
+pub struct ForgottenTopic {
+  /// v7-12
+  pub topic: String,
+  /// v13+
+  pub topic_id: Uuid,
+  pub partitions: Vec<i32>,
+}
+
+impl DecodeVersioned for ForgottenTopic {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<ForgottenTopic> {
+    let flexible = version >= 12;
+    let topic = if version <= 12 { decode_string(input, flexible)? } else { String::new() };
+    let topic_id = if version >= 13 { Uuid::decode(input)? } else { Uuid::ZERO };
+
+    let topic = ForgottenTopic {
+      topic,
+      topic_id,
+      partitions: decode_array(input, flexible, Int32::decode)?,
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(topic)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FetchRequest {
+  /// Tag 0, v12+
+  #[allow(dead_code)] // only KRaft controllers check it against their own
+  pub cluster_id: Option<String>,
+  /// -1 for consumers. A field up to v14, in the ReplicaState tagged field (tag 1) from v15.
+  #[allow(dead_code)] // no followers, every fetch reads like a consumer's
+  pub replica_id: i32,
+  pub max_wait_ms: i32,
+  pub min_bytes: i32,
+  pub max_bytes: i32,
+  /// 0 = READ_UNCOMMITTED, 1 = READ_COMMITTED
+  pub isolation_level: i8,
+  /// v7+
+  pub session_id: i32,
+  /// v7+, -1 for a fetch outside any session
+  pub session_epoch: i32,
+  pub topics: Vec<FetchTopic>,
+  /// v7+
+  pub forgotten_topics_data: Vec<ForgottenTopic>,
+  /// v11+
+  #[allow(dead_code)] // picks a preferred read replica, and each partition has only this one
+  pub rack_id: String,
+}
+
+impl DecodeVersioned for FetchRequest {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<FetchRequest> {
+    let flexible = version >= 12;
+    let replica_id = if version <= 14 { Int32::decode(input)? } else { -1 };
+    let max_wait_ms = Int32::decode(input)?;
+    let min_bytes = Int32::decode(input)?;
+    let max_bytes = Int32::decode(input)?;
+    let isolation_level = Int8::decode(input)?;
+    let (session_id, session_epoch) = if version >= 7 {
+      (Int32::decode(input)?, Int32::decode(input)?)
+    } else {
+      (0, -1)
+    };
+    let topics = decode_array(input, flexible, |buf| FetchTopic::decode(buf, version))?;
+    let forgotten_topics_data = if version >= 7 {
+      decode_array(input, flexible, |buf| ForgottenTopic::decode(buf, version))?
+    } else {
+      vec![]
+    };
+    let rack_id = if version >= 11 { decode_string(input, flexible)? } else { String::new() };
+    let tagged_fields = if flexible { TaggedFields::decode(input)? } else { TaggedFields::default() };
+
+    let cluster_id = tagged_fields.get_with(0, CompactNullableString::decode)?.flatten();
+    let replica_id = match tagged_fields.get_with(1, Int32::decode)? {
+      Some(id) if version >= 15 => id,
+      _ => replica_id,
+    };
+
+    Ok(FetchRequest {
+      cluster_id,
+      replica_id,
+      max_wait_ms,
+      min_bytes,
+      max_bytes,
+      isolation_level,
+      session_id,
+      session_epoch,
+      topics,
+      forgotten_topics_data,
+      rack_id,
+    })
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct ListOffsetsPartition {
+  pub partition_index: i32,
+  /// v4+, -1 when the client doesn't know it
+  pub current_leader_epoch: i32,
+  /// The timestamp to look up, or one of the special values in `handlers::list_offsets`.
+  pub timestamp: i64,
+}
+
+impl Default for ListOffsetsPartition {
+  fn default() -> Self {
+    ListOffsetsPartition {
+      partition_index: 0,
+      current_leader_epoch: -1,
+      timestamp: 0,
+    }
+  }
+}
+
+impl DecodeVersioned for ListOffsetsPartition {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<ListOffsetsPartition> {
+    let partition = ListOffsetsPartition {
+      partition_index: Int32::decode(input)?,
+      current_leader_epoch: if version >= 4 { Int32::decode(input)? } else { -1 },
+      timestamp: Int64::decode(input)?,
+    };
+    if version >= 6 {
+      TaggedFields::decode(input)?;
+    }
+    Ok(partition)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListOffsetsTopic {
+  pub name: String,
+  pub partitions: Vec<ListOffsetsPartition>,
+}
+
+impl DecodeVersioned for ListOffsetsTopic {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<ListOffsetsTopic> {
+    let flexible = version >= 6;
+    let topic = ListOffsetsTopic {
+      name: decode_string(input, flexible)?,
+      partitions: decode_array(input, flexible, |buf| ListOffsetsPartition::decode(buf, version))?,
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(topic)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListOffsetsRequest {
+  /// -1 for consumers
+  #[allow(dead_code)] // followers would see up to the log end offset, which is the high watermark here
+  pub replica_id: i32,
+  /// v2+, 0 = READ_UNCOMMITTED, 1 = READ_COMMITTED
+  pub isolation_level: i8,
+  pub topics: Vec<ListOffsetsTopic>,
+}
+
+impl DecodeVersioned for ListOffsetsRequest {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<ListOffsetsRequest> {
+    let flexible = version >= 6;
+    let request = ListOffsetsRequest {
+      replica_id: Int32::decode(input)?,
+      isolation_level: if version >= 2 { Int8::decode(input)? } else { 0 },
+      topics: decode_array(input, flexible, |buf| ListOffsetsTopic::decode(buf, version))?,
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(request)
+  }
+}
+
+/// `DescribeClusterRequest.endpoint_type` asking for brokers, the only kind before v1.
+pub const BROKER_ENDPOINT_TYPE: i8 = 1;
+/// `DescribeClusterRequest.endpoint_type` asking for controllers.
+pub const CONTROLLER_ENDPOINT_TYPE: i8 = 2;
+
+#[derive(Debug, Clone)]
+pub struct DescribeClusterRequest {
+  pub include_cluster_authorized_operations: bool,
+  /// v1+
+  pub endpoint_type: i8,
+}
+
+impl Default for DescribeClusterRequest {
+  fn default() -> Self {
+    DescribeClusterRequest {
+      include_cluster_authorized_operations: false,
+      endpoint_type: BROKER_ENDPOINT_TYPE,
+    }
+  }
+}
+
+impl DecodeVersioned for DescribeClusterRequest {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<DescribeClusterRequest> {
+    let request = DescribeClusterRequest {
+      include_cluster_authorized_operations: Boolean::decode(input)?,
+      endpoint_type: if version >= 1 { Int8::decode(input)? } else { BROKER_ENDPOINT_TYPE },
+    };
+    TaggedFields::decode(input)?;
+    Ok(request)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SaslHandshakeRequest {
+  pub mechanism: String,
+}
+
+impl DecodeVersioned for SaslHandshakeRequest {
+  fn decode<B: Buf>(input: &mut B, _version: i16) -> crate::kafka::codec::Result<SaslHandshakeRequest> {
+    Ok(SaslHandshakeRequest { mechanism: decode_string(input, false)? })
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SaslAuthenticateRequest {
+  /// The next message of the mechanism's exchange.
+  pub auth_bytes: Bytes,
+}
+
+impl DecodeVersioned for SaslAuthenticateRequest {
+  fn decode<B: Buf>(input: &mut B, version: i16) -> crate::kafka::codec::Result<SaslAuthenticateRequest> {
+    let flexible = version >= 2;
+    let request = SaslAuthenticateRequest {
+      auth_bytes: if flexible { CompactBytes::decode(input)? } else { KafkaBytes::decode(input)? },
+    };
+    if flexible {
+      TaggedFields::decode(input)?;
+    }
+    Ok(request)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UserName {
+  pub name: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DescribeUserScramCredentialsRequest {
+  /// `None` (or empty) describes every user with credentials.
+  pub users: Option<Vec<UserName>>,
+}
+
+impl DecodeVersioned for DescribeUserScramCredentialsRequest {
+  fn decode<B: Buf>(input: &mut B, _version: i16) -> crate::kafka::codec::Result<DescribeUserScramCredentialsRequest> {
+    let request = DescribeUserScramCredentialsRequest {
+      users: decode_nullable_array(input, true, |buf| {
+        let user = UserName { name: CompactString::decode(buf)? };
+        TaggedFields::decode(buf)?;
+        Ok(user)
+      })?,
+    };
+    TaggedFields::decode(input)?;
+    Ok(request)
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScramCredentialDeletion {
+  pub name: String,
+  pub mechanism: i8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScramCredentialUpsertion {
+  pub name: String,
+  pub mechanism: i8,
+  pub iterations: i32,
+  pub salt: Bytes,
+  /// Hi(password, salt, iterations), the client salts so the password never leaves it.
+  pub salted_password: Bytes,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AlterUserScramCredentialsRequest {
+  pub deletions: Vec<ScramCredentialDeletion>,
+  pub upsertions: Vec<ScramCredentialUpsertion>,
+}
+
+impl DecodeVersioned for AlterUserScramCredentialsRequest {
+  fn decode<B: Buf>(input: &mut B, _version: i16) -> crate::kafka::codec::Result<AlterUserScramCredentialsRequest> {
+    let request = AlterUserScramCredentialsRequest {
+      deletions: decode_array(input, true, |buf| {
+        let deletion = ScramCredentialDeletion { name: CompactString::decode(buf)?, mechanism: Int8::decode(buf)? };
+        TaggedFields::decode(buf)?;
+        Ok(deletion)
+      })?,
+      upsertions: decode_array(input, true, |buf| {
+        let upsertion = ScramCredentialUpsertion {
+          name: CompactString::decode(buf)?,
+          mechanism: Int8::decode(buf)?,
+          iterations: Int32::decode(buf)?,
+          salt: CompactBytes::decode(buf)?,
+          salted_password: CompactBytes::decode(buf)?,
+        };
+        TaggedFields::decode(buf)?;
+        Ok(upsertion)
+      })?,
+    };
+    TaggedFields::decode(input)?;
+    Ok(request)
+  }
+}
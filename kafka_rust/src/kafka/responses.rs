@@ -1,213 +1,1012 @@
-use std::{fs::File, io::{self, BufRead, BufReader, Read}, path::Path};
+use bytes::{Bytes, BytesMut};
 
-use anyhow::Result;
-use bytes::{Buf, BytesMut};
+use crate::kafka::codec::{
+  encode_array, encode_nullable_array, encode_nullable_string, encode_string, Array, Boolean, CompactArray,
+  CompactBytes, CompactNullableBytes, CompactNullableString, CompactString, Encode, EncodeVersioned, Int16, Int32,
+  Int64, Int8, KafkaBytes, KafkaString, NullableBytes, TaggedFields, Uuid,
+};
+use crate::kafka::framing;
+use crate::kafka::header::{RequestHeader, ResponseHeader};
+use crate::kafka::requests::{DTPCursor, BROKER_ENDPOINT_TYPE};
 
-use crate::kafka::metadata_log_file::{TopicRecord, MetadataLogFile, Record};
-use std::ops::ControlFlow::Break;
+#[derive(Debug, Clone)]
+pub struct Response {
+  pub header: ResponseHeader,
+  pub api_version: i16,
+  pub body: AllResponses,
+}
 
-use super::metadata_log_file::MetadataComponents;
+impl Response {
+  pub fn new(request: &RequestHeader, body: AllResponses) -> Response {
+    Response {
+      header: ResponseHeader::for_request(request),
+      api_version: request.request_api_version,
+      body,
+    }
+  }
+
+  /// Encodes the response as a complete frame, message_size prefix included.
+  pub fn get_vec(self) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    self.header.encode(&mut buf);
+    self.body.encode(&mut buf, self.api_version);
+    framing::frame(&buf)
+  }
+}
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum AllResponses {
-  ApiVersionResponses(ApiVersionResponses),
-  DTPResponse(DTPResponse)
+  ApiVersionsResponse(ApiVersionsResponse),
+  DTPResponse(DTPResponse),
+  MetadataResponse(MetadataResponse),
+  ProduceResponse(ProduceResponse),
+  FetchResponse(FetchResponse),
+  ListOffsetsResponse(ListOffsetsResponse),
+  DescribeClusterResponse(DescribeClusterResponse),
+  SaslHandshakeResponse(SaslHandshakeResponse),
+  SaslAuthenticateResponse(SaslAuthenticateResponse),
+  DescribeUserScramCredentialsResponse(DescribeUserScramCredentialsResponse),
+  AlterUserScramCredentialsResponse(AlterUserScramCredentialsResponse),
 }
 
-impl AllResponses {
-  pub fn get_vec(self) -> Vec<u8> {
+impl EncodeVersioned for AllResponses {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
     match self {
-      AllResponses::ApiVersionResponses(resp) => resp.get_vec(),
-      AllResponses::DTPResponse(resp) => resp.get_vec()
+      AllResponses::ApiVersionsResponse(resp) => resp.encode(buf, version),
+      AllResponses::DTPResponse(resp) => resp.encode(buf, version),
+      AllResponses::MetadataResponse(resp) => resp.encode(buf, version),
+      AllResponses::ProduceResponse(resp) => resp.encode(buf, version),
+      AllResponses::FetchResponse(resp) => resp.encode(buf, version),
+      AllResponses::ListOffsetsResponse(resp) => resp.encode(buf, version),
+      AllResponses::DescribeClusterResponse(resp) => resp.encode(buf, version),
+      AllResponses::SaslHandshakeResponse(resp) => resp.encode(buf, version),
+      AllResponses::SaslAuthenticateResponse(resp) => resp.encode(buf, version),
+      AllResponses::DescribeUserScramCredentialsResponse(resp) => resp.encode(buf, version),
+      AllResponses::AlterUserScramCredentialsResponse(resp) => resp.encode(buf, version),
     }
   }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum ApiVersionResponses {
-  ApiVersionsResponse(ApiVersionsResponse),
-  UnsupportedVersionResponse(UnsupportedVersionResponse)
+#[derive(Debug, Clone, Default)]
+pub struct ApiVersion {
+  pub api_key: i16,
+  pub min_version: i16,
+  pub max_version: i16,
+  pub tagged_fields: TaggedFields,
 }
 
-impl ApiVersionResponses {
-  pub fn get_vec(self) -> Vec<u8> {
-      match self {
-          ApiVersionResponses::ApiVersionsResponse(resp) => resp.get_vec(),
-          ApiVersionResponses::UnsupportedVersionResponse(resp) => resp.get_vec(),
+impl EncodeVersioned for ApiVersion {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    Int16::encode(buf, &self.api_key);
+    Int16::encode(buf, &self.min_version);
+    Int16::encode(buf, &self.max_version);
+    if version >= 3 {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SupportedFeatureKey {
+  pub name: String,
+  pub min_version: i16,
+  pub max_version: i16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FinalizedFeatureKey {
+  pub name: String,
+  pub max_version_level: i16,
+  pub min_version_level: i16,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiVersionsResponse {
+  pub error_code: i16,
+  pub api_keys: Vec<ApiVersion>,
+  pub throttle_time_ms: i32,
+  // Tagged fields from v3 on
+  pub supported_features: Vec<SupportedFeatureKey>,
+  pub finalized_features_epoch: i64,
+  pub finalized_features: Vec<FinalizedFeatureKey>,
+  pub zk_migration_ready: bool,
+  pub tagged_fields: TaggedFields,
+}
+
+impl Default for ApiVersionsResponse {
+  fn default() -> Self {
+    ApiVersionsResponse {
+      error_code: 0,
+      api_keys: vec![],
+      throttle_time_ms: 0,
+      supported_features: vec![],
+      finalized_features_epoch: -1,
+      finalized_features: vec![],
+      zk_migration_ready: false,
+      tagged_fields: TaggedFields::default(),
+    }
+  }
+}
+
+impl EncodeVersioned for ApiVersionsResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    Int16::encode(buf, &self.error_code);
+    encode_array(buf, &self.api_keys, version >= 3, |buf, api| api.encode(buf, version));
+    if version >= 1 {
+      Int32::encode(buf, &self.throttle_time_ms);
+    }
+    if version >= 3 {
+      // Tagged fields are only written when they differ from their default
+      let mut tagged_fields = self.tagged_fields.clone();
+      if !self.supported_features.is_empty() {
+        tagged_fields.put_with(0, |buf| encode_array(buf, &self.supported_features, true, |buf, feature| {
+          CompactString::encode(buf, &feature.name);
+          Int16::encode(buf, &feature.min_version);
+          Int16::encode(buf, &feature.max_version);
+          TaggedFields::encode(buf, &TaggedFields::default());
+        }));
+      }
+      if self.finalized_features_epoch != -1 {
+        tagged_fields.put::<_, Int64>(1, &self.finalized_features_epoch);
+      }
+      if !self.finalized_features.is_empty() {
+        tagged_fields.put_with(2, |buf| encode_array(buf, &self.finalized_features, true, |buf, feature| {
+          CompactString::encode(buf, &feature.name);
+          Int16::encode(buf, &feature.max_version_level);
+          Int16::encode(buf, &feature.min_version_level);
+          TaggedFields::encode(buf, &TaggedFields::default());
+        }));
+      }
+      if self.zk_migration_ready {
+        tagged_fields.put::<_, Boolean>(3, &self.zk_migration_ready);
       }
+      TaggedFields::encode(buf, &tagged_fields);
+    }
   }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct ApiVersion {
-  pub api_key: [u8; 2],
-  pub min_version: [u8; 2],
-  pub max_version: [u8; 2],
-  pub tag_buffer: [u8; 1],
+#[derive(Debug, Clone, Default)]
+pub struct DTPResponsePartition {
+  pub error_code: i16,
+  pub partition_index: i32,
+  pub leader_id: i32,
+  pub leader_epoch: i32,
+  pub replica_nodes: Vec<i32>,
+  pub isr_nodes: Vec<i32>,
+  pub eligible_leader_replicas: Vec<i32>,
+  pub last_known_elr: Vec<i32>,
+  pub offline_replicas: Vec<i32>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for DTPResponsePartition {
+  fn encode(&self, buf: &mut BytesMut, _version: i16) {
+    Int16::encode(buf, &self.error_code);
+    Int32::encode(buf, &self.partition_index);
+    Int32::encode(buf, &self.leader_id);
+    Int32::encode(buf, &self.leader_epoch);
+    CompactArray::<Int32>::encode(buf, &self.replica_nodes);
+    CompactArray::<Int32>::encode(buf, &self.isr_nodes);
+    CompactArray::<Int32>::encode(buf, &self.eligible_leader_replicas);
+    CompactArray::<Int32>::encode(buf, &self.last_known_elr);
+    CompactArray::<Int32>::encode(buf, &self.offline_replicas);
+    TaggedFields::encode(buf, &self.tagged_fields);
+  }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct ApiVersionsResponse<> {
-  pub message: [u8; 4],
-  pub correlation_id: [u8; 4],
-  pub error_code: [u8; 2],
-  pub api_keys_len: [u8; 1],
-  pub api_versions: [ApiVersion; 2],
-  pub throttle_time_ms: [u8; 4],
-  pub tag_buffer_end: [u8; 1]
+#[derive(Debug, Clone, Default)]
+pub struct DTPResponseBodyTopic {
+  pub error_code: i16,
+  pub topic_name: Option<String>,
+  pub topic_id: Uuid,
+  pub is_internal: bool,
+  pub partitions: Vec<DTPResponsePartition>,
+  pub topic_authorized_operations: i32,
+  pub tagged_fields: TaggedFields,
 }
 
-impl ApiVersionsResponse {
-  pub fn get_vec(self) -> Vec<u8> {
-    let mut buf = vec![];
+impl EncodeVersioned for DTPResponseBodyTopic {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    Int16::encode(buf, &self.error_code);
+    CompactNullableString::encode(buf, &self.topic_name);
+    Uuid::encode(buf, &self.topic_id);
+    Boolean::encode(buf, &self.is_internal);
+    encode_array(buf, &self.partitions, true, |buf, partition| partition.encode(buf, version));
+    Int32::encode(buf, &self.topic_authorized_operations);
+    TaggedFields::encode(buf, &self.tagged_fields);
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DTPResponse {
+  pub throttle_time: i32,
+  pub topics: Vec<DTPResponseBodyTopic>,
+  pub next_cursor: Option<DTPCursor>,
+  pub tagged_fields: TaggedFields,
+}
 
-    // Header
-    buf.extend_from_slice(&self.message);
-    buf.extend_from_slice(&self.correlation_id);
-    buf.extend_from_slice(&self.error_code);
-    buf.extend_from_slice(&self.api_keys_len);
-    
-    for version in &self.api_versions {
-      buf.extend_from_slice(&version.api_key);
-      buf.extend_from_slice(&version.min_version);
-      buf.extend_from_slice(&version.max_version);
-      buf.extend_from_slice(&version.tag_buffer);
+impl EncodeVersioned for DTPResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    Int32::encode(buf, &self.throttle_time);
+    encode_array(buf, &self.topics, true, |buf, topic| topic.encode(buf, version));
+    match &self.next_cursor {
+      Some(cursor) => {
+        Int8::encode(buf, &1);
+        cursor.encode(buf, version);
+      }
+      None => Int8::encode(buf, &-1),
     }
+    TaggedFields::encode(buf, &self.tagged_fields);
+  }
+}
 
-    buf.extend_from_slice(&self.throttle_time_ms);
-    buf.extend_from_slice(&self.tag_buffer_end);
+/// `*_authorized_operations` when the client didn't ask for them.
+pub const AUTHORIZED_OPERATIONS_OMITTED: i32 = i32::MIN;
+
+#[derive(Debug, Clone, Default)]
+pub struct MetadataResponseBroker {
+  pub node_id: i32,
+  pub host: String,
+  pub port: i32,
+  /// v1+
+  pub rack: Option<String>,
+  pub tagged_fields: TaggedFields,
+}
 
-    buf
+impl EncodeVersioned for MetadataResponseBroker {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 9;
+    Int32::encode(buf, &self.node_id);
+    encode_string(buf, &self.host, flexible);
+    Int32::encode(buf, &self.port);
+    if version >= 1 {
+      encode_nullable_string(buf, &self.rack, flexible);
+    }
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetadataResponsePartition {
+  pub error_code: i16,
+  pub partition_index: i32,
+  pub leader_id: i32,
+  /// v7+
+  pub leader_epoch: i32,
+  pub replica_nodes: Vec<i32>,
+  pub isr_nodes: Vec<i32>,
+  /// v5+
+  pub offline_replicas: Vec<i32>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for MetadataResponsePartition {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 9;
+    Int16::encode(buf, &self.error_code);
+    Int32::encode(buf, &self.partition_index);
+    Int32::encode(buf, &self.leader_id);
+    if version >= 7 {
+      Int32::encode(buf, &self.leader_epoch);
+    }
+    encode_array(buf, &self.replica_nodes, flexible, Int32::encode);
+    encode_array(buf, &self.isr_nodes, flexible, Int32::encode);
+    if version >= 5 {
+      encode_array(buf, &self.offline_replicas, flexible, Int32::encode);
+    }
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetadataResponseTopic {
+  pub error_code: i16,
+  /// Only null from v12, for a topic id we don't know.
+  pub name: Option<String>,
+  /// v10+
+  pub topic_id: Uuid,
+  /// v1+
+  pub is_internal: bool,
+  pub partitions: Vec<MetadataResponsePartition>,
+  /// v8+
+  pub topic_authorized_operations: i32,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for MetadataResponseTopic {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 9;
+    Int16::encode(buf, &self.error_code);
+    if version >= 12 {
+      encode_nullable_string(buf, &self.name, flexible);
+    } else {
+      encode_string(buf, &self.name.clone().unwrap_or_default(), flexible);
+    }
+    if version >= 10 {
+      Uuid::encode(buf, &self.topic_id);
+    }
+    if version >= 1 {
+      Boolean::encode(buf, &self.is_internal);
+    }
+    encode_array(buf, &self.partitions, flexible, |buf, partition| partition.encode(buf, version));
+    if version >= 8 {
+      Int32::encode(buf, &self.topic_authorized_operations);
+    }
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
   }
 }
 
 #[derive(Debug, Clone)]
-pub struct DTPResponseBodyTopic {
-  pub error_code: [u8; 2],
-  pub topic_name_length: [u8; 1],
-  pub topic_name: Vec<u8>,
-  pub topic_id: [u8; 16],
-  pub is_internal: [u8; 1],
-  pub partitions_array: [u8; 1],
-  pub topic_authorized_operations: [u8; 4],
-  pub tag_buffer: [u8; 1]
+pub struct MetadataResponse {
+  /// v3+
+  pub throttle_time_ms: i32,
+  pub brokers: Vec<MetadataResponseBroker>,
+  /// v2+
+  pub cluster_id: Option<String>,
+  /// v1+
+  pub controller_id: i32,
+  pub topics: Vec<MetadataResponseTopic>,
+  /// v8-10
+  pub cluster_authorized_operations: i32,
+  pub tagged_fields: TaggedFields,
+}
+
+impl Default for MetadataResponse {
+  fn default() -> Self {
+    MetadataResponse {
+      throttle_time_ms: 0,
+      brokers: vec![],
+      cluster_id: None,
+      controller_id: -1,
+      topics: vec![],
+      cluster_authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+      tagged_fields: TaggedFields::default(),
+    }
+  }
+}
+
+impl EncodeVersioned for MetadataResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 9;
+    if version >= 3 {
+      Int32::encode(buf, &self.throttle_time_ms);
+    }
+    encode_array(buf, &self.brokers, flexible, |buf, broker| broker.encode(buf, version));
+    if version >= 2 {
+      encode_nullable_string(buf, &self.cluster_id, flexible);
+    }
+    if version >= 1 {
+      Int32::encode(buf, &self.controller_id);
+    }
+    encode_array(buf, &self.topics, flexible, |buf, topic| topic.encode(buf, version));
+    if (8..=10).contains(&version) {
+      Int32::encode(buf, &self.cluster_authorized_operations);
+    }
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BatchIndexAndErrorMessage {
+  pub batch_index: i32,
+  pub batch_index_error_message: Option<String>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for BatchIndexAndErrorMessage {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 9;
+    Int32::encode(buf, &self.batch_index);
+    encode_nullable_string(buf, &self.batch_index_error_message, flexible);
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProducePartitionResponse {
+  pub index: i32,
+  pub error_code: i16,
+  pub base_offset: i64,
+  /// v2+, -1 unless the topic uses LogAppendTime
+  pub log_append_time_ms: i64,
+  /// v5+
+  pub log_start_offset: i64,
+  /// v8+
+  pub record_errors: Vec<BatchIndexAndErrorMessage>,
+  /// v8+
+  pub error_message: Option<String>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl Default for ProducePartitionResponse {
+  fn default() -> Self {
+    ProducePartitionResponse {
+      index: 0,
+      error_code: 0,
+      base_offset: -1,
+      log_append_time_ms: -1,
+      log_start_offset: -1,
+      record_errors: vec![],
+      error_message: None,
+      tagged_fields: TaggedFields::default(),
+    }
+  }
+}
+
+impl EncodeVersioned for ProducePartitionResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 9;
+    Int32::encode(buf, &self.index);
+    Int16::encode(buf, &self.error_code);
+    Int64::encode(buf, &self.base_offset);
+    if version >= 2 {
+      Int64::encode(buf, &self.log_append_time_ms);
+    }
+    if version >= 5 {
+      Int64::encode(buf, &self.log_start_offset);
+    }
+    if version >= 8 {
+      encode_array(buf, &self.record_errors, flexible, |buf, error| error.encode(buf, version));
+      encode_nullable_string(buf, &self.error_message, flexible);
+    }
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProduceTopicResponse {
+  pub name: String,
+  pub partition_responses: Vec<ProducePartitionResponse>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for ProduceTopicResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 9;
+    encode_string(buf, &self.name, flexible);
+    encode_array(buf, &self.partition_responses, flexible, |buf, partition| partition.encode(buf, version));
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProduceResponse {
+  pub responses: Vec<ProduceTopicResponse>,
+  /// v1+
+  pub throttle_time_ms: i32,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for ProduceResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 9;
+    encode_array(buf, &self.responses, flexible, |buf, topic| topic.encode(buf, version));
+    if version >= 1 {
+      Int32::encode(buf, &self.throttle_time_ms);
+    }
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AbortedTransaction {
+  pub producer_id: i64,
+  pub first_offset: i64,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for AbortedTransaction {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    Int64::encode(buf, &self.producer_id);
+    Int64::encode(buf, &self.first_offset);
+    if version >= 12 {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
 }
 
 #[derive(Debug, Clone)]
-pub struct DTPResponseBody {
-  pub throttle_time: [u8; 4],
-  pub topic_arr_length: [u8; 1],
-  pub topic: DTPResponseBodyTopic,
-  pub next_cursor: [u8; 1],
-  pub tag_buffer: [u8; 1],
+pub struct FetchPartitionResponse {
+  pub partition_index: i32,
+  pub error_code: i16,
+  pub high_watermark: i64,
+  /// v4+
+  pub last_stable_offset: i64,
+  /// v5+
+  pub log_start_offset: i64,
+  /// v4+, null unless the fetch is READ_COMMITTED
+  pub aborted_transactions: Option<Vec<AbortedTransaction>>,
+  /// v11+
+  pub preferred_read_replica: i32,
+  pub records: Option<Bytes>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl Default for FetchPartitionResponse {
+  fn default() -> Self {
+    FetchPartitionResponse {
+      partition_index: 0,
+      error_code: 0,
+      high_watermark: -1,
+      last_stable_offset: -1,
+      log_start_offset: -1,
+      aborted_transactions: None,
+      preferred_read_replica: -1,
+      records: None,
+      tagged_fields: TaggedFields::default(),
+    }
+  }
+}
+
+impl EncodeVersioned for FetchPartitionResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 12;
+    Int32::encode(buf, &self.partition_index);
+    Int16::encode(buf, &self.error_code);
+    Int64::encode(buf, &self.high_watermark);
+    if version >= 4 {
+      Int64::encode(buf, &self.last_stable_offset);
+    }
+    if version >= 5 {
+      Int64::encode(buf, &self.log_start_offset);
+    }
+    if version >= 4 {
+      let aborted = self.aborted_transactions.as_deref();
+      encode_nullable_array(buf, aborted, flexible, |buf, transaction| transaction.encode(buf, version));
+    }
+    if version >= 11 {
+      Int32::encode(buf, &self.preferred_read_replica);
+    }
+    if flexible {
+      CompactNullableBytes::encode(buf, &self.records);
+      TaggedFields::encode(buf, &self.tagged_fields);
+    } else {
+      NullableBytes::encode(buf, &self.records);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FetchTopicResponse {
+  /// v0-12
+  pub topic: String,
+  /// v13+
+  pub topic_id: Uuid,
+  pub partitions: Vec<FetchPartitionResponse>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for FetchTopicResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 12;
+    if version <= 12 {
+      encode_string(buf, &self.topic, flexible);
+    } else {
+      Uuid::encode(buf, &self.topic_id);
+    }
+    encode_array(buf, &self.partitions, flexible, |buf, partition| partition.encode(buf, version));
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FetchResponse {
+  pub throttle_time_ms: i32,
+  /// v7+, errors with the fetch session as a whole
+  pub error_code: i16,
+  /// v7+
+  pub session_id: i32,
+  pub responses: Vec<FetchTopicResponse>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for FetchResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 12;
+    Int32::encode(buf, &self.throttle_time_ms);
+    if version >= 7 {
+      Int16::encode(buf, &self.error_code);
+      Int32::encode(buf, &self.session_id);
+    }
+    encode_array(buf, &self.responses, flexible, |buf, topic| topic.encode(buf, version));
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
 }
 
 #[derive(Debug, Clone)]
-pub struct DTPResponse {
-  pub message: [u8; 4],
-  pub correlation_id: [u8; 4],
-  pub tag_buffer: [u8; 1],
-  pub response_body: DTPResponseBody
-}
-
-
-impl DTPResponse {
-  pub fn get_vec(&self) -> Vec<u8> { 
-    let mut buf = vec![];
-    let body = &self.response_body;
-    let topic = &self.response_body.topic;
-
-    // Header
-    buf.extend_from_slice(&self.message);
-    buf.extend_from_slice(&self.correlation_id);
-    buf.extend_from_slice(&self.tag_buffer);
-    
-    // Body
-    buf.extend_from_slice(&body.throttle_time);
-    buf.extend_from_slice(&body.topic_arr_length);
-    
-    // Topic
-    buf.extend_from_slice(&topic.error_code);
-    buf.extend_from_slice(&topic.topic_name_length);
-    buf.extend_from_slice(&topic.topic_name);
-    buf.extend_from_slice(&topic.topic_id);
-    buf.extend_from_slice(&topic.is_internal);
-    buf.extend_from_slice(&topic.partitions_array);
-    buf.extend_from_slice(&topic.topic_authorized_operations);
-    buf.extend_from_slice(&topic.tag_buffer);
-
-    // Body again
-    buf.extend_from_slice(&body.next_cursor);
-    buf.extend_from_slice(&body.tag_buffer);
-
-    buf
-  }
-
-  pub fn helper(&self, log_file: &MetadataLogFile) -> [u8; 16] {
-    let binding = String::from_utf8(self.response_body.topic.topic_name.clone()).expect("Invalid UTF-8");
-    let topic_name_as_string: &str = binding.as_str();
-
-    println!("Number of records: {}", log_file.records.len());
-
-    let mut res: [u8; 16] = 0_i128.to_be_bytes();
-    log_file.records.iter().for_each(|record| {
-      println!("do iter stuff: {:?}", record.topic_records);
-      println!("on this amount of topic records: {:?}", record.value_length);
-      return record.topic_records.iter().for_each(|tr| {
-        let tr_name_binding = String::from_utf8(tr.name.clone()).expect("Invalid UTF-8");
-        let tr_name = tr_name_binding.as_str();
-        println!("tr name: {:?}", tr_name);
-        println!("topic_name_as_string: {:?}", topic_name_as_string);
-        // if tr_name == topic_name_as_string {
-          res = tr.topic_uuid.to_be_bytes();
-        // }
-      })
-    });
+pub struct ListOffsetsPartitionResponse {
+  pub partition_index: i32,
+  pub error_code: i16,
+  /// -1 unless a timestamp was looked up
+  pub timestamp: i64,
+  pub offset: i64,
+  /// v4+
+  pub leader_epoch: i32,
+  pub tagged_fields: TaggedFields,
+}
 
-    if u128::from_be_bytes(res) == 0 as u128 {
-      println!("DIDN'T FIND A CORRECT TopicRecord WITH GIVEN NAME: {:?}!!!!", topic_name_as_string);
+impl Default for ListOffsetsPartitionResponse {
+  fn default() -> Self {
+    ListOffsetsPartitionResponse {
+      partition_index: 0,
+      error_code: 0,
+      timestamp: -1,
+      offset: -1,
+      leader_epoch: -1,
+      tagged_fields: TaggedFields::default(),
     }
+  }
+}
 
-    res
+impl EncodeVersioned for ListOffsetsPartitionResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    Int32::encode(buf, &self.partition_index);
+    Int16::encode(buf, &self.error_code);
+    Int64::encode(buf, &self.timestamp);
+    Int64::encode(buf, &self.offset);
+    if version >= 4 {
+      Int32::encode(buf, &self.leader_epoch);
+    }
+    if version >= 6 {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
   }
-  
-  pub fn topic_exists_in_log(&self) -> anyhow::Result<[u8; 16]> {
-    let mut file = File::open("/tmp/kraft-combined-logs/__cluster_metadata-0/00000000000000000000.log")?;
-    let mut buffer: [u8; 1024] = [0; 1024];
+}
 
-    let bytes_read = match file.read(&mut buffer) {
-      Ok(0) => {
-          println!("Log file has 0 bytes!");
-          0
-      }
-      Ok(n) => n,
-      Err(_) => panic!("ups"),
-    };
+#[derive(Debug, Clone, Default)]
+pub struct ListOffsetsTopicResponse {
+  pub name: String,
+  pub partitions: Vec<ListOffsetsPartitionResponse>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for ListOffsetsTopicResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 6;
+    encode_string(buf, &self.name, flexible);
+    encode_array(buf, &self.partitions, flexible, |buf, partition| partition.encode(buf, version));
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
 
-    let buf: BytesMut = bytes::BytesMut::from(&buffer[..bytes_read]);
+#[derive(Debug, Clone, Default)]
+pub struct ListOffsetsResponse {
+  /// v2+
+  pub throttle_time_ms: i32,
+  pub topics: Vec<ListOffsetsTopicResponse>,
+  pub tagged_fields: TaggedFields,
+}
 
-    println!("Read file with n: {:?} bytes!", bytes_read);
+impl EncodeVersioned for ListOffsetsResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 6;
+    if version >= 2 {
+      Int32::encode(buf, &self.throttle_time_ms);
+    }
+    encode_array(buf, &self.topics, flexible, |buf, topic| topic.encode(buf, version));
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
 
-    let log_file: MetadataLogFile = MetadataLogFile::from_bytes(buf).unwrap();
-    println!("Log file: {:?}", log_file);
+#[derive(Debug, Clone, Default)]
+pub struct DescribeClusterBroker {
+  pub broker_id: i32,
+  pub host: String,
+  pub port: i32,
+  pub rack: Option<String>,
+  pub tagged_fields: TaggedFields,
+}
 
-    Ok(self.helper(&log_file))
+#[derive(Debug, Clone)]
+pub struct DescribeClusterResponse {
+  pub throttle_time_ms: i32,
+  pub error_code: i16,
+  pub error_message: Option<String>,
+  /// v1+
+  pub endpoint_type: i8,
+  pub cluster_id: String,
+  pub controller_id: i32,
+  pub brokers: Vec<DescribeClusterBroker>,
+  pub cluster_authorized_operations: i32,
+  pub tagged_fields: TaggedFields,
+}
+
+impl Default for DescribeClusterResponse {
+  fn default() -> Self {
+    DescribeClusterResponse {
+      throttle_time_ms: 0,
+      error_code: 0,
+      error_message: None,
+      endpoint_type: BROKER_ENDPOINT_TYPE,
+      cluster_id: String::new(),
+      controller_id: -1,
+      brokers: vec![],
+      cluster_authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+      tagged_fields: TaggedFields::default(),
+    }
+  }
+}
+
+impl EncodeVersioned for DescribeClusterResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    Int32::encode(buf, &self.throttle_time_ms);
+    Int16::encode(buf, &self.error_code);
+    CompactNullableString::encode(buf, &self.error_message);
+    if version >= 1 {
+      Int8::encode(buf, &self.endpoint_type);
+    }
+    CompactString::encode(buf, &self.cluster_id);
+    Int32::encode(buf, &self.controller_id);
+    encode_array(buf, &self.brokers, true, |buf, broker| {
+      Int32::encode(buf, &broker.broker_id);
+      CompactString::encode(buf, &broker.host);
+      Int32::encode(buf, &broker.port);
+      CompactNullableString::encode(buf, &broker.rack);
+      TaggedFields::encode(buf, &broker.tagged_fields);
+    });
+    Int32::encode(buf, &self.cluster_authorized_operations);
+    TaggedFields::encode(buf, &self.tagged_fields);
   }
 }
-  
-#[derive(Debug, Copy, Clone)]
-pub struct UnsupportedVersionResponse {
-  pub message: [u8; 4],
-  pub correlation_id: [u8; 4],
-  pub error_code: [u8; 2]
+
+#[derive(Debug, Clone, Default)]
+pub struct SaslHandshakeResponse {
+  pub error_code: i16,
+  /// The mechanisms enabled on the listener.
+  pub mechanisms: Vec<String>,
 }
 
-impl UnsupportedVersionResponse {
-  pub fn get_vec(self) -> Vec<u8> {
-      return [
-          self.message.as_slice(),
-          self.correlation_id.as_slice(),
-          self.error_code.as_slice(),
-      ].concat();
+impl EncodeVersioned for SaslHandshakeResponse {
+  fn encode(&self, buf: &mut BytesMut, _version: i16) {
+    Int16::encode(buf, &self.error_code);
+    Array::<KafkaString>::encode(buf, &self.mechanisms);
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SaslAuthenticateResponse {
+  pub error_code: i16,
+  pub error_message: Option<String>,
+  pub auth_bytes: Bytes,
+  /// v1+, how long until the client has to re-authenticate, 0 for never.
+  pub session_lifetime_ms: i64,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for SaslAuthenticateResponse {
+  fn encode(&self, buf: &mut BytesMut, version: i16) {
+    let flexible = version >= 2;
+    Int16::encode(buf, &self.error_code);
+    encode_nullable_string(buf, &self.error_message, flexible);
+    if flexible {
+      CompactBytes::encode(buf, &self.auth_bytes);
+    } else {
+      KafkaBytes::encode(buf, &self.auth_bytes);
+    }
+    if version >= 1 {
+      Int64::encode(buf, &self.session_lifetime_ms);
+    }
+    if flexible {
+      TaggedFields::encode(buf, &self.tagged_fields);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CredentialInfo {
+  pub mechanism: i8,
+  pub iterations: i32,
+  pub tagged_fields: TaggedFields,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DescribeUserScramCredentialsResult {
+  pub user: String,
+  pub error_code: i16,
+  pub error_message: Option<String>,
+  pub credential_infos: Vec<CredentialInfo>,
+  pub tagged_fields: TaggedFields,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DescribeUserScramCredentialsResponse {
+  pub throttle_time_ms: i32,
+  pub error_code: i16,
+  pub error_message: Option<String>,
+  pub results: Vec<DescribeUserScramCredentialsResult>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for DescribeUserScramCredentialsResponse {
+  fn encode(&self, buf: &mut BytesMut, _version: i16) {
+    Int32::encode(buf, &self.throttle_time_ms);
+    Int16::encode(buf, &self.error_code);
+    CompactNullableString::encode(buf, &self.error_message);
+    encode_array(buf, &self.results, true, |buf, result| {
+      CompactString::encode(buf, &result.user);
+      Int16::encode(buf, &result.error_code);
+      CompactNullableString::encode(buf, &result.error_message);
+      encode_array(buf, &result.credential_infos, true, |buf, info| {
+        Int8::encode(buf, &info.mechanism);
+        Int32::encode(buf, &info.iterations);
+        TaggedFields::encode(buf, &info.tagged_fields);
+      });
+      TaggedFields::encode(buf, &result.tagged_fields);
+    });
+    TaggedFields::encode(buf, &self.tagged_fields);
   }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AlterUserScramCredentialsResult {
+  pub user: String,
+  pub error_code: i16,
+  pub error_message: Option<String>,
+  pub tagged_fields: TaggedFields,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AlterUserScramCredentialsResponse {
+  pub throttle_time_ms: i32,
+  pub results: Vec<AlterUserScramCredentialsResult>,
+  pub tagged_fields: TaggedFields,
+}
+
+impl EncodeVersioned for AlterUserScramCredentialsResponse {
+  fn encode(&self, buf: &mut BytesMut, _version: i16) {
+    Int32::encode(buf, &self.throttle_time_ms);
+    encode_array(buf, &self.results, true, |buf, result| {
+      CompactString::encode(buf, &result.user);
+      Int16::encode(buf, &result.error_code);
+      CompactNullableString::encode(buf, &result.error_message);
+      TaggedFields::encode(buf, &result.tagged_fields);
+    });
+    TaggedFields::encode(buf, &self.tagged_fields);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::{Buf, Bytes};
+
+  use super::*;
+  use crate::kafka::codec::{decode_array, CompactArray, CompactNullableString, Decode};
+
+  /// Checks the length prefix matches what follows it and hands back the payload.
+  fn unframe(frame: Vec<u8>) -> Bytes {
+    let mut frame = Bytes::from(frame);
+    let message_size = frame.get_i32();
+    assert_eq!(message_size as usize, frame.len(), "message_size must cover header + body");
+    frame
+  }
+
+  fn request_header(api_key: i16, api_version: i16, correlation_id: i32) -> RequestHeader {
+    let apis = crate::kafka::handlers::registry();
+    RequestHeader {
+      request_api_key: api_key,
+      request_api_version: api_version,
+      correlation_id,
+      flexible: apis.is_flexible(api_key, api_version),
+      ..Default::default()
+    }
+  }
+
+  fn api_versions_response(version: i16) -> Response {
+    Response::new(&request_header(18, version, 1234), AllResponses::ApiVersionsResponse(ApiVersionsResponse {
+      api_keys: vec![
+        ApiVersion { api_key: 18, min_version: 0, max_version: 4, ..Default::default() },
+        ApiVersion { api_key: 75, min_version: 0, max_version: 0, ..Default::default() },
+      ],
+      supported_features: vec![SupportedFeatureKey { name: "metadata.version".to_string(), min_version: 1, max_version: 20 }],
+      ..Default::default()
+    }))
+  }
+
+  #[test]
+  fn api_versions_is_framed_for_every_version() {
+    for version in 0..=4 {
+      let mut payload = unframe(api_versions_response(version).get_vec());
+
+      assert_eq!(Int32::decode(&mut payload).unwrap(), 1234);
+      assert_eq!(Int16::decode(&mut payload).unwrap(), 0);
+      let api_keys = decode_array(&mut payload, version >= 3, |buf| {
+        let key = Int16::decode(buf)?;
+        let _min = Int16::decode(buf)?;
+        let _max = Int16::decode(buf)?;
+        if version >= 3 {
+          TaggedFields::decode(buf)?;
+        }
+        Ok(key)
+      }).unwrap();
+      assert_eq!(api_keys, vec![18, 75]);
+      if version >= 1 {
+        assert_eq!(Int32::decode(&mut payload).unwrap(), 0);
+      }
+      if version >= 3 {
+        let tagged_fields = TaggedFields::decode(&mut payload).unwrap();
+        let mut features = tagged_fields.get(0).unwrap().clone();
+        let names = decode_array(&mut features, true, |buf| {
+          let name = CompactString::decode(buf)?;
+          assert_eq!((Int16::decode(buf)?, Int16::decode(buf)?), (1, 20));
+          TaggedFields::decode(buf)?;
+          Ok(name)
+        }).unwrap();
+        assert_eq!(names, vec!["metadata.version"]);
+      }
+      assert!(payload.is_empty(), "trailing bytes for version {version}");
+    }
+  }
+
+  #[test]
+  fn describe_topic_partitions_is_framed_for_any_topic_name_length() {
+    for name in ["a", "foo", "a-much-longer-topic-name-than-the-tester-uses"] {
+      let response = Response::new(&request_header(75, 0, 99), AllResponses::DTPResponse(DTPResponse {
+        topics: vec![DTPResponseBodyTopic {
+          topic_name: Some(name.to_string()),
+          topic_id: Uuid(42),
+          partitions: vec![DTPResponsePartition { replica_nodes: vec![1], isr_nodes: vec![1], ..Default::default() }],
+          ..Default::default()
+        }],
+        next_cursor: Some(DTPCursor { topic_name: name.to_string(), partition_index: 1, ..Default::default() }),
+        ..Default::default()
+      }));
+      let mut payload = unframe(response.get_vec());
+
+      assert_eq!(Int32::decode(&mut payload).unwrap(), 99);
+      TaggedFields::decode(&mut payload).unwrap();
+      assert_eq!(Int32::decode(&mut payload).unwrap(), 0);
+      let topics = decode_array(&mut payload, true, |buf| {
+        let _error_code = Int16::decode(buf)?;
+        let name = CompactNullableString::decode(buf)?;
+        let id = Uuid::decode(buf)?;
+        let _is_internal = Boolean::decode(buf)?;
+        let partitions = decode_array(buf, true, |buf| {
+          let _error_code = Int16::decode(buf)?;
+          let index = Int32::decode(buf)?;
+          let _leader = Int32::decode(buf)?;
+          let _leader_epoch = Int32::decode(buf)?;
+          for _ in 0..5 {
+            CompactArray::<Int32>::decode(buf)?;
+          }
+          TaggedFields::decode(buf)?;
+          Ok(index)
+        })?;
+        let _authorized_operations = Int32::decode(buf)?;
+        TaggedFields::decode(buf)?;
+        Ok((name, id, partitions))
+      }).unwrap();
+      assert_eq!(topics, vec![(Some(name.to_string()), Uuid(42), vec![0])]);
+      assert_eq!(Int8::decode(&mut payload).unwrap(), 1);
+      assert_eq!(CompactString::decode(&mut payload).unwrap(), name);
+      assert_eq!(Int32::decode(&mut payload).unwrap(), 1);
+      TaggedFields::decode(&mut payload).unwrap();
+      TaggedFields::decode(&mut payload).unwrap();
+      assert!(payload.is_empty());
+    }
+  }
+}
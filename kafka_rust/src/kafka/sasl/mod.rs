@@ -0,0 +1,330 @@
+pub mod oauthbearer;
+pub mod plain;
+pub mod scram;
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+
+use crate::kafka::broker::Broker;
+use crate::kafka::common::ErrorCode;
+use crate::kafka::handlers::{api_versions, sasl_authenticate, sasl_handshake};
+use crate::kafka::log_segment::now_ms;
+use crate::kafka::request_context::{KafkaPrincipal, RequestContext};
+use crate::kafka::requests::AllRequests;
+use crate::kafka::responses::{AllResponses, Response, SaslAuthenticateResponse};
+use crate::kafka::sasl::scram::ScramMechanism;
+
+pub const PLAIN: &str = "PLAIN";
+pub const OAUTHBEARER: &str = "OAUTHBEARER";
+
+/// The `sasl.enabled.mechanisms` we can serve.
+pub const MECHANISMS: [&str; 4] = [PLAIN, scram::SCRAM_SHA_256, scram::SCRAM_SHA_512, OAUTHBEARER];
+
+/// Where an exchange stands after the client's latest message.
+pub enum Step {
+  /// The exchange goes on, the client answers this with its next message.
+  Challenge(Bytes),
+  /// The client authenticated. `expires_at_ms` is when its credential runs out, if it does.
+  Complete { auth_bytes: Bytes, principal: KafkaPrincipal, expires_at_ms: Option<i64> },
+}
+
+/// The server side of one mechanism's exchange, a new one for every authentication.
+pub trait SaslServer: Send {
+  /// Takes the client's next message. An `Err` fails the authentication and its message
+  /// goes back to the client.
+  fn evaluate(&mut self, broker: &Broker, response: &[u8]) -> Result<Step>;
+}
+
+fn new_server(broker: &Broker, context: &RequestContext, mechanism: &str) -> Option<Box<dyn SaslServer>> {
+  if !broker.config.sasl_enabled_mechanisms.iter().any(|enabled| enabled == mechanism) {
+    return None;
+  }
+  match mechanism {
+    PLAIN => {
+      let users = broker.config.sasl_plain_users.get(&context.listener_name).cloned().unwrap_or_default();
+      Some(Box::new(plain::PlainServer::new(users)))
+    }
+    OAUTHBEARER => Some(Box::new(oauthbearer::OAuthBearerServer::default())),
+    _ => ScramMechanism::from_name(mechanism).map(|mechanism| Box::new(scram::ScramServer::new(mechanism)) as Box<dyn SaslServer>),
+  }
+}
+
+enum State {
+  /// Nothing but ApiVersions and SaslHandshake until the client picks a mechanism.
+  Handshake,
+  /// SaslAuthenticate requests carry the exchange until the server is done.
+  Authenticating { mechanism: String, server: Box<dyn SaslServer> },
+  /// Requests are served until the session expires, a SaslHandshake starts re-authenticating.
+  Authenticated { expires_at: Option<Instant> },
+  /// The client was told authentication failed, whatever it sends next closes the connection.
+  Failed,
+}
+
+/// SASL authentication of one connection on a SASL_PLAINTEXT or SASL_SSL listener, KIP-43
+/// framing: SaslHandshake picks the mechanism, SaslAuthenticate requests carry its exchange.
+///
+/// With `connections.max.reauth.ms` a session lasts that long (or until the token expires
+/// for OAUTHBEARER). Before that the client re-authenticates with the same mechanism and
+/// principal (KIP-368), a request after it closes the connection.
+pub struct SaslAuthenticator {
+  state: State,
+  /// Mechanism and principal of the last successful authentication.
+  authenticated: Option<(String, KafkaPrincipal)>,
+}
+
+impl Default for SaslAuthenticator {
+  fn default() -> Self {
+    SaslAuthenticator { state: State::Handshake, authenticated: None }
+  }
+}
+
+impl SaslAuthenticator {
+  /// Handles one request frame like `Broker::handle_request`, once the client has
+  /// authenticated. Before that it takes part in authentication, which sets the context's
+  /// principal, or closes the connection.
+  pub fn handle_request(&mut self, broker: &Broker, context: &mut RequestContext, frame: BytesMut) -> Result<Option<Response>> {
+    // After the message_size, every request header starts with the api key
+    let api_key = frame.get(4..6).map(|key| i16::from_be_bytes([key[0], key[1]]));
+    match (&self.state, api_key) {
+      (State::Authenticated { .. }, Some(sasl_handshake::API_KEY)) => self.handshake(broker, context, frame),
+      (State::Authenticated { expires_at: Some(expires_at) }, _) if Instant::now() >= *expires_at => {
+        anyhow::bail!("the SASL session of {} expired without re-authenticating", context.principal)
+      }
+      (State::Authenticated { .. }, _) | (State::Handshake, Some(api_versions::API_KEY)) => broker.handle_request(context, frame),
+      (State::Handshake, Some(sasl_handshake::API_KEY)) => self.handshake(broker, context, frame),
+      (State::Authenticating { .. }, Some(sasl_authenticate::API_KEY)) => self.authenticate(broker, context, frame),
+      (State::Failed, _) => anyhow::bail!("SASL authentication failed"),
+      (_, api_key) => anyhow::bail!("{}: api key {:?} during SASL authentication", ErrorCode::IllegalSaslState, api_key),
+    }
+  }
+
+  fn handshake(&mut self, broker: &Broker, context: &RequestContext, frame: BytesMut) -> Result<Option<Response>> {
+    let (header, body) = broker.decode_request(context, frame)?;
+    let AllRequests::SaslHandshakeRequest(request) = body else {
+      anyhow::bail!("SaslHandshake got {:?}", body);
+    };
+    if let Some((mechanism, _)) = &self.authenticated {
+      if *mechanism != request.mechanism {
+        anyhow::bail!("re-authentication has to use {} again, not {}", mechanism, request.mechanism);
+      }
+    }
+
+    let error_code = match new_server(broker, context, &request.mechanism) {
+      Some(server) => {
+        self.state = State::Authenticating { mechanism: request.mechanism, server };
+        ErrorCode::None
+      }
+      None => {
+        println!("SASL mechanism {:?} isn't enabled on {}", request.mechanism, context.listener_name);
+        ErrorCode::UnsupportedSaslMechanism
+      }
+    };
+    Ok(Some(Response::new(&header, sasl_handshake::response(broker, error_code))))
+  }
+
+  fn authenticate(&mut self, broker: &Broker, context: &mut RequestContext, frame: BytesMut) -> Result<Option<Response>> {
+    let (header, body) = broker.decode_request(context, frame)?;
+    let AllRequests::SaslAuthenticateRequest(request) = body else {
+      anyhow::bail!("SaslAuthenticate got {:?}", body);
+    };
+    let State::Authenticating { mechanism, server } = &mut self.state else {
+      unreachable!("only called while authenticating");
+    };
+    let mechanism = mechanism.clone();
+
+    let evaluated = server.evaluate(broker, &request.auth_bytes).and_then(|step| match step {
+      Step::Complete { principal, .. } if self.authenticated.as_ref().is_some_and(|(_, previous)| *previous != principal) => {
+        anyhow::bail!("re-authenticated as {} rather than {}", principal, context.principal)
+      }
+      step => Ok(step),
+    });
+    let response = match evaluated {
+      Ok(Step::Challenge(challenge)) => SaslAuthenticateResponse { auth_bytes: challenge, ..Default::default() },
+      Ok(Step::Complete { auth_bytes, principal, expires_at_ms }) => {
+        let session_lifetime_ms = session_lifetime_ms(broker.config.connections_max_reauth_ms, expires_at_ms);
+        println!("{} on {} authenticated as {} with {}", client(context), context.listener_name, principal, mechanism);
+        self.state = State::Authenticated {
+          expires_at: (session_lifetime_ms > 0).then(|| Instant::now() + Duration::from_millis(session_lifetime_ms as u64)),
+        };
+        self.authenticated = Some((mechanism, principal.clone()));
+        context.principal = principal;
+        SaslAuthenticateResponse { auth_bytes, session_lifetime_ms, ..Default::default() }
+      }
+      Err(e) => {
+        println!("{} on {} failed {} authentication: {:#}", client(context), context.listener_name, mechanism, e);
+        self.state = State::Failed;
+        SaslAuthenticateResponse {
+          error_code: ErrorCode::SaslAuthenticationFailed.code(),
+          error_message: Some(format!("Authentication failed: {:#}", e)),
+          ..Default::default()
+        }
+      }
+    };
+    Ok(Some(Response::new(&header, AllResponses::SaslAuthenticateResponse(response))))
+  }
+}
+
+/// How long a session lasts, 0 for as long as the connection. A token can't outlive its
+/// expiry, but that's only enforced along with `connections.max.reauth.ms`, as in Kafka.
+fn session_lifetime_ms(max_reauth_ms: i64, expires_at_ms: Option<i64>) -> i64 {
+  match expires_at_ms {
+    _ if max_reauth_ms == 0 => 0,
+    Some(expires_at_ms) => max_reauth_ms.min(expires_at_ms - now_ms()).max(1),
+    None => max_reauth_ms,
+  }
+}
+
+fn client(context: &RequestContext) -> String {
+  context.client_address.map_or("client".to_string(), |address| address.to_string())
+}
+
+/// Compares secrets in time that depends on their length only.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::BufMut;
+
+  use super::*;
+  use crate::kafka::codec::{encode_string, Encode, KafkaBytes, TaggedFields};
+  use crate::kafka::config::{BrokerConfig, SecurityProtocol};
+  use crate::kafka::framing;
+  use crate::kafka::handlers::metadata;
+  use crate::kafka::metadata_image::MetadataImage;
+
+  fn broker(connections_max_reauth_ms: i64) -> Broker {
+    let config = BrokerConfig::from_properties(&[
+      ("listeners", "SASL_PLAINTEXT://:9092"),
+      ("inter.broker.listener.name", "SASL_PLAINTEXT"),
+      ("sasl.enabled.mechanisms", "PLAIN,SCRAM-SHA-256"),
+      (
+        "listener.name.sasl_plaintext.plain.sasl.jaas.config",
+        "org.apache.kafka.common.security.plain.PlainLoginModule required user_alice=\"alice-secret\" user_bob=\"bob-secret\";",
+      ),
+      ("connections.max.reauth.ms", &connections_max_reauth_ms.to_string()),
+    ].into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()).unwrap();
+    Broker::new(config, MetadataImage::empty())
+  }
+
+  fn context() -> RequestContext {
+    RequestContext {
+      listener_name: "SASL_PLAINTEXT".to_string(),
+      security_protocol: SecurityProtocol::SaslPlaintext,
+      ..Default::default()
+    }
+  }
+
+  fn request(api_key: i16, version: i16, body: impl FnOnce(&mut BytesMut)) -> BytesMut {
+    let mut request = BytesMut::new();
+    request.put_i16(api_key);
+    request.put_i16(version);
+    request.put_i32(7);
+    request.put_i16(-1); // client_id
+    body(&mut request);
+    BytesMut::from(&framing::frame(&request)[..])
+  }
+
+  fn handshake(mechanism: &str) -> BytesMut {
+    request(sasl_handshake::API_KEY, 1, |buf| encode_string(buf, &mechanism.to_string(), false))
+  }
+
+  fn authenticate(auth_bytes: &str) -> BytesMut {
+    request(sasl_authenticate::API_KEY, 1, |buf| KafkaBytes::encode(buf, &Bytes::copy_from_slice(auth_bytes.as_bytes())))
+  }
+
+  fn metadata_request() -> BytesMut {
+    request(metadata::API_KEY, 1, |buf| buf.put_i32(-1)) // every topic
+  }
+
+  fn handshake_response(response: Result<Option<Response>>) -> (i16, Vec<String>) {
+    match response.unwrap().unwrap().body {
+      AllResponses::SaslHandshakeResponse(response) => (response.error_code, response.mechanisms),
+      body => panic!("expected a SaslHandshake response, got {:?}", body),
+    }
+  }
+
+  fn authenticate_response(response: Result<Option<Response>>) -> SaslAuthenticateResponse {
+    match response.unwrap().unwrap().body {
+      AllResponses::SaslAuthenticateResponse(response) => response,
+      body => panic!("expected a SaslAuthenticate response, got {:?}", body),
+    }
+  }
+
+  /// Authenticates `user` with PLAIN, returning the session lifetime.
+  fn plain(sasl: &mut SaslAuthenticator, broker: &Broker, context: &mut RequestContext, user: &str) -> i64 {
+    assert_eq!(handshake_response(sasl.handle_request(broker, context, handshake(PLAIN))).0, 0);
+    let credentials = format!("\0{}\0{}-secret", user, user);
+    let response = authenticate_response(sasl.handle_request(broker, context, authenticate(&credentials)));
+    assert_eq!(response.error_code, 0, "{:?}", response.error_message);
+    response.session_lifetime_ms
+  }
+
+  #[test]
+  fn serves_requests_only_after_authenticating() {
+    let (broker, mut context) = (broker(0), context());
+    assert!(SaslAuthenticator::default().handle_request(&broker, &mut context, metadata_request()).is_err());
+
+    let mut sasl = SaslAuthenticator::default();
+    let api_versions = request(api_versions::API_KEY, 0, |_| {});
+    assert!(sasl.handle_request(&broker, &mut context, api_versions).unwrap().is_some());
+    let (error_code, mechanisms) = handshake_response(sasl.handle_request(&broker, &mut context, handshake("GSSAPI")));
+    assert_eq!((error_code, mechanisms), (ErrorCode::UnsupportedSaslMechanism.code(), vec![PLAIN.to_string(), scram::SCRAM_SHA_256.to_string()]));
+
+    assert_eq!(plain(&mut sasl, &broker, &mut context, "alice"), 0);
+    assert_eq!(context.principal, KafkaPrincipal::user("alice"));
+    assert!(sasl.handle_request(&broker, &mut context, metadata_request()).unwrap().is_some());
+    let again = authenticate_response(sasl.handle_request(&broker, &mut context, authenticate("\0alice\0alice-secret")));
+    assert_eq!(again.error_code, ErrorCode::IllegalSaslState.code());
+  }
+
+  #[test]
+  fn closes_the_connection_after_a_failed_authentication() {
+    let (broker, mut context) = (broker(0), context());
+    let mut sasl = SaslAuthenticator::default();
+    assert_eq!(handshake_response(sasl.handle_request(&broker, &mut context, handshake(PLAIN))).0, 0);
+
+    let failed = authenticate_response(sasl.handle_request(&broker, &mut context, authenticate("\0alice\0guess")));
+    assert_eq!(failed.error_code, ErrorCode::SaslAuthenticationFailed.code());
+    assert!(failed.error_message.unwrap().contains("invalid username or password"));
+    assert_eq!(context.principal, KafkaPrincipal::anonymous());
+    let api_versions = request(api_versions::API_KEY, 0, |_| {});
+    assert!(sasl.handle_request(&broker, &mut context, api_versions).is_err());
+  }
+
+  #[test]
+  fn reauthenticates_as_the_same_principal_before_the_session_expires() {
+    let (broker, mut context) = (broker(100), context());
+    let mut sasl = SaslAuthenticator::default();
+    assert_eq!(plain(&mut sasl, &broker, &mut context, "alice"), 100);
+    assert_eq!(plain(&mut sasl, &broker, &mut context, "alice"), 100);
+
+    // Neither the principal nor the mechanism can change
+    assert_eq!(handshake_response(sasl.handle_request(&broker, &mut context, handshake(PLAIN))).0, 0);
+    let changed = authenticate_response(sasl.handle_request(&broker, &mut context, authenticate("\0bob\0bob-secret")));
+    assert_eq!(changed.error_code, ErrorCode::SaslAuthenticationFailed.code());
+    assert_eq!(context.principal, KafkaPrincipal::user("alice"));
+    let mut sasl = SaslAuthenticator::default();
+    plain(&mut sasl, &broker, &mut context, "alice");
+    assert!(sasl.handle_request(&broker, &mut context, handshake(scram::SCRAM_SHA_256)).is_err());
+
+    let mut sasl = SaslAuthenticator::default();
+    plain(&mut sasl, &broker, &mut context, "alice");
+    assert!(sasl.handle_request(&broker, &mut context, metadata_request()).unwrap().is_some());
+    std::thread::sleep(Duration::from_millis(150));
+    let expired = sasl.handle_request(&broker, &mut context, metadata_request());
+    assert!(expired.unwrap_err().to_string().contains("expired"));
+  }
+
+  #[test]
+  fn tokens_bound_the_session_only_with_reauthentication() {
+    let expires_at_ms = now_ms() + 60_000;
+    assert_eq!(session_lifetime_ms(0, Some(expires_at_ms)), 0);
+    assert_eq!(session_lifetime_ms(1000, None), 1000);
+    assert_eq!(session_lifetime_ms(1000, Some(expires_at_ms)), 1000);
+    assert!((59_000..=60_000).contains(&session_lifetime_ms(3_600_000, Some(expires_at_ms))));
+  }
+}
@@ -0,0 +1,330 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde_json::Value;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::config::BrokerConfig;
+use crate::kafka::log_segment::now_ms;
+use crate::kafka::request_context::KafkaPrincipal;
+use crate::kafka::sasl::{SaslServer, Step};
+
+/// What the server answers a rejected token with (RFC 7628 3.2.2).
+const INVALID_TOKEN: &str = r#"{"status":"invalid_token"}"#;
+
+/// OAUTHBEARER (RFC 7628) with JWT bearer tokens. Tokens are unsecured (`alg` none) unless
+/// `sasl.oauthbearer.jwks.endpoint.url` names the keys their signatures are checked with.
+/// The principal is the `sasl.oauthbearer.sub.claim.name` claim and the session can't
+/// outlive `exp`.
+#[derive(Default)]
+pub struct OAuthBearerServer {
+  /// Why the token was rejected, the error once the client acknowledges the challenge.
+  failure: Option<String>,
+}
+
+impl SaslServer for OAuthBearerServer {
+  fn evaluate(&mut self, broker: &Broker, response: &[u8]) -> Result<Step> {
+    // A rejected token gets an error challenge, which the client acknowledges with %x01
+    if let Some(failure) = self.failure.take() {
+      anyhow::bail!("{}", failure);
+    }
+
+    let message = std::str::from_utf8(response).map_err(|_| anyhow::anyhow!("OAUTHBEARER message isn't UTF-8"))?;
+    let (authzid, token) = parse_client_response(message)?;
+    let (principal, expires_at_ms) = match validate(&broker.config, token) {
+      Ok(validated) => validated,
+      Err(e) => {
+        self.failure = Some(format!("invalid token: {:#}", e));
+        return Ok(Step::Challenge(Bytes::from_static(INVALID_TOKEN.as_bytes())));
+      }
+    };
+    if authzid.is_some_and(|authzid| authzid != principal) {
+      anyhow::bail!("authorization id isn't the token's principal {}", principal);
+    }
+    Ok(Step::Complete { auth_bytes: Bytes::new(), principal: KafkaPrincipal::user(&principal), expires_at_ms: Some(expires_at_ms) })
+  }
+}
+
+/// The authzid and bearer token of `gs2-header %x01 auth=Bearer <token> %x01 [kvpairs] %x01`.
+/// Other key/value pairs are SASL extensions, which we don't use.
+fn parse_client_response(message: &str) -> Result<(Option<&str>, &str)> {
+  let malformed = || anyhow::anyhow!("malformed OAUTHBEARER client response");
+  let (gs2_header, pairs) = message.split_once('\x01').ok_or_else(malformed)?;
+  let mut gs2 = gs2_header.splitn(3, ',');
+  let (Some("n" | "y"), Some(authzid), Some("")) = (gs2.next(), gs2.next(), gs2.next()) else {
+    return Err(malformed());
+  };
+  let authzid = match authzid.strip_prefix("a=") {
+    Some(authzid) => Some(authzid),
+    None if authzid.is_empty() => None,
+    None => return Err(malformed()),
+  };
+
+  let pairs = pairs.strip_suffix("\x01\x01").ok_or_else(malformed)?;
+  let auth = pairs.split('\x01')
+    .filter_map(|pair| pair.split_once('='))
+    .find(|(key, _)| *key == "auth")
+    .ok_or_else(malformed)?
+    .1;
+  match auth.split_once(' ') {
+    Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") && !token.trim().is_empty() => Ok((authzid, token.trim())),
+    _ => Err(malformed()),
+  }
+}
+
+/// Checks a JWT and returns its principal and expiry in ms.
+fn validate(config: &BrokerConfig, token: &str) -> Result<(String, i64)> {
+  let [header, payload, signature] = token.split('.').collect::<Vec<_>>()[..] else {
+    anyhow::bail!("not a JWT");
+  };
+  let alg = json(header)?["alg"].as_str().unwrap_or_default().to_string();
+  match &config.sasl_oauthbearer_jwks_endpoint_url {
+    None if alg != "none" || !signature.is_empty() => {
+      anyhow::bail!("only unsecured tokens are accepted without sasl.oauthbearer.jwks.endpoint.url, not {}", alg)
+    }
+    None => {}
+    Some(jwks) => {
+      let signature = URL_SAFE_NO_PAD.decode(signature).context("signature isn't base64url")?;
+      let kid = json(header)?["kid"].as_str().map(str::to_string);
+      let signing_input = &token[..header.len() + 1 + payload.len()];
+      verify_signature(jwks, &alg, kid.as_deref(), signing_input.as_bytes(), &signature)?;
+    }
+  }
+
+  let claims = json(payload)?;
+  let now = now_ms();
+  let expires_at_ms = claims["exp"].as_f64().map(|exp| (exp * 1000.0) as i64).context("no exp claim")?;
+  if expires_at_ms <= now {
+    anyhow::bail!("expired");
+  }
+  if claims["nbf"].as_f64().is_some_and(|nbf| (nbf * 1000.0) as i64 > now) {
+    anyhow::bail!("not valid yet");
+  }
+  let expected_audience = &config.sasl_oauthbearer_expected_audience;
+  if !expected_audience.is_empty() {
+    let audience = match &claims["aud"] {
+      Value::String(audience) => vec![audience.as_str()],
+      Value::Array(audience) => audience.iter().filter_map(Value::as_str).collect(),
+      _ => vec![],
+    };
+    if !audience.iter().any(|audience| expected_audience.iter().any(|expected| expected == audience)) {
+      anyhow::bail!("aud {:?} isn't one of {:?}", audience, expected_audience);
+    }
+  }
+  if let Some(expected_issuer) = &config.sasl_oauthbearer_expected_issuer {
+    if claims["iss"].as_str() != Some(expected_issuer) {
+      anyhow::bail!("iss {} isn't {}", claims["iss"], expected_issuer);
+    }
+  }
+  let sub_claim = &config.sasl_oauthbearer_sub_claim_name;
+  match claims[sub_claim].as_str() {
+    Some(principal) if !principal.is_empty() => Ok((principal.to_string(), expires_at_ms)),
+    _ => anyhow::bail!("no {} claim", sub_claim),
+  }
+}
+
+fn json(part: &str) -> Result<Value> {
+  let decoded = URL_SAFE_NO_PAD.decode(part).context("JWT part isn't base64url")?;
+  serde_json::from_slice(&decoded).context("JWT part isn't JSON")
+}
+
+/// Checks `signature` with the JWKS keys `kid` names, or any key when the token names none.
+fn verify_signature(jwks: &Path, alg: &str, kid: Option<&str>, signing_input: &[u8], signature: &[u8]) -> Result<()> {
+  let contents = fs::read(jwks).with_context(|| format!("reading {}", jwks.display()))?;
+  let jwks_json: Value = serde_json::from_slice(&contents).with_context(|| format!("parsing {}", jwks.display()))?;
+  let keys = jwks_json["keys"].as_array().with_context(|| format!("{} has no keys", jwks.display()))?;
+
+  let mut candidates = keys.iter().filter(|key| kid.is_none() || key["kid"].as_str() == kid).peekable();
+  if candidates.peek().is_none() {
+    anyhow::bail!("no key {:?} in {}", kid.unwrap_or_default(), jwks.display());
+  }
+  // Without a kid every key is tried, those of another type or algorithm aren't the one
+  let mut checked = false;
+  let mut mismatch = None;
+  for key in candidates.filter(|key| key["alg"].as_str().map_or(true, |key_alg| key_alg == alg)) {
+    match verify_with(key, alg, signing_input, signature) {
+      Ok(true) => return Ok(()),
+      Ok(false) => checked = true,
+      Err(e) => mismatch = Some(e),
+    }
+  }
+  if checked {
+    anyhow::bail!("signature doesn't verify");
+  }
+  Err(mismatch.unwrap_or_else(|| anyhow::anyhow!("no {} key in {}", alg, jwks.display())))
+}
+
+/// Whether `jwk` verifies `signature`, an error when it can't check `alg` signatures.
+fn verify_with(jwk: &Value, alg: &str, signing_input: &[u8], signature: &[u8]) -> Result<bool> {
+  let component = |name: &str| -> Result<Vec<u8>> {
+    let value = jwk[name].as_str().with_context(|| format!("JWK without {}", name))?;
+    URL_SAFE_NO_PAD.decode(value).with_context(|| format!("JWK {} isn't base64url", name))
+  };
+  let verified = match (alg, jwk["kty"].as_str()) {
+    ("RS256" | "RS384" | "RS512", Some("RSA")) => {
+      let params = match alg {
+        "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+        "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+        _ => &signature::RSA_PKCS1_2048_8192_SHA512,
+      };
+      let (n, e) = (component("n")?, component("e")?);
+      RsaPublicKeyComponents { n: &n, e: &e }.verify(params, signing_input, signature).is_ok()
+    }
+    ("ES256" | "ES384", Some("EC")) => {
+      let algorithm = match (alg, jwk["crv"].as_str()) {
+        ("ES256", Some("P-256")) => &signature::ECDSA_P256_SHA256_FIXED,
+        ("ES384", Some("P-384")) => &signature::ECDSA_P384_SHA384_FIXED,
+        (_, crv) => anyhow::bail!("{} doesn't go with curve {:?}", alg, crv),
+      };
+      // An uncompressed point
+      let point = [&[4][..], &component("x")?, &component("y")?].concat();
+      UnparsedPublicKey::new(algorithm, point).verify(signing_input, signature).is_ok()
+    }
+    (alg, kty) => anyhow::bail!("{} signatures can't be checked with a {:?} key", alg, kty),
+  };
+  Ok(verified)
+}
+
+#[cfg(test)]
+mod tests {
+  use ring::rand::SystemRandom;
+  use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+  use serde_json::json;
+
+  use super::*;
+  use crate::kafka::metadata_image::MetadataImage;
+  use crate::kafka::test_support::temp_dir;
+
+  fn encode(value: &Value) -> String {
+    URL_SAFE_NO_PAD.encode(value.to_string())
+  }
+
+  fn client_response(token: &str) -> Vec<u8> {
+    format!("n,,\x01host=localhost\x01auth=Bearer {}\x01\x01", token).into_bytes()
+  }
+
+  fn claims(sub: &str, exp_in_s: i64) -> Value {
+    json!({ "sub": sub, "exp": now_ms() / 1000 + exp_in_s, "iat": now_ms() / 1000, "aud": ["kafka"] })
+  }
+
+  fn ec_key(rng: &SystemRandom) -> EcdsaKeyPair {
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng).unwrap();
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), rng).unwrap()
+  }
+
+  fn ec_jwk(key: &EcdsaKeyPair) -> Value {
+    let point = key.public_key().as_ref();
+    json!({
+      "kty": "EC", "crv": "P-256", "use": "sig",
+      "x": URL_SAFE_NO_PAD.encode(&point[1..33]), "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+    })
+  }
+
+  fn jwks_broker(dir: &Path, keys: &[Value]) -> Broker {
+    fs::write(dir.join("jwks.json"), json!({ "keys": keys }).to_string()).unwrap();
+    let config = BrokerConfig {
+      sasl_oauthbearer_jwks_endpoint_url: Some(dir.join("jwks.json")),
+      sasl_oauthbearer_expected_audience: vec!["kafka".to_string()],
+      ..Default::default()
+    };
+    Broker::new(config, MetadataImage::empty())
+  }
+
+  fn authenticate(broker: &Broker, token: &str) -> Result<Step> {
+    let mut server = OAuthBearerServer::default();
+    match server.evaluate(broker, &client_response(token))? {
+      Step::Challenge(challenge) => {
+        assert_eq!(&challenge[..], INVALID_TOKEN.as_bytes());
+        server.evaluate(broker, b"\x01")
+      }
+      complete => Ok(complete),
+    }
+  }
+
+  fn outcome(step: Result<Step>) -> String {
+    match step {
+      Ok(Step::Complete { principal, .. }) => principal.to_string(),
+      Ok(Step::Challenge(_)) => panic!("expected the exchange to be over"),
+      Err(e) => format!("{:#}", e),
+    }
+  }
+
+  #[test]
+  fn accepts_unsecured_tokens_without_a_jwks() {
+    let broker = Broker::new(BrokerConfig::default(), MetadataImage::empty());
+    let unsecured = |claims: &Value| format!("{}.{}.", encode(&json!({ "alg": "none" })), encode(claims));
+
+    let step = authenticate(&broker, &unsecured(&claims("alice", 60))).unwrap();
+    let Step::Complete { principal, expires_at_ms, .. } = step else {
+      panic!("expected alice to authenticate");
+    };
+    assert_eq!(principal.to_string(), "User:alice");
+    assert!(expires_at_ms.unwrap() > now_ms());
+
+    assert!(outcome(authenticate(&broker, &unsecured(&claims("alice", -60)))).contains("expired"));
+    assert!(outcome(authenticate(&broker, &unsecured(&json!({ "exp": now_ms() / 1000 + 60 })))).contains("no sub claim"));
+    let mut server = OAuthBearerServer::default();
+    let authzid = format!("n,a=bob,\x01auth=Bearer {}\x01\x01", unsecured(&claims("alice", 60)));
+    assert!(server.evaluate(&broker, authzid.as_bytes()).is_err());
+    assert!(OAuthBearerServer::default().evaluate(&broker, b"n,,auth=Bearer x").is_err());
+  }
+
+  #[test]
+  fn verifies_signed_tokens_with_the_jwks() {
+    let dir = temp_dir();
+    let rng = SystemRandom::new();
+    let key = ec_key(&rng);
+    let mut jwk = ec_jwk(&key);
+    jwk["kid"] = json!("key-1");
+    let broker = jwks_broker(dir.path(), &[jwk]);
+    let signed = |kid: &str, claims: &Value| {
+      let signing_input = format!("{}.{}", encode(&json!({ "alg": "ES256", "kid": kid })), encode(claims));
+      let signature = key.sign(&rng, signing_input.as_bytes()).unwrap();
+      format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+    };
+
+    assert_eq!(outcome(authenticate(&broker, &signed("key-1", &claims("alice", 60)))), "User:alice");
+
+    let token = signed("key-1", &claims("alice", 60));
+    let (signing_input, signature) = token.rsplit_once('.').unwrap();
+    let (header, _) = signing_input.split_once('.').unwrap();
+    let tampered = format!("{}.{}.{}", header, encode(&claims("admin", 60)), signature);
+    assert!(outcome(authenticate(&broker, &tampered)).contains("signature doesn't verify"));
+    assert!(outcome(authenticate(&broker, &signed("key-2", &claims("alice", 60)))).contains("no key"));
+    let unsecured = format!("{}.{}.", encode(&json!({ "alg": "none" })), encode(&claims("alice", 60)));
+    assert!(outcome(authenticate(&broker, &unsecured)).contains("none signatures can't be checked"));
+    let mut other_audience = claims("alice", 60);
+    other_audience["aud"] = json!("billing");
+    assert!(outcome(authenticate(&broker, &signed("key-1", &other_audience))).contains("aud"));
+  }
+
+  #[test]
+  fn tries_every_key_of_the_jwks_without_a_kid() {
+    let dir = temp_dir();
+    let rng = SystemRandom::new();
+    let (key, other_key) = (ec_key(&rng), ec_key(&rng));
+    // Never verifies anything, it's only there to be skipped
+    let rsa = json!({ "kty": "RSA", "alg": "RS256", "n": URL_SAFE_NO_PAD.encode([0xc5; 256]), "e": "AQAB" });
+    let rsa_without_alg = json!({ "kty": "RSA", "n": URL_SAFE_NO_PAD.encode([0xc5; 256]), "e": "AQAB" });
+    let signed = |claims: &Value| {
+      let signing_input = format!("{}.{}", encode(&json!({ "alg": "ES256" })), encode(claims));
+      let signature = key.sign(&rng, signing_input.as_bytes()).unwrap();
+      format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+    };
+
+    let mixed = jwks_broker(dir.path(), &[rsa.clone(), rsa_without_alg.clone(), ec_jwk(&other_key), ec_jwk(&key)]);
+    assert_eq!(outcome(authenticate(&mixed, &signed(&claims("alice", 60)))), "User:alice");
+
+    let wrong_ec = jwks_broker(dir.path(), &[rsa.clone(), ec_jwk(&other_key), rsa_without_alg.clone()]);
+    assert!(outcome(authenticate(&wrong_ec, &signed(&claims("alice", 60)))).contains("signature doesn't verify"));
+    let only_rsa = jwks_broker(dir.path(), &[rsa.clone(), rsa_without_alg]);
+    assert!(outcome(authenticate(&only_rsa, &signed(&claims("alice", 60)))).contains("can't be checked with a Some(\"RSA\") key"));
+    let only_rs256 = jwks_broker(dir.path(), &[rsa]);
+    assert!(outcome(authenticate(&only_rs256, &signed(&claims("alice", 60)))).contains("no ES256 key"));
+  }
+}
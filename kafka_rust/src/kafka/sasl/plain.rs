@@ -0,0 +1,42 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::request_context::KafkaPrincipal;
+use crate::kafka::sasl::{constant_time_eq, SaslServer, Step};
+
+/// PLAIN (RFC 4616): one message with the password in the clear, checked against the
+/// listener's `user_<name>` JAAS options. Only sensible over SASL_SSL.
+pub struct PlainServer {
+  users: BTreeMap<String, String>,
+}
+
+impl PlainServer {
+  pub fn new(users: BTreeMap<String, String>) -> PlainServer {
+    PlainServer { users }
+  }
+}
+
+impl SaslServer for PlainServer {
+  fn evaluate(&mut self, _broker: &Broker, response: &[u8]) -> Result<Step> {
+    // [authzid] NUL authcid NUL passwd
+    let message = std::str::from_utf8(response).map_err(|_| anyhow::anyhow!("PLAIN message isn't UTF-8"))?;
+    let [authzid, username, password] = message.split('\0').collect::<Vec<_>>()[..] else {
+      anyhow::bail!("PLAIN message isn't authzid NUL username NUL password");
+    };
+    if username.is_empty() || password.is_empty() {
+      anyhow::bail!("PLAIN message has an empty username or password");
+    }
+    if !authzid.is_empty() && authzid != username {
+      anyhow::bail!("authorization id {} isn't the username", authzid);
+    }
+
+    let expected = self.users.get(username).map_or(&[][..], |expected| expected.as_bytes());
+    if !constant_time_eq(expected, password.as_bytes()) {
+      anyhow::bail!("invalid username or password");
+    }
+    Ok(Step::Complete { auth_bytes: Bytes::new(), principal: KafkaPrincipal::user(username), expires_at_ms: None })
+  }
+}
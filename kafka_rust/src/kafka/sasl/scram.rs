@@ -0,0 +1,311 @@
+use std::num::NonZeroU32;
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac, pbkdf2};
+
+use crate::kafka::broker::Broker;
+use crate::kafka::metadata_image::ScramCredential;
+use crate::kafka::request_context::KafkaPrincipal;
+use crate::kafka::sasl::{constant_time_eq, SaslServer, Step};
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+pub const SCRAM_SHA_512: &str = "SCRAM-SHA-512";
+
+/// A SCRAM mechanism, numbered as in `UserScramCredentialRecord.Mechanism`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScramMechanism {
+  Sha256,
+  Sha512,
+}
+
+impl ScramMechanism {
+  pub const ALL: [ScramMechanism; 2] = [ScramMechanism::Sha256, ScramMechanism::Sha512];
+
+  /// Kafka's bounds on credential iterations.
+  pub const MIN_ITERATIONS: i32 = 4096;
+  pub const MAX_ITERATIONS: i32 = 16384;
+
+  pub fn id(self) -> i8 {
+    match self {
+      ScramMechanism::Sha256 => 1,
+      ScramMechanism::Sha512 => 2,
+    }
+  }
+
+  pub fn from_id(id: i8) -> Option<ScramMechanism> {
+    ScramMechanism::ALL.into_iter().find(|mechanism| mechanism.id() == id)
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      ScramMechanism::Sha256 => SCRAM_SHA_256,
+      ScramMechanism::Sha512 => SCRAM_SHA_512,
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<ScramMechanism> {
+    ScramMechanism::ALL.into_iter().find(|mechanism| mechanism.name() == name)
+  }
+
+  fn digest(self) -> &'static digest::Algorithm {
+    match self {
+      ScramMechanism::Sha256 => &digest::SHA256,
+      ScramMechanism::Sha512 => &digest::SHA512,
+    }
+  }
+
+  fn hmac(self, key: &[u8], message: &[u8]) -> Vec<u8> {
+    let algorithm = match self {
+      ScramMechanism::Sha256 => hmac::HMAC_SHA256,
+      ScramMechanism::Sha512 => hmac::HMAC_SHA512,
+    };
+    hmac::sign(&hmac::Key::new(algorithm, key), message).as_ref().to_vec()
+  }
+
+  /// Hi(password, salt, iterations), what clients send AlterUserScramCredentials so the
+  /// password itself never reaches the broker. Only tests play the client.
+  #[cfg(test)]
+  pub fn salted_password(self, password: &str, salt: &[u8], iterations: i32) -> Vec<u8> {
+    let algorithm = match self {
+      ScramMechanism::Sha256 => pbkdf2::PBKDF2_HMAC_SHA256,
+      ScramMechanism::Sha512 => pbkdf2::PBKDF2_HMAC_SHA512,
+    };
+    let mut salted = vec![0; self.digest().output_len()];
+    let iterations = NonZeroU32::new(iterations.max(1) as u32).expect("at least 1");
+    pbkdf2::derive(algorithm, iterations, salt, password.as_bytes(), &mut salted);
+    salted
+  }
+
+  /// The stored and server keys of a salted password, all the server keeps of it.
+  pub fn credential(self, salt: Bytes, salted_password: &[u8], iterations: i32) -> ScramCredential {
+    let client_key = self.hmac(salted_password, b"Client Key");
+    ScramCredential {
+      salt,
+      stored_key: Bytes::copy_from_slice(digest::digest(self.digest(), &client_key).as_ref()),
+      server_key: Bytes::from(self.hmac(salted_password, b"Server Key")),
+      iterations,
+    }
+  }
+}
+
+enum ScramState {
+  ClientFirst,
+  ClientFinal(Box<ClientFinal>),
+}
+
+/// What checking the client-final message needs from the first round.
+struct ClientFinal {
+  user: String,
+  credential: ScramCredential,
+  gs2_header: String,
+  client_first_bare: String,
+  server_first: String,
+  nonce: String,
+}
+
+/// The server side of SCRAM (RFC 5802) against the credentials in the metadata log:
+/// client-first, server-first, client-final with the proof, server-final with the server's.
+pub struct ScramServer {
+  mechanism: ScramMechanism,
+  state: ScramState,
+}
+
+impl ScramServer {
+  pub fn new(mechanism: ScramMechanism) -> ScramServer {
+    ScramServer { mechanism, state: ScramState::ClientFirst }
+  }
+
+  /// Unknown users and wrong passwords get the same answer.
+  fn invalid_credentials(&self) -> anyhow::Error {
+    anyhow::anyhow!("invalid credentials with SASL mechanism {}", self.mechanism.name())
+  }
+
+  fn client_first(&mut self, broker: &Broker, message: &str) -> Result<Step> {
+    // gs2-header (channel binding flag, authzid) then client-first-message-bare
+    let mut parts = message.splitn(3, ',');
+    let (Some(binding), Some(authzid), Some(bare)) = (parts.next(), parts.next(), parts.next()) else {
+      anyhow::bail!("malformed client-first message");
+    };
+    if binding != "n" && binding != "y" {
+      anyhow::bail!("channel binding isn't supported");
+    }
+    let mut attributes = bare.split(',');
+    let (Some(user), Some(client_nonce)) = (
+      attributes.next().and_then(|user| user.strip_prefix("n=")),
+      attributes.next().and_then(|nonce| nonce.strip_prefix("r=")),
+    ) else {
+      anyhow::bail!("malformed client-first message");
+    };
+    // Extensions, Kafka's tokenauth for delegation tokens being the one clients send
+    if attributes.any(|extension| extension == "tokenauth=true") {
+      anyhow::bail!("delegation tokens aren't supported");
+    }
+    let user = decode_saslname(user)?;
+    match authzid.strip_prefix("a=") {
+      Some(authzid) if decode_saslname(authzid)? != user => anyhow::bail!("authorization id isn't the username"),
+      None if !authzid.is_empty() => anyhow::bail!("malformed client-first message"),
+      _ => {}
+    }
+
+    let credential = broker.scram_credentials.read().unwrap()
+      .get(&(user.clone(), self.mechanism.id()))
+      .cloned()
+      .ok_or_else(|| self.invalid_credentials())?;
+    let mut server_nonce = [0; 24];
+    SystemRandom::new().fill(&mut server_nonce).map_err(|_| anyhow::anyhow!("no randomness for a nonce"))?;
+    let nonce = format!("{}{}", client_nonce, STANDARD.encode(server_nonce));
+    let server_first = format!("r={},s={},i={}", nonce, STANDARD.encode(&credential.salt), credential.iterations);
+
+    self.state = ScramState::ClientFinal(Box::new(ClientFinal {
+      user,
+      credential,
+      gs2_header: format!("{},{},", binding, authzid),
+      client_first_bare: bare.to_string(),
+      server_first: server_first.clone(),
+      nonce,
+    }));
+    Ok(Step::Challenge(Bytes::from(server_first)))
+  }
+
+  fn client_final(&self, message: &str) -> Result<Step> {
+    let ScramState::ClientFinal(first) = &self.state else {
+      unreachable!("only called after client-first");
+    };
+    let ClientFinal { user, credential, gs2_header, client_first_bare, server_first, nonce } = first.as_ref();
+    let Some((without_proof, proof)) = message.rsplit_once(",p=") else {
+      anyhow::bail!("malformed client-final message");
+    };
+    let mut attributes = without_proof.split(',');
+    let (Some(binding), Some(final_nonce)) = (
+      attributes.next().and_then(|binding| binding.strip_prefix("c=")),
+      attributes.next().and_then(|nonce| nonce.strip_prefix("r=")),
+    ) else {
+      anyhow::bail!("malformed client-final message");
+    };
+    if STANDARD.decode(binding).ok().as_deref() != Some(gs2_header.as_bytes()) {
+      anyhow::bail!("channel binding doesn't match the client-first message");
+    }
+    if final_nonce != nonce {
+      anyhow::bail!("nonce doesn't match the server-first message");
+    }
+    let proof = STANDARD.decode(proof).map_err(|_| anyhow::anyhow!("malformed client proof"))?;
+
+    // ClientKey = ClientProof XOR HMAC(StoredKey, AuthMessage), and StoredKey = H(ClientKey)
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+    let client_signature = self.mechanism.hmac(&credential.stored_key, auth_message.as_bytes());
+    if proof.len() != client_signature.len() {
+      return Err(self.invalid_credentials());
+    }
+    let client_key = proof.iter().zip(&client_signature).map(|(proof, signature)| proof ^ signature).collect::<Vec<_>>();
+    let stored_key = digest::digest(self.mechanism.digest(), &client_key);
+    if !constant_time_eq(stored_key.as_ref(), &credential.stored_key) {
+      return Err(self.invalid_credentials());
+    }
+
+    let server_signature = self.mechanism.hmac(&credential.server_key, auth_message.as_bytes());
+    Ok(Step::Complete {
+      auth_bytes: Bytes::from(format!("v={}", STANDARD.encode(server_signature))),
+      principal: KafkaPrincipal::user(user),
+      expires_at_ms: None,
+    })
+  }
+}
+
+impl SaslServer for ScramServer {
+  fn evaluate(&mut self, broker: &Broker, response: &[u8]) -> Result<Step> {
+    let message = std::str::from_utf8(response).map_err(|_| anyhow::anyhow!("SCRAM message isn't UTF-8"))?;
+    match self.state {
+      ScramState::ClientFirst => self.client_first(broker, message),
+      ScramState::ClientFinal(_) => self.client_final(message),
+    }
+  }
+}
+
+/// A username with `,` and `=` escaped as `=2C` and `=3D`.
+fn decode_saslname(name: &str) -> Result<String> {
+  let decoded = name.replace("=2C", ",").replace("=3D", "=");
+  if name.matches('=').count() != name.matches("=2C").count() + name.matches("=3D").count() {
+    anyhow::bail!("invalid escape in username {:?}", name);
+  }
+  Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::kafka::config::BrokerConfig;
+  use crate::kafka::metadata_image::MetadataImage;
+
+  /// A broker knowing `user`'s SCRAM-SHA-512 and SCRAM-SHA-256 credentials for `password`.
+  fn broker_with_user(user: &str, password: &str) -> Broker {
+    let mut metadata = MetadataImage::empty();
+    for mechanism in ScramMechanism::ALL {
+      let salt = Bytes::from_static(b"pepper");
+      let salted = mechanism.salted_password(password, &salt, ScramMechanism::MIN_ITERATIONS);
+      let credential = mechanism.credential(salt, &salted, ScramMechanism::MIN_ITERATIONS);
+      metadata.scram_credentials.insert((user.to_string(), mechanism.id()), credential);
+    }
+    Broker::new(BrokerConfig::default(), metadata)
+  }
+
+  /// Runs the client side of a SCRAM exchange against `server`, checking the server's
+  /// signature when it succeeds.
+  fn authenticate(server: &mut dyn SaslServer, broker: &Broker, mechanism: ScramMechanism, user: &str, password: &str) -> Result<Step> {
+    let client_first_bare = format!("n={},r=clientnonce", user.replace('=', "=3D").replace(',', "=2C"));
+    let Step::Challenge(server_first) = server.evaluate(broker, format!("n,,{}", client_first_bare).as_bytes())? else {
+      panic!("expected a server-first message");
+    };
+    let server_first = String::from_utf8(server_first.to_vec()).unwrap();
+    let mut attributes = server_first.split(',');
+    let nonce = attributes.next().unwrap().strip_prefix("r=").unwrap();
+    let salt = STANDARD.decode(attributes.next().unwrap().strip_prefix("s=").unwrap()).unwrap();
+    let iterations = attributes.next().unwrap().strip_prefix("i=").unwrap().parse().unwrap();
+    assert!(nonce.starts_with("clientnonce"));
+
+    let salted = mechanism.salted_password(password, &salt, iterations);
+    let client_key = mechanism.hmac(&salted, b"Client Key");
+    let stored_key = digest::digest(mechanism.digest(), &client_key);
+    let without_proof = format!("c={},r={}", STANDARD.encode("n,,"), nonce);
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+    let client_signature = mechanism.hmac(stored_key.as_ref(), auth_message.as_bytes());
+    let proof = client_key.iter().zip(&client_signature).map(|(key, signature)| key ^ signature).collect::<Vec<_>>();
+
+    let step = server.evaluate(broker, format!("{},p={}", without_proof, STANDARD.encode(proof)).as_bytes())?;
+    if let Step::Complete { auth_bytes, .. } = &step {
+      let server_signature = mechanism.hmac(&mechanism.hmac(&salted, b"Server Key"), auth_message.as_bytes());
+      assert_eq!(&auth_bytes[..], format!("v={}", STANDARD.encode(server_signature)).as_bytes());
+    }
+    Ok(step)
+  }
+
+  #[test]
+  fn authenticates_with_either_mechanism() {
+    let broker = broker_with_user("ali,ce", "secret");
+    for mechanism in ScramMechanism::ALL {
+      let step = authenticate(&mut ScramServer::new(mechanism), &broker, mechanism, "ali,ce", "secret").unwrap();
+      let Step::Complete { principal, expires_at_ms, .. } = step else {
+        panic!("expected {} to complete", mechanism.name());
+      };
+      assert_eq!((principal.to_string(), expires_at_ms), ("User:ali,ce".to_string(), None));
+    }
+  }
+
+  #[test]
+  fn rejects_wrong_passwords_and_unknown_users() {
+    let broker = broker_with_user("alice", "secret");
+    let mechanism = ScramMechanism::Sha256;
+    let wrong = authenticate(&mut ScramServer::new(mechanism), &broker, mechanism, "alice", "guess");
+    assert!(wrong.err().unwrap().to_string().contains("invalid credentials"));
+    let unknown = ScramServer::new(mechanism).evaluate(&broker, b"n,,n=bob,r=nonce");
+    assert!(unknown.err().unwrap().to_string().contains("invalid credentials"));
+
+    let channel_binding = ScramServer::new(mechanism).evaluate(&broker, b"p=tls-unique,,n=alice,r=nonce");
+    assert!(channel_binding.is_err());
+    let delegation_token = ScramServer::new(mechanism).evaluate(&broker, b"n,,n=alice,r=nonce,tokenauth=true");
+    assert!(delegation_token.is_err());
+  }
+}
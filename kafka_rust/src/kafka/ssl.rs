@@ -0,0 +1,295 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ServerConnection, WebPkiClientVerifier};
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::objects::{oid2abbrev, oid_registry};
+use x509_parser::prelude::FromDer;
+use x509_parser::x509::X509Name;
+
+use crate::kafka::config::{BrokerConfig, SslClientAuth};
+use crate::kafka::request_context::KafkaPrincipal;
+
+/// Builds the TLS side of SSL and SASL_SSL listeners from the PEM keystore and truststore.
+///
+/// The files are checked again before every handshake and reloaded when they changed, so
+/// certificates can be rotated without a restart. A reload that fails keeps the previous
+/// certificates and is retried when the files change again.
+#[derive(Debug)]
+pub struct SslFactory {
+  keystore: PathBuf,
+  truststore: Option<PathBuf>,
+  client_auth: SslClientAuth,
+  current: Mutex<Loaded>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+  config: Arc<ServerConfig>,
+  /// The keystore and truststore as they were when `config` was built.
+  versions: Vec<FileVersion>,
+}
+
+/// Modification time and size, `None` for a file that can't be read.
+type FileVersion = Option<(SystemTime, u64)>;
+
+impl SslFactory {
+  /// Loads the certificates once, failing when they don't make a usable TLS config.
+  pub fn new(config: &BrokerConfig) -> Result<SslFactory> {
+    let keystore = config.ssl_keystore_location.clone().context("ssl.keystore.location isn't set")?;
+    let truststore = config.ssl_truststore_location.clone();
+    let versions = versions(&keystore, truststore.as_deref());
+    let loaded = load(&keystore, truststore.as_deref(), config.ssl_client_auth)?;
+    Ok(SslFactory {
+      keystore,
+      truststore,
+      client_auth: config.ssl_client_auth,
+      current: Mutex::new(Loaded { config: loaded, versions }),
+    })
+  }
+
+  /// The acceptor for the next handshake, with the certificates currently on disk.
+  pub fn acceptor(&self) -> TlsAcceptor {
+    let mut current = self.current.lock().unwrap();
+    let versions = versions(&self.keystore, self.truststore.as_deref());
+    if versions != current.versions {
+      match load(&self.keystore, self.truststore.as_deref(), self.client_auth) {
+        Ok(config) => {
+          println!("Reloaded the SSL keystore {}", self.keystore.display());
+          current.config = config;
+        }
+        Err(e) => println!("Keeping the previous SSL certificates: {:#}", e),
+      }
+      current.versions = versions;
+    }
+    TlsAcceptor::from(Arc::clone(&current.config))
+  }
+}
+
+fn versions(keystore: &Path, truststore: Option<&Path>) -> Vec<FileVersion> {
+  let version = |path: &Path| {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+  };
+  std::iter::once(keystore)
+    .chain(truststore)
+    .map(version)
+    .collect()
+}
+
+fn load(keystore: &Path, truststore: Option<&Path>, client_auth: SslClientAuth) -> Result<Arc<ServerConfig>> {
+  let provider = Arc::new(ring::default_provider());
+  let certificates = read_certificates(keystore)?;
+  if certificates.is_empty() {
+    anyhow::bail!("{} has no certificate", keystore.display());
+  }
+  let key = PrivateKeyDer::from_pem_file(keystore)
+    .with_context(|| format!("reading the private key from {}", keystore.display()))?;
+
+  let builder = ServerConfig::builder_with_provider(Arc::clone(&provider)).with_safe_default_protocol_versions()?;
+  let builder = match (truststore, client_auth) {
+    (_, SslClientAuth::None) => builder.with_no_client_auth(),
+    (None, _) => anyhow::bail!("ssl.client.auth needs ssl.truststore.location"),
+    (Some(truststore), client_auth) => {
+      let mut roots = RootCertStore::empty();
+      for certificate in read_certificates(truststore)? {
+        roots.add(certificate).with_context(|| format!("adding a CA from {}", truststore.display()))?;
+      }
+      let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+      let verifier = match client_auth {
+        SslClientAuth::Requested => verifier.allow_unauthenticated(),
+        _ => verifier,
+      };
+      builder.with_client_cert_verifier(verifier.build()?)
+    }
+  };
+  let config = builder.with_single_cert(certificates, key)
+    .with_context(|| format!("using the keystore {}", keystore.display()))?;
+  Ok(Arc::new(config))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+  CertificateDer::pem_file_iter(path)
+    .and_then(|certificates| certificates.collect())
+    .with_context(|| format!("reading certificates from {}", path.display()))
+}
+
+/// Like Kafka's default `ssl.principal.mapping.rules`, the user named by the client
+/// certificate's subject in RFC 2253 form, ANONYMOUS when there's no certificate.
+pub fn principal(connection: &ServerConnection) -> KafkaPrincipal {
+  let Some(certificate) = connection.peer_certificates().and_then(|certificates| certificates.first()) else {
+    return KafkaPrincipal::anonymous();
+  };
+  match X509Certificate::from_der(certificate) {
+    Ok((_, certificate)) => KafkaPrincipal::user(&distinguished_name(certificate.subject())),
+    Err(e) => {
+      println!("Can't read the client certificate subject, treating it as ANONYMOUS: {}", e);
+      KafkaPrincipal::anonymous()
+    }
+  }
+}
+
+/// `CN=client,OU=eng,O=Acme`: the most specific attribute first, as Java prints names.
+fn distinguished_name(name: &X509Name) -> String {
+  let rdns: Vec<_> = name.iter()
+    .map(|rdn| {
+      let attributes: Vec<_> = rdn.iter()
+        .map(|attribute| {
+          let oid = attribute.attr_type();
+          let key = oid2abbrev(oid, oid_registry()).map(str::to_string).unwrap_or_else(|_| oid.to_id_string());
+          let value = match attribute.as_str() {
+            Ok(value) => escape(value),
+            Err(_) => format!("#{}", attribute.attr_value().data.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+          };
+          format!("{}={}", key, value)
+        })
+        .collect();
+      attributes.join("+")
+    })
+    .collect();
+  rdns.into_iter().rev().collect::<Vec<_>>().join(",")
+}
+
+fn escape(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for (i, c) in value.chars().enumerate() {
+    let leading = i == 0 && (c == ' ' || c == '#');
+    let trailing = i == value.chars().count() - 1 && c == ' ';
+    if leading || trailing || matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=') {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod tests {
+  use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair};
+  use tokio_rustls::rustls::pki_types::ServerName;
+  use tokio_rustls::rustls::ClientConfig;
+  use tempfile::TempDir;
+  use tokio_rustls::TlsConnector;
+
+  use super::*;
+  use crate::kafka::test_support::temp_dir;
+
+  /// A CA and PEM files signed by it, in a directory of their own.
+  struct Pki {
+    dir: TempDir,
+    ca: rcgen::Certificate,
+    ca_key: KeyPair,
+  }
+
+  impl Pki {
+    fn new() -> Pki {
+      let dir = temp_dir();
+      let ca_key = KeyPair::generate().unwrap();
+      let mut params = CertificateParams::new(vec![]).unwrap();
+      params.distinguished_name.push(DnType::CommonName, "test CA");
+      params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+      let ca = params.self_signed(&ca_key).unwrap();
+      fs::write(dir.path().join("truststore.pem"), ca.pem()).unwrap();
+      Pki { dir, ca, ca_key }
+    }
+
+    /// A certificate for `names` with `subject`, and its key.
+    fn issue(&self, names: &[&str], subject: &[(DnType, &str)]) -> (rcgen::Certificate, KeyPair) {
+      let key = KeyPair::generate().unwrap();
+      let mut params = CertificateParams::new(names.iter().map(|name| name.to_string()).collect::<Vec<_>>()).unwrap();
+      // The default subject already has a CN, which would keep its place
+      params.distinguished_name = DistinguishedName::new();
+      for (dn_type, value) in subject {
+        params.distinguished_name.push(dn_type.clone(), *value);
+      }
+      (params.signed_by(&key, &self.ca, &self.ca_key).unwrap(), key)
+    }
+
+    /// Writes a keystore for `localhost` and returns its certificate.
+    fn write_keystore(&self) -> CertificateDer<'static> {
+      let (certificate, key) = self.issue(&["localhost"], &[(DnType::CommonName, "broker")]);
+      fs::write(self.dir.path().join("keystore.pem"), format!("{}{}", key.serialize_pem(), certificate.pem())).unwrap();
+      certificate.der().clone()
+    }
+
+    fn config(&self, client_auth: SslClientAuth) -> BrokerConfig {
+      BrokerConfig {
+        ssl_keystore_location: Some(self.dir.path().join("keystore.pem")),
+        ssl_truststore_location: Some(self.dir.path().join("truststore.pem")),
+        ssl_client_auth: client_auth,
+        ..Default::default()
+      }
+    }
+
+    fn connector(&self, client_certificate: Option<(rcgen::Certificate, KeyPair)>) -> TlsConnector {
+      let mut roots = RootCertStore::empty();
+      roots.add(self.ca.der().clone()).unwrap();
+      let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+      let config = match client_certificate {
+        None => builder.with_no_client_auth(),
+        Some((certificate, key)) => {
+          let key = PrivateKeyDer::from_pem_slice(key.serialize_pem().as_bytes()).unwrap();
+          builder.with_client_auth_cert(vec![certificate.der().clone()], key).unwrap()
+        }
+      };
+      TlsConnector::from(Arc::new(config))
+    }
+  }
+
+  /// Handshakes over an in-memory pipe, returning the server's principal for the client and
+  /// the certificate the client was shown.
+  async fn handshake(factory: &SslFactory, connector: &TlsConnector) -> Result<(KafkaPrincipal, CertificateDer<'static>)> {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let acceptor = factory.acceptor();
+    let server = tokio::spawn(async move { acceptor.accept(server).await });
+    let client = connector.connect(ServerName::try_from("localhost").unwrap(), client).await?;
+    let server = server.await??;
+    let shown = client.get_ref().1.peer_certificates().unwrap()[0].clone();
+    Ok((principal(server.get_ref().1), shown))
+  }
+
+  #[tokio::test]
+  async fn maps_the_client_certificate_subject_to_a_principal() {
+    let pki = Pki::new();
+    pki.write_keystore();
+    let factory = SslFactory::new(&pki.config(SslClientAuth::Required)).unwrap();
+
+    let client = pki.issue(&[], &[(DnType::OrganizationName, "Acme, Inc"), (DnType::CommonName, "client")]);
+    let (principal, _) = handshake(&factory, &pki.connector(Some(client))).await.unwrap();
+    assert_eq!(principal.to_string(), "User:CN=client,O=Acme\\, Inc");
+
+    assert!(handshake(&factory, &pki.connector(None)).await.is_err());
+
+    // Requested lets clients without a certificate in, as ANONYMOUS
+    let factory = SslFactory::new(&pki.config(SslClientAuth::Requested)).unwrap();
+    let (principal, _) = handshake(&factory, &pki.connector(None)).await.unwrap();
+    assert_eq!(principal, KafkaPrincipal::anonymous());
+  }
+
+  #[tokio::test]
+  async fn reloads_the_keystore_when_it_changes() {
+    let pki = Pki::new();
+    let first = pki.write_keystore();
+    let factory = SslFactory::new(&pki.config(SslClientAuth::None)).unwrap();
+    let connector = pki.connector(None);
+    assert_eq!(handshake(&factory, &connector).await.unwrap().1, first);
+
+    let second = pki.write_keystore();
+    assert_eq!(handshake(&factory, &connector).await.unwrap().1, second);
+
+    // A broken keystore leaves the last good one in use
+    fs::write(pki.dir.path().join("keystore.pem"), "not a keystore").unwrap();
+    assert_eq!(handshake(&factory, &connector).await.unwrap().1, second);
+  }
+}
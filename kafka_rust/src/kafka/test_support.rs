@@ -0,0 +1,96 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use std::path::Path;
+
+use bytes::{Bytes, BytesMut};
+use tempfile::TempDir;
+
+use crate::kafka::broker::Broker;
+use crate::kafka::codec::{TaggedFields, Uuid};
+use crate::kafka::config::BrokerConfig;
+use crate::kafka::log::LogConfig;
+use crate::kafka::metadata_image::MetadataImage;
+use crate::kafka::metadata_records::{MetadataRecord, PartitionRecord, TopicRecord};
+use crate::kafka::record_batch::{Record, RecordBatch};
+
+/// A fresh directory under the system temp dir, removed with everything in it on drop.
+pub fn temp_dir() -> TempDir {
+  tempfile::Builder::new().prefix("kafka-").tempdir().unwrap()
+}
+
+/// A broker keeping its logs in `log_dirs` that leads every partition of topic "foo"
+/// (id 1) in `leader_epoch`.
+pub fn broker(log_dirs: &Path, partitions: i32, leader_epoch: i32) -> Broker {
+  let mut metadata = MetadataImage::empty();
+  let topic = TopicRecord { name: "foo".to_string(), topic_uuid: Uuid(1), tagged_fields: TaggedFields::default() };
+  metadata.replay(0, MetadataRecord::Topic(topic));
+  for partition_id in 0..partitions {
+    let partition = PartitionRecord { partition_id, topic_id: Uuid(1), replicas: vec![1], isr: vec![1], leader: 1, leader_epoch, ..Default::default() };
+    metadata.replay(1 + partition_id as i64, MetadataRecord::Partition(partition));
+  }
+  Broker::new(BrokerConfig { log_dirs: log_dirs.to_path_buf(), ..Default::default() }, metadata)
+}
+
+/// A log that never rolls, deletes or compacts on its own and indexes every batch.
+pub fn log_config() -> LogConfig {
+  LogConfig {
+    segment_bytes: 1024 * 1024,
+    segment_ms: i64::MAX,
+    index_interval_bytes: 0,
+    segment_index_bytes: 1024,
+    retention_ms: -1,
+    retention_bytes: -1,
+    delete: true,
+    compact: false,
+    delete_retention_ms: 0,
+    min_cleanable_dirty_ratio: 0.5,
+    min_compaction_lag_ms: 0,
+    compression_type: None,
+  }
+}
+
+/// A batch with one record per timestamp, valued "value 0", "value 1", ...
+pub fn timestamp_batch(timestamps: &[i64]) -> BytesMut {
+  let values: Vec<_> = (0..timestamps.len()).map(|i| format!("value {}", i)).collect();
+  let records: Vec<_> = timestamps.iter().zip(&values).map(|(timestamp, value)| (*timestamp, None, Some(value.as_str()))).collect();
+  batch(&records)
+}
+
+/// A batch of `values` without keys, all written at 1000.
+pub fn value_batch(values: &[&str]) -> BytesMut {
+  let records: Vec<_> = values.iter().map(|value| (1_000, None, Some(*value))).collect();
+  batch(&records)
+}
+
+/// A batch of (key, value) records, all written at `timestamp`.
+pub fn keyed_batch(records: &[(&str, Option<&str>)], timestamp: i64) -> BytesMut {
+  let records: Vec<_> = records.iter().map(|(key, value)| (timestamp, Some(*key), *value)).collect();
+  batch(&records)
+}
+
+/// An uncompressed batch of (timestamp, key, value) records as a producer sends it: base
+/// offset 0, no producer id and a valid CRC.
+fn batch(records: &[(i64, Option<&str>, Option<&str>)]) -> BytesMut {
+  let base_timestamp = records[0].0;
+  let bytes = |text: Option<&str>| text.map(|text| Bytes::copy_from_slice(text.as_bytes()));
+  RecordBatch {
+    last_offset_delta: records.len() as i32 - 1,
+    base_timestamp,
+    max_timestamp: records.iter().map(|(timestamp, _, _)| *timestamp).max().unwrap(),
+    producer_id: -1,
+    producer_epoch: -1,
+    base_sequence: -1,
+    records: records.iter()
+      .enumerate()
+      .map(|(i, (timestamp, key, value))| Record {
+        offset_delta: i as i32,
+        timestamp_delta: timestamp - base_timestamp,
+        key: bytes(*key),
+        value: bytes(*value),
+        ..Default::default()
+      })
+      .collect(),
+    ..Default::default()
+  }
+  .encode()
+}